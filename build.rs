@@ -3,5 +3,6 @@ extern crate lalrpop;
 fn main() {
     lalrpop::Configuration::new()
         .generate_in_source_tree()
-        .process();
+        .process()
+        .unwrap();
 }
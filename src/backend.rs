@@ -0,0 +1,412 @@
+use crate::{state::Get, Cell, IrOp, Program, TEMP5, TEMP6};
+
+/// Generates code for a target from the operations a program is lowered to.
+/// `Program::compile_with_backend` lowers a program, calls the method for
+/// each operation in order, and then takes the code from `finish`.
+pub trait Backend {
+    /// Clear `size` cells that a value is about to use
+    fn alloc(&mut self, at: Cell, size: u32) {
+        self.zero(at, size)
+    }
+
+    /// Clear `size` cells that a value is done with
+    fn free(&mut self, at: Cell, size: u32) {
+        self.zero(at, size)
+    }
+
+    fn zero(&mut self, at: Cell, size: u32);
+    fn set(&mut self, at: Cell, value: usize);
+
+    /// Store the characters of a string in the cells, followed by a cell holding 1
+    fn set_string(&mut self, at: Cell, text: &str);
+
+    /// Move data between values, one cell at a time
+    fn copy(&mut self, to: Cell, from: Cell, size: u32);
+    fn add(&mut self, to: Cell, from: Cell, size: u32);
+    fn sub(&mut self, to: Cell, from: Cell, size: u32);
+
+    /// Store the address of `of` in `to`
+    fn refer(&mut self, to: Cell, of: Cell);
+
+    /// Allocate cells on the heap and store their address in `to`. With a size,
+    /// the cells are marked as used, and without one, `to` holds the number of cells.
+    fn heap_alloc(&mut self, to: Cell, size: Option<u32>);
+
+    /// Begin code that runs while the cell is not zero
+    fn loop_begin(&mut self, at: Cell);
+    fn loop_end(&mut self, at: Cell);
+
+    /// Begin code that runs if the cell is not zero
+    fn if_begin(&mut self, at: Cell);
+    /// Begin code that runs if the cell given to `if_begin` was zero
+    fn else_begin(&mut self);
+    fn if_end(&mut self);
+
+    /// Print each of `size` cells as a character
+    fn print(&mut self, at: Cell, size: u32);
+    /// Print the string the cell points to, which ends at a cell holding 1
+    fn print_cstr(&mut self, at: Cell);
+    /// Read a character into the cell, which is unchanged at the end of the input
+    fn read(&mut self, at: Cell);
+
+    /// Stop the program, with the exit status in `status`. By default, the cell
+    /// is set to 1 and looped on forever.
+    fn halt(&mut self, at: Cell, _status: Cell) {
+        self.set(at, 1);
+        self.loop_begin(at);
+        self.loop_end(at);
+    }
+
+    fn bind(&mut self, _name: &str, _at: Cell) {}
+    fn function_begin(&mut self, _name: &str) {}
+    fn function_end(&mut self, _name: &str) {}
+
+    /// Return the code generated so far, and start over
+    fn finish(&mut self) -> String;
+
+    /// Generate the code for an operation
+    fn emit(&mut self, op: &IrOp) {
+        match op {
+            IrOp::Alloc { at, size } => self.alloc(*at, *size),
+            IrOp::Free { at, size } => self.free(*at, *size),
+            IrOp::Zero { at, size } => self.zero(*at, *size),
+            IrOp::Set { at, value } => self.set(*at, *value),
+            IrOp::SetString { at, text } => self.set_string(*at, text),
+            IrOp::Copy { to, from, size } => self.copy(*to, *from, *size),
+            IrOp::Add { to, from, size } => self.add(*to, *from, *size),
+            IrOp::Sub { to, from, size } => self.sub(*to, *from, *size),
+            IrOp::Refer { to, of } => self.refer(*to, *of),
+            IrOp::HeapAlloc { to, size } => self.heap_alloc(*to, *size),
+            IrOp::LoopBegin(at) => self.loop_begin(*at),
+            IrOp::LoopEnd(at) => self.loop_end(*at),
+            IrOp::IfBegin(at) => self.if_begin(*at),
+            IrOp::Else => self.else_begin(),
+            IrOp::IfEnd => self.if_end(),
+            IrOp::Print { at, size } => self.print(*at, *size),
+            IrOp::PrintCStr(at) => self.print_cstr(*at),
+            IrOp::Read(at) => self.read(*at),
+            IrOp::Halt { at, status } => self.halt(*at, *status),
+            IrOp::Bind { name, at } => self.bind(name, *at),
+            IrOp::FnBegin(name) => self.function_begin(name),
+            IrOp::FnEnd(name) => self.function_end(name),
+        }
+    }
+}
+
+/// The default target, which generates the same SMPL instructions as `Program::compile`.
+/// The code has no comments, and the optimization passes that work on instructions aren't run.
+#[derive(Default)]
+pub struct SmplBackend {
+    code: String,
+}
+
+impl SmplBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Backend for SmplBackend {
+    fn zero(&mut self, at: Cell, size: u32) {
+        self.code += &at.zero_code(size);
+    }
+
+    fn set(&mut self, at: Cell, value: usize) {
+        self.code += &at.set_code(value);
+    }
+
+    fn set_string(&mut self, at: Cell, text: &str) {
+        self.code += &at.string_code(text);
+    }
+
+    fn copy(&mut self, to: Cell, from: Cell, size: u32) {
+        self.code += &to.transfer_code(from, size, '+', true);
+    }
+
+    fn add(&mut self, to: Cell, from: Cell, size: u32) {
+        self.code += &to.transfer_code(from, size, '+', false);
+    }
+
+    fn sub(&mut self, to: Cell, from: Cell, size: u32) {
+        self.code += &to.transfer_code(from, size, '-', false);
+    }
+
+    fn refer(&mut self, to: Cell, of: Cell) {
+        self.code += &to.add_code(of.offset as usize);
+    }
+
+    fn heap_alloc(&mut self, to: Cell, size: Option<u32>) {
+        self.code += &match size {
+            Some(size) => to.heap_alloc_code(size),
+            None => to.variable_heap_alloc_code(),
+        };
+    }
+
+    fn loop_begin(&mut self, at: Cell) {
+        self.code += &at.loop_begin_code();
+    }
+
+    fn loop_end(&mut self, at: Cell) {
+        self.code += &at.loop_end_code();
+    }
+
+    /// The then branch is a loop on a copy of the condition, and the else
+    /// branch is a loop on a flag that the then branch clears
+    fn if_begin(&mut self, at: Cell) {
+        self.code += &TEMP5.get().cell().transfer_code(at, 1, '+', true);
+        self.code += &TEMP6.get().cell().set_code(1);
+        self.code += &TEMP5.get().cell().loop_begin_code();
+    }
+
+    fn else_begin(&mut self) {
+        self.code += &TEMP5.get().cell().zero_code(1);
+        self.code += &TEMP6.get().cell().zero_code(1);
+        self.code += &TEMP5.get().cell().loop_end_code();
+        self.code += &TEMP6.get().cell().loop_begin_code();
+    }
+
+    fn if_end(&mut self) {
+        self.code += &TEMP6.get().cell().zero_code(1);
+        self.code += &TEMP6.get().cell().loop_end_code();
+    }
+
+    fn print(&mut self, at: Cell, size: u32) {
+        self.code += &at.print_code(size);
+    }
+
+    fn print_cstr(&mut self, at: Cell) {
+        self.code += &at.print_cstr_code();
+    }
+
+    fn read(&mut self, at: Cell) {
+        self.code += &at.read_code();
+    }
+
+    fn halt(&mut self, at: Cell, _status: Cell) {
+        self.code += &at.halt_code();
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.code)
+    }
+}
+
+/// Generates a self-contained C file, where each cell of the tape is an
+/// element of an array. The cells are as wide as the program's cell size.
+#[derive(Default)]
+pub struct CBackend {
+    body: String,
+    /// The number of blocks the next line is nested in
+    depth: usize,
+}
+
+impl CBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn line(&mut self, text: impl AsRef<str>) {
+        self.body += &"    ".repeat(self.depth + 1);
+        self.body += text.as_ref();
+        self.body.push('\n');
+    }
+
+    /// The C expression for the address of a cell, `index` cells after `at`
+    fn address(at: Cell, index: &str) -> String {
+        if at.depth == 0 {
+            if let Ok(index) = index.parse::<u32>() {
+                return (at.offset + index).to_string();
+            }
+        }
+        let mut address = at.offset.to_string();
+        for _ in 0..at.depth {
+            address = format!("tape[{}]", address);
+        }
+        match index {
+            "0" => address,
+            index => format!("{} + {}", address, index),
+        }
+    }
+
+    /// The C expression for a cell, `index` cells after `at`
+    fn cell(at: Cell, index: &str) -> String {
+        format!("tape[{}]", Self::address(at, index))
+    }
+
+    /// Write a statement for each of `size` cells, which is given the index of the cell
+    fn each_cell(&mut self, size: u32, statement: impl Fn(&str) -> String) {
+        match size {
+            0 => {}
+            1 => self.line(statement("0")),
+            size => self.line(format!(
+                "for (unsigned int i = 0; i < {}; i++) {}",
+                size,
+                statement("i")
+            )),
+        }
+    }
+
+    fn block_begin(&mut self, text: impl AsRef<str>) {
+        self.line(text);
+        self.depth += 1;
+    }
+
+    fn block_end(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        self.line("}");
+    }
+
+    fn prelude() -> String {
+        format!(
+            r#"#include <stdio.h>
+
+#define TAPE_SIZE {TAPE_SIZE}
+#define MAX_ADDRESS {MAX_ADDRESS}
+#define HEAP_START {HEAP_START}
+
+typedef {CELL_TYPE} cell;
+cell tape[TAPE_SIZE];
+
+/* Allocate `size` cells on the heap, and return the address they start at, or 0 if there
+   is no room. The cell before each block holds its size, so the blocks are walked from the
+   start of the heap, and the cells between them are zero. */
+unsigned int allocate(unsigned int size) {{
+    if (size == 0) {{ size = 1; }}
+    unsigned int start = HEAP_START;
+    unsigned int i = start;
+    while (i < MAX_ADDRESS) {{
+        if (tape[i] == 0) {{
+            i++;
+            if (i - start == size + 1) {{ tape[start] = size; return start + 1; }}
+        }} else {{
+            i += tape[i] + 1;
+            start = i;
+        }}
+    }}
+    return 0;
+}}
+
+/* Print the string at an address, which ends at a cell holding 1 */
+void print_cstr(unsigned int at) {{
+    while (tape[at] != 1) {{ putchar(tape[at++] % 256); }}
+}}
+
+/* Read a character into a cell, which is unchanged at the end of the input */
+void read_cell(cell *at) {{
+    int ch = getchar();
+    if (ch != EOF) {{ *at = ch; }}
+}}
+
+int main() {{
+"#,
+            TAPE_SIZE = Program::tape_size(),
+            MAX_ADDRESS = Program::max_address(),
+            HEAP_START = Program::heap_start(),
+            CELL_TYPE = Program::cell_width().c_type()
+        )
+    }
+}
+
+impl Backend for CBackend {
+    fn zero(&mut self, at: Cell, size: u32) {
+        self.each_cell(size, |i| format!("{} = 0;", Self::cell(at, i)));
+    }
+
+    fn set(&mut self, at: Cell, value: usize) {
+        self.line(format!("{} = {};", Self::cell(at, "0"), value));
+    }
+
+    fn set_string(&mut self, at: Cell, text: &str) {
+        let mut count = 0;
+        for (i, byte) in text.bytes().enumerate() {
+            self.line(format!("{} = {};", Self::cell(at, &i.to_string()), byte));
+            count += 1;
+        }
+        self.line(format!("{} = 1;", Self::cell(at, &count.to_string())));
+    }
+
+    fn copy(&mut self, to: Cell, from: Cell, size: u32) {
+        self.each_cell(size, |i| format!("{} = {};", Self::cell(to, i), Self::cell(from, i)));
+    }
+
+    fn add(&mut self, to: Cell, from: Cell, size: u32) {
+        self.each_cell(size, |i| format!("{} += {};", Self::cell(to, i), Self::cell(from, i)));
+    }
+
+    fn sub(&mut self, to: Cell, from: Cell, size: u32) {
+        self.each_cell(size, |i| format!("{} -= {};", Self::cell(to, i), Self::cell(from, i)));
+    }
+
+    fn refer(&mut self, to: Cell, of: Cell) {
+        self.line(format!("{} = {};", Self::cell(to, "0"), of.offset));
+    }
+
+    fn heap_alloc(&mut self, to: Cell, size: Option<u32>) {
+        let pointer = Self::cell(to, "0");
+        match size {
+            Some(size) => {
+                self.line(format!("{} = allocate({});", pointer, size));
+                let cells = Cell {
+                    offset: to.offset,
+                    depth: to.depth + 1,
+                };
+                self.each_cell(size, |i| format!("{}++;", Self::cell(cells, i)));
+            }
+            None => self.line(format!("{} = allocate({});", pointer, pointer)),
+        }
+    }
+
+    fn loop_begin(&mut self, at: Cell) {
+        self.block_begin(format!("while ({}) {{", Self::cell(at, "0")));
+    }
+
+    fn loop_end(&mut self, _at: Cell) {
+        self.block_end();
+    }
+
+    fn if_begin(&mut self, at: Cell) {
+        self.block_begin(format!("if ({}) {{", Self::cell(at, "0")));
+    }
+
+    fn else_begin(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        self.block_begin("} else {");
+    }
+
+    fn if_end(&mut self) {
+        self.block_end();
+    }
+
+    fn print(&mut self, at: Cell, size: u32) {
+        self.each_cell(size, |i| format!("putchar({} % 256);", Self::cell(at, i)));
+    }
+
+    fn print_cstr(&mut self, at: Cell) {
+        self.line(format!("print_cstr({});", Self::cell(at, "0")));
+    }
+
+    fn read(&mut self, at: Cell) {
+        self.line(format!("read_cell(&{});", Self::cell(at, "0")));
+    }
+
+    fn halt(&mut self, _at: Cell, status: Cell) {
+        self.line(format!("return {} % 256;", Self::cell(status, "0")));
+    }
+
+    fn bind(&mut self, name: &str, at: Cell) {
+        self.line(format!("/* {} is {} */", name, Self::cell(at, "0")));
+    }
+
+    fn function_begin(&mut self, name: &str) {
+        self.line(format!("/* fn {} */", name));
+    }
+
+    fn function_end(&mut self, name: &str) {
+        self.line(format!("/* end fn {} */", name));
+    }
+
+    fn finish(&mut self) -> String {
+        self.depth = 0;
+        Self::prelude() + &std::mem::take(&mut self.body) + "    return 0;\n}\n"
+    }
+}
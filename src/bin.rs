@@ -1,7 +1,7 @@
-use fr::{Simplify, C, Error, Program};
+use fr::{stdlib, Simplify, C, Error, Flag, Program};
 use clap::{clap_app, crate_version,  AppSettings};
 use std::{
-	fs::{read_to_string, write},
+	fs::write,
 	process::exit
 };
 
@@ -17,6 +17,8 @@ fn main() -> Result<(), Error> {
 		(about: "Compiles code written in the Free programming language")
 		(@arg input: +takes_value +required "Path to free file to compile")
 		(@arg output: +takes_value "Path to output file")
+		(@arg optimize: -O --optimize +takes_value "Optimization level from 0 to 2 (defaults to 1)")
+		(@arg debug: -g --debug "Annotate the SMPL output with comments saying what code it came from")
 		// (@arg leak_check: --leakcheck "Add memory leak checks")
 		(@group target => 
 			(@arg c: -c --c "Compile to C")
@@ -30,8 +32,6 @@ fn main() -> Result<(), Error> {
 	let target = if matches.is_present("bf") { Target::BrainFuck }
 				 else { Target::C };
 
-	let optimization = 10;
-
 	// let leak_check = matches.is_present("leak_check");
 
 	let output_file = match matches.value_of("output") {
@@ -44,44 +44,54 @@ fn main() -> Result<(), Error> {
 
 
 	if let Some(file) = matches.value_of("input") {
-		if let Ok(contents) = read_to_string(file) {
-			let compiled = optimize(match Program::from(contents).compile() {
-				Ok(c) => c,
-				Err(e) => {
-					println!("Could not compile program: {:#?}", e);
-					exit(1);
-				}
-			}, optimization);
-
-			let output_contents = match target {
-				Target::C => C::simplify(compiled),
-				Target::BrainFuck => compiled
-			};
-
-			if let Ok(_) = write(&output_file, &output_contents) {
-				println!("Successfully compiled program to {}", output_file);
+		let program = match Program::from_file(file) {
+			Ok(program) => program,
+			Err(e) => {
+				println!("Could not read program: {:#?}", e);
+				exit(1);
+			}
+		};
+		stdlib::install();
+
+		// The command line overrides the optimization level from the source,
+		// and programs that don't set one are optimized a little
+		let source_level = program.flags().iter().any(|flag| matches!(flag, Flag::Optimize(_)));
+		match matches.value_of("optimize").map(str::parse) {
+			Some(Ok(level)) => Program::set_optimize_level(level),
+			Some(Err(_)) => {
+				println!("Invalid optimization level");
+				exit(1);
 			}
+			None if !source_level => Program::set_optimize_level(1),
+			None => {}
 		}
-	}
-	
-    Ok(())
-}
 
+		if matches.is_present("debug") {
+			Program::set_debug_comments(true);
+		}
 
-pub fn optimize(s: impl ToString, level: usize) -> String {
-    let mut compiled = s.to_string().chars().filter(|ch| ['>', '<', ',', '.', '[', ']', '+', '-', '*', '?', '&'].contains(ch)).collect::<String>();
-    let original_len = compiled.len();
+		let compiled = match program.compile() {
+			Ok(c) => c,
+			Err(e) => {
+				println!("Could not compile program: {}", e);
+				exit(1);
+			}
+		};
 
-    for n in 1..level+1 {
-        let to = ">".repeat(n);
-        let back = "<".repeat(n);
+		for warning in Program::warnings() {
+			eprintln!("Warning: {}", warning);
+		}
+
+		let output_contents = match target {
+			Target::C => C::simplify(compiled),
+			Target::BrainFuck => compiled
+		};
 
-        let move1 = to.clone() + &back;
-        let move2 = back + &to;
-        for _ in 0..10 {       	
-	        compiled = compiled.replace(&move1, "").replace(&move2, "");
-        }
-    }
+		if write(output_file, &output_contents).is_ok() {
+			println!("Successfully compiled program to {}", output_file);
+		}
+	}
+	
+    Ok(())
+}
 
-    compiled
-}
\ No newline at end of file
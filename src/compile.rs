@@ -1,566 +1,4941 @@
 use crate::{
-    STACK_SIZE, HEAP_SIZE, add_to_compiled, set_stack, compile, init, Control, Env, ProgramParser, Stdout, Value, RETURN, STACK_PTR,
+    Backend, calls_in_body, fn_refs_in_body, lint, LINTS, enabled_passes, rename_calls, Pass, fold, substitute, variables_in_body, variables_in_eval, defines, MAX_OPTIMIZE_LEVEL, TAPE_SIZE, HEAP_SIZE, Allocation, set_stack, forget_zero_cells, add_to_compiled, take_compiled, reset_tape, emit_ir, record_ir, IrOp, INSTRUCTIONS, compile, init, instruction_count, peak_stack_ptr,
+    reset_peak_stack_ptr, Control, Env, FlagParser, ProgramParser, Stdout, Value, ValueKind, RETURN, STACK_PTR,
+    end_capture, ir_len, ir_since, start_capture, Fold, Visit, walk_eval, walk_expr, TapeState, add_allocations, allocation_count, take_holes, restore_holes,
+    reachable_functions, repeated_strings, state::Get, NumberKind, WIDE_DIGITS, operands, sum_digits, add_numbers, sub_numbers, neg_number,
+    cast_number, less_than, mul_numbers, div_numbers, lower_as, fold_fixed, FIXED_RANGE,
 };
-use comment::rust::strip;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use lalrpop_util::ParseError;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
-lazy_static! {
+compiler_state! {
     /// This is used to manage variable definitions within scopes.
     /// When a function is called, a scope is pushed on the scope stack,
     /// and all new definitions are contained in the new scope.
     /// When the function call finishes, the scope is popped and freed.
-    pub static ref SCOPE_STACK: Mutex<Vec<Env>> = Mutex::new(vec![Env::new()]);
+    pub static SCOPE_STACK: Vec<Env> = vec![Env::new()];
+    /// The constants defined in each scope on the scope stack. Constants
+    /// are substituted where they are used, so they don't take any cells.
+    static CONSTANTS: Vec<HashMap<String, Literal>> = vec![HashMap::new()];
 
     /// If the user enables brainfuck compatibility mode, this flag is set
-    static ref ENABLE_BRAINFUCK: Mutex<bool> = Mutex::new(false);
+    static ENABLE_BRAINFUCK: bool = false;
     /// If the user enables size warnings, this flag is set
-    static ref ENABLE_SIZE_WARN: Mutex<bool> = Mutex::new(false);
+    static ENABLE_SIZE_WARN: bool = false;
+    /// If the output is annotated with comments saying what code it came from, this flag is set
+    static DEBUG_COMMENTS: bool = false;
+    /// If the user disables pointers in their own code, this flag is set
+    static DISABLE_PTRS: bool = false;
+    /// If asserts are compiled to nothing, this flag is set
+    static STRIP_ASSERTS: bool = false;
+    /// If arithmetic stops the program when it overflows instead of wrapping, this flag is set
+    static OVERFLOW_CHECKS: bool = false;
+    /// If indexing with a runtime index stops the program when the index is past the end, this flag is set
+    static BOUNDS_CHECKS: bool = false;
+    /// If strings are stored as their UTF-8 bytes, so they may have characters that aren't ASCII, this flag is set
+    static UTF8_STRINGS: bool = false;
+    /// If every use of a string literal gets its own cells, this flag is set
+    static DISABLE_INTERNING: bool = false;
+    /// The width of each cell on the tape of the target machine
+    static CELL_WIDTH: CellWidth = CellWidth::Sixteen;
+    /// The value a cell holds after reading from input that has ended
+    static EOF_VALUE: u8 = 0;
+    /// How many calls to a function can be compiled inside a call to the same function
+    static RECURSION_LIMIT: u32 = DEFAULT_RECURSION_LIMIT;
+    /// The optimization level, which decides which passes are run
+    static OPTIMIZE_LEVEL: u8 = 0;
+    /// If functions may replace previously defined functions, this flag is set
+    static ALLOW_REDEFINE: bool = false;
+    /// The name of the function the program starts in
+    static ENTRY: String = ENTRY_POINT.to_string();
+    /// While a library is compiled, there is no entry point, so this flag is set
+    static LIBRARY_MODE: bool = false;
 
-    /// This hashmap contains all the user defined functions for the program
-    static ref FN_DEFS: Mutex<HashMap<String, UserFn>> = Mutex::new(HashMap::new());
-    /// This hashmap contains all the compiler defined functions for the program
-    static ref FOREIGN_FN_DEFS: Mutex<HashMap<String, ForeignFn>> = Mutex::new(HashMap::new());
+    /// The functions currently being compiled, from the outermost call to
+    /// the innermost, each with the span of the statement that called it
+    static CALL_STACK: Vec<Frame> = vec![];
+
+    /// Where compile events are reported, if anywhere
+    static DIAGNOSTIC_SINK: Option<Box<dyn DiagnosticSink>> = None;
+    /// Where compiled code is written as it is compiled, if anywhere
+    static OUTPUT: Option<Box<dyn Write + Send>> = None;
+    /// The warnings produced while compiling the program
+    static WARNINGS: Vec<Warning> = vec![];
+    /// The lints that don't produce warnings
+    static ALLOWED_LINTS: Vec<String> = vec![];
+    /// The functions that are used in ways the compiler can't see,
+    /// such as by foreign functions, so they are never unused
+    static USED_FNS: Vec<String> = vec![];
+    /// The size statistics for each user defined function, in the order they were first called
+    static SIZE_REPORT: Vec<FnSize> = vec![];
+    /// The number of instructions a function may generate before a size warning is emitted
+    static SIZE_WARN_THRESHOLD: usize = 10000;
+
+    /// This hashmap contains all the user defined functions for the program,
+    /// with every overload of a name. Functions are shared so a call can hold
+    /// on to its function while the table is changed by the function's own body.
+    static FN_DEFS: HashMap<String, Vec<Arc<UserFn>>> = HashMap::new();
+    /// This hashmap contains all the compiler defined functions for the program, with every overload of a name
+    static FOREIGN_FN_DEFS: HashMap<String, Vec<ForeignFn>> = HashMap::new();
+    /// The source code of every program that has been parsed, so spans can be
+    /// turned into lines and columns. The last source is the one being parsed.
+    static SOURCES: Vec<Source> = vec![];
+    /// The span of the statement being compiled
+    static LOCATION: Option<Span> = None;
+
+    /// The struct definitions for the program. Values refer to
+    /// their struct by its index in this table.
+    static STRUCT_DEFS: Vec<Struct> = vec![];
+    /// The variables in the outermost scope that hold the string literals
+    /// used more than once, by the text of the string
+    static INTERNED: HashMap<String, String> = HashMap::new();
+
+    /// The number of temporary variables named so far. Every temporary gets
+    /// a name no other variable has had, so defining one never frees another.
+    static TEMP_COUNTER: u64 = 0;
+
+    /// The code compiled for calls to user defined functions, by the hash
+    /// of everything the code depends on, when compiling incrementally
+    static FN_CACHE: Option<HashMap<u64, CachedCall>> = None;
+    /// The fingerprint of each function that has been called, by name
+    static FINGERPRINTS: HashMap<String, u64> = HashMap::new();
+    /// The function sizes recorded since each capture started, the innermost capture last
+    static SIZE_CAPTURES: Vec<Vec<FnSize>> = vec![];
+    /// The number of calls compiled so far
+    static CALL_COUNT: usize = 0;
+    /// The instructions generated for the calls to user defined functions
+    /// made by each call being compiled, the innermost call last
+    static NESTED_INSTRUCTIONS: Vec<usize> = vec![];
+    /// If the last function called was a foreign function that returned a character, this is set
+    static RETURNED_CHARACTER: bool = false;
+    /// How the number the last foreign function called returned is read
+    static RETURNED_NUMBER: NumberKind = NumberKind::Unsigned;
+    /// The cells in the caller's frame that the 4 byte number the last foreign
+    /// function called returned was moved to, since it doesn't fit in RETURN
+    static RETURNED_WIDE: Option<Value> = None;
+    /// The cells in the caller's frame that each call returning a tuple
+    /// writes its values into, with the depth of the call on the call stack
+    static RETURN_AREAS: Vec<(usize, Value)> = vec![];
+    /// The copies of the values calls returned, and the other values made while
+    /// lowering an expression that nothing names, each numbered like a temporary,
+    /// which are freed with the temporaries of the statement that used them
+    static RETURN_TEMPS: Vec<(u64, Value)> = vec![];
+    /// The functions whose references are taken anywhere in the program. A
+    /// reference to a function is one more than its place in this list.
+    static FN_REFS: Vec<String> = vec![];
 }
 
-/// Generate a random string, used for naming temporary variables
-fn rand_str() -> String {
-    thread_rng().sample_iter(&Alphanumeric).take(30).collect()
+/// Forget every program compiled on this thread, so the next program is
+/// compiled as if it were the first. The functions, variables, structs,
+/// warnings, and compiled code are removed, and the settings go back to
+/// their defaults, but the diagnostic sink, the output, and the code kept
+/// for compiling incrementally are kept.
+/// Parsing a program sets its flags, so this is called before the next
+/// program is parsed, and the foreign functions it uses are installed
+/// again afterwards, such as with `stdlib::install`.
+pub fn reset() {
+    SCOPE_STACK.set(vec![Env::new()]);
+    CONSTANTS.set(vec![HashMap::new()]);
+
+    ENABLE_BRAINFUCK.set(false);
+    ENABLE_SIZE_WARN.set(false);
+    DEBUG_COMMENTS.set(false);
+    DISABLE_PTRS.set(false);
+    STRIP_ASSERTS.set(false);
+    OVERFLOW_CHECKS.set(false);
+    BOUNDS_CHECKS.set(false);
+    UTF8_STRINGS.set(false);
+    DISABLE_INTERNING.set(false);
+    CELL_WIDTH.set(CellWidth::Sixteen);
+    EOF_VALUE.set(0);
+    RECURSION_LIMIT.set(DEFAULT_RECURSION_LIMIT);
+    OPTIMIZE_LEVEL.set(0);
+    ALLOW_REDEFINE.set(false);
+    ENTRY.set(ENTRY_POINT.to_string());
+    LIBRARY_MODE.set(false);
+
+    CALL_STACK.with_borrow_mut(|call_stack| call_stack.clear());
+    WARNINGS.with_borrow_mut(|warnings| warnings.clear());
+    ALLOWED_LINTS.with_borrow_mut(|allowed_lints| allowed_lints.clear());
+    USED_FNS.with_borrow_mut(|used_fns| used_fns.clear());
+    SIZE_REPORT.with_borrow_mut(|size_report| size_report.clear());
+    SIZE_WARN_THRESHOLD.set(10000);
+
+    FN_DEFS.with_borrow_mut(|fn_defs| fn_defs.clear());
+    FOREIGN_FN_DEFS.with_borrow_mut(|foreign| foreign.clear());
+    SOURCES.with_borrow_mut(|sources| sources.clear());
+    LOCATION.set(None);
+    STRUCT_DEFS.with_borrow_mut(|struct_defs| struct_defs.clear());
+    INTERNED.with_borrow_mut(|interned| interned.clear());
+    TEMP_COUNTER.set(0);
+    FINGERPRINTS.with_borrow_mut(|fingerprints| fingerprints.clear());
+    SIZE_CAPTURES.with_borrow_mut(|size_captures| size_captures.clear());
+    CALL_COUNT.set(0);
+    NESTED_INSTRUCTIONS.with_borrow_mut(|nested| nested.clear());
+    RETURNED_CHARACTER.set(false);
+    RETURNED_NUMBER.set(NumberKind::Unsigned);
+    RETURNED_WIDE.set(None);
+    RETURN_AREAS.with_borrow_mut(|return_areas| return_areas.clear());
+    RETURN_TEMPS.with_borrow_mut(|return_temps| return_temps.clear());
+    FN_REFS.with_borrow_mut(|fn_refs| fn_refs.clear());
+
+    reset_tape();
 }
 
-/// This object manages compiling the program, and setting the enabled flags.
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Program(Vec<Flag>, Vec<UserFn>);
+/// Generate a unique name for a temporary variable
+pub fn temp_name() -> String {
+    format!("%TEMP#{}%", TEMP_COUNTER.replace(TEMP_COUNTER.get() + 1))
+}
 
-impl<T: ToString> From<T> for Program {
-    fn from(t: T) -> Self {
-        match ProgramParser::new().parse(&strip(t.to_string()).unwrap()) {
-            Ok(val) => val,
-            Err(e) => panic!("{:#?}", e),
-        }
+/// The number in a temporary variable's name, or None for any other name
+fn temp_number(name: &str) -> Option<u64> {
+    name.strip_prefix("%TEMP#")?.strip_suffix('%')?.parse().ok()
+}
+
+/// Free the temporaries in the current scope that were named since `first`,
+/// and the values returned to them
+fn free_temporaries(first: u64) -> Result<(), Error> {
+    with_scope(|scope| scope.free_matching(|name| temp_number(name).is_some_and(|n| n >= first)))?;
+
+    let (returned, kept): (Vec<_>, Vec<_>) = RETURN_TEMPS.take().into_iter().partition(|&(n, _)| n >= first);
+    RETURN_TEMPS.set(kept);
+    for (_, val) in returned.iter().rev() {
+        val.discard();
     }
+    Ok(())
 }
 
-impl Program {
-    /// Create new Program object
-    pub fn new(flags: Vec<Flag>, funs: Vec<UserFn>) -> Self {
-        // Set the flags for the compiler
-        for flag in &flags {
-            match flag {
-                Flag::EnableBrainFuck => *ENABLE_BRAINFUCK.lock().unwrap() = true,
-                Flag::EnableSizeWarn => *ENABLE_SIZE_WARN.lock().unwrap() = true,
-            }
+/// Take a value out of the temporaries of the current statement, if it is one and
+/// nothing else has its cells, so it outlives the statement instead of being freed
+fn take_temporary(val: &Value) -> Result<bool, Error> {
+    let taken = with_scope(|scope| {
+        let owners: Vec<String> = scope
+            .iter()
+            .filter(|(_, owned)| owned.same_cells(val))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        match owners.as_slice() {
+            [name] if temp_number(name).is_some() => scope.take(name).is_some(),
+            _ => false,
         }
-        // Return self
-        Self(flags, funs)
+    })?;
+    if taken {
+        return Ok(true);
     }
 
-    /// Instantiate compiler functions
-    fn prelude() {
-        init();
+    let returned = RETURN_TEMPS.with_borrow(|temps| temps.iter().position(|(_, returned)| returned.same_cells(val)));
+    match returned {
+        Some(i) => {
+            RETURN_TEMPS.with_borrow_mut(|temps| temps.remove(i));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
 
-        // Add lhs and rhs
-        deforfun("add", &["a", "b"], || {
-            get("a")?.plus_eq(get("b")?);
-            set_return(get("a")?)?;
-            Ok(())
-        });
+/// Run `f`, then free every temporary it defined in the current scope
+fn with_temporaries(f: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    let first = TEMP_COUNTER.get();
+    f()?;
+    free_temporaries(first)
+}
 
-        // Subtract rhs from lhs
-        deforfun("sub", &["a", "b"], || {
-            get("a")?.minus_eq(get("b")?);
-            set_return(get("a")?)?;
-            Ok(())
-        });
-        
-        // Print function
-        deforfun("print", &["a"], || {
-            Stdout::print(get("a")?);
-            Ok(())
-        });
+/// The name of the function that programs start in, unless another is chosen
+pub const ENTRY_POINT: &str = "start";
 
-        // Println function
-        deforfun("println", &["a"], || {
-            Stdout::print(get("a")?);
-            Stdout::print(Eval::Literal(Literal::character('\n')).lower()?);
-            Ok(())
-        });
-        
-        // Print function
-        deforfun("cprint", &["a"], || {
-            Stdout::print_cstr(get("a")?)?;
-            Ok(())
-        });
+/// How deep a function can call itself, unless another limit is chosen. Compiling
+/// each call takes some of the compiler's own stack, so this is kept low enough for
+/// the small stacks of threads other than the main thread.
+pub const DEFAULT_RECURSION_LIMIT: u32 = 32;
 
-        // Println function
-        deforfun("cprintln", &["a"], || {
-            Stdout::print_cstr(get("a")?)?;
-            Stdout::print(Eval::Literal(Literal::character('\n')).lower()?);
-            Ok(())
-        });
+/// Record a warning to be reported after compilation, and report it to
+/// the diagnostic sink, unless the lint it belongs to is allowed
+pub fn warn(warning: impl Into<Warning>) {
+    let warning = warning.into();
+    if warning.lint().is_some_and(Program::lint_allowed) {
+        return;
+    }
+    report(|depth| CompileEvent::Warn { warning: warning.clone(), depth });
+    WARNINGS.with_borrow_mut(|warnings| warnings.push(warning));
+}
 
-        // Allocate `size` number of bytes
-        deforfun("alloc", &["size"], || {
-            define("ptr", Eval::Value(Value::variable_alloc(get("size")?)?))?;
-            set_return(get("ptr")?)?;
-            Ok(())
-        });
+/// Debug comments are written on their own line, starting with this
+const DEBUG_COMMENT: &str = "\n# ";
 
-        // Free a byte at ptr
-        deforfun("free_byte", &["ptr"], || {
-            get("ptr")?.deref()?.free();
-            Ok(())
-        });
+/// Write a comment into the output saying what code the instructions
+/// after it came from, if debug comments are enabled. Instruction
+/// characters in the comment are replaced, so it never changes the program.
+pub fn debug_comment(text: impl ToString) {
+    if Program::debug_comments_enabled() {
+        let text: String = text
+            .to_string()
+            .chars()
+            .map(|ch| if INSTRUCTIONS.contains(&ch) || ch == '\n' { '_' } else { ch })
+            .collect();
+        add_to_compiled(format!("{}{}\n", DEBUG_COMMENT, text));
     }
+}
 
-    /// Is brainfuck compatibility mode enabled?
-    pub fn brainfuck_enabled() -> bool {
-        *ENABLE_BRAINFUCK.lock().unwrap()
+/// Split compiled code into the code between debug comments, and the debug
+/// comments themselves. Each part is paired with whether it is a comment.
+fn debug_parts(code: &str) -> Vec<(&str, bool)> {
+    let mut parts = vec![];
+    let mut rest = code;
+    while let Some(start) = rest.find(DEBUG_COMMENT) {
+        let end = match rest[start + 1..].find('\n') {
+            Some(i) => start + i + 2,
+            None => rest.len(),
+        };
+        parts.push((&rest[..start], false));
+        parts.push((&rest[start..end], true));
+        rest = &rest[end..];
     }
+    parts.push((rest, false));
+    parts
+}
 
-    /// Are size warnings enabled?
-    pub fn size_warn_enabled() -> bool {
-        *ENABLE_SIZE_WARN.lock().unwrap()
-    }
+/// Rewrite the code between debug comments, keeping the comments
+pub fn map_code(code: &str, f: impl Fn(&str) -> String) -> String {
+    debug_parts(code)
+        .into_iter()
+        .map(|(part, comment)| if comment { part.to_string() } else { f(part) })
+        .collect()
+}
 
-    /// Get the stack size
-    pub fn stack_size() -> u32 {
-        *STACK_SIZE.lock().unwrap()
-    }
+/// Remove the debug comments from compiled code
+pub fn strip_debug(code: &str) -> String {
+    debug_parts(code)
+        .into_iter()
+        .filter(|(_, comment)| !comment)
+        .map(|(part, _)| part)
+        .collect()
+}
 
-    /// Get the heap size
-    pub fn heap_size() -> u32 {
-        *HEAP_SIZE.lock().unwrap()
+/// Something the compiler did, reported to the diagnostic sink.
+/// The depth is the number of function calls being compiled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileEvent {
+    /// A call to a function is about to be compiled
+    Call { name: String, depth: usize },
+    /// A call to a function has been compiled
+    CallDone { name: String, depth: usize },
+    /// A variable has been defined
+    Define { name: String, depth: usize },
+    /// A reference was left alone when its scope was freed
+    KeepReference { name: String, depth: usize },
+    /// A warning was recorded
+    Warn { warning: Warning, depth: usize },
+    /// The body of a call was compiled before in the same state,
+    /// so the code compiled for it then is used instead of lowering it again
+    Reuse { name: String, depth: usize },
+}
+
+/// A writer that the output sink shares with `Program::compile_to`,
+/// so the writer can be given back when compiling is done
+struct SharedOutput<W>(Arc<Mutex<W>>);
+
+impl<W: Write> Write for SharedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
     }
 
-    /// Get the tape size
-    pub fn tape_size() -> u32 {
-        Self::stack_size() + Self::heap_size()
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
     }
+}
 
-    /// Compile the code
-    pub fn compile(self) -> Result<String, Error> {
-        // Add the compiler functions
-        Self::prelude();
+/// Receives the events of a compilation, for debugging the compiler
+/// or the programs it compiles
+pub trait DiagnosticSink: Send {
+    fn on_event(&self, event: CompileEvent);
+}
 
-        // Get function definitions
-        let Program(_, funs) = self;
-        
-        // Compile
-        for fun in funs {
-            fun.compile();
+/// Report an event to the diagnostic sink. Without a sink, nothing happens.
+pub fn report(event: impl FnOnce(usize) -> CompileEvent) {
+    DIAGNOSTIC_SINK.with_borrow(|sink| {
+        if let Some(sink) = sink {
+            sink.on_event(event(CALL_STACK.with_borrow(Vec::len)));
         }
+    });
+}
 
-        call("start", &vec![])?;
-
-        // Return compiled code
-        Ok(compile())
-    }
+/// The size of the code generated for a user defined function,
+/// accumulated over every call to the function. Everything but
+/// `own_instructions` includes the functions it calls.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FnSize {
+    pub name: String,
+    pub calls: usize,
+    pub instructions: usize,
+    /// The instructions generated for the function itself, leaving out the
+    /// user defined functions it calls. The own instructions of every function,
+    /// and the instructions outside of any function, add up to `instruction_count()`.
+    pub own_instructions: usize,
+    /// The number of values allocated on the stack
+    pub allocations: usize,
+    /// The number of calls made to user defined and foreign functions
+    pub calls_made: usize,
+    pub peak_stack: u32,
+    /// The highest the stack got above where it was when the function was called
+    pub frame_peak: u32,
+    pub live_cells: u32,
 }
 
+/// This object manages compiling the program, and setting the enabled flags.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Program(
+    pub(crate) Vec<Flag>,
+    pub(crate) Vec<UserFn>,
+    pub(crate) Vec<Define>,
+    pub(crate) Vec<Const>,
+    pub(crate) Vec<Struct>,
+    pub(crate) Vec<String>,
+);
+
+/// Foreign functions are closures kept in FOREIGN_FN_DEFS, so they must
+/// never become part of a program, or programs couldn't be serialized
+#[cfg(feature = "serde")]
+const _: fn() = || {
+    fn serializable<T: Serialize + for<'de> Deserialize<'de>>() {}
+    serializable::<Program>();
+};
 
-/// The possible flags to be returned by the parser
+/// A definition at the top level of a program
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub enum Flag {
-    EnableBrainFuck,
-    EnableSizeWarn,
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Item {
+    Function(UserFn),
+    Global(Define),
+    Const(Const),
+    Enum(Enum),
+    Struct(Struct),
+    Import(String),
+    Module(String, Vec<Item>),
 }
 
-/// The possible compiler errors
-#[derive(Clone, Debug)]
-pub enum Error {
-    StackOverflow,
-    MustReturnSingleByte,
-    CannotReferenceAReference,
-    CannotUsePointersInBrainFuckMode,
-    CannotUseUnsignedShortsInBrainFuckMode,
-    CannotAssignLargerValueToSmallerValueInBrainFuckMode,
-    FunctionNotDefined(String),
-    VariableNotDefined(String, Env),
+/// The namespace a function name is in, or the empty string for the root namespace
+pub fn namespace_of(name: &str) -> &str {
+    match name.rfind("::") {
+        Some(i) => &name[..i],
+        None => "",
+    }
 }
 
-/// This trait describes objects that are lowered
-/// into values rather than expressions, such as function calls
-/// literals, variables, etc..
-pub trait Lower {
-    fn lower(&self) -> Result<Value, Error>;
+/// Put a name in a namespace
+pub fn qualify(namespace: &str, name: &str) -> String {
+    if namespace.is_empty() {
+        name.to_string()
+    } else if name.is_empty() {
+        namespace.to_string()
+    } else {
+        format!("{}::{}", namespace, name)
+    }
 }
 
-/// A value can be `lowered` into a value
-impl Lower for Value {
-    fn lower(&self) -> Result<Value, Error> {
-        Ok(*self)
+/// The namespaces a name used in `namespace` is looked for in,
+/// from `namespace` itself out to the root namespace
+pub fn enclosing_namespaces(namespace: &str) -> Vec<String> {
+    let mut result = vec![];
+    let mut namespace = namespace;
+    while !namespace.is_empty() {
+        result.push(namespace.to_string());
+        namespace = namespace_of(namespace);
     }
+    result.push(String::new());
+    result
 }
 
-/// This trait is for objects that compile into expressions
-/// rather than values. This is applicable for definitions,
-/// assignments, return expressions, while loops, etc..
-pub trait Compile {
-    fn compile(&self) -> Result<(), Error>;
+/// Is the function `inner` the function `outer`, or defined inside it?
+fn encloses(outer: &str, inner: &str) -> bool {
+    inner == outer || inner.strip_prefix(outer).is_some_and(|rest| rest.starts_with("::"))
 }
 
-/// This function creates a new scope on the scope stack.
-/// THIS IS ONLY TO BE USED BY FUNCTION DEFINITIONS
-fn push_scope(env: Env) {
-    SCOPE_STACK.lock().unwrap().push(env);
+/// Move the functions of a module, and of the modules inside it, into the namespace `prefix`
+fn flatten_module(prefix: &str, items: Vec<Item>, result: &mut Vec<Item>) {
+    for item in items {
+        match item {
+            Item::Function(mut fun) => {
+                fun.name = qualify(prefix, &fun.name);
+                // Local functions are in the namespace of the function they are defined in
+                let locals = mem::take(&mut fun.locals)
+                    .into_iter()
+                    .map(|local| Item::Function(UserFn { enclosing: Some(fun.name.clone()), ..local }))
+                    .collect();
+                let name = fun.name.clone();
+                result.push(Item::Function(fun));
+                flatten_module(&name, locals, result);
+            }
+            Item::Module(name, items) => flatten_module(&qualify(prefix, &name), items, result),
+            other => result.push(other),
+        }
+    }
 }
 
-/// This function destroys a scope on the scope stack.
-/// THIS IS ONLY TO BE USED BY FUNCTION DEFINITIONS
-fn pop_scope() -> Env {
-    SCOPE_STACK.lock().unwrap().pop().unwrap()
+/// Parse a program. Files it imports are found from the current directory.
+impl<T: ToString> From<T> for Program {
+    fn from(t: T) -> Self {
+        let program = match Self::parse(t, None) {
+            Ok(val) => val,
+            Err(e) => panic!("{}", e),
+        };
+        match Linker::default().link(program, None) {
+            Ok(val) => val,
+            Err(e) => panic!("{:#?}", e),
+        }
+    }
 }
 
-/// This sets the RETURN value object to a specific value
-pub fn set_return(val: Value) -> Result<(), Error> {
-    if val.size() > 1 {
-        Err(Error::MustReturnSingleByte)
-    } else {
-        RETURN.assign(val)?;
-        Ok(())
+/// Replace the escape sequences in a string or character literal with
+/// the characters they stand for. Unknown escapes are kept as they are.
+pub fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some(escaped @ ('\\' | '"' | '\'')) => result.push(escaped),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
     }
+    result
 }
 
-/// This retreives the last value returned by a function
-pub fn get_return() -> Result<Value, Error> {
-    // let val = Eval::Value(*RETURN);
-    // let name = format!("%TEMP_RETURN{}%", *STACK_PTR.lock().unwrap());
-    // define(&name, val)?;
-    // get(name)
-    Ok(*RETURN)
+/// The length in bytes of the string or character literal `text` starts with, if it starts
+/// with one. A raw string, like `r"..."` or `r#"..."#`, ends at the first quote followed by
+/// as many `#` as it started with, and other literals, including byte strings like `b"..."`,
+/// end at the first unescaped quote. A literal that isn't closed runs to the end of the text.
+fn literal_len(text: &str) -> Option<usize> {
+    if let Some(bytes) = text.strip_prefix('b').filter(|rest| rest.starts_with('"')) {
+        return literal_len(bytes).map(|len| len + 1);
+    }
+    if let Some(rest) = text.strip_prefix('r') {
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        if rest[hashes..].starts_with('"') {
+            let close = format!("\"{}", "#".repeat(hashes));
+            let body = hashes + 2;
+            return Some(text[body..].find(&close).map_or(text.len(), |end| body + end + close.len()));
+        }
+    }
+
+    let quote = text.chars().next().filter(|&ch| ch == '"' || ch == '\'')?;
+    let mut escaped = false;
+    for (i, ch) in text.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if ch == quote {
+            return Some(i + 1);
+        }
+    }
+    Some(text.len())
 }
 
+/// Replace the comments in source code with spaces, keeping newlines,
+/// so everything else stays at the same byte offset
+fn blank_comments(source: &str) -> String {
+    let mut result = String::with_capacity(source.len());
+    let blank = |text: &str| -> String {
+        text.chars()
+            .map(|ch| if ch == '\n' { String::from("\n") } else { " ".repeat(ch.len_utf8()) })
+            .collect()
+    };
 
-/// This represents a value that can be evaluated. Variables,
-/// literals, function calls, value dereferences, variable references,
-/// and values (this is a cheat for letting the compiler easily use
-/// values in place of long Evals) can all be evaluated into a Value
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub enum Eval {
-    Load(Load),
-    Literal(Literal),
-    Call(Call),
-    Deref(Deref),
-    Refer(Refer),
-    Value(Value),
+    let mut i = 0;
+    let mut previous = ' ';
+    while let Some(ch) = source[i..].chars().next() {
+        let rest = &source[i..];
+        let len = if rest.starts_with("//") {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            result += &blank(&rest[..end]);
+            end
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            let end = comment.find("*/").map_or(rest.len(), |end| end + 4);
+            result += &blank(&rest[..end]);
+            end
+        } else if let Some(end) = literal_len(rest).filter(|_| !is_ident_char(previous)) {
+            // Copy the literal, so comment markers inside it are kept
+            result += &rest[..end];
+            end
+        } else {
+            result.push(ch);
+            ch.len_utf8()
+        };
+        previous = rest[..len].chars().last().unwrap_or(ch);
+        i += len;
+    }
+    result
 }
 
-/// An Eval expression is evaluated by the expression it contains
-impl Lower for Eval {
-    fn lower(&self) -> Result<Value, Error> {
-        // Lower the contained expression
-        match self {
-            Self::Load(l) => l.lower(),
-            Self::Literal(l) => l.lower(),
-            Self::Deref(r) => r.lower(),
-            Self::Call(c) => c.lower(),
-            Self::Refer(v) => v.lower(),
-            Self::Value(v) => v.lower(),
+/// Can this character be part of an identifier?
+fn is_ident_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+/// The keywords that start a top level item
+const ITEM_KEYWORDS: [&str; 7] = ["fn", "mod", "def", "const", "enum", "struct", "import"];
+
+/// The names of the flags written like `#[name]`. Anything else in that
+/// form is an attribute, which belongs to the item after it.
+const FLAG_NAMES: [&str; 10] = [
+    "optimize",
+    "cell_size",
+    "eof",
+    "entry_point",
+    "heap_size",
+    "tape_size",
+    "recursion_limit",
+    "enable",
+    "disable",
+    "allow",
+];
+
+/// Is the text at the start of this source code an attribute of the next item?
+fn starts_attribute(source: &str) -> bool {
+    match source.strip_prefix("#[") {
+        Some(rest) => {
+            let name: String = rest.trim_start().chars().take_while(|&ch| is_ident_char(ch)).collect();
+            !name.is_empty() && !FLAG_NAMES.contains(&name.as_str())
         }
+        None => false,
     }
 }
 
-/// This represents a statement as opposed to a value.
-/// A value can also be a statement, though.
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub enum Expr {
-    If(If),
-    While(While),
-    Eval(Eval),
-    Define(Define),
-    Assign(Assign),
-    Return(Return)
-}
+/// Find the byte ranges of the top level items and flags in source code without comments
+fn top_level_ranges(source: &str) -> Vec<(usize, usize)> {
+    let mut starts = vec![];
+    // The start of the attributes before the next item
+    let mut attributes = None;
+    let mut depth = 0;
+    let mut previous = ' ';
+    let mut i = 0;
+    while let Some(ch) = source[i..].chars().next() {
+        // Skip over literals, so the braces and keywords in them aren't counted
+        if let Some(len) = literal_len(&source[i..]).filter(|_| !is_ident_char(previous)) {
+            i += len;
+            previous = '"';
+            continue;
+        }
 
-/// An Expression is evaluated by the expression it contains
-impl Compile for Expr {
-    fn compile(&self) -> Result<(), Error> {
-        match self {
-            Self::If(l) => l.compile()?,
-            Self::Eval(e) => {
-                e.lower()?; // Dont return value from lower
+        match ch {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '#' if depth == 0 && starts_attribute(&source[i..]) => {
+                attributes.get_or_insert(i);
             }
-            Self::Define(def) => def.compile()?,
-            Self::Assign(a) => a.compile()?,
-            Self::While(w) => w.compile()?,
-            Self::Return(r) => r.compile()?,
+            '#' if depth == 0 => starts.push(i),
+            _ if depth == 0 && !is_ident_char(previous) => {
+                let rest = &source[i..];
+                let starts_item = ITEM_KEYWORDS.iter().any(|keyword| {
+                    rest.starts_with(keyword) && !rest[keyword.len()..].starts_with(is_ident_char)
+                });
+                if starts_item {
+                    starts.push(attributes.take().unwrap_or(i));
+                }
+            }
+            _ => {}
         }
-        Ok(())
+        previous = ch;
+        i += ch.len_utf8();
+    }
+    // Attributes that aren't followed by an item are reported on their own
+    starts.extend(attributes);
+
+    // Anything before the first item is part of it, so it is reported as an error
+    if let Some(first) = starts.first_mut() {
+        *first = 0;
     }
+    let ends = starts.iter().skip(1).copied().chain(std::iter::once(source.len()));
+    starts.iter().copied().zip(ends).collect()
 }
 
-/// This sets the RETURN register to an Eval
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Return(Eval);
+/// Turn an error from the parser into a compiler error at the place it happened
+fn syntax_error<T: fmt::Display>(error: ParseError<usize, T, String>, item_start: usize) -> Error {
+    let (span, message) = match error {
+        ParseError::InvalidToken { location } => (Span::new(location, location), String::from("invalid token")),
+        ParseError::UnrecognizedEOF { location, expected } => (
+            Span::new(location, location),
+            format!("unexpected end of item, expected one of {}", expected.join(", ")),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (start, token, end),
+            expected,
+        } => (
+            Span::new(start, end),
+            format!("unexpected {}, expected one of {}", token, expected.join(", ")),
+        ),
+        ParseError::ExtraToken {
+            token: (start, token, end),
+        } => (Span::new(start, end), format!("unexpected {}", token)),
+        ParseError::User { error } => (Span::new(item_start, item_start), error),
+    };
+    Error::Syntax(message).at(Some(span))
+}
 
-impl Return {
-    pub fn new(val: Eval) -> Self {
-        Return(val)
+/// The source code of a program, and the file it was read from
+struct Source {
+    path: Option<PathBuf>,
+    text: String,
+}
+
+/// A range of bytes in the source code of a program
+#[derive(Copy, Clone, Debug, Default, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    /// The index of the source code the span is in
+    source: usize,
+}
+
+impl Span {
+    /// Create a span in the source code being parsed
+    pub fn new(start: usize, end: usize) -> Self {
+        Self {
+            start,
+            end,
+            source: SOURCES.with_borrow(|sources| sources.len().saturating_sub(1)),
+        }
+    }
+
+    /// The line and column the span starts at, counting from 1
+    pub fn line_column(&self) -> (usize, usize) {
+        SOURCES.with_borrow(|sources| {
+            let text = match sources.get(self.source) {
+                Some(source) => &source.text[..self.start.min(source.text.len())],
+                None => return (1, self.start + 1),
+            };
+            let line = text.matches('\n').count() + 1;
+            let column = text.chars().rev().take_while(|&ch| ch != '\n').count() + 1;
+            (line, column)
+        })
+    }
+
+    /// The file the span is in, if the source code was read from a file
+    pub fn file(&self) -> Option<PathBuf> {
+        SOURCES.with_borrow(|sources| sources.get(self.source).and_then(|source| source.path.clone()))
     }
 }
 
-impl Compile for Return {
-    fn compile(&self) -> Result<(), Error> {
-        let Return(val) = self;
-        set_return(val.lower()?)?;
-        Ok(())
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (line, column) = self.line_column();
+        match self.file() {
+            Some(path) => write!(f, "{}:{}:{}", path.display(), line, column),
+            None => write!(f, "{}:{}", line, column),
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Call(String, Vec<Eval>);
+/// The span of the statement being compiled
+pub fn location() -> Option<Span> {
+    LOCATION.get()
+}
 
-impl Call {
-    pub fn new(name: impl ToString, args: Vec<Eval>) -> Self {
-        Self(name.to_string(), args)
+fn set_location(span: Option<Span>) {
+    LOCATION.set(span);
+}
+
+/// Keeps track of the files linked into a program while its imports are read
+#[derive(Default)]
+struct Linker {
+    /// The files whose imports are being read, from the first file to the latest
+    importing: Vec<PathBuf>,
+    /// The files that are already part of the program
+    linked: Vec<PathBuf>,
+    /// The file each function was defined in, with every overload of a name
+    origins: Vec<(UserFn, PathBuf)>,
+}
+
+impl Linker {
+    /// Read a file and every file it imports into one program
+    fn load(&mut self, path: &Path) -> Result<Program, Error> {
+        let source = fs::read_to_string(path).map_err(|_| Error::ImportNotFound(path.to_path_buf()))?;
+        let program = Program::parse(source, Some(path)).map_err(|message| Error::ParseFailed {
+            path: path.to_path_buf(),
+            message,
+        })?;
+        self.link(program, Some(path))
+    }
+
+    /// Put the files a program imports before the program. Imports are
+    /// relative to the file the program was read from, and each file
+    /// is linked once, however many files import it.
+    fn link(&mut self, mut program: Program, file: Option<&Path>) -> Result<Program, Error> {
+        let root = file.and_then(Path::parent).unwrap_or_else(|| Path::new("."));
+        if let Some(file) = file {
+            self.importing.push(file.to_path_buf());
+        }
+
+        let mut result = Program::new(vec![], vec![]);
+        for import in mem::take(&mut program.5) {
+            let path = root.join(&import);
+            let path = path.canonicalize().map_err(|_| Error::ImportNotFound(path))?;
+
+            if let Some(i) = self.importing.iter().position(|p| *p == path) {
+                let mut cycle = self.importing[i..].to_vec();
+                cycle.push(path);
+                return Err(Error::ImportCycle(cycle));
+            }
+            if !self.linked.contains(&path) {
+                let imported = self.load(&path)?;
+                result.append(imported);
+            }
+        }
+
+        if let Some(file) = file {
+            self.claim(&program.1, file)?;
+            self.importing.pop();
+            self.linked.push(file.to_path_buf());
+        }
+        result.append(program);
+        Ok(result)
+    }
+
+    /// Record the file that defines each function, making sure no two files define the same function
+    fn claim(&mut self, funs: &[UserFn], file: &Path) -> Result<(), Error> {
+        for fun in funs {
+            if let Some((_, first)) = self.origins.iter().find(|(f, _)| f.overlaps(fun)) {
+                if !Program::redefine_allowed() {
+                    return Err(Error::FunctionRedefinedInFiles {
+                        name: fun.name.clone(),
+                        first: first.clone(),
+                        second: file.to_path_buf(),
+                    });
+                }
+            }
+            self.origins.retain(|(f, _)| !f.overlaps(fun));
+            self.origins.push((fun.clone(), file.to_path_buf()));
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    /// Create new Program object
+    pub fn new(flags: Vec<Flag>, funs: Vec<UserFn>) -> Self {
+        // Set the flags for the compiler
+        for flag in &flags {
+            match flag {
+                Flag::EnableBrainFuck => ENABLE_BRAINFUCK.set(true),
+                Flag::EnableSizeWarn => ENABLE_SIZE_WARN.set(true),
+                Flag::EmitDebugComments => Self::set_debug_comments(true),
+                Flag::DisablePtrs => DISABLE_PTRS.set(true),
+                Flag::StripAsserts => Self::set_strip_asserts(true),
+                Flag::OverflowChecks => Self::set_overflow_checks(true),
+                Flag::BoundsChecks => Self::set_bounds_checks(true),
+                Flag::Utf8Strings => Self::set_utf8_strings(true),
+                Flag::DisableInterning => Self::set_disable_interning(true),
+                Flag::HeapSize(size) => Self::set_heap_size(*size),
+                Flag::TapeSize(size) => Self::set_tape_size(*size),
+                Flag::RecursionLimit(limit) => Self::set_recursion_limit(*limit),
+                Flag::CellSize(width) => CELL_WIDTH.set(*width),
+                Flag::Optimize(level) => Self::set_optimize_level(*level),
+                Flag::EofValue(value) => EOF_VALUE.set(*value),
+                Flag::EntryPoint(name) => Self::set_entry_point(name),
+                Flag::Allow(lint) => Self::allow_lint(lint),
+            }
+        }
+        // Return self
+        Self(flags, funs, vec![], vec![], vec![], vec![])
+    }
+
+    /// Read a program from a file, along with every file it imports
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let path = path.canonicalize().map_err(|_| Error::ImportNotFound(path.to_path_buf()))?;
+        Linker::default().load(&path)
+    }
+
+    /// Parse a program without reading the files it imports
+    fn parse(t: impl ToString, path: Option<&Path>) -> Result<Self, String> {
+        let text = t.to_string();
+        let source = blank_comments(&text);
+        SOURCES.with_borrow_mut(|sources| sources.push(Source {
+            path: path.map(Path::to_path_buf),
+            text,
+        }));
+        ProgramParser::new().parse(&source).map_err(|e| format!("{:#?}", e))
+    }
+
+    /// Link another program after this one. A function can't be defined by both
+    /// programs, and a flag can't be given different values by each program.
+    pub fn merge(mut self, other: Self) -> Result<Self, Error> {
+        if !Self::redefine_allowed() {
+            if let Some(fun) = other.1.iter().find(|f| self.1.iter().any(|g| g.overlaps(f))) {
+                return Err(Error::FunctionRedefined(fun.name.clone()));
+            }
+        }
+
+        for flag in &other.0 {
+            // A program may allow any number of lints
+            let same_kind = match flag {
+                Flag::Allow(_) => self.0.iter().find(|f| *f == flag),
+                _ => self.0.iter().find(|f| mem::discriminant(*f) == mem::discriminant(flag)),
+            };
+            match same_kind {
+                Some(f) if f != flag => return Err(Error::ConflictingFlags(f.clone(), flag.clone())),
+                Some(_) => {}
+                None => self.0.push(flag.clone()),
+            }
+        }
+
+        let Program(_, funs, globals, constants, structs, imports) = other;
+        self.append(Program(vec![], funs, globals, constants, structs, imports));
+        Ok(self)
+    }
+
+    /// The flags the program was created with
+    pub fn flags(&self) -> &[Flag] {
+        &self.0
+    }
+
+    /// The functions the program defines, in the order they were defined
+    pub fn functions(&self) -> &[UserFn] {
+        &self.1
+    }
+
+    /// Parse a program, continuing after syntax errors so every error is
+    /// reported at once. Each top level item is parsed on its own, so an
+    /// error is reported once for the item it is in, and the items that
+    /// parse are kept. The program is `None` if no item could be parsed.
+    pub fn parse_all(t: impl ToString) -> (Option<Self>, Vec<Error>) {
+        let text = t.to_string();
+        let source = blank_comments(&text);
+        SOURCES.with_borrow_mut(|sources| sources.push(Source { path: None, text }));
+
+        let (mut flags, mut programs, mut errors) = (vec![], vec![], vec![]);
+        for (start, end) in top_level_ranges(&source) {
+            // Parse the item where it is, so its spans point into the whole source
+            let item: String = source
+                .char_indices()
+                .map(|(i, ch)| if (start..end).contains(&i) || ch == '\n' { ch } else { ' ' })
+                .collect();
+
+            // The first item also has the blank lines before it
+            let text = source[start..].trim_start();
+            if text.starts_with('#') && !starts_attribute(text) {
+                match FlagParser::new().parse(&item) {
+                    Ok(flag) if !programs.is_empty() => errors.push(
+                        Error::Syntax(format!("flag {:?} must come before every definition", flag))
+                            .at(Some(Span::new(start, end))),
+                    ),
+                    Ok(flag) => flags.push(flag),
+                    Err(e) => errors.push(syntax_error(e, start)),
+                }
+            } else {
+                match ProgramParser::new().parse(&item) {
+                    Ok(program) => programs.push(program),
+                    Err(e) => errors.push(syntax_error(e, start)),
+                }
+            }
+        }
+
+        if programs.is_empty() {
+            return (None, errors);
+        }
+        let mut result = Self::new(flags, vec![]);
+        for program in programs {
+            result.append(program);
+        }
+        match Linker::default().link(result, None) {
+            Ok(program) => (Some(program), errors),
+            Err(e) => {
+                errors.push(e);
+                (None, errors)
+            }
+        }
+    }
+
+    /// Add the definitions of another program after the definitions of this one
+    fn append(&mut self, other: Self) {
+        let Program(flags, funs, globals, constants, structs, imports) = other;
+        self.0.extend(flags);
+        self.1.extend(funs);
+        self.2.extend(globals);
+        self.3.extend(constants);
+        self.4.extend(structs);
+        self.5.extend(imports);
+    }
+
+    /// Create a new Program object from the items at the top level of a file
+    pub fn from_items(flags: Vec<Flag>, items: Vec<Item>) -> Self {
+        let mut flattened = vec![];
+        flatten_module("", items, &mut flattened);
+
+        let mut funs = vec![];
+        let mut globals = vec![];
+        let mut constants = vec![];
+        let mut structs = vec![];
+        let mut imports = vec![];
+        for item in flattened {
+            match item {
+                Item::Function(fun) => funs.push(fun),
+                Item::Global(global) => globals.push(global),
+                Item::Const(constant) => constants.push(constant),
+                Item::Enum(enumeration) => constants.extend(enumeration.constants()),
+                Item::Struct(structure) => structs.push(structure),
+                Item::Import(path) => imports.push(path),
+                Item::Module(..) => unreachable!("modules are flattened"),
+            }
+        }
+
+        // A call is to the function in the innermost namespace around the caller that defines it,
+        // starting with the caller's own local functions. Other local functions can't be seen.
+        let names: Vec<String> = funs.iter().map(|f: &UserFn| f.name.clone()).collect();
+        let locals: Vec<(String, String)> =
+            funs.iter().filter_map(|f| Some((f.name.clone(), f.enclosing.clone()?))).collect();
+        for fun in &mut funs {
+            let caller = fun.name.clone();
+            let namespace = namespace_of(&fun.name).to_string();
+            let resolve = |name: &str| {
+                let visible = |qualified: &String| match locals.iter().find(|(local, _)| local == qualified) {
+                    Some((_, outer)) => encloses(outer, &caller),
+                    None => names.contains(qualified),
+                };
+                let own = Some(qualify(&caller, name)).filter(|own| locals.contains(&(own.clone(), caller.clone())));
+                own.into_iter()
+                    .chain(enclosing_namespaces(&namespace).iter().map(|outer| qualify(outer, name)))
+                    .find(visible)
+                    .unwrap_or_else(|| name.to_string())
+            };
+            fun.body = rename_calls(&fun.body, &resolve);
+        }
+        let mut program = Self::new(flags, funs).globals(globals).constants(constants).structs(structs);
+        program.5 = imports;
+        program
+    }
+
+    /// Add constants, which every function can use. A constant may use the constants before it.
+    pub fn constants(mut self, constants: Vec<Const>) -> Self {
+        self.3.extend(constants);
+        self
+    }
+
+    /// Add global variables, which every function can use
+    pub fn globals(mut self, globals: Vec<Define>) -> Self {
+        self.2.extend(globals);
+        self
+    }
+
+    /// Add struct definitions, which every function can construct
+    pub fn structs(mut self, structs: Vec<Struct>) -> Self {
+        self.4.extend(structs);
+        self
+    }
+
+    /// Instantiate compiler functions
+    fn prelude() {
+        init();
+
+        // Add lhs and rhs. Adding a number to a character makes a character.
+        defforeign_raw("add", &["a", "b"], |args| {
+            let (a, b) = operands("add", &args[0], &args[1])?;
+            add_numbers(a, b).map(Some)
+        });
+
+        // Subtract rhs from lhs. Subtracting a number from a character makes
+        // a character, and subtracting two characters makes a number.
+        defforeign_raw("sub", &["a", "b"], |args| {
+            let (a, b) = operands("sub", &args[0], &args[1])?;
+            sub_numbers(a, b).map(Some)
+        });
+        
+        // Negate a number, in two's complement, which makes it signed
+        defforeign("neg", &["a"], |args| neg_number(args[0]).map(Some));
+
+        // Compare two numbers, returning 1 if lhs is less than rhs, and 0 otherwise
+        defforeign_raw("lt", &["a", "b"], |args| {
+            let (a, b) = operands("lt", &args[0], &args[1])?;
+            less_than(a, b).map(Some)
+        });
+
+        // Compare two numbers, returning 1 if lhs is greater than rhs, and 0 otherwise
+        defforeign_raw("gt", &["a", "b"], |args| {
+            let (a, b) = operands("gt", &args[0], &args[1])?;
+            less_than(b, a).map(Some)
+        });
+
+        // Multiply lhs by rhs. Fixed point literals are multiplied while compiling.
+        defforeign_raw("mul", &["a", "b"], |args| {
+            if let Some(product) = fold_fixed(&args[0], &args[1], |a, b| a * b) {
+                return product.map(Some);
+            }
+            let (a, b) = operands("mul", &args[0], &args[1])?;
+            mul_numbers(a, b).map(Some)
+        });
+
+        // Divide lhs by rhs, rounding toward zero. Fixed point literals are divided while compiling.
+        defforeign_raw("div", &["a", "b"], |args| {
+            if let Some(quotient) = fold_fixed(&args[0], &args[1], |a, b| a / b) {
+                return quotient.map(Some);
+            }
+            let (a, b) = operands("div", &args[0], &args[1])?;
+            div_numbers(a, b).map(Some)
+        });
+
+        // Read a number as signed, or as unsigned, without changing its cells.
+        // A 4 byte number is cut down to a cell, keeping the bits that fit.
+        defforeign_raw("signed", &["a"], |args| cast_number(&args[0], NumberKind::Signed).map(Some));
+        defforeign_raw("unsigned", &["a"], |args| cast_number(&args[0], NumberKind::Unsigned).map(Some));
+
+        // Convert a number to a 4 byte number. A signed cell keeps its sign, and an
+        // `i32` and a `u32` are read as each other without changing their cells.
+        defforeign_raw("i32", &["a"], |args| cast_number(&args[0], NumberKind::Signed4Byte).map(Some));
+        defforeign_raw("u32", &["a"], |args| cast_number(&args[0], NumberKind::Unsigned4Byte).map(Some));
+
+        // Convert a number to fixed point, or back to a whole number, rounding toward zero
+        defforeign_raw("fixed", &["a"], |args| cast_number(&args[0], NumberKind::Fixed).map(Some));
+
+        // Print function, prints every argument
+        defforeign("print", &["a", VARIADIC, "-> void"], |args| {
+            for val in args {
+                Stdout::print(*val);
+            }
+            Ok(None)
+        });
+
+        // Println function, prints every argument
+        defforeign("println", &["a", VARIADIC, "-> void"], |args| {
+            for val in args {
+                Stdout::print(*val);
+            }
+            Stdout::print(Eval::Literal(Literal::character('\n')).lower()?);
+            Ok(None)
+        });
+        
+        // Print function
+        defforeign("cprint", &["a", "-> void"], |args| {
+            Stdout::print_cstr(args[0])?;
+            Ok(None)
+        });
+
+        // Println function
+        defforeign("cprintln", &["a", "-> void"], |args| {
+            Stdout::print_cstr(args[0])?;
+            Stdout::print(Eval::Literal(Literal::character('\n')).lower()?);
+            Ok(None)
+        });
+
+        // Allocate `size` number of zeroed cells on the heap
+        defforeign("alloc", &["size"], |args| Ok(Some(Value::variable_alloc(args[0])?)));
+
+        // Give cells from `alloc` back to the heap
+        defforeign("free", &["ptr: ref", "-> void"], |args| {
+            args[0].heap_free()?;
+            Ok(None)
+        });
+
+        // Free a byte at ptr
+        defforeign("free_byte", &["ptr: ref", "-> void"], |args| {
+            args[0].deref()?.free();
+            Ok(None)
+        });
+    }
+
+    /// Is brainfuck compatibility mode enabled?
+    pub fn brainfuck_enabled() -> bool {
+        ENABLE_BRAINFUCK.get()
+    }
+
+    /// Are size warnings enabled?
+    pub fn size_warn_enabled() -> bool {
+        ENABLE_SIZE_WARN.get()
+    }
+
+    /// Annotate the output with comments saying what code it came from, or stop
+    pub fn set_debug_comments(enable: bool) {
+        DEBUG_COMMENTS.set(enable);
+    }
+
+    /// Is the output annotated with debug comments?
+    pub fn debug_comments_enabled() -> bool {
+        DEBUG_COMMENTS.get()
+    }
+
+    /// Are pointers disabled in user code?
+    pub fn ptrs_disabled() -> bool {
+        DISABLE_PTRS.get()
+    }
+
+    /// Compile asserts to nothing, such as for a release build, or stop
+    pub fn set_strip_asserts(enable: bool) {
+        STRIP_ASSERTS.set(enable);
+    }
+
+    /// Are asserts compiled to nothing?
+    pub fn asserts_stripped() -> bool {
+        STRIP_ASSERTS.get()
+    }
+
+    /// Make `add` and `sub` stop the program with a message when they
+    /// overflow, instead of wrapping around, or stop
+    pub fn set_overflow_checks(enable: bool) {
+        OVERFLOW_CHECKS.set(enable);
+    }
+
+    /// Does arithmetic stop the program when it overflows?
+    pub fn overflow_checks_enabled() -> bool {
+        OVERFLOW_CHECKS.get()
+    }
+
+    /// Make indexing a string or an array with a runtime index stop the
+    /// program with a message when the index is past the end, or don't check it
+    pub fn set_bounds_checks(enable: bool) {
+        BOUNDS_CHECKS.set(enable);
+    }
+
+    /// Does a runtime index past the end of a value stop the program?
+    pub fn bounds_checks_enabled() -> bool {
+        BOUNDS_CHECKS.get()
+    }
+
+    /// Store strings as their UTF-8 bytes, one byte to a cell, so they
+    /// may have characters that aren't ASCII, or only allow ASCII strings
+    pub fn set_utf8_strings(enable: bool) {
+        UTF8_STRINGS.set(enable);
+    }
+
+    /// Are strings stored as their UTF-8 bytes?
+    pub fn utf8_strings_enabled() -> bool {
+        UTF8_STRINGS.get()
+    }
+
+    /// Give every use of a string literal its own cells, or share the cells
+    /// of a string literal used more than once between all of its uses
+    pub fn set_disable_interning(disable: bool) {
+        DISABLE_INTERNING.set(disable);
+    }
+
+    /// Does every use of a string literal get its own cells?
+    pub fn interning_disabled() -> bool {
+        DISABLE_INTERNING.get()
+    }
+
+    /// Get the width of the cells on the tape
+    pub fn cell_width() -> CellWidth {
+        CELL_WIDTH.get()
+    }
+
+    /// Get the value a cell holds after reading from input that has ended
+    pub fn eof_value() -> u8 {
+        EOF_VALUE.get()
+    }
+
+    /// Get the name of the function the program starts in
+    pub fn entry_point() -> String {
+        ENTRY.get()
+    }
+
+    /// Start the program in another function than `start`
+    pub fn set_entry_point(name: impl ToString) {
+        ENTRY.set(name.to_string());
+    }
+
+    /// Get the optimization level
+    pub fn optimize_level() -> u8 {
+        OPTIMIZE_LEVEL.get()
+    }
+
+    /// Set the optimization level. Levels above the highest level are clamped.
+    pub fn set_optimize_level(level: u8) {
+        OPTIMIZE_LEVEL.set(level.min(MAX_OPTIMIZE_LEVEL));
+    }
+
+    /// Report compile events to a sink, or stop reporting them with `None`
+    pub fn set_diagnostic_sink(sink: Option<Box<dyn DiagnosticSink>>) {
+        DIAGNOSTIC_SINK.set(sink);
+    }
+
+    /// Write compiled code to a sink as it is compiled instead of returning
+    /// it from `compile`, or stop writing it with `None`. The code compiled
+    /// so far is written and flushed whenever a function is done and no loop
+    /// is left open, and the optimization passes that rewrite instructions
+    /// are run on each piece of code written.
+    pub fn set_output(sink: Option<Box<dyn Write + Send>>) {
+        OUTPUT.set(sink);
+    }
+
+    /// Keep the code compiled for each call to a user defined function, and
+    /// use it again when the same call is compiled in the same state, or stop
+    /// and forget the code kept. The code is kept across `reset`, so compiling
+    /// a program again after changing some of its functions only lowers the
+    /// calls to the functions that changed or that call one that changed, and
+    /// the calls made where the stack, the variables, or the settings differ.
+    pub fn set_incremental(enable: bool) {
+        FN_CACHE.with_borrow_mut(|cache| match (enable, cache.is_some()) {
+            (true, false) => *cache = Some(HashMap::new()),
+            (false, _) => *cache = None,
+            _ => {}
+        })
+    }
+
+    /// Is the code compiled for calls kept and used again?
+    pub fn incremental() -> bool {
+        FN_CACHE.with_borrow(|fn_cache| fn_cache.is_some())
+    }
+
+    /// Save the code kept for compiling incrementally to a file, so another
+    /// process can load it. A cache is only valid for the build of the
+    /// compiler that saved it.
+    #[cfg(feature = "serde")]
+    pub fn save_cache_to(path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let failed = |message: String| Error::CacheFailed { path: path.to_path_buf(), message };
+        let json = FN_CACHE
+            .with_borrow(|cache| match cache {
+                Some(cache) => serde_json::to_string(cache),
+                None => serde_json::to_string(&HashMap::<u64, CachedCall>::new()),
+            })
+            .map_err(|e| failed(e.to_string()))?;
+        fs::write(path, json).map_err(|e| failed(e.to_string()))
+    }
+
+    /// Compile incrementally, starting with the code kept in a file saved by `save_cache_to`
+    #[cfg(feature = "serde")]
+    pub fn load_cache_from(path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let failed = |message: String| Error::CacheFailed { path: path.to_path_buf(), message };
+        let json = fs::read_to_string(path).map_err(|e| failed(e.to_string()))?;
+        let cache = serde_json::from_str(&json).map_err(|e| failed(e.to_string()))?;
+        FN_CACHE.set(Some(cache));
+        Ok(())
+    }
+
+    /// Write the code compiled since it was last written to the output, if there is one
+    fn flush_output() -> Result<(), Error> {
+        OUTPUT.with_borrow_mut(|output| {
+            let sink = match output.as_mut() {
+                Some(sink) => sink,
+                None => return Ok(()),
+            };
+            if let Some(mut code) = take_compiled() {
+                for pass in enabled_passes() {
+                    code = pass.transform_output(code);
+                }
+                sink.write_all(code.as_bytes())?;
+                sink.flush()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Allow or forbid defining a function with the name of a function that is already defined
+    pub fn allow_redefine(allow: bool) {
+        ALLOW_REDEFINE.set(allow);
+    }
+
+    /// May functions replace previously defined functions?
+    pub fn redefine_allowed() -> bool {
+        ALLOW_REDEFINE.get()
+    }
+
+    /// Get the warnings produced while compiling
+    pub fn warnings() -> Vec<Warning> {
+        WARNINGS.get()
+    }
+
+    /// Stop a lint, such as `unused`, from producing warnings
+    pub fn allow_lint(name: impl ToString) {
+        let name = name.to_string();
+        ALLOWED_LINTS.with_borrow_mut(|allowed| {
+            if !allowed.contains(&name) {
+                allowed.push(name);
+            }
+        });
+    }
+
+    /// Count a function as used, along with the functions it calls, even if the
+    /// program never calls it, such as a function only a foreign function calls
+    pub fn mark_used(name: impl ToString) {
+        let name = name.to_string();
+        USED_FNS.with_borrow_mut(|used| {
+            if !used.contains(&name) {
+                used.push(name);
+            }
+        });
+    }
+
+    /// Was a function marked used with `mark_used`?
+    pub fn marked_used(name: &str) -> bool {
+        USED_FNS.with_borrow(|used_fns| used_fns.iter().any(|used| used == name))
+    }
+
+    /// Is a lint stopped from producing warnings?
+    pub fn lint_allowed(name: &str) -> bool {
+        ALLOWED_LINTS.with_borrow(|allowed_lints| allowed_lints.iter().any(|lint| lint == name))
+    }
+
+    /// Get the size statistics for each function that was compiled
+    pub fn size_report() -> Vec<FnSize> {
+        SIZE_REPORT.get()
+    }
+
+    /// Set the number of instructions a function may generate before a size warning is emitted
+    pub fn set_size_warn_threshold(threshold: usize) {
+        SIZE_WARN_THRESHOLD.set(threshold);
+    }
+
+    /// Add the size report to the warnings
+    fn warn_sizes() {
+        let threshold = SIZE_WARN_THRESHOLD.get();
+        let report = Self::size_report();
+        for f in &report {
+            warn(format!(
+                "function `{}` generated {} instructions ({} of its own) over {} call(s), allocated {} value(s), made {} call(s), peak stack {} ({} above its frame), {} live cell(s) at exit",
+                f.name,
+                f.instructions,
+                f.own_instructions,
+                f.calls,
+                f.allocations,
+                f.calls_made,
+                f.peak_stack,
+                f.frame_peak,
+                f.live_cells
+            ));
+            if f.instructions > threshold {
+                warn(format!(
+                    "function `{}` generated {} instructions, more than the limit of {}",
+                    f.name, f.instructions, threshold
+                ));
+            }
+        }
+        warn(format!(
+            "program generated {} instructions, peak stack {}",
+            instruction_count(),
+            peak_stack_ptr()
+        ));
+    }
+
+    /// Get the stack size, which is every cell on the tape that the heap doesn't use
+    pub fn stack_size() -> u32 {
+        Self::tape_size().saturating_sub(Self::heap_size())
+    }
+
+    /// Get the heap size
+    pub fn heap_size() -> u32 {
+        HEAP_SIZE.get()
+    }
+
+    /// Set the number of cells `alloc` can use
+    pub fn set_heap_size(size: u32) {
+        HEAP_SIZE.set(size);
+    }
+
+    /// Get how many calls to a function can be compiled inside a call to the same function
+    pub fn recursion_limit() -> u32 {
+        RECURSION_LIMIT.get()
+    }
+
+    /// Set how deep a function can call itself. A limit of 0 makes any recursion an error,
+    /// for targets where it can't work.
+    pub fn set_recursion_limit(limit: u32) {
+        RECURSION_LIMIT.set(limit);
+    }
+
+    /// Get the tape size
+    pub fn tape_size() -> u32 {
+        TAPE_SIZE.get()
+    }
+
+    /// Set the number of cells on the tape of the target machine. A program
+    /// whose stack and heap don't fit on it doesn't compile.
+    pub fn set_tape_size(size: u32) {
+        TAPE_SIZE.set(size);
+    }
+
+    /// The one past the highest address a cell can hold, or the end of the tape
+    pub fn max_address() -> u32 {
+        Self::tape_size().min(Self::cell_width().modulus())
+    }
+
+    /// The first cell of the heap. The heap is the last cells an address can
+    /// reach, up to the heap size, but it never starts below the highest the stack got.
+    pub fn heap_start() -> u32 {
+        Self::max_address().saturating_sub(Self::heap_size()).max(peak_stack_ptr())
+    }
+
+    /// Make sure no function is defined twice
+    fn validate_definitions(funs: &[UserFn]) -> Result<(), Error> {
+        if Self::redefine_allowed() {
+            return Ok(());
+        }
+
+        for (i, fun) in funs.iter().enumerate() {
+            if funs[..i].iter().any(|f| f.overlaps(fun)) {
+                return Err(Error::FunctionRedefined(fun.name.clone()).at(fun.span));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure every function that is called is defined by the program,
+    /// by a library compiled before it, or is a foreign function
+    fn validate_calls(funs: &[UserFn]) -> Result<(), Error> {
+        let foreign = FOREIGN_FN_DEFS.get();
+        let registered = FN_DEFS.get();
+        let mut errors = vec![];
+        for fun in funs {
+            let mut sites = CallSites::default();
+            sites.visit_user_fn(fun);
+
+            // A local function can only be called from inside the function it is defined in
+            let callable = |f: &UserFn| f.enclosing.as_ref().is_none_or(|outer| encloses(outer, &fun.name));
+            let mut reported: Vec<(String, Option<usize>)> = vec![];
+            for (name, count, span) in sites.calls {
+                let user_fns: Vec<&UserFn> = funs
+                    .iter()
+                    .filter(|f| f.name == name && callable(f))
+                    .chain(registered.get(&name).into_iter().flatten().map(|f| &**f).filter(|f| callable(f)))
+                    .collect();
+                let foreign_fns = foreign.get(&name).cloned().unwrap_or_default();
+                if reported.contains(&(name.clone(), count)) || reported.contains(&(name.clone(), None)) {
+                    continue;
+                }
+
+                let span = span.or(fun.span);
+                if user_fns.is_empty() && foreign_fns.is_empty() {
+                    // Calls were resolved against every namespace around the caller
+                    let searched = enclosing_namespaces(namespace_of(&fun.name))
+                        .iter()
+                        .map(|outer| qualify(outer, namespace_of(&name)))
+                        .collect();
+                    reported.push((name.clone(), None));
+                    errors.push(Error::FunctionNotDefined { name, searched }.at(span));
+                    continue;
+                }
+
+                // A function whose reference is taken can be called with any arguments its overloads take
+                let count = match count {
+                    Some(count) => count,
+                    None => continue,
+                };
+                if user_fns.iter().any(|f| f.accepts(count)) || foreign_fns.iter().any(|f| f.accepts(count)) {
+                    continue;
+                }
+                reported.push((name.clone(), Some(count)));
+                let error = match (user_fns.as_slice(), foreign_fns.as_slice()) {
+                    ([f], []) => Error::ArgumentCountMismatch { function: name, expected: f.parameters.len(), got: count },
+                    ([], [f]) => Error::ArgumentCountMismatch { function: name, expected: f.parameters.len(), got: count },
+                    _ => {
+                        let mut available: Vec<usize> = user_fns.iter().map(|f| f.min_arity()).collect();
+                        available.extend(foreign_fns.iter().map(|f| f.parameters.len()));
+                        available.sort_unstable();
+                        available.dedup();
+                        Error::NoMatchingOverload { name, got: count, available }
+                    }
+                };
+                errors.push(error.at(span));
+            }
+        }
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::Errors(errors)),
+        }
+    }
+
+    /// Make sure the entry point is defined, by the program or by a library
+    /// compiled before it, and that it can be called without arguments
+    fn validate_entry_point(funs: &[UserFn]) -> Result<(), Error> {
+        let name = Self::entry_point();
+        // An entry point with overloads is called with the fewest parameters any of them takes
+        let registered = FN_DEFS
+            .with_borrow(|fn_defs| fn_defs.get(&name).and_then(|fs| fs.iter().map(|f| f.parameters.len()).min()));
+        let parameters = match funs.iter().filter(|f| f.name == name).map(|f| f.parameters.len()).min() {
+            Some(parameters) => parameters,
+            None => match registered {
+                Some(parameters) => parameters,
+                None => {
+                    let mut found: Vec<String> = funs.iter().map(|f| f.name.clone()).collect();
+                    let mut libraries: Vec<String> = FN_DEFS.with_borrow(|fn_defs| fn_defs.keys().cloned().collect());
+                    libraries.sort();
+                    found.extend(libraries);
+                    return Err(Error::NoEntryPoint { name, found });
+                }
+            },
+        };
+
+        if parameters > 0 {
+            return Err(Error::InvalidEntryPoint {
+                name,
+                reason: format!("it takes {} parameters, but an entry point can't take any", parameters),
+            });
+        }
+        Ok(())
+    }
+
+    /// Make sure functions that declare they return a value always return one.
+    /// Functions declared `void` are checked when their return statements are compiled.
+    fn validate_returns(funs: &[UserFn]) -> Result<(), Error> {
+        for fun in funs {
+            if let Some(expected) = fun.return_type {
+                if expected != Type::Void && !always_returns(&fun.body) {
+                    return Err(Error::ReturnTypeMismatch {
+                        function: fun.name.clone(),
+                        expected,
+                        got: Type::Void,
+                    }
+                    .at(fun.span));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure a function that must be inlined doesn't call itself, since
+    /// its body would have to be expanded forever
+    fn validate_attributes(funs: &[UserFn]) -> Result<(), Error> {
+        for fun in funs.iter().filter(|f| f.has_attribute(FnAttr::Inline)) {
+            let mut called = vec![];
+            calls_in_body(&fun.body, &mut called);
+            if called.iter().any(|name| reachable_functions(funs, name).contains(&fun.name)) {
+                return Err(Error::RecursiveInline(fun.name.clone()).at(fun.span));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure local functions don't use the variables of the functions they are
+    /// defined in, since a local function has its own scope like any other function
+    fn validate_locals(funs: &[UserFn], globals: &[Define], constants: &[Const]) -> Result<(), Error> {
+        for fun in funs {
+            let mut used = vec![];
+            variables_in_body(&fun.body, &mut used);
+            used.retain(|var| {
+                let own = fun.parameters.contains(var) || defines(&fun.body, var);
+                let global = globals.iter().any(|g| &g.0 == var) || constants.iter().any(|c| &c.0 == var);
+                !own && !global
+            });
+
+            let mut outer = fun.enclosing.clone();
+            while let Some(enclosing) = outer.and_then(|name| funs.iter().find(|f| f.name == name)) {
+                let captured = used
+                    .iter()
+                    .find(|var| enclosing.parameters.contains(var) || defines(&enclosing.body, var));
+                if let Some(variable) = captured {
+                    return Err(Error::CapturedVariable {
+                        function: fun.name.clone(),
+                        variable: variable.clone(),
+                        defined_in: enclosing.name.clone(),
+                    }
+                    .at(locate_variable(&fun.body, variable).or(fun.span)));
+                }
+                outer = enclosing.enclosing.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure every variable a function uses is defined before it is used, even
+    /// in code that is never compiled. A variable defined in a branch of an if
+    /// statement, or in the body of a loop, isn't counted as defined after it.
+    fn validate_variables(funs: &[UserFn], globals: &[Define], constants: &[Const]) -> Result<(), Error> {
+        // Globals and constants can also come from a library compiled before the program
+        let outer = |name: &str| {
+            name.starts_with('%')
+                || globals.iter().any(|g| g.0 == name)
+                || constants.iter().any(|c| c.0 == name)
+                || constant(name).is_some()
+                || SCOPE_STACK.with_borrow(|scope_stack| scope_stack[0].contains(name))
+        };
+
+        let mut errors = vec![];
+        for fun in funs {
+            let mut defined = fun.parameters.clone();
+            let mut undefined = vec![];
+            undefined_variables(&fun.body, &mut defined, &outer, &mut fun.span.clone(), &mut undefined);
+
+            let mut reported: Vec<String> = vec![];
+            for (name, in_scope, span) in undefined {
+                if !reported.contains(&name) {
+                    reported.push(name.clone());
+                    errors.push(Error::VariableNotDefined(name, in_scope).at(span));
+                }
+            }
+        }
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::Errors(errors)),
+        }
+    }
+
+    /// Make sure every global is initialized with a constant, so the order
+    /// they are defined in doesn't matter. Returns the folded initializers.
+    fn validate_globals(globals: Vec<Define>) -> Result<Vec<Define>, Error> {
+        globals
+            .into_iter()
+            .map(|Define(name, value)| match constant_value(&value) {
+                Some(literal) => Ok(Define(name, Eval::Literal(literal))),
+                None => Err(Error::NotConstant(name)),
+            })
+            .collect()
+    }
+
+    /// Compile the code
+    pub fn compile(self) -> Result<String, Error> {
+        Self::validate_entry_point(&self.1)?;
+        let passes = self.register()?;
+
+        call(Self::entry_point(), &[])?;
+
+        // Free the globals
+        SCOPE_STACK.with_borrow_mut(|scope_stack| scope_stack[0].free());
+
+        // Run the optimization passes over the compiled code
+        let mut result = compile();
+        for pass in &passes {
+            result = pass.transform_output(result);
+        }
+
+        if Self::size_warn_enabled() {
+            Self::warn_sizes();
+        }
+        OUTPUT.with_borrow_mut(|output| match output.as_mut() {
+            Some(sink) => {
+                take_compiled();
+                sink.write_all(result.as_bytes())?;
+                sink.flush()?;
+                Ok(String::new())
+            }
+            None => Ok(result),
+        })
+    }
+
+    /// Compile the code, writing it to `out` as it is compiled, and return `out`
+    pub fn compile_to<W: Write + Send + 'static>(self, out: W) -> Result<W, Error> {
+        let out = Arc::new(Mutex::new(out));
+        Self::set_output(Some(Box::new(SharedOutput(out.clone()))));
+        let result = self.compile();
+        Self::set_output(None);
+        result?;
+        match Arc::try_unwrap(out) {
+            Ok(out) => Ok(out.into_inner().unwrap()),
+            Err(_) => unreachable!("the output is only shared with the sink, which was dropped"),
+        }
+    }
+
+    /// Compile the code, and return the operations it was lowered to
+    /// instead of the instructions they were compiled to
+    pub fn compile_to_ir(self) -> Result<Vec<IrOp>, Error> {
+        record_ir(true);
+        let result = self.compile();
+        let ops = record_ir(false);
+        result.map(|_| ops)
+    }
+
+    /// Compile the code with a backend, which generates code for its
+    /// own target from the operations the program is lowered to
+    pub fn compile_with_backend(self, backend: &mut dyn Backend) -> Result<String, Error> {
+        for op in &self.compile_to_ir()? {
+            backend.emit(op);
+        }
+        Ok(backend.finish())
+    }
+
+    /// Compile the code, starting in the function `name`
+    pub fn compile_with_entry(self, name: &str) -> Result<String, Error> {
+        Self::set_entry_point(name);
+        self.compile()
+    }
+
+    /// Check and register the functions of the program without calling an
+    /// entry point, so programs compiled afterwards can call them. Functions
+    /// aren't removed for being unreachable, because nothing is called yet.
+    pub fn compile_library(self) -> Result<(), Error> {
+        LIBRARY_MODE.set(true);
+        let result = self.register();
+        LIBRARY_MODE.set(false);
+        result.map(|_| ())
+    }
+
+    /// Is a library being compiled?
+    pub fn library_mode() -> bool {
+        LIBRARY_MODE.get()
+    }
+
+    /// Check the program, register its definitions, and compile its globals.
+    /// Returns the enabled optimization passes.
+    fn register(self) -> Result<Vec<Box<dyn Pass>>, Error> {
+        // Add the compiler functions
+        Self::prelude();
+
+        // Get function definitions
+        let Program(_, mut funs, globals, constants, structs, _) = self;
+
+        for structure in structs {
+            structure.compile()?;
+        }
+
+        // Every function is known before any is compiled, so check
+        // every definition and every call now, including calls in code
+        // that is never compiled
+        Self::validate_definitions(&funs)?;
+        Self::validate_calls(&funs)?;
+        Self::validate_returns(&funs)?;
+        Self::validate_attributes(&funs)?;
+        Self::validate_locals(&funs, &globals, &constants)?;
+        Self::validate_variables(&funs, &globals, &constants)?;
+        for warning in lint(&funs, &globals) {
+            warn(warning);
+        }
+
+        // Constants are defined in order, so each can use the ones before it
+        for constant in &constants {
+            constant.compile()?;
+        }
+        let globals = Self::validate_globals(globals)?;
+
+        // Run the optimization passes over the functions
+        let passes = enabled_passes();
+        for pass in &passes {
+            funs = pass.transform(funs);
+        }
+
+        // Every indirect call can reach every function whose reference is
+        // taken, so they are all known before any function is compiled
+        for fun in &funs {
+            FN_REFS.with_borrow_mut(|fn_refs| fn_refs_in_body(&fun.body, fn_refs));
+        }
+
+        // The string literals used more than once by the functions that can run
+        let repeated = if Self::interning_disabled() || Self::library_mode() {
+            vec![]
+        } else {
+            let reachable = reachable_functions(&funs, &Self::entry_point());
+            repeated_strings(funs.iter().filter(|f| reachable.contains(&f.name)))
+        };
+
+        // Compile
+        for fun in funs {
+            fun.compile()?;
+        }
+
+        // Globals live in the outermost scope, below every function's stack frame
+        for global in &globals {
+            global.compile()?;
+        }
+
+        // So do the repeated string literals, which are written once instead of at each use
+        for text in repeated {
+            intern(text)?;
+        }
+        Ok(passes)
+    }
+}
+
+
+/// The possible flags to be returned by the parser
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Flag {
+    EnableBrainFuck,
+    EnableSizeWarn,
+    EmitDebugComments,
+    DisablePtrs,
+    /// Compile asserts to nothing
+    StripAsserts,
+    /// Stop the program when arithmetic overflows
+    OverflowChecks,
+    /// Stop the program when a runtime index is past the end of what it indexes
+    BoundsChecks,
+    /// Store strings as their UTF-8 bytes
+    Utf8Strings,
+    /// Give every use of a string literal its own cells
+    DisableInterning,
+    /// The number of cells `alloc` can use
+    HeapSize(u32),
+    /// The number of cells on the tape, which the stack and the heap share
+    TapeSize(u32),
+    /// How deep a function can call itself, where 0 allows no recursion at all
+    RecursionLimit(u32),
+    CellSize(CellWidth),
+    Optimize(u8),
+    EofValue(u8),
+    EntryPoint(String),
+    /// Stop a lint from producing warnings
+    Allow(String),
+}
+
+impl Flag {
+    /// Get the flag for a pragma, like `#![optimize(2)]`, from its name and argument
+    pub fn from_pragma(name: &str, arg: Option<&str>) -> Result<Self, String> {
+        let number = || arg.and_then(|arg| arg.parse::<u8>().ok());
+        let flag = match (name, arg) {
+            ("brainfuck", None) => Self::EnableBrainFuck,
+            ("size_warn", None) => Self::EnableSizeWarn,
+            ("debug_comments", None) => Self::EmitDebugComments,
+            ("disable_ptrs", None) => Self::DisablePtrs,
+            ("strip_asserts", None) => Self::StripAsserts,
+            ("overflow_checks", None) => Self::OverflowChecks,
+            ("bounds_checks", None) => Self::BoundsChecks,
+            ("utf8_strings", None) => Self::Utf8Strings,
+            ("disable_interning", None) => Self::DisableInterning,
+            ("optimize", Some(_)) => match number() {
+                Some(level) if level <= MAX_OPTIMIZE_LEVEL => Self::Optimize(level),
+                _ => return Err(format!("optimization level must be at most {}", MAX_OPTIMIZE_LEVEL)),
+            },
+            ("cell_size", Some(_)) => match number() {
+                Some(8) => Self::CellSize(CellWidth::Eight),
+                Some(16) => Self::CellSize(CellWidth::Sixteen),
+                _ => return Err(String::from("cell size must be 8 or 16")),
+            },
+            ("eof", Some(_)) => match number() {
+                Some(value @ 0) | Some(value @ 255) => Self::EofValue(value),
+                _ => return Err(String::from("eof value must be 0 or 255")),
+            },
+            ("entry_point", Some(name)) => Self::EntryPoint(name.to_string()),
+            ("heap_size", Some(size)) => match size.parse() {
+                Ok(size) => Self::HeapSize(size),
+                Err(_) => return Err(String::from("heap size must be a number of cells")),
+            },
+            ("tape_size", Some(size)) => match size.parse() {
+                Ok(size) => Self::TapeSize(size),
+                Err(_) => return Err(String::from("tape size must be a number of cells")),
+            },
+            ("recursion_limit", Some(limit)) => match limit.parse() {
+                Ok(limit) => Self::RecursionLimit(limit),
+                Err(_) => return Err(String::from("recursion limit must be a number of calls")),
+            },
+            ("allow", Some(lint)) if LINTS.contains(&lint) => Self::Allow(lint.to_string()),
+            ("allow", Some(lint)) => return Err(format!("unknown lint `{}`", lint)),
+            ("brainfuck", Some(_)) | ("size_warn", Some(_)) | ("debug_comments", Some(_)) | ("disable_ptrs", Some(_))
+            | ("strip_asserts", Some(_))
+            | ("overflow_checks", Some(_))
+            | ("bounds_checks", Some(_))
+            | ("utf8_strings", Some(_))
+            | ("disable_interning", Some(_)) => {
+                return Err(format!("pragma `{}` doesn't take an argument", name))
+            }
+            ("optimize", None) | ("cell_size", None) | ("eof", None) | ("entry_point", None) | ("allow", None)
+            | ("heap_size", None)
+            | ("tape_size", None)
+            | ("recursion_limit", None) => {
+                return Err(format!("pragma `{}` needs an argument", name))
+            }
+            _ => return Err(format!("unknown pragma `{}`", name)),
+        };
+        Ok(flag)
+    }
+}
+
+/// The possible widths of a cell on the tape
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CellWidth {
+    Eight,
+    Sixteen,
+}
+
+impl CellWidth {
+    /// The largest number a single cell can hold
+    pub fn max_value(self) -> u32 {
+        match self {
+            Self::Eight => u8::MAX as u32,
+            Self::Sixteen => u16::MAX as u32,
+        }
+    }
+
+    /// The number of values a cell can hold, which arithmetic on a cell wraps around at
+    pub fn modulus(self) -> u32 {
+        self.max_value() + 1
+    }
+
+    /// The C type used to represent a cell
+    pub fn c_type(self) -> &'static str {
+        match self {
+            Self::Eight => "unsigned char",
+            Self::Sixteen => "unsigned short",
+        }
+    }
+}
+
+/// The possible compiler errors
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// An error in the statement at a span of the source code
+    At(Span, Box<Error>),
+    /// An error in a function call, with the calls that led to it
+    Trace(Vec<Frame>, Box<Error>),
+    /// Errors found together by checking the whole program, each with its own span
+    Errors(Vec<Error>),
+    Syntax(String),
+    /// The compiled code could not be written to the output
+    Io(Arc<io::Error>),
+    /// The stack grew past the cells the tape has left after the heap
+    TapeOverflow {
+        needed: u32,
+        limit: u32,
+        function: String,
+        largest: Vec<Allocation>,
+    },
+    NoActiveScope,
+    CouldNotDefine {
+        name: String,
+        value: String,
+        error: Box<Error>,
+    },
+    MustReturnSingleByte,
+    ExitStatusNotSingleByte,
+    /// A value was assigned to a variable with a different number of cells
+    AssignSizeMismatch {
+        target: String,
+        target_size: u32,
+        source_size: u32,
+    },
+    CannotReferenceAReference,
+    CannotConcatenateAReference,
+    CannotUsePointersInBrainFuckMode,
+    CannotUseUnsignedShortsInBrainFuckMode,
+    CannotAssignLargerValueToSmallerValueInBrainFuckMode,
+    ValueTooLargeForCell(u32, CellWidth),
+    /// A fixed point number is outside the range of fixed point numbers
+    FixedOutOfRange(f64),
+    /// An arithmetic function was given two different kinds of numbers
+    MixedNumbers {
+        function: String,
+        left: NumberKind,
+        right: NumberKind,
+    },
+    /// A number literal doesn't fit in the kind of number it is used as
+    NumberOutOfRange {
+        number: String,
+        kind: NumberKind,
+    },
+    /// A string has characters that aren't ASCII, and strings aren't stored as UTF-8
+    NonAsciiString(String),
+    /// The namespaces searched are listed from the innermost,
+    /// and the root namespace is the empty string
+    FunctionNotDefined {
+        name: String,
+        searched: Vec<String>,
+    },
+    FunctionRedefined(String),
+    RecursiveInline(String),
+    /// A function called itself more times than the recursion limit allows. The
+    /// chain is the calls that repeat, from the function back to itself.
+    RecursionLimitExceeded {
+        function: String,
+        limit: u32,
+        chain: Vec<String>,
+    },
+    CapturedVariable {
+        function: String,
+        variable: String,
+        defined_in: String,
+    },
+    FunctionRedefinedInFiles {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    ImportNotFound(PathBuf),
+    ConflictingFlags(Flag, Flag),
+    InvalidEntryPoint {
+        name: String,
+        reason: String,
+    },
+    NoEntryPoint {
+        name: String,
+        found: Vec<String>,
+    },
+    ImportCycle(Vec<PathBuf>),
+    ParseFailed {
+        path: PathBuf,
+        message: String,
+    },
+    /// The code kept for compiling incrementally could not be saved or loaded
+    CacheFailed {
+        path: PathBuf,
+        message: String,
+    },
+    /// The name that isn't defined, and the names that are defined in its scope
+    VariableNotDefined(String, Vec<String>),
+    /// A reference to this variable outlives the scope the variable is defined in
+    DanglingReference(String),
+    /// The value being dereferenced is known to be data rather than a reference
+    DerefOfNonReference(String),
+    NotAString(String),
+    /// A bound of a ranged `for` loop is more than one cell
+    RangeBoundNotSingleCell(String),
+    /// The condition of a `while` loop is a 4 byte number, which the loop can't test again
+    WideLoopCondition(String),
+    /// A function returns a 4 byte number without declaring it, so its caller has no cells for it
+    WideReturnUndeclared(String),
+    /// The count of a `repeat` isn't a whole number known while compiling
+    RepeatCountNotConstant(String),
+    NotConstant(String),
+    AssignToConst(String),
+    DuplicateVariant(String),
+    DuplicateVariantValue(String, u8),
+    VariantValueTooLarge(String),
+    StructNotDefined(String),
+    StructRedefined(String),
+    NotAStruct(String),
+    UnknownField {
+        structure: String,
+        field: String,
+    },
+    MissingField {
+        structure: String,
+        field: String,
+    },
+    DuplicateField {
+        structure: String,
+        field: String,
+    },
+    FieldNotSingleCell {
+        structure: String,
+        field: String,
+    },
+    IndexOutOfRange {
+        index: u32,
+        length: u32,
+    },
+    PointersDisabled(String),
+    ReturnTypeMismatch {
+        function: String,
+        expected: Type,
+        got: Type,
+    },
+    ArgumentTypeMismatch {
+        function: String,
+        parameter: String,
+        expected: Type,
+        got: Type,
+    },
+    ArgumentCountMismatch {
+        function: String,
+        expected: usize,
+        got: usize,
+    },
+    NoMatchingOverload {
+        name: String,
+        got: usize,
+        available: Vec<usize>,
+    },
+    /// An indirect call is made, but no function's reference is ever taken
+    NoFunctionReferences,
+    /// An indirect call is made through a value that can't be a function reference
+    NotAFunctionReference(String),
+    UnknownArgument {
+        function: String,
+        parameter: String,
+    },
+    DuplicateArgument {
+        function: String,
+        parameter: String,
+    },
+    MissingArgument {
+        function: String,
+        parameter: String,
+    },
+    ReferenceArgumentNotAVariable {
+        function: String,
+        parameter: String,
+        argument: String,
+    },
+    TupleElementNotSingleCell(String),
+    VoidValueUsed(String),
+    ReturnArityMismatch {
+        function: String,
+        expected: usize,
+        got: usize,
+    },
+    DestructureArityMismatch {
+        names: usize,
+        values: usize,
+    },
+    FormatNotLiteral(String),
+    FormatArgumentMismatch {
+        format: String,
+        placeholders: usize,
+        arguments: usize,
+    },
+}
+
+impl Error {
+    /// Report this error at a span, unless it already has one
+    pub fn at(self, span: Option<Span>) -> Self {
+        match (self, span) {
+            (Self::At(span, error), _) => Self::At(span, error),
+            (Self::Errors(errors), _) => Self::Errors(errors),
+            (Self::Trace(frames, error), span) => Self::Trace(frames, Box::new(error.at(span))),
+            (error, Some(span)) => Self::At(span, Box::new(error)),
+            (error, None) => error,
+        }
+    }
+
+    /// Attach the calls being compiled to this error, unless it already has them
+    pub fn traced(self) -> Self {
+        match self {
+            Self::Trace(frames, error) => Self::Trace(frames, error),
+            error => {
+                let mut frames = backtrace();
+                // The chain has the calls that repeat, so only the calls that led into them are traced
+                let inner = match &error {
+                    Self::At(_, inner) => &**inner,
+                    other => other,
+                };
+                if let Self::RecursionLimitExceeded { function, .. } = inner {
+                    if let Some(first) = frames.iter().position(|frame| &frame.name == function && !frame.foreign) {
+                        frames.truncate(first + 1);
+                    }
+                }
+                Self::Trace(frames, Box::new(error))
+            }
+        }
+    }
+}
+
+/// Something suspicious in a program, which doesn't stop it from compiling
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Warning {
+    /// A variable defined in a function, or a parameter, that the function never uses
+    UnusedVariable {
+        name: String,
+        function: String,
+        span: Option<Span>,
+    },
+    /// A variable defined in a function, or a parameter, with the name of a
+    /// global, which hides the global from the function
+    ShadowedVariable {
+        name: String,
+        function: String,
+        original_span: Option<Span>,
+        shadow_span: Option<Span>,
+    },
+    /// A variable defined again in the same function, which replaces
+    /// the first variable instead of hiding it
+    Redefinition {
+        name: String,
+        function: String,
+        original_span: Option<Span>,
+        span: Option<Span>,
+    },
+    /// A variable defined again in the same function with a different
+    /// number of cells, so code expecting the old size reads the wrong cells
+    Resized {
+        name: String,
+        function: String,
+        old_size: u32,
+        new_size: u32,
+        span: Option<Span>,
+    },
+    /// A function that can't be reached from the entry point
+    UnusedFunction { name: String, span: Option<Span> },
+    /// A warning that isn't produced by a lint, such as the size report
+    Message(String),
+}
+
+impl Warning {
+    /// The name of the lint that produced this warning, which
+    /// `#![allow(...)]` or `Program::allow_lint` can turn off
+    pub fn lint(&self) -> Option<&'static str> {
+        match self {
+            Self::UnusedVariable { .. } | Self::UnusedFunction { .. } => Some("unused"),
+            Self::ShadowedVariable { .. } | Self::Redefinition { .. } | Self::Resized { .. } => Some("shadowing"),
+            Self::Message(_) => None,
+        }
+    }
+
+    /// The span of the code the warning is about
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::UnusedVariable { span, .. }
+            | Self::Redefinition { span, .. }
+            | Self::Resized { span, .. }
+            | Self::UnusedFunction { span, .. } => *span,
+            Self::ShadowedVariable { shadow_span, .. } => *shadow_span,
+            Self::Message(_) => None,
+        }
+    }
+}
+
+impl From<String> for Warning {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+impl From<&str> for Warning {
+    fn from(message: &str) -> Self {
+        Self::Message(message.to_string())
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}: ", span)?;
+        }
+        match self {
+            Self::UnusedVariable { name, function, .. } => {
+                write!(f, "variable `{}` is never used in function `{}`", name, function)
+            }
+            Self::UnusedFunction { name, .. } => write!(f, "function `{}` is never called", name),
+            Self::ShadowedVariable { name, function, original_span, .. } => {
+                write!(f, "variable `{}` in function `{}` shadows the global `{}`", name, function, name)?;
+                match original_span {
+                    Some(span) => write!(f, " defined at {}", span),
+                    None => Ok(()),
+                }
+            }
+            Self::Redefinition { name, function, original_span, .. } => {
+                write!(f, "variable `{}` is defined again in function `{}`, replacing the first definition", name, function)?;
+                match original_span {
+                    Some(span) => write!(f, " at {}", span),
+                    None => Ok(()),
+                }
+            }
+            Self::Resized {
+                name,
+                function,
+                old_size,
+                new_size,
+                ..
+            } => write!(
+                f,
+                "variable `{}` is defined again in function `{}` with {} cell(s) instead of {}",
+                name, function, new_size, old_size
+            ),
+            Self::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A function call in a backtrace
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+pub struct Frame {
+    pub name: String,
+    /// The number of arguments the function was called with, which picks its overload
+    pub arity: usize,
+    /// Foreign functions have no source, so they have no span
+    pub foreign: bool,
+    /// The statement being compiled in the function
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.foreign, self.span) {
+            (true, _) => write!(f, "{} (foreign)", self.name),
+            (false, Some(span)) => write!(f, "{} at {}", self.name, span),
+            (false, None) => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// The calls being compiled, from the outermost to the innermost
+pub fn backtrace() -> Vec<Frame> {
+    let stack = CALL_STACK.get();
+    // Each frame is at the statement that made the next call
+    let mut spans: Vec<Option<Span>> = stack.iter().skip(1).map(|frame| frame.span).collect();
+    spans.push(location());
+    stack
+        .into_iter()
+        .zip(spans)
+        .map(|(frame, span)| Frame {
+            span: if frame.foreign { None } else { span },
+            ..frame
+        })
+        .collect()
+}
+
+/// Every call is compiled where it is made, so a function that calls itself
+/// is compiled inside itself. Stop before that goes deeper than the limit.
+fn check_recursion(name: &str, arity: usize) -> Result<(), Error> {
+    // Each overload is a different function, so one overload can call another
+    let same = |frame: &Frame| frame.name == name && frame.arity == arity && !frame.foreign;
+    let stack = CALL_STACK.get();
+    let calls = stack.iter().filter(|frame| same(frame)).count();
+    let limit = Program::recursion_limit();
+    if calls <= limit as usize {
+        return Ok(());
+    }
+
+    // Foreign functions that lower their own arguments are left out, since the
+    // calls in the arguments are still made by the function the call is in
+    let start = stack.iter().rposition(same).unwrap_or(0);
+    let mut chain: Vec<String> =
+        stack[start..].iter().filter(|frame| !frame.foreign).map(|frame| frame.name.clone()).collect();
+    chain.push(name.to_string());
+    Err(Error::RecursionLimitExceeded {
+        function: name.to_string(),
+        limit,
+        chain,
+    })
+}
+
+/// Mark the start of a call in the call stack
+fn enter_call(name: &str, arity: usize, foreign: bool) {
+    CALL_STACK.with_borrow_mut(|call_stack| call_stack.push(Frame {
+        name: name.to_string(),
+        arity,
+        foreign,
+        span: location(),
+    }));
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(Arc::new(error))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::At(span, error) => write!(f, "{}: {}", span, error),
+            Self::Trace(frames, error) => {
+                write!(f, "{}", error)?;
+                for frame in frames {
+                    write!(f, "\n    in {}", frame)?;
+                }
+                Ok(())
+            }
+            Self::Errors(errors) => {
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            Self::Syntax(message) => write!(f, "syntax error: {}", message),
+            Self::Io(error) => write!(f, "could not write the compiled code: {}", error),
+            Self::AssignSizeMismatch {
+                target,
+                target_size,
+                source_size,
+            } => write!(
+                f,
+                "cannot assign a value of {} cell(s) to `{}`, which is {} cell(s)",
+                source_size, target, target_size
+            ),
+            Self::CacheFailed { path, message } => {
+                write!(f, "could not use the compile cache at {}: {}", path.display(), message)
+            }
+            Self::NoEntryPoint { name, found } if found.is_empty() => {
+                write!(f, "the entry point `{}` is not defined, and no functions are defined", name)
+            }
+            Self::NoEntryPoint { name, found } => write!(
+                f,
+                "the entry point `{}` is not defined, the functions defined are: {}",
+                name,
+                found.join(", ")
+            ),
+            Self::TapeOverflow { needed, limit, function, largest } => {
+                write!(
+                    f,
+                    "the program needs {} cells of tape in function `{}`, more than the {} there are",
+                    needed, function, limit
+                )?;
+                if !largest.is_empty() {
+                    let largest: Vec<String> = largest.iter().map(|allocation| allocation.to_string()).collect();
+                    write!(f, ", the largest allocations are {}", largest.join(", "))?;
+                }
+                Ok(())
+            }
+            Self::RecursionLimitExceeded { function, limit: 0, chain } => {
+                write!(f, "function `{}` calls itself, and recursion is not allowed: {}", function, chain.join(" -> "))
+            }
+            Self::RecursionLimitExceeded { function, limit, chain } => write!(
+                f,
+                "function `{}` calls itself more than {} calls deep: {}",
+                function,
+                limit,
+                chain.join(" -> ")
+            ),
+            Self::VariableNotDefined(name, in_scope) if in_scope.is_empty() => {
+                write!(f, "the variable `{}` is not defined, and no variables are in scope", name)
+            }
+            Self::VariableNotDefined(name, in_scope) => write!(
+                f,
+                "the variable `{}` is not defined, the variables in scope are: {}",
+                name,
+                in_scope.join(", ")
+            ),
+            Self::DerefOfNonReference(name) => {
+                write!(f, "cannot dereference `{}`, which is not a reference", name)
+            }
+            Self::MixedNumbers { function, left, right } => write!(
+                f,
+                "`{}` cannot mix {} with {}, one of them must be cast to the other's kind",
+                function, left, right
+            ),
+            Self::NumberOutOfRange { number, kind } => write!(f, "{} does not fit in {}", number, kind),
+            Self::FixedOutOfRange(n) => write!(
+                f,
+                "fixed point number {:?} is out of range, it must be at least {} and less than {}",
+                n, FIXED_RANGE.start, FIXED_RANGE.end
+            ),
+            Self::WideLoopCondition(condition) => write!(
+                f,
+                "the condition of a while loop must be a single cell, but `{}` is a 4 byte number",
+                condition
+            ),
+            Self::WideReturnUndeclared(function) => write!(
+                f,
+                "function `{}` returns a 4 byte number, so it must declare its return type, like `-> i32`",
+                function
+            ),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// The types a function may declare for its parameters and return value
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Type {
+    Byte,
+    Short,
+    /// A cell read as a number in two's complement
+    Signed,
+    /// A 4 byte number in eight cells
+    U32,
+    /// A 4 byte number in eight cells, in two's complement
+    I32,
+    /// A fixed point number in eight cells
+    Fixed,
+    Char,
+    Str,
+    Ref,
+    Void,
+}
+
+impl Type {
+    /// Get the type with the name used in the source code
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "byte" => Some(Self::Byte),
+            "u16" => Some(Self::Short),
+            "signed" => Some(Self::Signed),
+            "u32" => Some(Self::U32),
+            "i32" => Some(Self::I32),
+            "fixed" => Some(Self::Fixed),
+            "char" => Some(Self::Char),
+            "string" => Some(Self::Str),
+            "ref" => Some(Self::Ref),
+            "void" => Some(Self::Void),
+            _ => None,
+        }
+    }
+
+    /// The name of the type used in the source code
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Byte => "byte",
+            Self::Short => "u16",
+            Self::Signed => "signed",
+            Self::U32 => "u32",
+            Self::I32 => "i32",
+            Self::Fixed => "fixed",
+            Self::Char => "char",
+            Self::Str => "string",
+            Self::Ref => "ref",
+            Self::Void => "void",
+        }
+    }
+
+    /// The closest type to a lowered value
+    pub fn of(val: &Value) -> Self {
+        if val.number == NumberKind::Unsigned4Byte {
+            Self::U32
+        } else if val.number == NumberKind::Signed4Byte {
+            Self::I32
+        } else if val.number == NumberKind::Fixed {
+            Self::Fixed
+        } else if val.size() > 1 {
+            Self::Str
+        } else if val.kind == ValueKind::Reference {
+            Self::Ref
+        } else if val.number.is_signed() {
+            Self::Signed
+        } else {
+            Self::Byte
+        }
+    }
+
+    /// Can a value of this type be `val`?
+    pub fn accepts(self, val: &Value) -> bool {
+        match self {
+            Self::Byte | Self::Short | Self::Char => val.size() == 1 && val.kind != ValueKind::Reference,
+            Self::Signed => val.size() == 1 && val.number.is_signed(),
+            Self::U32 => val.number == NumberKind::Unsigned4Byte,
+            Self::I32 => val.number == NumberKind::Signed4Byte,
+            Self::Fixed => val.number == NumberKind::Fixed,
+            Self::Ref => val.size() == 1 && val.kind != ValueKind::Data,
+            Self::Str => val.size() > 1 && !val.number.is_wide(),
+            Self::Void => false,
+        }
+    }
+
+    /// The kind of value a value of this type is
+    pub fn kind(self) -> ValueKind {
+        match self {
+            Self::Ref => ValueKind::Reference,
+            Self::Void => ValueKind::Unknown,
+            _ => ValueKind::Data,
+        }
+    }
+
+    /// How a value of this type is read as a number
+    pub fn number(self) -> NumberKind {
+        match self {
+            Self::Signed => NumberKind::Signed,
+            Self::U32 => NumberKind::Unsigned4Byte,
+            Self::I32 => NumberKind::Signed4Byte,
+            Self::Fixed => NumberKind::Fixed,
+            _ => NumberKind::Unsigned,
+        }
+    }
+}
+
+/// This trait describes objects that are lowered
+/// into values rather than expressions, such as function calls
+/// literals, variables, etc..
+pub trait Lower {
+    fn lower(&self) -> Result<Value, Error>;
+}
+
+/// A value can be `lowered` into a value
+impl Lower for Value {
+    fn lower(&self) -> Result<Value, Error> {
+        Ok(*self)
+    }
+}
+
+/// This trait is for objects that compile into expressions
+/// rather than values. This is applicable for definitions,
+/// assignments, return expressions, while loops, etc..
+pub trait Compile {
+    fn compile(&self) -> Result<(), Error>;
+}
+
+/// This function creates a new scope on the scope stack.
+/// THIS IS ONLY TO BE USED BY FUNCTION DEFINITIONS
+fn push_scope(env: Env) {
+    SCOPE_STACK.with_borrow_mut(|scope_stack| scope_stack.push(env));
+    CONSTANTS.with_borrow_mut(|constants| constants.push(HashMap::new()));
+}
+
+/// This function destroys a scope on the scope stack.
+/// THIS IS ONLY TO BE USED BY FUNCTION DEFINITIONS
+fn pop_scope() -> Result<Env, Error> {
+    CONSTANTS.with_borrow_mut(|constants| constants.pop());
+    SCOPE_STACK.with_borrow_mut(|scope_stack| scope_stack.pop().ok_or(Error::NoActiveScope))
+}
+
+/// Run a function on the innermost scope
+fn with_scope<T>(f: impl FnOnce(&mut Env) -> T) -> Result<T, Error> {
+    SCOPE_STACK.with_borrow_mut(|scopes| scopes.last_mut().map(f).ok_or(Error::NoActiveScope))
+}
+
+/// Record what a variable points to, in the scope the variable is defined in
+fn set_referent(name: &str, referent: Option<(u32, u32)>) {
+    SCOPE_STACK.with_borrow_mut(|scope_stack| {
+        let in_scope = scope_stack.last().is_some_and(|scope| scope.contains(name));
+        let scope = if in_scope { scope_stack.last_mut() } else { scope_stack.first_mut() };
+        if let Some(scope) = scope {
+            scope.set_referent(name, referent);
+        }
+    });
+}
+
+/// The name of a variable in a popped scope that a variable in a
+/// remaining scope still points to
+fn dangling_reference(env: &Env) -> Option<String> {
+    SCOPE_STACK.with_borrow(|scope_stack| {
+        scope_stack
+            .iter()
+            .flat_map(Env::iter)
+            .find_map(|(_, val)| env.referred_by(val).map(String::from))
+    })
+}
+
+/// Give a string literal cells in the outermost scope, which every use of it shares
+fn intern(text: String) -> Result<(), Error> {
+    let name = format!("%STR#{}%", INTERNED.with_borrow(|interned| interned.len()));
+    let value = Value::string(&text)
+        .map_err(|error| could_not_define(&name, format!("{:?}", Literal::String(text.clone())), error))?;
+    with_scope(|scope| scope.define_no_cp(&name, value))?;
+    INTERNED.with_borrow_mut(|interned| interned.insert(text, name));
+    Ok(())
+}
+
+/// Get the value of a constant defined in the current scope, or at the top level
+pub fn constant(name: impl ToString) -> Option<Literal> {
+    let name = name.to_string();
+    CONSTANTS.with_borrow(|constants| {
+        constants
+            .last()
+            .and_then(|scope| scope.get(&name))
+            .or_else(|| constants[0].get(&name))
+            .cloned()
+    })
+}
+
+/// Replace the constants in an expression with their values, and fold it into a literal
+fn constant_value(value: &Eval) -> Option<Literal> {
+    let mut names = vec![];
+    variables_in_eval(value, &mut names);
+    let substitutions: Vec<(String, Eval)> = names
+        .into_iter()
+        .filter_map(|name| constant(&name).map(|literal| (name, Eval::Literal(literal))))
+        .collect();
+
+    match fold(substitute(value, &substitutions)) {
+        Eval::Literal(literal) => Some(literal),
+        _ => None,
+    }
+}
+
+/// This returns the name of the user defined function being compiled
+pub fn current_function() -> String {
+    CALL_STACK.with_borrow(|stack| stack.last().map(|frame| frame.name.clone()).unwrap_or_default())
+}
+
+/// This returns the number of arguments the function being compiled was called with
+fn current_arity() -> usize {
+    CALL_STACK.with_borrow(|call_stack| call_stack.last().map_or(0, |frame| frame.arity))
+}
+
+/// This returns an error if pointers are disabled for user code
+fn check_ptrs_enabled() -> Result<(), Error> {
+    if Program::ptrs_disabled() {
+        Err(Error::PointersDisabled(current_function()))
+    } else {
+        Ok(())
+    }
+}
+
+/// This sets the RETURN value object to a specific value
+pub fn set_return(val: Value) -> Result<(), Error> {
+    if val.size() > 1 {
+        Err(Error::MustReturnSingleByte)
+    } else {
+        RETURN.get().assign(val)?;
+        Ok(())
+    }
+}
+
+/// This retreives the last value returned by a function. The value is a
+/// copy of RETURN, which is freed once the statement using it is done.
+pub fn get_return() -> Result<Value, Error> {
+    RETURN.get().copy().map(unnamed_temporary)
+}
+
+/// Free a value made while lowering an expression along with the temporaries
+/// of the statement using it, when nothing names it
+fn unnamed_temporary(val: Value) -> Value {
+    let number = TEMP_COUNTER.replace(TEMP_COUNTER.get() + 1);
+    RETURN_TEMPS.with_borrow_mut(|return_temps| return_temps.push((number, val)));
+    val
+}
+
+
+/// Lower the condition of an if statement or an assert to the cell it tests.
+/// A 4 byte number is tested as the sum of its digits, in a cell that is
+/// freed with the temporaries of the statement.
+fn lower_condition(condition: &Eval) -> Result<Value, Error> {
+    let val = condition.lower()?;
+    if val.number.is_wide() {
+        sum_digits(val).map(unnamed_temporary)
+    } else {
+        Ok(val)
+    }
+}
+
+/// This represents a value that can be evaluated. Variables,
+/// literals, function calls, value dereferences, variable references,
+/// and values (this is a cheat for letting the compiler easily use
+/// values in place of long Evals) can all be evaluated into a Value
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Eval {
+    Load(Load),
+    Literal(Literal),
+    Call(Call),
+    Deref(Deref),
+    Refer(Refer),
+    Concat(Concat),
+    Index(Index),
+    Construct(Construct),
+    Field(Field),
+    Tuple(Tuple),
+    FnRef(FnRef),
+    CallIndirect(CallIndirect),
+    /// Values only exist while compiling, so they are never part of a parsed program
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Value(Value),
+}
+
+/// An Eval expression is evaluated by the expression it contains
+impl Lower for Eval {
+    fn lower(&self) -> Result<Value, Error> {
+        // Lower the contained expression
+        match self {
+            Self::Load(l) => l.lower(),
+            Self::Literal(l) => l.lower(),
+            Self::Deref(r) => r.lower(),
+            Self::Call(c) => c.lower(),
+            Self::Refer(v) => v.lower(),
+            Self::Concat(c) => c.lower(),
+            Self::Index(i) => i.lower(),
+            Self::Construct(c) => c.lower(),
+            Self::Field(f) => f.lower(),
+            Self::Tuple(t) => t.lower(),
+            Self::FnRef(r) => r.lower(),
+            Self::CallIndirect(c) => c.lower(),
+            Self::Value(v) => v.lower(),
+        }
+    }
+}
+
+/// This represents a statement as opposed to a value.
+/// A value can also be a statement, though.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Expr {
+    If(If),
+    While(While),
+    ForRange(ForRange),
+    Repeat(Repeat),
+    Eval(Eval),
+    Define(Define),
+    Destructure(Destructure),
+    Const(Const),
+    Assign(Assign),
+    Return(Return),
+    Assert(Assert),
+    Exit(Exit),
+    /// Marks where the statement after it is in the source code
+    Locate(Span),
+}
+
+/// An Expression is evaluated by the expression it contains
+impl Expr {
+    /// A short description of the statement for debug comments, or None
+    /// if it generates no code
+    fn describe(&self) -> Option<String> {
+        let description = match self {
+            Self::If(_) => String::from("if"),
+            Self::While(While(Eval::Load(Load(name)), _)) => format!("while {}", name),
+            Self::While(_) => String::from("while"),
+            Self::ForRange(ForRange(name, ..)) => format!("for {}", name),
+            Self::Repeat(Repeat(count, _)) => format!("repeat {}", count),
+            Self::Eval(Eval::Call(Call(name, ..))) => format!("call {}", name),
+            Self::Eval(Eval::CallIndirect(_)) => String::from("indirect call"),
+            Self::Eval(_) => String::from("eval"),
+            Self::Define(Define(name, _)) => format!("define {}", name),
+            Self::Destructure(Destructure(names, _)) => format!("define ({})", names.join(", ")),
+            Self::Assign(Assign(Eval::Load(Load(name)), _)) => format!("assign {}", name),
+            Self::Assign(_) => String::from("assign"),
+            Self::Return(_) => String::from("return"),
+            Self::Assert(_) => String::from("assert"),
+            Self::Exit(_) => String::from("exit"),
+            Self::Const(_) | Self::Locate(_) => return None,
+        };
+        Some(description)
+    }
+}
+
+impl Compile for Expr {
+    fn compile(&self) -> Result<(), Error> {
+        let description = self.describe();
+        if let Some(description) = &description {
+            match location() {
+                Some(span) => debug_comment(format!("{} @ line {}", description, span.line_column().0)),
+                None => debug_comment(description),
+            }
+        }
+
+        match self {
+            Self::If(l) => l.compile()?,
+            // The value is discarded, so nothing can use the temporaries it was built from
+            Self::Eval(Eval::Call(call)) => with_temporaries(|| call.compile())?,
+            Self::Eval(Eval::CallIndirect(call)) => with_temporaries(|| call.compile())?,
+            Self::Eval(e) => with_temporaries(|| e.lower().map(|_| ()))?,
+            // A definition copies its value, and assignments and returns copy
+            // theirs, so the temporaries are dead once the statement is done
+            Self::Define(def) => with_temporaries(|| def.compile())?,
+            Self::Destructure(def) => with_temporaries(|| def.compile())?,
+            Self::Const(c) => c.compile()?,
+            Self::Assign(a) => with_temporaries(|| a.compile())?,
+            Self::While(w) => w.compile()?,
+            Self::ForRange(r) => r.compile()?,
+            Self::Repeat(r) => r.compile()?,
+            Self::Return(r) => with_temporaries(|| r.compile())?,
+            Self::Assert(a) => with_temporaries(|| a.compile())?,
+            Self::Exit(e) => with_temporaries(|| e.compile())?,
+            Self::Locate(span) => set_location(Some(*span)),
+        }
+
+        // Blocks are closed, so the statements in them can be told apart from the ones after
+        if let (Self::If(_) | Self::While(_) | Self::ForRange(_) | Self::Repeat(_), Some(description)) = (self, description) {
+            debug_comment(format!("end {}", description));
+        }
+        Ok(())
+    }
+}
+
+/// This sets the RETURN register to an Eval
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Return(pub(crate) Eval);
+
+impl Return {
+    pub fn new(val: Eval) -> Self {
+        Return(val)
+    }
+}
+
+impl Compile for Return {
+    fn compile(&self) -> Result<(), Error> {
+        let Return(val) = self;
+        let function = current_function();
+        let declared = return_type(&function, current_arity());
+        let wide = declared.is_some_and(|ty| ty.number().is_wide());
+        if let Some(area) = return_area().filter(|_| !wide) {
+            return return_tuple(&function, val, area);
+        }
+        let val = lower_typed(val, declared)?;
+
+        // Make sure the value fits the declared return type
+        if let Some(expected) = return_type(&function, current_arity()) {
+            if !expected.accepts(&val) {
+                return Err(Error::ReturnTypeMismatch {
+                    function,
+                    expected,
+                    got: Type::of(&val),
+                });
+            }
+        }
+
+        // The function's variables are freed when it returns
+        if let Some(name) = with_scope(|scope| scope.referred_by(&val).map(String::from))? {
+            return Err(Error::DanglingReference(name));
+        }
+
+        // A 4 byte number doesn't fit in RETURN, so it is written into the caller's cells
+        match return_area() {
+            Some(area) => area.assign(val),
+            None if val.number.is_wide() => Err(Error::WideReturnUndeclared(function)),
+            None => set_return(val),
+        }
+    }
+}
+
+/// The cells the call being compiled writes its tuple into, if it returns one
+fn return_area() -> Option<Value> {
+    let depth = CALL_STACK.with_borrow(|call_stack| call_stack.len());
+    match RETURN_AREAS.with_borrow(|return_areas| return_areas.last().copied()) {
+        Some((area_depth, area)) if area_depth == depth => Some(area),
+        _ => None,
+    }
+}
+
+/// Write the values of a returned tuple into the caller's cells, one cell each
+fn return_tuple(function: &str, val: &Eval, area: Value) -> Result<(), Error> {
+    let elements = match val {
+        Eval::Tuple(Tuple(elements)) => elements.as_slice(),
+        single => std::slice::from_ref(single),
+    };
+    if elements.len() != area.size() as usize {
+        return Err(Error::ReturnArityMismatch {
+            function: function.to_string(),
+            expected: area.size() as usize,
+            got: elements.len(),
+        });
+    }
+
+    for (i, element) in elements.iter().enumerate() {
+        let val = element.lower()?;
+        if val.size() != 1 {
+            return Err(Error::TupleElementNotSingleCell(element.to_string()));
+        }
+        // Each value must fit the declared return type
+        if let Some(expected) = return_type(function, current_arity()) {
+            if !expected.accepts(&val) {
+                return Err(Error::ReturnTypeMismatch {
+                    function: function.to_string(),
+                    expected,
+                    got: Type::of(&val),
+                });
+            }
+        }
+        if let Some(name) = with_scope(|scope| scope.referred_by(&val).map(String::from))? {
+            return Err(Error::DanglingReference(name));
+        }
+        area.cells(i as u32, 1).assign(val)?;
+    }
+    Ok(())
+}
+
+/// This stops the program with a message if a condition is zero
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Assert(pub(crate) Eval, pub(crate) Option<String>);
+
+impl Assert {
+    pub fn new(condition: Eval, message: Option<String>) -> Self {
+        Assert(condition, message)
+    }
+
+    /// The message printed when the condition is zero. Without one of its own,
+    /// the assert says which function it is in when debug comments are enabled.
+    fn message(&self) -> String {
+        match &self.1 {
+            Some(message) => message.clone(),
+            None if Program::debug_comments_enabled() => format!("ASSERT FAILED in {}", current_function()),
+            None => String::from("ASSERT FAILED"),
+        }
+    }
+}
+
+impl Compile for Assert {
+    fn compile(&self) -> Result<(), Error> {
+        // Stripped asserts don't evaluate their condition at all
+        if Program::asserts_stripped() {
+            return Ok(());
+        }
+
+        let Assert(condition, _) = self;
+        Control::if_begin(lower_condition(condition)?)?;
+        Control::else_begin()?;
+        Control::abort(&self.message())?;
+        Control::if_end()
+    }
+}
+
+/// This stops the program wherever it is reached, with an exit status of 0 unless one is given
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Exit(pub(crate) Option<Eval>);
+
+impl Exit {
+    pub fn new(status: Option<Eval>) -> Self {
+        Exit(status)
+    }
+}
+
+impl Compile for Exit {
+    fn compile(&self) -> Result<(), Error> {
+        let status = match &self.0 {
+            Some(status) => status.lower()?,
+            None => Eval::Literal(Literal::byte_int(0)).lower()?,
+        };
+        if status.size() > 1 {
+            return Err(Error::ExitStatusNotSingleByte);
+        }
+        Control::halt(status)
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Call(pub(crate) String, pub(crate) Vec<Eval>, pub(crate) Vec<(String, Eval)>);
+
+impl Call {
+    pub fn new(name: impl ToString, args: Vec<Eval>) -> Self {
+        Self(name.to_string(), args, vec![])
+    }
+
+    /// The name of the function called
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Pass arguments by the names of the parameters they bind to,
+    /// after the positional arguments
+    pub fn named(mut self, named: Vec<(String, Eval)>) -> Self {
+        self.2 = named;
+        self
+    }
+}
+
+impl Lower for Call {
+    fn lower(&self) -> Result<Value, Error> {
+        let Call(name, args, named) = self;
+        let args = &resolve_arguments(name, args, named)?;
+        if is_void(name, args.len()) {
+            return Err(Error::VoidValueUsed(name.clone()));
+        }
+        report(|depth| CompileEvent::Call { name: name.clone(), depth });
+
+        // A tuple, or a 4 byte number, is written into cells the caller sets aside before the call
+        if let Some(size) = user_fn(name, args.len()).and_then(|f| f.return_area_size()) {
+            let mut area = Value::new(size)?;
+            area.array = user_fn(name, args.len()).is_some_and(|f| f.tuple_arity().is_some());
+            if let Some(ty) = return_type(name, args.len()) {
+                area.kind = ty.kind();
+                area.character = ty == Type::Char;
+                area.number = ty.number();
+            }
+            with_scope(|scope| scope.define_no_cp(temp_name(), area))?;
+            let depth = CALL_STACK.with_borrow(|call_stack| call_stack.len()) + 1;
+            RETURN_AREAS.with_borrow_mut(|return_areas| return_areas.push((depth, area)));
+            let result = call(name, args);
+            RETURN_AREAS.with_borrow_mut(|return_areas| return_areas.pop());
+            result?;
+            report(|depth| CompileEvent::CallDone { name: name.clone(), depth });
+            return Ok(area);
+        }
+
+        call(name, args)?;
+        report(|depth| CompileEvent::CallDone { name: name.clone(), depth });
+
+        // The declared return type says more about the value than RETURN does
+        let mut val = match RETURNED_WIDE.take() {
+            Some(val) => val,
+            None => get_return()?,
+        };
+        val.character = RETURNED_CHARACTER.get();
+        val.number = RETURNED_NUMBER.get();
+        if let Some(ty) = return_type(name, args.len()) {
+            val.kind = ty.kind();
+            val.character = ty == Type::Char;
+            val.number = ty.number();
+        }
+        Ok(val)
+    }
+}
+
+/// A call made as a statement discards what it returns, so it isn't copied out of RETURN
+impl Compile for Call {
+    fn compile(&self) -> Result<(), Error> {
+        let Call(name, args, named) = self;
+        let args = &resolve_arguments(name, args, named)?;
+        // A tuple or a 4 byte number still needs cells in the caller to be written into
+        if user_fn(name, args.len()).and_then(|f| f.return_area_size()).is_some() {
+            return self.lower().map(|_| ());
+        }
+
+        report(|depth| CompileEvent::Call { name: name.clone(), depth });
+        call(name, args)?;
+        report(|depth| CompileEvent::CallDone { name: name.clone(), depth });
+        Ok(())
+    }
+}
+
+/// A reference to a function, which is a number that indirect calls
+/// can be made through. No function's reference is zero.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FnRef(pub(crate) String);
+
+impl FnRef {
+    pub fn new(name: impl ToString) -> Self {
+        Self(name.to_string())
+    }
+}
+
+impl Lower for FnRef {
+    fn lower(&self) -> Result<Value, Error> {
+        let FnRef(name) = self;
+        let place = FN_REFS.with_borrow(|fn_refs| fn_refs.iter().position(|f| f == name));
+        match place {
+            Some(i) => Eval::Literal(Literal::number(i as u16 + 1)).lower(),
+            None => {
+                let searched = vec![namespace_of(name).to_string()];
+                Err(Error::FunctionNotDefined { name: name.clone(), searched })
+            }
+        }
+    }
+}
+
+/// A call through a function reference. Code can't jump to a place worked
+/// out while it runs, so this is a call to every function whose reference
+/// is taken, and only the one the reference is to runs.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CallIndirect(pub(crate) Arc<Eval>, pub(crate) Vec<Eval>);
+
+impl CallIndirect {
+    pub fn new(callee: Eval, args: Vec<Eval>) -> Self {
+        Self(Arc::new(callee), args)
+    }
+
+    /// The functions the call can reach, which must all take its arguments
+    fn targets(&self) -> Result<Vec<String>, Error> {
+        let count = self.1.len();
+        let targets = FN_REFS.get();
+        if targets.is_empty() {
+            return Err(Error::NoFunctionReferences);
+        }
+        for name in &targets {
+            if overload(name, count).is_none() {
+                return Err(Error::NoMatchingOverload {
+                    name: name.clone(),
+                    got: count,
+                    available: arities(name),
+                });
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Compile a call to each target, which runs if the callee is its reference.
+    /// A callee that is no function's reference stops the program.
+    fn dispatch(&self, targets: &[String]) -> Result<(), Error> {
+        let CallIndirect(callee, args) = self;
+        let selector = callee.lower()?;
+        if selector.size() != 1 || selector.kind == ValueKind::Reference {
+            return Err(Error::NotAFunctionReference(eval_name(callee)));
+        }
+
+        // The arguments are worked out once, not once for every target. Variables
+        // are passed as they are, so they can still bind to reference parameters.
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                Eval::Load(_) | Eval::Literal(_) => Ok(arg.clone()),
+                Eval::Refer(Refer(inner)) if matches!(**inner, Eval::Load(_)) => Ok(arg.clone()),
+                other => other.lower().map(Eval::Value),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let unmatched = Value::byte_int(1)?;
+        for (i, name) in targets.iter().enumerate() {
+            let difference = selector.copy()?;
+            difference.minus_eq(Eval::Literal(Literal::number(i as u16 + 1)).lower()?);
+            Control::if_begin(difference)?;
+            difference.discard();
+            Control::else_begin()?;
+            unmatched.zero();
+            report(|depth| CompileEvent::Call { name: name.clone(), depth });
+            call(name, &args)?;
+            report(|depth| CompileEvent::CallDone { name: name.clone(), depth });
+            Control::if_end()?;
+        }
+
+        Control::if_begin(unmatched)?;
+        Control::abort("BAD FUNCTION REFERENCE")?;
+        Control::else_begin()?;
+        Control::if_end()?;
+        unmatched.discard();
+        Ok(())
+    }
+}
+
+impl Lower for CallIndirect {
+    fn lower(&self) -> Result<Value, Error> {
+        let count = self.1.len();
+        let targets = self.targets()?;
+        if let Some(name) = targets.iter().find(|name| is_void(name, count)) {
+            return Err(Error::VoidValueUsed(name.clone()));
+        }
+        self.dispatch(&targets)?;
+
+        // The value is only known to have a type if every target declares the same one
+        let mut val = get_return()?;
+        val.character = false;
+        let types: Vec<Option<Type>> = targets.iter().map(|name| return_type(name, count)).collect();
+        if let Some(ty) = types[0].filter(|&ty| types.iter().all(|&other| other == Some(ty))) {
+            val.kind = ty.kind();
+            val.character = ty == Type::Char;
+            val.number = ty.number();
+        }
+        Ok(val)
+    }
+}
+
+/// A call made as a statement may be to functions that don't return anything
+impl Compile for CallIndirect {
+    fn compile(&self) -> Result<(), Error> {
+        self.dispatch(&self.targets()?)
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Deref(pub(crate) Arc<Eval>);
+
+impl Deref {
+    pub fn new(refer: Eval) -> Self {
+        Self(Arc::new(refer))
+    }
+}
+
+impl Lower for Deref {
+    fn lower(&self) -> Result<Value, Error> {
+        let Deref(refer) = self;
+        check_ptrs_enabled()?;
+        let val = refer.lower()?;
+        if val.is_data() {
+            return Err(Error::DerefOfNonReference(eval_name(refer)));
+        }
+        val.deref()
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Refer(pub(crate) Arc<Eval>);
+
+impl Refer {
+    pub fn new(var: Eval) -> Self {
+        Self(Arc::new(var))
+    }
+}
+
+impl Lower for Refer {
+    fn lower(&self) -> Result<Value, Error> {
+        let Refer(var) = self;
+        check_ptrs_enabled()?;
+        var.lower()?.refer().map(unnamed_temporary)
+    }
+}
+
+/// This gets a character of a string value, or of the string a reference points to.
+/// The character can be assigned to, and assigning the terminator ends the string there.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Index(pub(crate) Arc<Eval>, pub(crate) Arc<Eval>);
+
+impl Index {
+    pub fn new(base: Eval, index: Eval) -> Self {
+        Self(Arc::new(base), Arc::new(index))
+    }
+}
+
+impl Lower for Index {
+    fn lower(&self) -> Result<Value, Error> {
+        let Index(base_eval, index) = self;
+        let base = base_eval.lower()?;
+        let is_string_value = base.size() > 1 && !base.array;
+        let in_value = is_string_value || base.array;
+
+        if !in_value && base.is_data() {
+            return Err(Error::NotAString(eval_name(base_eval)));
+        }
+
+        // A constant index into a string value or byte array is just one of its cells
+        if let Eval::Literal(literal) = &**index {
+            if let (true, Some(i)) = (in_value, literal.cell_value()) {
+                let length = if base.array { base.size() } else { base.size() - 1 };
+                if i >= length {
+                    return Err(Error::IndexOutOfRange { index: i, length });
+                }
+                return Ok(Value {
+                    character: is_string_value,
+                    ..base.cells(i, 1)
+                });
+            }
+        }
+
+        // Otherwise walk a pointer to the character. Only a value's length is
+        // known, so indexing through a pointer is never checked.
+        let offset = index.lower()?;
+        if in_value && Program::bounds_checks_enabled() {
+            let length = if base.array { base.size() } else { base.size() - 1 };
+            let name = match &**base_eval {
+                Eval::Load(Load(name)) => Some(name.as_str()),
+                _ => None,
+            };
+            offset.check_index(length, name)?;
+        }
+        let ptr = unnamed_temporary(if in_value { base.refer()? } else { base.copy()? });
+        ptr.plus_eq(offset);
+        let mut cell = ptr.deref()?;
+        // Only the cells of a string value are known to be characters
+        cell.character = is_string_value;
+        Ok(cell)
+    }
+}
+
+/// Get the name an expression is reported by in errors
+fn eval_name(eval: &Eval) -> String {
+    match eval {
+        Eval::Load(Load(name)) => name.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// A group of named cells that are stored next to each other.
+/// Every field is a single cell.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Struct(pub(crate) String, pub(crate) Vec<String>);
+
+impl Struct {
+    pub fn new(name: impl ToString, fields: Vec<String>) -> Self {
+        Self(name.to_string(), fields)
+    }
+
+    /// The cell that holds a field, counted from the start of the struct
+    fn offset(&self, field: &str) -> Result<u32, Error> {
+        let Struct(name, fields) = self;
+        match fields.iter().position(|f| f == field) {
+            Some(i) => Ok(i as u32),
+            None => Err(Error::UnknownField {
+                structure: name.clone(),
+                field: field.to_string(),
+            }),
+        }
+    }
+
+    /// Get the definition of the struct a value holds
+    fn of(val: &Value) -> Option<Self> {
+        val.layout.map(|i| STRUCT_DEFS.with_borrow(|struct_defs| struct_defs[i].clone()))
+    }
+}
+
+/// Register a struct so it can be constructed
+impl Compile for Struct {
+    fn compile(&self) -> Result<(), Error> {
+        let Struct(name, fields) = self;
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].contains(field) {
+                return Err(Error::DuplicateField {
+                    structure: name.clone(),
+                    field: field.clone(),
+                });
+            }
+        }
+
+        STRUCT_DEFS.with_borrow_mut(|table| {
+            if table.iter().any(|s| s.0 == *name) {
+                return Err(Error::StructRedefined(name.clone()));
+            }
+            table.push(self.clone());
+            Ok(())
+        })
+    }
+}
+
+/// This builds a struct from a value for each of its fields
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Construct(pub(crate) String, pub(crate) Vec<(String, Eval)>);
+
+impl Construct {
+    pub fn new(name: impl ToString, fields: Vec<(String, Eval)>) -> Self {
+        Self(name.to_string(), fields)
+    }
+}
+
+impl Lower for Construct {
+    fn lower(&self) -> Result<Value, Error> {
+        let Construct(name, values) = self;
+        let (layout, structure) = STRUCT_DEFS.with_borrow(|table| match table.iter().position(|s| s.0 == *name) {
+            Some(i) => Ok((i, table[i].clone())),
+            None => Err(Error::StructNotDefined(name.clone())),
+        })?;
+
+        for (i, (field, _)) in values.iter().enumerate() {
+            structure.offset(field)?;
+            if values[..i].iter().any(|(f, _)| f == field) {
+                return Err(Error::DuplicateField {
+                    structure: name.clone(),
+                    field: field.clone(),
+                });
+            }
+        }
+        if let Some(field) = structure.1.iter().find(|f| !values.iter().any(|(v, _)| v == *f)) {
+            return Err(Error::MissingField {
+                structure: name.clone(),
+                field: field.clone(),
+            });
+        }
+
+        let mut result = Value::new(structure.1.len() as u32)?;
+        result.kind = ValueKind::Data;
+        result.layout = Some(layout);
+        for (field, value) in values {
+            let val = value.lower()?;
+            if val.size() > 1 {
+                return Err(Error::FieldNotSingleCell {
+                    structure: name.clone(),
+                    field: field.clone(),
+                });
+            }
+            result.cells(structure.offset(field)?, 1).assign(val)?;
+        }
+
+        // The new struct belongs to the scope, so it is freed like a literal
+        let name = temp_name();
+        define_no_cp(&name, Eval::Value(result))?;
+        get(name)
+    }
+}
+
+/// This is a fixed number of single cell values, stored back to back as an
+/// array. A function that returns a tuple writes it into its caller's cells.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tuple(pub(crate) Vec<Eval>);
+
+impl Tuple {
+    pub fn new(elements: Vec<Eval>) -> Self {
+        Self(elements)
+    }
+}
+
+impl Lower for Tuple {
+    fn lower(&self) -> Result<Value, Error> {
+        let Tuple(elements) = self;
+        let mut result = Value::new(elements.len() as u32)?;
+        result.array = true;
+        for (i, element) in elements.iter().enumerate() {
+            let val = element.lower()?;
+            if val.size() != 1 {
+                return Err(Error::TupleElementNotSingleCell(element.to_string()));
+            }
+            result.kind = if i == 0 { val.kind } else { result.kind.merge(val.kind) };
+            result.cells(i as u32, 1).assign(val)?;
+        }
+        Ok(result)
+    }
+}
+
+/// This gets a field of a struct, or of the struct a reference points to.
+/// The field can be assigned to.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Field(pub(crate) Arc<Eval>, pub(crate) String);
+
+impl Field {
+    pub fn new(base: Eval, field: impl ToString) -> Self {
+        Self(Arc::new(base), field.to_string())
+    }
+}
+
+impl Lower for Field {
+    fn lower(&self) -> Result<Value, Error> {
+        let Field(base_eval, field) = self;
+        let base = base_eval.lower()?;
+        let structure = Struct::of(&base).ok_or_else(|| Error::NotAStruct(eval_name(base_eval)))?;
+        let offset = structure.offset(field)?;
+
+        if base.kind != ValueKind::Reference {
+            return Ok(base.cells(offset, 1));
+        }
+
+        // Walk a pointer to the field
+        check_ptrs_enabled()?;
+        let ptr = base.copy()?;
+        ptr.plus_eq(Literal::number(offset as u16).lower()?);
+        ptr.deref()
+    }
+}
+
+/// This joins two strings, or a string and a character, into a new string
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Concat(pub(crate) Arc<Eval>, pub(crate) Arc<Eval>);
+
+impl Concat {
+    pub fn new(lhs: Eval, rhs: Eval) -> Self {
+        Self(Arc::new(lhs), Arc::new(rhs))
+    }
+
+    /// The number of characters in a string value, the number of bytes in a
+    /// byte array, or 1 for a character.
+    /// The length of a string behind a reference is not known while compiling.
+    fn length(eval: &Eval, val: &Value) -> Result<u32, Error> {
+        // An empty string literal is a single cell, like a character
+        if let Eval::Literal(Literal::String(s)) = eval {
+            Ok(s.len() as u32)
+        } else if val.array {
+            Ok(val.size())
+        } else if val.size() > 1 {
+            Ok(val.size() - 1)
+        } else if val.kind == ValueKind::Reference {
+            Err(Error::CannotConcatenateAReference)
+        } else {
+            Ok(1)
+        }
+    }
+}
+
+impl Lower for Concat {
+    fn lower(&self) -> Result<Value, Error> {
+        let Concat(lhs_eval, rhs_eval) = self;
+        let lhs = lhs_eval.lower()?;
+        let rhs = rhs_eval.lower()?;
+        let lhs_len = Self::length(lhs_eval, &lhs)?;
+        let rhs_len = Self::length(rhs_eval, &rhs)?;
+
+        let mut result = Value::new(lhs_len + rhs_len + 1)?;
+        result.kind = ValueKind::Data;
+        result.cells(0, lhs_len).assign(lhs.cells(0, lhs_len))?;
+        result.cells(lhs_len, rhs_len).assign(rhs.cells(0, rhs_len))?;
+        result.cells(lhs_len + rhs_len, 1).set(1u8);
+
+        // The new string belongs to the scope, so it is freed like a literal
+        let name = temp_name();
+        define_no_cp(&name, Eval::Value(result))?;
+        get(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Load(pub(crate) String);
+
+impl Load {
+    pub fn new(s: impl ToString) -> Self {
+        Self(s.to_string())
+    }
+
+    /// The name of the variable loaded
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Lower for Load {
+    fn lower(&self) -> Result<Value, Error> {
+        let Load(name) = self;
+        match constant(name) {
+            Some(literal) => literal.lower(),
+            None => get(name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Literal {
+    String(String),
+    Character(char),
+    ByteInt(u8),
+    UnsignedShort(u16),
+    /// A number too large for a cell, stored as a `u32` in eight cells
+    Unsigned4ByteInt(u32),
+    /// A negative number, stored as an `i32` in eight cells
+    Signed4ByteInt(i32),
+    /// A number with a fractional part, stored in eight cells as fixed point,
+    /// with 16 bits before the point and 16 after it
+    Fixed(f64),
+    /// The bytes of a byte string, stored as an array without a terminator
+    Bytes(Vec<u8>),
+}
+
+impl Literal {
+    pub fn string(s: impl ToString) -> Self {
+        Self::String(s.to_string())
+    }
+
+    pub fn character(ch: char) -> Self {
+        Self::Character(ch)
+    }
+
+    pub fn byte_int(b: u8) -> Self {
+        Self::ByteInt(b)
+    }
+
+    pub fn unsigned_short(ui: u16) -> Self {
+        Self::UnsignedShort(ui)
+    }
+
+    pub fn unsigned_4byte_int(ui: u32) -> Self {
+        Self::Unsigned4ByteInt(ui)
+    }
+
+    pub fn signed_4byte_int(i: i32) -> Self {
+        Self::Signed4ByteInt(i)
+    }
+
+    pub fn fixed(n: f64) -> Self {
+        Self::Fixed(n)
+    }
+
+    pub fn bytes(bytes: impl Into<Vec<u8>>) -> Self {
+        Self::Bytes(bytes.into())
+    }
+
+    /// Create the smallest number literal that holds `n`
+    pub fn number(n: u16) -> Self {
+        if n <= u8::MAX as u16 {
+            Self::ByteInt(n as u8)
+        } else {
+            Self::UnsignedShort(n)
+        }
+    }
+
+    /// The number this literal is, if it is a whole number
+    pub fn integer(&self) -> Option<i64> {
+        match self {
+            Self::ByteInt(byte) => Some(*byte as i64),
+            Self::UnsignedShort(ui) => Some(*ui as i64),
+            Self::Unsigned4ByteInt(ui) => Some(*ui as i64),
+            Self::Signed4ByteInt(i) => Some(*i as i64),
+            _ => None,
+        }
+    }
+
+    /// The value of the cell this literal lowers to, if it is a single cell
+    pub fn cell_value(&self) -> Option<u32> {
+        match self {
+            Self::String(_) | Self::Bytes(_) | Self::Unsigned4ByteInt(_) | Self::Signed4ByteInt(_) | Self::Fixed(_) => None,
+            Self::Character(ch) => Some(*ch as u32),
+            Self::ByteInt(byte) => Some(*byte as u32),
+            Self::UnsignedShort(ui) => Some(*ui as u32),
+        }
+    }
+}
+
+impl Lower for Literal {
+    fn lower(&self) -> Result<Value, Error> {
+        // An interned string is already in its cells
+        if let Self::String(s) = self {
+            let interned = INTERNED.with_borrow(|interned| interned.get(s).cloned());
+            if let Some(name) = interned {
+                return get(name);
+            }
+        }
+        // The parser checks this, but a program built some other way may not have
+        if let Self::Fixed(n) = self {
+            if !FIXED_RANGE.contains(n) {
+                return Err(Error::FixedOutOfRange(*n));
+            }
+        }
+
+        let (name, value) = match self {
+            Self::String(s) => (temp_name(), Value::string(s)),
+            Self::Character(ch) => (temp_name(), Value::character(*ch)),
+            Self::ByteInt(byte) => (temp_name(), Value::byte_int(*byte)),
+            Self::UnsignedShort(ui) => (temp_name(), Value::unsigned_short(*ui)),
+            Self::Unsigned4ByteInt(ui) => (temp_name(), Value::unsigned_4byte_int(*ui)),
+            Self::Signed4ByteInt(i) => (temp_name(), Value::signed_4byte_int(*i)),
+            Self::Fixed(n) => (temp_name(), Value::fixed(*n)),
+            Self::Bytes(bytes) => (temp_name(), Value::bytes(bytes)),
+        };
+
+        let value = value.map_err(|error| could_not_define(&name, format!("{:?}", self), error))?;
+        define_no_cp(&name, Eval::Value(value))?;
+        get(name)
+    }
+}
+
+/// Define a foreign function that gets its arguments with `get` and sets RETURN itself
+#[deprecated(note = "use `defforeign`, which passes the arguments to the body")]
+pub fn deforfun<F>(name: impl ToString, args: &[&'static str], fun: F)
+where
+    F: Fn() -> Result<(), Error> + Send + Sync + 'static,
+{
+    ForeignFn::define(name, args.to_vec(), ForeignBody::Scoped(Arc::new(fun)));
+}
+
+/// Define a foreign function that is passed its arguments before they are lowered.
+/// The value it returns is put in RETURN.
+pub fn defforeign_raw<F>(name: impl ToString, args: &[&'static str], fun: F)
+where
+    F: Fn(&[Eval]) -> Result<Option<Value>, Error> + Send + Sync + 'static,
+{
+    ForeignFn::define(name, args.to_vec(), ForeignBody::Raw(Arc::new(fun)));
+}
+
+/// Define a foreign function. The body is passed the arguments, and the value it returns is put in RETURN.
+pub fn defforeign<F>(name: impl ToString, args: &[&'static str], fun: F)
+where
+    F: Fn(&[Value]) -> Result<Option<Value>, Error> + Send + Sync + 'static,
+{
+    ForeignFn::define(name, args.to_vec(), ForeignBody::Direct(Arc::new(fun)));
+}
+
+/// The kinds of functions a name can be bound to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FnKind {
+    User,
+    Foreign,
+}
+
+/// Find what kind of function a name is bound to. When a user defined
+/// function and a foreign function share a name, calls go to the
+/// user defined function, unless only the foreign function takes
+/// the number of arguments they pass.
+pub fn lookup(name: impl ToString) -> Option<FnKind> {
+    let name = name.to_string();
+    if FN_DEFS.with_borrow(|fn_defs| fn_defs.contains_key(&name)) {
+        Some(FnKind::User)
+    } else if FOREIGN_FN_DEFS.with_borrow(|foreign| foreign.contains_key(&name)) {
+        Some(FnKind::Foreign)
+    } else {
+        None
+    }
+}
+
+/// An attribute written before a function, like `#[inline]`
+#[derive(Clone, Copy, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FnAttr {
+    /// Inline calls to the function however large it is
+    Inline,
+    /// Never inline calls to the function
+    NeverInline,
+    /// Keep the function when nothing calls it, without warning that it is unused
+    Keep,
+}
+
+impl FnAttr {
+    /// Get the attribute written as `#[name]` or `#[name(arg)]`
+    pub fn from_name(name: &str, arg: Option<&str>) -> Result<Self, String> {
+        match (name, arg) {
+            ("inline", None) => Ok(Self::Inline),
+            ("inline", Some("never")) => Ok(Self::NeverInline),
+            ("inline", Some(arg)) => Err(format!("attribute `inline` takes `never`, not `{}`", arg)),
+            ("keep", None) => Ok(Self::Keep),
+            ("keep", Some(_)) => Err(String::from("attribute `keep` doesn't take an argument")),
+            _ => Err(format!("unknown attribute `{}`", name)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UserFn {
+    pub(crate) name: String,
+    pub(crate) parameters: Vec<String>,
+    pub(crate) parameter_types: Vec<Option<Type>>,
+    pub(crate) parameter_defaults: Vec<Option<Literal>>,
+    pub(crate) reference_parameters: Vec<bool>,
+    pub(crate) body: Vec<Expr>,
+    pub(crate) return_type: Option<Type>,
+    pub(crate) attributes: Vec<FnAttr>,
+    /// The functions defined in the body, until they are moved into the function's namespace
+    pub(crate) locals: Vec<UserFn>,
+    /// The function this one is defined in, if it is a local function
+    pub(crate) enclosing: Option<String>,
+    pub(crate) span: Option<Span>,
+}
+
+impl UserFn {
+    pub fn new(name: impl ToString, parameters: Vec<String>, body: Vec<Expr>) -> Self {
+        Self {
+            name: name.to_string(),
+            parameter_types: vec![None; parameters.len()],
+            parameter_defaults: vec![None; parameters.len()],
+            reference_parameters: vec![false; parameters.len()],
+            parameters: parameters.iter().map(ToString::to_string).collect(),
+            body,
+            return_type: None,
+            attributes: vec![],
+            locals: vec![],
+            enclosing: None,
+            span: None,
+        }
+    }
+
+    /// Record where the function is defined in the source code
+    pub fn located(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// The name the function is called by
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Declare the types of the parameters. Parameters without a type accept any value.
+    pub fn parameter_types(mut self, types: Vec<Option<Type>>) -> Self {
+        self.parameter_types = types;
+        self
+    }
+
+    /// Put attributes on the function, which change how it is optimized and checked
+    pub fn attributes(mut self, attributes: Vec<FnAttr>) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Define functions in the body of this one. Only this function and
+    /// its other local functions can call them, and they can't use its variables.
+    pub fn locals(mut self, locals: Vec<UserFn>) -> Self {
+        self.locals = locals;
+        self
+    }
+
+    /// Does the function have this attribute?
+    pub fn has_attribute(&self, attribute: FnAttr) -> bool {
+        self.attributes.contains(&attribute)
+    }
+
+    /// Declare the values used for trailing parameters that a call leaves out
+    pub fn parameter_defaults(mut self, defaults: Vec<Option<Literal>>) -> Self {
+        self.parameter_defaults = defaults;
+        self
+    }
+
+    /// Declare which parameters are references. A reference parameter is
+    /// bound to the cells of the caller's variable instead of a copy of it,
+    /// so assigning to the parameter assigns to the caller's variable.
+    pub fn reference_parameters(mut self, by_reference: Vec<bool>) -> Self {
+        self.reference_parameters = by_reference;
+        self
+    }
+
+    /// The fewest arguments a call can pass, leaving out the parameters with defaults
+    pub fn min_arity(&self) -> usize {
+        self.parameters.len() - self.parameter_defaults.iter().rev().take_while(|d| d.is_some()).count()
+    }
+
+    /// Can this function be called with this many arguments?
+    pub fn accepts(&self, count: usize) -> bool {
+        (self.min_arity()..=self.parameters.len()).contains(&count)
+    }
+
+    /// Could a call with some number of arguments go to either function? Functions
+    /// with the same name are overloads, as long as they take different numbers of arguments.
+    pub fn overlaps(&self, other: &UserFn) -> bool {
+        self.name == other.name && (other.min_arity()..=other.parameters.len()).any(|count| self.accepts(count))
+    }
+
+    /// Is the parameter at this position a reference?
+    pub(crate) fn is_reference_parameter(&self, i: usize) -> bool {
+        self.reference_parameters.get(i).copied().unwrap_or(false)
+    }
+
+    /// Does this function never return a value? A function is void if it is
+    /// declared `void`, or if it has no declared type and no return statements.
+    pub fn is_void(&self) -> bool {
+        fn has_return(body: &[Expr]) -> bool {
+            body.iter().any(|expr| match expr {
+                Expr::Return(_) => true,
+                Expr::If(If(_, then, otherwise)) => has_return(then) || has_return(otherwise),
+                Expr::While(While(_, body)) | Expr::ForRange(ForRange(_, _, _, body)) | Expr::Repeat(Repeat(_, body)) => {
+                    has_return(body)
+                }
+                _ => false,
+            })
+        }
+        match self.return_type {
+            Some(ty) => ty == Type::Void,
+            None => !has_return(&self.body),
+        }
+    }
+
+    /// The number of values in the tuples this function returns, if it returns tuples
+    pub(crate) fn tuple_arity(&self) -> Option<usize> {
+        fn arity(body: &[Expr]) -> Option<usize> {
+            body.iter().find_map(|expr| match expr {
+                Expr::Return(Return(Eval::Tuple(Tuple(elements)))) => Some(elements.len()),
+                Expr::If(If(_, then, otherwise)) => arity(then).or_else(|| arity(otherwise)),
+                Expr::While(While(_, body)) | Expr::ForRange(ForRange(_, _, _, body)) | Expr::Repeat(Repeat(_, body)) => {
+                    arity(body)
+                }
+                _ => None,
+            })
+        }
+        arity(&self.body)
+    }
+
+    /// The number of cells a caller sets aside for what this function returns,
+    /// if it returns a 4 byte number or tuples, which don't fit in RETURN
+    pub(crate) fn return_area_size(&self) -> Option<u32> {
+        match self.return_type {
+            Some(ty) if ty.number().is_wide() => Some(WIDE_DIGITS),
+            _ => self.tuple_arity().map(|arity| arity as u32),
+        }
+    }
+
+    /// Find the cells that the argument to a reference parameter names,
+    /// without copying them. The argument may be written as `&name` or `name`.
+    fn borrow_argument(&self, parameter: &str, arg: &Eval) -> Result<Value, Error> {
+        let place = match arg {
+            Eval::Refer(Refer(inner)) => {
+                check_ptrs_enabled()?;
+                inner.as_ref()
+            }
+            other => other,
+        };
+        match place {
+            Eval::Load(Load(name)) if constant(name).is_none() => get(name),
+            Eval::Deref(_) | Eval::Index(_) | Eval::Field(_) => place.lower(),
+            _ => Err(Error::ReferenceArgumentNotAVariable {
+                function: self.name.clone(),
+                parameter: parameter.to_string(),
+                argument: arg.to_string(),
+            }),
+        }
+    }
+
+    /// Add the default values of the parameters a call leaves out.
+    /// Each default is a fresh literal, so every call gets its own cells.
+    fn fill_defaults(&self, args: &[Eval]) -> Result<Vec<Eval>, Error> {
+        let mut args = args.to_vec();
+        while args.len() < self.parameters.len() {
+            match &self.parameter_defaults[args.len()] {
+                Some(default) => args.push(Eval::Literal(default.clone())),
+                None => break,
+            }
+        }
+
+        if args.len() != self.parameters.len() {
+            return Err(Error::ArgumentCountMismatch {
+                function: self.name.clone(),
+                expected: self.parameters.len(),
+                got: args.len(),
+            });
+        }
+        Ok(args)
+    }
+
+    /// Declare the type of the values this function returns
+    pub fn returns(mut self, ty: Type) -> Self {
+        self.return_type = Some(ty);
+        self
+    }
+
+    /// Register this function so it can be called. It is added to the other
+    /// overloads of its name, unless it takes the same number of arguments as one.
+    pub fn compile(self) -> Result<(), Error> {
+        FN_DEFS.with_borrow_mut(|table| {
+            let overloads = table.entry(self.name.clone()).or_default();
+            if overloads.iter().any(|f| f.overlaps(&self)) {
+                if !Program::redefine_allowed() {
+                    return Err(Error::FunctionRedefined(self.name.clone()));
+                }
+                overloads.retain(|f| !f.overlaps(&self));
+            }
+            let foreign =
+                FOREIGN_FN_DEFS.with_borrow(|foreign| foreign.get(&self.name).cloned().unwrap_or_default());
+            if foreign.iter().any(|f| (self.min_arity()..=self.parameters.len()).any(|count| f.accepts(count))) {
+                warn(format!(
+                    "function `{}` shadows the foreign function of the same name",
+                    self.name
+                ));
+            }
+            overloads.push(Arc::new(self));
+            Ok(())
+        })?;
+        // The fingerprints of the functions that call this one are out of date
+        FINGERPRINTS.with_borrow_mut(|fingerprints| fingerprints.clear());
+        Ok(())
+    }
+
+    pub fn call(&self, args: &[Eval]) -> Result<(), Error> {
+        check_recursion(&self.name, self.parameters.len())?;
+        let stack_frame = STACK_PTR.get();
+        let first_temp = TEMP_COUNTER.get();
+        let start_instructions = instruction_count();
+        let start_allocations = allocation_count();
+        let start_calls = CALL_COUNT.get();
+        let outer_peak = reset_peak_stack_ptr(stack_frame);
+        NESTED_INSTRUCTIONS.with_borrow_mut(|nested| nested.push(0));
+
+        let args = self.fill_defaults(args)?;
+        let mut env = Env::new();
+
+        for (i, p) in self.parameters.iter().enumerate() {
+            if self.is_reference_parameter(i) {
+                let val = self.borrow_argument(p, &args[i])?;
+                check_argument(&self.name, p, self.parameter_types[i], &val)?;
+                env.borrow(p, val);
+            } else {
+                let val = lower_typed(&args[i], self.parameter_types[i])?;
+                check_argument(&self.name, p, self.parameter_types[i], &val)?;
+                env.define(p.to_string(), val)?;
+            }
+        }
+
+        // The body is compiled the same way wherever it is called from
+        forget_zero_cells();
+        let holes = take_holes();
+        let live_cells = match cache_key(&self.name, &env) {
+            Some(key) => self.call_cached(key, env, stack_frame, first_temp)?,
+            None => self.call_body(env, stack_frame, first_temp)?,
+        };
+        restore_holes(holes);
+
+        let peak = reset_peak_stack_ptr(outer_peak.max(peak_stack_ptr()));
+        let instructions = instruction_count() - start_instructions;
+        let own_instructions = NESTED_INSTRUCTIONS.with_borrow_mut(|nested| {
+            let own_instructions = instructions - nested.pop().unwrap_or(0);
+            if let Some(outer) = nested.last_mut() {
+                *outer += instructions;
+            }
+            own_instructions
+        });
+
+        record_fn_size(FnSize {
+            name: self.name.clone(),
+            calls: 1,
+            instructions,
+            own_instructions,
+            allocations: allocation_count() - start_allocations,
+            calls_made: CALL_COUNT.get() - start_calls,
+            peak_stack: peak,
+            frame_peak: peak - stack_frame,
+            live_cells,
+        });
+        Ok(())
+    }
+
+    /// Compile the body of a call to this function, once its arguments are
+    /// defined, and return the number of cells it left on the stack
+    fn call_body(&self, env: Env, stack_frame: u32, first_temp: u64) -> Result<u32, Error> {
+        push_scope(env);
+        enter_call(&self.name, self.parameters.len(), false);
+        debug_comment(format!("fn {}", self.name));
+        emit_ir(IrOp::FnBegin(self.name.clone()));
+
+        // An error is reported at the innermost statement it happened in,
+        // along with the calls that led to it
+        let caller_location = location();
+        let compiled = self
+            .body
+            .iter()
+            .try_for_each(|instruction| instruction.compile().map_err(|e| e.at(location()).traced()));
+        // A call that fails is over too, so it is taken off the call stack either way
+        CALL_STACK.with_borrow_mut(|call_stack| call_stack.pop());
+        compiled?;
+        set_location(caller_location);
+        debug_comment(format!("end fn {}", self.name));
+        emit_ir(IrOp::FnEnd(self.name.clone()));
+        Program::flush_output()?;
+
+        let live_cells = STACK_PTR.get() - stack_frame;
+        let mut env = pop_scope()?;
+        if let Some(name) = dangling_reference(&env) {
+            return Err(Error::DanglingReference(name));
+        }
+        env.free();
+        // The arguments were lowered in the caller's scope, and the stack is
+        // about to be reset under them
+        free_temporaries(first_temp)?;
+        set_stack(stack_frame)?;
+
+        Ok(live_cells)
+    }
+
+    /// Compile the body of a call with the code kept for it, if the same call
+    /// was compiled in the same state before. Otherwise, compile the body and
+    /// keep its code, and everything else compiling it changed, for next time.
+    fn call_cached(&self, key: u64, env: Env, stack_frame: u32, first_temp: u64) -> Result<u32, Error> {
+        let cached = FN_CACHE.with_borrow(|cache| cache.as_ref().and_then(|cache| cache.get(&key).cloned()));
+        if let Some(cached) = cached {
+            report(|depth| CompileEvent::Reuse { name: self.name.clone(), depth });
+            cached.replay();
+            add_allocations(cached.allocations);
+            CALL_COUNT.with_borrow_mut(|call_count| *call_count += cached.calls);
+            NESTED_INSTRUCTIONS.with_borrow_mut(|nested| {
+                if let Some(nested) = nested.last_mut() {
+                    *nested += cached.nested_instructions;
+                }
+            });
+            Program::flush_output()?;
+            return Ok(cached.live_cells);
+        }
+
+        let first_name = TEMP_COUNTER.get();
+        let first_warning = WARNINGS.with_borrow(|warnings| warnings.len());
+        let first_op = ir_len();
+        let first_allocation = allocation_count();
+        let first_call = CALL_COUNT.get();
+        let first_nested = NESTED_INSTRUCTIONS.with_borrow(|nested| nested.last().copied().unwrap_or(0));
+        start_capture();
+        SIZE_CAPTURES.with_borrow_mut(|size_captures| size_captures.push(vec![]));
+
+        let result = self.call_body(env, stack_frame, first_temp);
+
+        let code = end_capture();
+        let sizes = end_size_capture();
+        let live_cells = result?;
+
+        let cached = CachedCall {
+            code,
+            ir: first_op.map(ir_since).unwrap_or_default(),
+            warnings: WARNINGS.with_borrow(|warnings| warnings[first_warning..].to_vec()),
+            sizes,
+            first_name,
+            names: TEMP_COUNTER.get() - first_name,
+            tape: TapeState::get(),
+            scopes: SCOPE_STACK.get(),
+            return_temps: RETURN_TEMPS.get(),
+            live_cells,
+            allocations: allocation_count() - first_allocation,
+            calls: CALL_COUNT.get() - first_call,
+            nested_instructions: NESTED_INSTRUCTIONS.with_borrow(|nested| nested.last().copied().unwrap_or(0))
+                - first_nested,
+        };
+        FN_CACHE.with_borrow_mut(|cache| {
+            if let Some(cache) = cache.as_mut() {
+                cache.insert(key, cached);
+            }
+        });
+        Ok(live_cells)
+    }
+
+}
+
+/// Add the size of some calls to a function to the size report
+fn record_fn_size(size: FnSize) {
+    SIZE_CAPTURES.with_borrow_mut(|captures| {
+        if let Some(capture) = captures.last_mut() {
+            capture.push(size.clone());
+        }
+    });
+
+    SIZE_REPORT.with_borrow_mut(|report| match report.iter_mut().find(|f| f.name == size.name) {
+        Some(f) => {
+            f.calls += size.calls;
+            f.instructions += size.instructions;
+            f.own_instructions += size.own_instructions;
+            f.allocations += size.allocations;
+            f.calls_made += size.calls_made;
+            f.peak_stack = f.peak_stack.max(size.peak_stack);
+            f.frame_peak = f.frame_peak.max(size.frame_peak);
+            f.live_cells = f.live_cells.max(size.live_cells);
+        }
+        None => report.push(size),
+    })
+}
+
+/// Stop the innermost capture of function sizes and return the sizes it
+/// kept. The sizes are part of the capture around it too, if there is one.
+fn end_size_capture() -> Vec<FnSize> {
+    SIZE_CAPTURES.with_borrow_mut(|captures| {
+        let sizes = captures.pop().unwrap_or_default();
+        if let Some(outer) = captures.last_mut() {
+            outer.extend(sizes.iter().cloned());
+        }
+        sizes
+    })
+}
+
+/// What compiling the body of a call did, kept so the
+/// same call can be compiled again without lowering it
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct CachedCall {
+    code: String,
+    /// The operations recorded, if they were being recorded
+    ir: Vec<IrOp>,
+    warnings: Vec<Warning>,
+    /// The sizes of the calls made, this one's callees first
+    sizes: Vec<FnSize>,
+    /// The number of temporaries named before the body, and in it
+    first_name: u64,
+    names: u64,
+    tape: TapeState,
+    scopes: Vec<Env>,
+    return_temps: Vec<(u64, Value)>,
+    live_cells: u32,
+    /// The values allocated, the calls made, and the instructions of those
+    /// calls to user defined functions
+    allocations: usize,
+    calls: usize,
+    nested_instructions: usize,
+}
+
+impl CachedCall {
+    /// Do what compiling the body of the call did again. The call may come
+    /// after a different number of temporaries were named than last time,
+    /// so the temporaries it names are renumbered to follow them.
+    fn replay(&self) {
+        let first_name = TEMP_COUNTER.get();
+        let by = first_name as i64 - self.first_name as i64;
+
+        add_to_compiled(renumber_temps(&self.code, by));
+        for op in &self.ir {
+            emit_ir(match op {
+                IrOp::Bind { name, at } => IrOp::Bind { name: renumber_temps(name, by), at: *at },
+                other => other.clone(),
+            });
+        }
+        WARNINGS.with_borrow_mut(|warnings| warnings.extend(self.warnings.iter().cloned()));
+        for size in &self.sizes {
+            record_fn_size(size.clone());
+        }
+        TEMP_COUNTER.set(first_name + self.names);
+        self.tape.restore();
+        SCOPE_STACK.set(
+            self.scopes
+                .iter()
+                .map(|scope| scope.renamed(|name| renumber_temps(name, by)))
+                .collect(),
+        );
+        RETURN_TEMPS.set(
+            self.return_temps
+                .iter()
+                .map(|&(n, val)| ((n as i64 + by) as u64, val))
+                .collect(),
+        );
+    }
+}
+
+/// The key that the body of a call to a function is cached under, a hash of
+/// everything the code it compiles to depends on. This is None unless
+/// compiling incrementally.
+fn cache_key(name: &str, env: &Env) -> Option<u64> {
+    if !Program::incremental() {
+        return None;
+    }
+
+    // Temporaries are named by how long before the call they were named,
+    // so the call can be cached after a change to the number named before it
+    let first_name = TEMP_COUNTER.get() as i64;
+    let mut hasher = DefaultHasher::new();
+    fingerprint(name).hash(&mut hasher);
+    for scope in SCOPE_STACK.get().iter().chain(Some(env)) {
+        for (name, val) in scope.iter() {
+            renumber_temps(name, -first_name).hash(&mut hasher);
+            val.hash(&mut hasher);
+            scope.is_borrowed(name).hash(&mut hasher);
+        }
+        // Scopes are told apart, since the same variables in other scopes compile differently
+        0xff_u8.hash(&mut hasher);
+    }
+    for (n, val) in RETURN_TEMPS.get().iter() {
+        (*n as i64 - first_name).hash(&mut hasher);
+        val.hash(&mut hasher);
+    }
+    for scope in CONSTANTS.get().iter() {
+        let mut constants: Vec<String> = scope.iter().map(|constant| format!("{:?}", constant)).collect();
+        constants.sort();
+        constants.hash(&mut hasher);
+    }
+    for frame in CALL_STACK.get().iter() {
+        (&frame.name, frame.foreign).hash(&mut hasher);
+    }
+    // An indirect call compiles a call to every function whose reference is taken
+    for name in FN_REFS.get() {
+        fingerprint(&name).hash(&mut hasher);
+    }
+    format!("{:?}", STRUCT_DEFS.get()).hash(&mut hasher);
+    format!(
+        "{:?}",
+        (
+            Program::brainfuck_enabled(),
+            Program::size_warn_enabled(),
+            Program::debug_comments_enabled(),
+            Program::ptrs_disabled(),
+            Program::asserts_stripped(),
+            Program::overflow_checks_enabled(),
+            Program::utf8_strings_enabled(),
+            Program::interning_disabled(),
+            Program::cell_width(),
+            Program::eof_value(),
+            Program::optimize_level(),
+            SIZE_WARN_THRESHOLD.get(),
+        )
+    )
+    .hash(&mut hasher);
+    Program::recursion_limit().hash(&mut hasher);
+    Program::bounds_checks_enabled().hash(&mut hasher);
+    TapeState::get().hash(&mut hasher);
+    ir_len().is_some().hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Add to the number of every temporary named in some text
+fn renumber_temps(text: &str, by: i64) -> String {
+    const PREFIX: &str = "%TEMP#";
+    if by == 0 || !text.contains(PREFIX) {
+        return text.to_string();
+    }
+
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find(PREFIX) {
+        result += &rest[..i];
+        let after = &rest[i + PREFIX.len()..];
+        let digits = after.len() - after.trim_start_matches(|ch: char| ch.is_ascii_digit()).len();
+        match after[..digits].parse::<i64>() {
+            Ok(n) if after[digits..].starts_with('%') => {
+                result += &format!("{}{}%", PREFIX, n + by);
+                rest = &after[digits + 1..];
+            }
+            _ => {
+                result += PREFIX;
+                rest = after;
+            }
+        }
+    }
+    result + rest
+}
+
+/// A hash of the definition of a function and of every function it calls,
+/// since calls are compiled inline. The spans are left out, so moving a
+/// function in its file doesn't change it.
+fn fingerprint(name: &str) -> u64 {
+    if let Some(hash) = FINGERPRINTS.with_borrow(|fingerprints| fingerprints.get(name).copied()) {
+        return hash;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    let user_fns = FN_DEFS.with_borrow(|fn_defs| fn_defs.get(name).cloned());
+    match user_fns {
+        Some(overloads) => {
+            // A function that calls itself never compiles, so
+            // this only has to stop the recursion
+            FINGERPRINTS.with_borrow_mut(|fingerprints| fingerprints.insert(name.to_string(), 0));
+
+            for fun in overloads {
+                let fun = WithoutSpans.fold_user_fn(UserFn { span: None, ..(*fun).clone() });
+                format!("{:?}", fun).hash(&mut hasher);
+                let mut called = vec![];
+                calls_in_body(&fun.body, &mut called);
+                for callee in called {
+                    fingerprint(&callee).hash(&mut hasher);
+                }
+            }
+        }
+        None if FOREIGN_FN_DEFS.with_borrow(|foreign| foreign.contains_key(name)) => {
+            ("foreign", name).hash(&mut hasher)
+        }
+        None => ("undefined", name).hash(&mut hasher),
+    }
+
+    let hash = hasher.finish();
+    FINGERPRINTS.with_borrow_mut(|fingerprints| fingerprints.insert(name.to_string(), hash));
+    hash
+}
+
+/// Removes the spans of the statements in a function
+struct WithoutSpans;
+
+impl Fold for WithoutSpans {
+    fn fold_body(&mut self, body: Vec<Expr>) -> Vec<Expr> {
+        body.into_iter()
+            .filter(|expr| !matches!(expr, Expr::Locate(_)))
+            .map(|expr| self.fold_expr(expr))
+            .collect()
     }
 }
 
-impl Lower for Call {
-    fn lower(&self) -> Result<Value, Error> {
-        let Call(name, args) = self;
-        add_to_compiled(format!("CALLING {}", name));
-        call(name, args)?;
-        add_to_compiled("DONE");
-        get_return()
-    }
+/// An overload of a function name, which is picked by the number of arguments
+#[derive(Clone)]
+enum Overload {
+    User(Arc<UserFn>),
+    Foreign(ForeignFn),
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Deref(Arc<Eval>);
+/// Find the overload of a function that takes this many arguments. User
+/// defined functions take precedence over foreign functions. A name with a
+/// single overload always finds it, so a call with the wrong number of
+/// arguments reports how many the function takes.
+fn overload(name: &str, count: usize) -> Option<Overload> {
+    let user_fns = FN_DEFS.with_borrow(|fn_defs| fn_defs.get(name).cloned().unwrap_or_default());
+    let foreign_fns = FOREIGN_FN_DEFS.with_borrow(|foreign| foreign.get(name).cloned().unwrap_or_default());
+    let single = user_fns.len() + foreign_fns.len() == 1;
 
-impl Deref {
-    pub fn new(refer: Eval) -> Self {
-        Self(Arc::new(refer))
+    if let Some(fun) = user_fns.into_iter().find(|f| single || f.accepts(count)) {
+        return Some(Overload::User(fun));
     }
+    foreign_fns.into_iter().find(|f| single || f.accepts(count)).map(Overload::Foreign)
 }
 
-impl Lower for Deref {
-    fn lower(&self) -> Result<Value, Error> {
-        let Deref(refer) = self;
-        Ok(refer.lower()?.deref()?)
+/// Find the user defined overload of a function that takes this many arguments
+fn user_fn(name: &str, count: usize) -> Option<Arc<UserFn>> {
+    match overload(name, count) {
+        Some(Overload::User(fun)) => Some(fun),
+        _ => None,
     }
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Refer(Arc<Eval>);
+/// The fewest arguments each overload of a function takes, in order
+pub fn arities(name: &str) -> Vec<usize> {
+    let mut arities: Vec<usize> =
+        FN_DEFS.with_borrow(|fn_defs| fn_defs.get(name).into_iter().flatten().map(|f| f.min_arity()).collect());
+    FOREIGN_FN_DEFS.with_borrow(|foreign| {
+        arities.extend(foreign.get(name).into_iter().flatten().map(|f| f.parameters.len()))
+    });
+    arities.sort_unstable();
+    arities.dedup();
+    arities
+}
 
-impl Refer {
-    pub fn new(var: Eval) -> Self {
-        Self(Arc::new(var))
+/// Get the declared return type of the overload of a function called with this many arguments
+pub fn return_type(name: impl ToString, count: usize) -> Option<Type> {
+    match overload(&name.to_string(), count)? {
+        Overload::User(f) => f.return_type,
+        Overload::Foreign(f) => f.return_type,
     }
 }
 
-impl Lower for Refer {
-    fn lower(&self) -> Result<Value, Error> {
-        let Refer(var) = self;
-        Ok(var.lower()?.refer()?)
+/// Does the overload of a function called with this many arguments never return a value?
+pub fn is_void(name: impl ToString, count: usize) -> bool {
+    match overload(&name.to_string(), count) {
+        Some(Overload::User(f)) => f.is_void(),
+        Some(Overload::Foreign(f)) => f.return_type == Some(Type::Void),
+        None => false,
     }
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Load(String);
-
-impl Load {
-    pub fn new(s: impl ToString) -> Self {
-        Self(s.to_string())
+/// Lower an argument for a parameter, or a value a function returns, as the
+/// kind of number its declared type is
+fn lower_typed(arg: &Eval, ty: Option<Type>) -> Result<Value, Error> {
+    match ty {
+        Some(ty) => lower_as(arg, ty.number()),
+        None => arg.lower(),
     }
 }
 
-impl Lower for Load {
-    fn lower(&self) -> Result<Value, Error> {
-        let Load(name) = self;
-        get(name)
+/// Make sure an argument fits the declared type of its parameter
+fn check_argument(function: &str, parameter: &str, ty: Option<Type>, val: &Value) -> Result<(), Error> {
+    match ty {
+        Some(expected) if !expected.accepts(val) => Err(Error::ArgumentTypeMismatch {
+            function: function.to_string(),
+            parameter: parameter.to_string(),
+            expected,
+            got: Type::of(val),
+        }),
+        _ => Ok(()),
     }
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub enum Literal {
-    String(String),
-    Character(char),
-    ByteInt(u8),
-    UnsignedShort(u16),
+/// The functions called in a function, with the number of arguments each
+/// call passes and the span of the statement it is in. A function whose
+/// reference is taken is called indirectly, with no number of arguments.
+#[derive(Default)]
+struct CallSites {
+    calls: Vec<(String, Option<usize>, Option<Span>)>,
+    /// The span of the statement being visited
+    span: Option<Span>,
 }
 
-impl Literal {
-    pub fn string(s: impl ToString) -> Self {
-        Self::String(s.to_string())
+impl Visit for CallSites {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::Locate(span) = expr {
+            self.span = Some(*span);
+        }
+        walk_expr(self, expr)
     }
 
-    pub fn character(ch: char) -> Self {
-        Self::Character(ch)
+    fn visit_eval(&mut self, eval: &Eval) {
+        match eval {
+            Eval::Call(Call(name, args, named)) => self.calls.push((name.clone(), Some(args.len() + named.len()), self.span)),
+            Eval::FnRef(FnRef(name)) => self.calls.push((name.clone(), None, self.span)),
+            _ => {}
+        }
+        walk_eval(self, eval)
     }
+}
 
-    pub fn byte_int(b: u8) -> Self {
-        Self::ByteInt(b)
-    }
+/// Find the statement in a body that uses a variable
+fn locate_variable(body: &[Expr], name: &str) -> Option<Span> {
+    locate(body, &|expr| {
+        let mut used = vec![];
+        variables_in_body(std::slice::from_ref(expr), &mut used);
+        used.iter().any(|used| used == name)
+    })
+}
 
-    pub fn unsigned_short(ui: u16) -> Self {
-        Self::UnsignedShort(ui)
+/// Find the innermost statement in a body that matches
+fn locate(body: &[Expr], matches: &dyn Fn(&Expr) -> bool) -> Option<Span> {
+    let mut span = None;
+    for expr in body {
+        let nested = match expr {
+            Expr::Locate(s) => {
+                span = Some(*s);
+                continue;
+            }
+            Expr::If(If(_, then, otherwise)) => locate(then, matches).or_else(|| locate(otherwise, matches)),
+            Expr::While(While(_, body)) | Expr::ForRange(ForRange(_, _, _, body)) | Expr::Repeat(Repeat(_, body)) => {
+                locate(body, matches)
+            }
+            _ => None,
+        };
+        if nested.is_some() {
+            return nested;
+        }
+        if matches(expr) {
+            return span;
+        }
     }
+    None
 }
 
-impl Lower for Literal {
-    fn lower(&self) -> Result<Value, Error> {
-        let name;
-        match self {
-            Self::String(s) => {
-                name = format!("%TEMP_STR_LITERAL_{}%", rand_str());
-                define_no_cp(&name, Eval::Value(Value::string(s)?))?;
+/// Collect the variables a body uses before they are defined, with the variables
+/// defined at that point and the span of the statement using them. `defined` holds
+/// the variables defined before the body, and afterwards the ones still defined after it.
+fn undefined_variables(
+    body: &[Expr],
+    defined: &mut Vec<String>,
+    outer: &dyn Fn(&str) -> bool,
+    span: &mut Option<Span>,
+    undefined: &mut Vec<(String, Vec<String>, Option<Span>)>,
+) {
+    let uses = |eval: &Eval, defined: &[String], span: Option<Span>, undefined: &mut Vec<_>| {
+        let mut used = vec![];
+        variables_in_eval(eval, &mut used);
+        for name in used {
+            if !defined.contains(&name) && !outer(&name) {
+                undefined.push((name, defined.to_vec(), span));
+            }
+        }
+    };
+
+    for expr in body {
+        match expr {
+            Expr::Locate(s) => *span = Some(*s),
+            Expr::Define(Define(name, value)) | Expr::Const(Const(name, value)) => {
+                uses(value, defined, *span, undefined);
+                defined.push(name.clone());
+            }
+            Expr::Destructure(Destructure(names, value)) => {
+                uses(value, defined, *span, undefined);
+                defined.extend(names.iter().cloned());
+            }
+            Expr::Assign(Assign(lhs, rhs)) => {
+                uses(lhs, defined, *span, undefined);
+                uses(rhs, defined, *span, undefined);
+            }
+            Expr::Eval(e) | Expr::Return(Return(e)) | Expr::Assert(Assert(e, _)) | Expr::Exit(Exit(Some(e))) => {
+                uses(e, defined, *span, undefined)
+            }
+            Expr::Exit(Exit(None)) => {}
+            // Each branch defines its variables in its own cells, so what either
+            // branch defines isn't defined after them, even if both define it
+            Expr::If(If(condition, then, otherwise)) => {
+                uses(condition, defined, *span, undefined);
+                undefined_variables(then, &mut defined.clone(), outer, span, undefined);
+                undefined_variables(otherwise, &mut defined.clone(), outer, span, undefined);
             }
-            Self::Character(ch) => {
-                name = format!("%TEMP_CHAR_LITERAL_{}%", rand_str());
-                define_no_cp(&name, Eval::Value(Value::character(*ch)?))?;
+            // The body of a loop may not run, so what it defines isn't defined after it
+            Expr::While(While(condition, body)) => {
+                uses(condition, defined, *span, undefined);
+                undefined_variables(body, &mut defined.clone(), outer, span, undefined);
             }
-            Self::ByteInt(byte) => {
-                name = format!("%TEMP_BYTE_LITERAL_{}%", rand_str());
-                define_no_cp(&name, Eval::Value(Value::byte_int(*byte)?))?;
+            Expr::ForRange(ForRange(var, start, end, body)) => {
+                uses(start, defined, *span, undefined);
+                uses(end, defined, *span, undefined);
+                let mut in_body = defined.clone();
+                in_body.push(var.clone());
+                undefined_variables(body, &mut in_body, outer, span, undefined);
             }
-            Self::UnsignedShort(ui) => {
-                name = format!("%TEMP_U16_LITERAL_{}%", rand_str());
-                define_no_cp(&name, Eval::Value(Value::unsigned_short(*ui)?))?;
+            Expr::Repeat(Repeat(count, body)) => {
+                uses(count, defined, *span, undefined);
+                undefined_variables(body, &mut defined.clone(), outer, span, undefined);
             }
         }
-        get(name)
     }
 }
 
-pub fn deforfun(name: impl ToString, args: &[&'static str], fun: fn() -> Result<(), Error>) {
-    FOREIGN_FN_DEFS
-        .lock()
-        .unwrap()
-        .insert(name.to_string(), ForeignFn::new(args.to_vec(), fun));
+/// Does every path through a body end in a return statement?
+fn always_returns(body: &[Expr]) -> bool {
+    match body.last() {
+        Some(Expr::Return(_)) => true,
+        Some(Expr::If(If(_, then, otherwise))) => always_returns(then) && always_returns(otherwise),
+        _ => false,
+    }
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct UserFn {
-    name: String,
-    parameters: Vec<String>,
-    body: Vec<Expr>,
-}
+/// Put named arguments in the positions of the parameters they name. Parameters
+/// that neither kind of argument binds get their defaults.
+pub fn resolve_arguments(name: &str, args: &[Eval], named: &[(String, Eval)]) -> Result<Vec<Eval>, Error> {
+    if named.is_empty() {
+        return Ok(args.to_vec());
+    }
 
-impl UserFn {
-    pub fn new(name: impl ToString, parameters: Vec<String>, body: Vec<Expr>) -> Self {
-        Self {
+    // Every argument counts toward picking the overload
+    let count = args.len() + named.len();
+    match overload(name, count) {
+        Some(Overload::User(fun)) => bind_arguments(name, &fun.parameters, &fun.parameter_defaults, args, named),
+        Some(Overload::Foreign(fun)) => bind_arguments(name, &fun.parameters, &[], args, named),
+        None if lookup(name).is_some() => Err(Error::NoMatchingOverload {
             name: name.to_string(),
-            parameters: parameters.iter().map(ToString::to_string).collect(),
-            body,
+            got: count,
+            available: arities(name),
+        }),
+        None => {
+            let searched = vec![namespace_of(name).to_string()];
+            Err(Error::FunctionNotDefined { name: name.to_string(), searched })
         }
     }
+}
 
-    pub fn compile(self) {
-        FN_DEFS.lock().unwrap().insert(self.name.clone(), self);
+/// Bind the arguments of a call to a function's parameters, in the order of the parameters
+pub(crate) fn bind_arguments(
+    name: &str,
+    parameters: &[String],
+    defaults: &[Option<Literal>],
+    args: &[Eval],
+    named: &[(String, Eval)],
+) -> Result<Vec<Eval>, Error> {
+    if args.len() > parameters.len() {
+        return Err(Error::ArgumentCountMismatch {
+            function: name.to_string(),
+            expected: parameters.len(),
+            got: args.len() + named.len(),
+        });
     }
 
-    pub fn call(&self, args: &Vec<Eval>) -> Result<(), Error> {
-        let stack_frame;
-        stack_frame = *STACK_PTR.lock().unwrap();
-
-        let mut env = Env::new();
-
-        for (i, p) in self.parameters.iter().enumerate() {
-            env.define(p.to_string(), args[i].lower()?)?;
-        }
-
-        push_scope(env);
-
-        for instruction in &self.body {
-            instruction.compile()?;
+    let mut bound: Vec<Option<Eval>> = args.iter().cloned().map(Some).collect();
+    bound.resize(parameters.len(), None);
+    for (parameter, arg) in named {
+        let i = parameters.iter().position(|p| p == parameter).ok_or_else(|| Error::UnknownArgument {
+            function: name.to_string(),
+            parameter: parameter.clone(),
+        })?;
+        if bound[i].is_some() {
+            return Err(Error::DuplicateArgument { function: name.to_string(), parameter: parameter.clone() });
         }
-
-        pop_scope().free();
-        set_stack(stack_frame)?;
-
-        Ok(())
+        bound[i] = Some(arg.clone());
     }
+
+    bound
+        .into_iter()
+        .enumerate()
+        .map(|(i, arg)| match (arg, defaults.get(i).cloned().flatten()) {
+            (Some(arg), _) => Ok(arg),
+            (None, Some(default)) => Ok(Eval::Literal(default)),
+            (None, None) => Err(Error::MissingArgument {
+                function: name.to_string(),
+                parameter: parameters[i].clone(),
+            }),
+        })
+        .collect()
 }
 
-pub fn call(name: impl ToString, args: &Vec<Eval>) -> Result<(), Error> {
-    let table = FN_DEFS.lock().unwrap();
-    if let Some(f_ref) = table.get(&name.to_string()) {
-        let fun = f_ref.clone();
-        drop(table);
-        fun.call(args)?;
-        return Ok(());
-    } else {
-        drop(table)
-    }
+pub fn call(name: impl ToString, args: &[Eval]) -> Result<(), Error> {
+    let name = name.to_string();
+    CALL_COUNT.with_borrow_mut(|call_count| *call_count += 1);
 
-    let table = FOREIGN_FN_DEFS.lock().unwrap();
-    if let Some(f_ref) = table.get(&name.to_string()) {
-        let fun = f_ref.clone();
-        drop(table);
-        fun.call(args)?;
-        return Ok(());
+    // The tables must not be locked during the call, the body may use them
+    match overload(&name, args.len()) {
+        Some(Overload::User(fun)) => {
+            fun.call(args)?;
+            // Only a declared return type says a user defined function returns a character
+            RETURNED_CHARACTER.set(false);
+            RETURNED_NUMBER.set(NumberKind::Unsigned);
+            RETURNED_WIDE.set(None);
+            return Ok(());
+        }
+        Some(Overload::Foreign(fun)) => return fun.call(args),
+        None if lookup(&name).is_some() => {
+            return Err(Error::NoMatchingOverload {
+                available: arities(&name),
+                got: args.len(),
+                name,
+            })
+        }
+        None => {}
     }
 
-    Err(Error::FunctionNotDefined(name.to_string()))
+    let searched = vec![namespace_of(&name).to_string()];
+    Err(Error::FunctionNotDefined { name, searched })
 }
 
 pub fn define(name: impl ToString, val: Eval) -> Result<(), Error> {
-    let temp_name = format!("%TEMP_DEFINE_{}%", rand_str());
-    Define::new(&temp_name, val).compile()?;
-    Define::new(name, Eval::Load(Load::new(temp_name))).compile()?;
-    Ok(())
+    Define::new(name, val).compile()
 }
 
-pub fn define_no_cp(final_name: impl ToString, value: Eval) -> Result<(), Error> {
-    let name = format!("%TEMP_DEFINE_{}%", rand_str());
-
-    let val = value.lower()?;
-    let mut scope_stack = SCOPE_STACK.lock().unwrap();
-    let scope = scope_stack.last_mut().unwrap();
-    scope.define_no_cp(&name, val);
-    drop(scope_stack);
-
-    let val = get(name)?;
-    let mut scope_stack = SCOPE_STACK.lock().unwrap();
-    let scope = scope_stack.last_mut().unwrap();
-    scope.define_no_cp(final_name, val);
-    drop(scope_stack);
+pub fn define_no_cp(name: impl ToString, value: Eval) -> Result<(), Error> {
+    let name = name.to_string();
+    let val = value.lower().map_err(|error| could_not_define(&name, format!("{:?}", value), error))?;
+    with_scope(|scope| scope.define_no_cp(name, val))
+}
 
-    Ok(())
+/// Say which definition an error happened in, unless the error is about the
+/// whole program, like running out of tape, rather than the value being defined
+fn could_not_define(name: &str, value: String, error: Error) -> Error {
+    match error {
+        Error::TapeOverflow { .. } => error,
+        error => Error::CouldNotDefine {
+            name: name.to_string(),
+            value,
+            error: Box::new(error),
+        },
+    }
 }
 
 pub fn get(name: impl ToString) -> Result<Value, Error> {
-    let mut scope_stack = SCOPE_STACK.lock().unwrap();
-    let scope = scope_stack.last_mut().unwrap();
-    scope.get(name.to_string())
+    SCOPE_STACK.with_borrow_mut(|scope_stack| {
+        let scope = scope_stack.last_mut().ok_or(Error::NoActiveScope)?;
+        match scope.get(name.to_string()) {
+            Ok(val) => Ok(val),
+            // Globals are defined in the outermost scope
+            Err(e) => scope_stack[0].get(name.to_string()).map_err(|_| e),
+        }
+    })
 }
 
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Define(String, Eval);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Define(pub(crate) String, pub(crate) Eval);
 
 impl Define {
     pub fn new(var: impl ToString, value: Eval) -> Self {
         Self(var.to_string(), value)
     }
+
+    /// The name of the variable defined
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// The expression the variable is defined as
+    pub fn value(&self) -> &Eval {
+        &self.1
+    }
 }
 
 impl Compile for Define {
     fn compile(&self) -> Result<(), Error> {
         let Define(name, value) = self;
-        add_to_compiled(format!("DEFINING {}", name));
 
+        if constant(name).is_some() {
+            warn(format!(
+                "definition of `{}` in function `{}` is hidden by the constant of the same name",
+                name,
+                current_function()
+            ));
+        }
+
+        let previous = with_scope(|scope| scope.contains(name).then(|| scope.get(name)))?.transpose()?;
         let val = value.lower()?;
-        let mut scope_stack = SCOPE_STACK.lock().unwrap();
-        let scope = scope_stack.last_mut().unwrap();
-        scope.define(&name, val)?;
-        drop(scope_stack);
+        match previous {
+            Some(previous) if previous.size() != val.size() && !name.starts_with('%') => warn(Warning::Resized {
+                name: name.clone(),
+                function: current_function(),
+                old_size: previous.size(),
+                new_size: val.size(),
+                span: location(),
+            }),
+            _ => {}
+        }
+        // A temporary is moved into the variable, and anything else is copied so it stays intact
+        if take_temporary(&val)? {
+            with_scope(|scope| scope.define_no_cp(name, val))?;
+        } else {
+            with_scope(|scope| scope.define(name, val))??;
+        }
+        report(|depth| CompileEvent::Define { name: name.clone(), depth });
+
+        Ok(())
+    }
+}
+
+/// This defines a variable for each value of a tuple, or each cell of an array
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Destructure(pub(crate) Vec<String>, pub(crate) Eval);
 
-        add_to_compiled("DONE");
+impl Destructure {
+    pub fn new(names: Vec<impl ToString>, value: Eval) -> Self {
+        Self(names.iter().map(ToString::to_string).collect(), value)
+    }
+}
+
+impl Compile for Destructure {
+    fn compile(&self) -> Result<(), Error> {
+        let Destructure(names, value) = self;
+        let val = value.lower()?;
+        // Anything but an array is a single value, however many cells it has
+        let values = if val.array { val.size() as usize } else { 1 };
+        if values != names.len() {
+            return Err(Error::DestructureArityMismatch {
+                names: names.len(),
+                values,
+            });
+        }
 
+        for (i, name) in names.iter().enumerate() {
+            let mut cell = val.cells(i as u32, 1);
+            cell.character = val.character;
+            with_scope(|scope| scope.define(name, cell))??;
+            report(|depth| CompileEvent::Define { name: name.clone(), depth });
+        }
         Ok(())
     }
 }
 
+/// This binds a name to a literal while compiling. Every use of the
+/// name is replaced with the literal, so a constant takes no cells.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Const(pub(crate) String, pub(crate) Eval);
+
+impl Const {
+    pub fn new(name: impl ToString, value: Eval) -> Self {
+        Self(name.to_string(), value)
+    }
+}
+
+impl Compile for Const {
+    fn compile(&self) -> Result<(), Error> {
+        let Const(name, value) = self;
+        let literal = constant_value(value).ok_or_else(|| Error::NotConstant(name.clone()))?;
+        CONSTANTS.with_borrow_mut(|constants| {
+            constants.last_mut().ok_or(Error::NoActiveScope)?.insert(name.clone(), literal);
+            Ok(())
+        })
+    }
+}
+
+/// A set of named byte constants. Variants are numbered from zero, or
+/// from one more than the variant before them, unless they are given a value.
+/// Each variant is the constant `Name::Variant`.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Enum(pub(crate) String, pub(crate) Vec<(String, u8)>);
+
+impl Enum {
+    pub fn new(name: impl ToString, variants: Vec<(String, Option<Literal>)>) -> Result<Self, Error> {
+        let name = name.to_string();
+        let mut result: Vec<(String, u8)> = vec![];
+        let mut next = 0;
+        for (variant, value) in variants {
+            let qualified = format!("{}::{}", name, variant);
+            let value = match value.map(|literal| literal.cell_value()) {
+                Some(Some(n)) => n,
+                Some(None) => return Err(Error::VariantValueTooLarge(qualified)),
+                None => next,
+            };
+            if value > u8::MAX as u32 {
+                return Err(Error::VariantValueTooLarge(qualified));
+            }
+
+            let value = value as u8;
+            if result.iter().any(|(v, _)| *v == variant) {
+                return Err(Error::DuplicateVariant(qualified));
+            }
+            if result.iter().any(|(_, n)| *n == value) {
+                return Err(Error::DuplicateVariantValue(qualified, value));
+            }
+            result.push((variant, value));
+            next = value as u32 + 1;
+        }
+        Ok(Self(name, result))
+    }
+
+    /// The constants for each variant
+    pub fn constants(&self) -> Vec<Const> {
+        let Enum(name, variants) = self;
+        variants
+            .iter()
+            .map(|(variant, value)| {
+                Const::new(format!("{}::{}", name, variant), Eval::Literal(Literal::byte_int(*value)))
+            })
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct Assign(Eval, Eval);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Assign(pub(crate) Eval, pub(crate) Eval);
 
 impl Assign {
     pub fn new(lhs: Eval, rhs: Eval) -> Self {
@@ -571,13 +4946,37 @@ impl Assign {
 impl Compile for Assign {
     fn compile(&self) -> Result<(), Error> {
         let Assign(lhs, rhs) = self;
-        lhs.lower()?.assign(rhs.lower()?)?;
+        if let Eval::Load(Load(name)) = lhs {
+            if constant(name).is_some() {
+                return Err(Error::AssignToConst(name.clone()));
+            }
+        }
+
+        let lhs_val = lhs.lower()?;
+        let rhs_val = rhs.lower()?;
+        // The size of what a pointer points to isn't known while compiling,
+        // so anything can be written through one
+        if !matches!(lhs, Eval::Deref(_)) && lhs_val.size() != rhs_val.size() {
+            return Err(Error::AssignSizeMismatch {
+                target: lhs.to_string(),
+                target_size: lhs_val.size(),
+                source_size: rhs_val.size(),
+            });
+        }
+        lhs_val.assign(rhs_val)?;
+
+        // A variable may now hold either kind of value
+        if let Eval::Load(Load(name)) = lhs {
+            with_scope(|scope| scope.set_kind(name, lhs_val.kind.merge(rhs_val.kind)))?;
+            set_referent(name, rhs_val.referent.or(lhs_val.referent));
+        }
         Ok(())
     }
 }
 
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct If(Eval, Vec<Expr>, Vec<Expr>);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct If(pub(crate) Eval, pub(crate) Vec<Expr>, pub(crate) Vec<Expr>);
 
 impl If {
     pub fn new(condition: Eval, then: Vec<Expr>, otherwise: Vec<Expr>) -> Self {
@@ -588,21 +4987,25 @@ impl If {
 impl Compile for If {
     fn compile(&self) -> Result<(), Error> {
         let If(condition, then, otherwise) = self;
-        Control::if_begin(condition.lower()?)?;
-        for exp in then {
-            exp.compile()?;
-        }
-        Control::else_begin()?;
-        for exp in otherwise {
-            exp.compile()?;
-        }
-        Control::if_end()?;
-        Ok(())
+        // The condition is copied before the branches, but its temporaries are
+        // freed after them, so the code that clears them always runs
+        with_temporaries(|| {
+            Control::if_begin(lower_condition(condition)?)?;
+            for exp in then {
+                exp.compile()?;
+            }
+            Control::else_begin()?;
+            for exp in otherwise {
+                exp.compile()?;
+            }
+            Control::if_end()
+        })
     }
 }
 
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
-pub struct While(Eval, Vec<Expr>);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct While(pub(crate) Eval, pub(crate) Vec<Expr>);
 
 impl While {
     pub fn new(condition: Eval, then: Vec<Expr>) -> Self {
@@ -613,13 +5016,136 @@ impl While {
 impl Compile for While {
     fn compile(&self) -> Result<(), Error> {
         let While(condition, then) = self;
-        Control::while_begin(condition.lower()?);
-        {
+        // The loop runs on the condition's cell, so its temporaries live until the loop ends
+        with_temporaries(|| {
+            let val = condition.lower()?;
+            if val.number.is_wide() {
+                return Err(Error::WideLoopCondition(condition.to_string()));
+            }
+            Control::while_begin(val);
             for exp in then {
                 exp.compile()?;
             }
+            Control::while_end();
+            Ok(())
+        })
+    }
+}
+
+/// Run a body once for every number from a start up to, but not including, an end.
+/// The loop variable is only defined in the body, and changing it there doesn't
+/// change how many times the body runs.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForRange(pub(crate) String, pub(crate) Eval, pub(crate) Eval, pub(crate) Vec<Expr>);
+
+impl ForRange {
+    pub fn new(var: impl ToString, start: Eval, end: Eval, body: Vec<Expr>) -> Self {
+        Self(var.to_string(), start, end, body)
+    }
+}
+
+impl Compile for ForRange {
+    fn compile(&self) -> Result<(), Error> {
+        with_temporaries(|| self.compile_loop())
+    }
+}
+
+impl ForRange {
+    fn compile_loop(&self) -> Result<(), Error> {
+        let ForRange(var, start, end, body) = self;
+        // Both bounds are lowered before the loop variable can hide a variable they use
+        let first = start.lower()?;
+        let last = end.lower()?;
+        for (bound, val) in [(start, first), (end, last)].iter() {
+            if val.size() != 1 {
+                return Err(Error::RangeBoundNotSingleCell(bound.to_string()));
+            }
+        }
+
+        // The body runs once for every step left, which a constant range knows while compiling
+        let constant = |eval: &Eval| match eval {
+            Eval::Literal(literal) => literal.cell_value(),
+            _ => None,
+        };
+        let steps = Value::new(1)?;
+        match (constant(start), constant(end)) {
+            (Some(a), Some(b)) if b <= a => return Ok(()),
+            (Some(a), Some(b)) => steps.set((b - a) as usize),
+            _ => {
+                steps.assign(last)?;
+                steps.saturating_minus_eq(first)?;
+            }
+        }
+
+        // The body gets a copy of the counter, so it can change or redefine the variable
+        let counter = first.copy()?;
+        let shadowed = with_scope(|scope| scope.take(var))?;
+        let one = Value::byte_int(1)?;
+        Control::while_begin(steps);
+        let current = counter.copy()?;
+        with_scope(|scope| scope.define_no_cp(var, current))?;
+        for exp in body {
+            exp.compile()?;
         }
+        counter.plus_eq(one);
+        steps.minus_eq(one);
         Control::while_end();
+
+        with_scope(|scope| {
+            scope.free_matching(|name| name == var);
+            match shadowed {
+                Some((val, true)) => scope.borrow(var, val),
+                Some((val, false)) => scope.define_no_cp(var, val),
+                None => {}
+            }
+        })?;
+        // The registers of the loop are given back last first, so the stack shrinks
+        for register in [one, counter, steps] {
+            register.discard();
+        }
+        Ok(())
+    }
+}
+
+/// A `repeat` with a larger count than this is warned about, since its body is compiled that many times
+const LARGE_REPEAT: u32 = 1024;
+
+/// Compile a body a number of times known while compiling, instead of looping
+/// over it. Each time through gets its own variables, so a body can define one.
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Repeat(pub(crate) Eval, pub(crate) Vec<Expr>);
+
+impl Repeat {
+    pub fn new(count: Eval, body: Vec<Expr>) -> Self {
+        Self(count, body)
+    }
+}
+
+impl Compile for Repeat {
+    fn compile(&self) -> Result<(), Error> {
+        let Repeat(count, body) = self;
+        let times = match constant_value(count) {
+            Some(Literal::ByteInt(n)) => n as u32,
+            Some(Literal::UnsignedShort(n)) => n as u32,
+            _ => return Err(Error::RepeatCountNotConstant(count.to_string())),
+        };
+        if times > LARGE_REPEAT {
+            warn(format!(
+                "`repeat` in function `{}` compiles its body {} times, which makes the output large",
+                current_function(),
+                times
+            ));
+        }
+
+        let outer: Vec<String> = with_scope(|scope| scope.iter().map(|(name, _)| name.to_string()).collect())?;
+        for _ in 0..times {
+            for exp in body {
+                exp.compile()?;
+            }
+            with_scope(|scope| scope.free_matching(|name| !outer.iter().any(|n| n == name)))?;
+        }
         Ok(())
     }
 }
@@ -627,42 +5153,252 @@ impl Compile for While {
 /// This class is only used for foreign functions. Do not use for regular functions.
 #[derive(Clone)]
 pub struct ForeignFn {
+    name: String,
     parameters: Vec<String>,
-    body: fn() -> Result<(), Error>,
+    parameter_types: Vec<Option<Type>>,
+    return_type: Option<Type>,
+    variadic: bool,
+    body: ForeignBody,
+}
+
+/// A foreign function body that is passed its arguments
+pub type DirectBody = Arc<dyn Fn(&[Value]) -> Result<Option<Value>, Error> + Send + Sync>;
+/// A foreign function body that gets its arguments with `get`
+pub type ScopedBody = Arc<dyn Fn() -> Result<(), Error> + Send + Sync>;
+/// A foreign function body that is passed its arguments before they are lowered
+pub type RawBody = Arc<dyn Fn(&[Eval]) -> Result<Option<Value>, Error> + Send + Sync>;
+
+/// The code run when a foreign function is called. Bodies may be closures,
+/// so the host can capture its own state in them.
+#[derive(Clone)]
+pub enum ForeignBody {
+    /// Passed the arguments, including any extra arguments, and returns the value to put in RETURN
+    Direct(DirectBody),
+    /// Gets the arguments with `get` and sets RETURN itself
+    Scoped(ScopedBody),
+    /// Passed the arguments before they are lowered, so it can look at literals
+    /// while compiling. It runs in the caller's scope and lowers the arguments itself.
+    Raw(RawBody),
+}
+
+/// Put the value the body of a foreign function returned, if it returned one, in RETURN.
+/// A 4 byte number doesn't fit in RETURN, so it is given back to be moved out of the call's cells.
+fn set_foreign_return(val: Option<Value>) -> Result<Option<Value>, Error> {
+    match val {
+        Some(val) if val.number.is_wide() => {
+            RETURNED_NUMBER.set(val.number);
+            Ok(Some(val))
+        }
+        Some(val) => {
+            RETURNED_CHARACTER.set(val.character);
+            RETURNED_NUMBER.set(val.number);
+            set_return(val)?;
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Copy a 4 byte number a foreign function returned to the top of the stack, above
+/// the cells it is moved down to once the call's cells are freed, so it outlives them
+fn lift_wide_return(val: Option<Value>, stack_frame: u32) -> Result<Option<Value>, Error> {
+    match val {
+        Some(val) => {
+            // The call's holes are dropped with its frame, so the copy isn't put in one
+            drop(take_holes());
+            set_stack(STACK_PTR.get().max(stack_frame + val.size()))?;
+            val.copy().map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Move a 4 byte number lifted out of a call's cells down into the caller's frame,
+/// where it is freed with the temporaries of the statement that made the call
+fn place_wide_return(lifted: Option<Value>) -> Result<(), Error> {
+    if let Some(lifted) = lifted {
+        let val = lifted.copy()?;
+        lifted.free();
+        RETURNED_WIDE.set(Some(unnamed_temporary(val)));
+    }
+    Ok(())
+}
+
+/// The marker ending the parameters of a foreign function that takes any number of extra arguments
+pub const VARIADIC: &str = "...";
+
+/// The marker that declares the return type of a foreign function after its
+/// parameters, like `"-> void"` for a function that doesn't return a value
+pub const RETURNS: &str = "->";
+
+/// The name of the variable holding the number of extra arguments passed to a variadic foreign function
+pub const VARARG_COUNT: &str = "%VARARG_COUNT%";
+
+/// The name of the variable holding an extra argument passed to a variadic foreign function
+pub fn vararg_name(i: usize) -> String {
+    format!("%VARARG{}%", i)
+}
+
+/// Get the extra arguments passed to the variadic foreign function being compiled, in order
+pub fn varargs() -> Vec<Value> {
+    let mut result = vec![];
+    while let Ok(val) = get(vararg_name(result.len())) {
+        result.push(val);
+    }
+    result
 }
 
 impl ForeignFn {
-    pub fn new(parameters: Vec<impl ToString>, body: fn() -> Result<(), Error>) -> Self {
+    /// Parameters may declare their type after a colon, like `"x: byte"`.
+    /// If the last parameter is `...`, the function takes any number of extra
+    /// arguments, which the body gets with `varargs` or `get(vararg_name(i))`.
+    /// The return type may be declared after the parameters, like `"-> void"`.
+    pub fn new(name: impl ToString, parameters: Vec<impl ToString>, body: ForeignBody) -> Self {
+        let mut parameters: Vec<String> = parameters.iter().map(ToString::to_string).collect();
+        let return_type = match parameters.last().and_then(|p| p.strip_prefix(RETURNS)) {
+            Some(ty) => match Type::from_name(ty.trim()) {
+                Some(ty) => Some(ty),
+                None => panic!("unknown return type `{}` for foreign function `{}`", ty.trim(), name.to_string()),
+            },
+            None => None,
+        };
+        if return_type.is_some() {
+            parameters.pop();
+        }
+        let variadic = parameters.last().map(String::as_str) == Some(VARIADIC);
+        if variadic {
+            parameters.pop();
+        }
+
+        let mut names = vec![];
+        let mut types = vec![];
+        for p in parameters {
+            match p.split_once(':') {
+                Some((name, ty)) => {
+                    names.push(name.trim().to_string());
+                    match Type::from_name(ty.trim()) {
+                        Some(ty) => types.push(Some(ty)),
+                        None => panic!("unknown type `{}` for parameter `{}`", ty.trim(), name.trim()),
+                    }
+                }
+                None => {
+                    names.push(p);
+                    types.push(None);
+                }
+            }
+        }
+
         Self {
-            parameters: parameters.iter().map(ToString::to_string).collect(),
+            name: name.to_string(),
+            parameters: names,
+            parameter_types: types,
+            return_type,
+            variadic,
             body,
         }
     }
 
-    pub fn define(name: impl ToString, args: Vec<impl ToString>, fun: fn() -> Result<(), Error>) {
-        FOREIGN_FN_DEFS.lock().unwrap().insert(
-            name.to_string(),
-            Self::new(args.iter().map(ToString::to_string).collect(), fun),
-        );
+    /// Create a foreign function from a closure that is passed the arguments
+    pub fn from_closure<F>(name: impl ToString, parameters: Vec<impl ToString>, body: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Option<Value>, Error> + Send + Sync + 'static,
+    {
+        Self::new(name, parameters, ForeignBody::Direct(Arc::new(body)))
+    }
+
+    /// Can this function be called with this many arguments?
+    pub fn accepts(&self, count: usize) -> bool {
+        count == self.parameters.len() || (self.variadic && count > self.parameters.len())
+    }
+
+    /// Register a foreign function. It is added to the other overloads of its
+    /// name, and replaces the one that takes the same number of arguments.
+    pub fn define(name: impl ToString, args: Vec<impl ToString>, fun: ForeignBody) {
+        let fun = Self::new(name.to_string(), args.iter().map(ToString::to_string).collect(), fun);
+        let user_fns = FN_DEFS.with_borrow(|fn_defs| fn_defs.get(&fun.name).cloned().unwrap_or_default());
+        if user_fns.iter().any(|f| f.accepts(fun.parameters.len())) {
+            warn(format!(
+                "foreign function `{}` is shadowed by the function of the same name defined by the program",
+                fun.name
+            ));
+        }
+        FOREIGN_FN_DEFS.with_borrow_mut(|table| {
+            let overloads = table.entry(fun.name.clone()).or_default();
+            overloads.retain(|f| f.parameters.len() != fun.parameters.len());
+            overloads.push(fun);
+        });
+        FINGERPRINTS.with_borrow_mut(|fingerprints| fingerprints.clear());
     }
 
-    pub fn call(&self, args: &Vec<Eval>) -> Result<(), Error> {
-        let stack_frame;
-        stack_frame = *STACK_PTR.lock().unwrap();
+    pub fn call(&self, args: &[Eval]) -> Result<(), Error> {
+        let stack_frame = STACK_PTR.get();
+        let first_temp = TEMP_COUNTER.get();
+
+        let too_few = args.len() < self.parameters.len();
+        let too_many = args.len() > self.parameters.len() && !self.variadic;
+        if too_few || too_many {
+            return Err(Error::ArgumentCountMismatch {
+                function: self.name.clone(),
+                expected: self.parameters.len(),
+                got: args.len(),
+            });
+        }
+
+        RETURNED_CHARACTER.set(false);
+        RETURNED_NUMBER.set(NumberKind::Unsigned);
+        RETURNED_WIDE.set(None);
+        if let ForeignBody::Raw(body) = &self.body {
+            let holes = take_holes();
+            enter_call(&self.name, args.len(), true);
+            let result = body(args).and_then(set_foreign_return).map_err(Error::traced);
+            CALL_STACK.with_borrow_mut(|call_stack| call_stack.pop());
+            let wide = lift_wide_return(result?, stack_frame)?;
+            free_temporaries(first_temp)?;
+            set_stack(stack_frame)?;
+            restore_holes(holes);
+            return place_wide_return(wide);
+        }
 
         let mut env = Env::new();
+        // The copies of the arguments in the function's scope
+        let mut values = vec![];
 
         for (i, p) in self.parameters.iter().enumerate() {
-            env.define(p.to_string(), args[i].lower()?)?;
+            let val = lower_typed(&args[i], self.parameter_types[i])?;
+            check_argument(&self.name, p, self.parameter_types[i], &val)?;
+            env.define(p.to_string(), val)?;
+            values.push(env.get(p)?);
         }
 
+        if self.variadic {
+            let extra = &args[self.parameters.len()..];
+            for (i, arg) in extra.iter().enumerate() {
+                env.define(vararg_name(i), arg.lower()?)?;
+                values.push(env.get(vararg_name(i))?);
+            }
+            env.define(VARARG_COUNT, Literal::number(extra.len() as u16).lower()?)?;
+        }
+
+        let holes = take_holes();
         push_scope(env);
+        enter_call(&self.name, args.len(), true);
 
-        (self.body)()?;
+        let result = match &self.body {
+            ForeignBody::Direct(body) => body(&values).and_then(set_foreign_return),
+            ForeignBody::Scoped(body) => body().map(|()| None),
+            ForeignBody::Raw(_) => unreachable!(),
+        };
+        // The error is traced while the call is still on the call stack, which
+        // the call is taken off of whether it failed or not
+        let result = result.map_err(Error::traced);
+        CALL_STACK.with_borrow_mut(|call_stack| call_stack.pop());
+        let wide = lift_wide_return(result?, stack_frame)?;
 
-        pop_scope().free();
+        pop_scope()?.free();
+        free_temporaries(first_temp)?;
         set_stack(stack_frame)?;
+        restore_holes(holes);
 
-        Ok(())
+        place_wide_return(wide)
     }
 }
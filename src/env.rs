@@ -1,49 +1,162 @@
-use crate::{add_to_compiled, Error, Value};
+use crate::{emit_ir, report, CompileEvent, Error, IrOp, Value, ValueKind};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Env {
     scope: HashMap<String, Value>,
+    /// The names in the scope, in the order they were defined
+    order: Vec<String>,
+    /// The names bound to the cells of a variable in another scope,
+    /// which are never freed with this scope
+    borrowed: Vec<String>,
 }
 
 impl Env {
     pub fn new() -> Self {
         Self {
             scope: HashMap::new(),
+            order: Vec::new(),
+            borrowed: Vec::new(),
         }
     }
 
     pub fn define(&mut self, name: impl ToString, value: Value) -> Result<(), Error> {
-        if let Ok(previous_value) = self.get(name.to_string()) {
-            previous_value.free();
-        }
-
-        self.scope.insert(name.to_string(), value.copy()?);
+        let value = value.copy()?;
+        self.define_no_cp(name, value);
         Ok(())
     }
 
+    /// Bind a name to the cells of a value owned by another scope, such as
+    /// the caller's variable given to a reference parameter
+    pub fn borrow(&mut self, name: impl ToString, value: Value) {
+        let name = name.to_string();
+        self.define_no_cp(&name, value);
+        self.borrowed.push(name);
+    }
+
+    /// Is this name bound to cells owned by another scope?
+    pub fn is_borrowed(&self, name: impl AsRef<str>) -> bool {
+        self.borrowed.iter().any(|n| n == name.as_ref())
+    }
+
     pub fn define_no_cp(&mut self, name: impl ToString, value: Value) {
-        if let Ok(previous_value) = self.get(name.to_string()) {
-            previous_value.free();
+        let name = name.to_string();
+        if let Some(&previous_value) = self.scope.get(&name) {
+            // The old cells are kept if something still points to them,
+            // or if they belong to another scope
+            if !self.is_borrowed(&name) && !self.iter().any(|(_, val)| val.refers_to(&previous_value)) {
+                previous_value.discard();
+            }
+            self.order.retain(|n| n != &name);
+            self.borrowed.retain(|n| n != &name);
         }
 
-        self.scope.insert(name.to_string(), value);
+        emit_ir(IrOp::Bind { name: name.clone(), at: value.cell() });
+        self.order.push(name.clone());
+        self.scope.insert(name, value);
+    }
+
+    /// Forget a variable without freeing its cells, and give back the
+    /// value it was bound to and whether it was borrowed
+    pub fn take(&mut self, name: impl AsRef<str>) -> Option<(Value, bool)> {
+        let name = name.as_ref();
+        let value = self.scope.remove(name)?;
+        let borrowed = self.is_borrowed(name);
+        self.order.retain(|n| n != name);
+        self.borrowed.retain(|n| n != name);
+        Some((value, borrowed))
     }
 
     pub fn get(&mut self, name: impl ToString) -> Result<Value, Error> {
         match self.scope.get(&name.to_string()) {
             Some(val) => Ok(*val),
-            None => Err(Error::VariableNotDefined(name.to_string(), self.clone())),
+            None => Err(Error::VariableNotDefined(name.to_string(), self.names())),
+        }
+    }
+
+    /// Is a variable with this name defined in the scope?
+    pub fn contains(&self, name: impl AsRef<str>) -> bool {
+        self.scope.contains_key(name.as_ref())
+    }
+
+    /// The names of the variables the program defined in the scope,
+    /// in the order they were defined. Temporaries are left out.
+    pub fn names(&self) -> Vec<String> {
+        self.order.iter().filter(|name| !name.starts_with('%')).cloned().collect()
+    }
+
+    /// The variables in the scope, in the order they were defined
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.order.iter().map(move |name| (name.as_str(), &self.scope[name]))
+    }
+
+    /// A copy of the scope with every variable renamed
+    pub fn renamed(&self, rename: impl Fn(&str) -> String) -> Self {
+        Self {
+            scope: self.scope.iter().map(|(name, val)| (rename(name), *val)).collect(),
+            order: self.order.iter().map(|name| rename(name)).collect(),
+            borrowed: self.borrowed.iter().map(|name| rename(name)).collect(),
+        }
+    }
+
+    /// The name of the variable in this scope that a reference points into
+    pub fn referred_by(&self, reference: &Value) -> Option<&str> {
+        self.iter()
+            .find(|(name, val)| !self.is_borrowed(name) && reference.refers_to(val))
+            .map(|(name, _)| name)
+    }
+
+    /// Change what a variable is known to hold without touching its cells
+    pub fn set_kind(&mut self, name: impl ToString, kind: ValueKind) {
+        if let Some(val) = self.scope.get_mut(&name.to_string()) {
+            val.kind = kind;
+        }
+    }
+
+    /// Change the cells a variable is known to point to
+    pub fn set_referent(&mut self, name: impl ToString, referent: Option<(u32, u32)>) {
+        if let Some(val) = self.scope.get_mut(&name.to_string()) {
+            val.referent = referent;
+        }
+    }
+
+    /// Free every variable whose name matches, and forget it. A variable
+    /// that is pointed to by a variable that is kept is kept too.
+    pub fn free_matching(&mut self, matches: impl Fn(&str) -> bool) {
+        let names: Vec<String> = self
+            .order
+            .iter()
+            .rev()
+            .filter(|name| matches(name) && !self.is_borrowed(name))
+            .cloned()
+            .collect();
+        for name in names {
+            let value = self.scope[&name];
+            let referenced = self
+                .iter()
+                .any(|(other, val)| !matches(other) && val.refers_to(&value));
+            if !referenced {
+                self.scope.remove(&name);
+                self.order.retain(|n| n != &name);
+                value.discard();
+            }
         }
     }
 
+    /// Free every variable, the most recently defined first, so a
+    /// reference is always freed before the value it points to
     pub fn free(&mut self) {
-        for value in self.scope.values() {
-            // value.free();
-            if !value.is_ref() {
-                value.free();
+        for name in self.order.iter().rev() {
+            let value = &self.scope[name];
+            if self.is_borrowed(name) {
+                continue;
+            } else if !value.is_ref() {
+                value.discard();
             } else {
-                add_to_compiled(format!("NOT FREEING {:#?}", value));
+                report(|depth| CompileEvent::KeepReference { name: name.clone(), depth });
             }
         }
     }
@@ -0,0 +1,307 @@
+use crate::{exit_status_cell, CellWidth, Error, Program, INSTRUCTIONS};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// The number of cells that remember where the pointer was before a dereference
+const REF_TAPE_SIZE: usize = 256;
+
+/// An error while running compiled code, as opposed to compiling it
+#[derive(Debug)]
+pub enum RunError {
+    /// The program could not be compiled
+    Compile(Error),
+    /// The code has a `[` or `]` without a partner, after this many operations
+    UnmatchedBracket(usize),
+    /// The pointer moved below the first cell of the tape
+    TapeUnderflow,
+    /// The pointer moved past the last cell of the tape, to this address
+    TapeOverflow(usize),
+    /// A `&` returned from a dereference that never happened
+    RefUnderflow,
+    /// The program executed as many steps as it was allowed to,
+    /// which usually means it loops forever
+    StepLimit(u64),
+    /// The input could not be read, or the output could not be written
+    Io(io::Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Compile(error) => write!(f, "{}", error),
+            Self::UnmatchedBracket(position) => write!(f, "unmatched bracket after {} operations", position),
+            Self::TapeUnderflow => write!(f, "the pointer moved below the tape"),
+            Self::TapeOverflow(ptr) => write!(f, "the pointer moved past the end of the tape to {}", ptr),
+            Self::RefUnderflow => write!(f, "returned from a dereference that never happened"),
+            Self::StepLimit(limit) => write!(f, "the program did not stop after {} steps", limit),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<Error> for RunError {
+    fn from(error: Error) -> Self {
+        Self::Compile(error)
+    }
+}
+
+impl From<io::Error> for RunError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// An instruction, or a run of the same instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Right(usize),
+    Left(usize),
+    Add(u32),
+    Sub(u32),
+    /// `[-]`, which sets the cell to zero
+    Zero,
+    Deref,
+    Refer,
+    Alloc,
+    Print,
+    Read,
+    /// Jump past the matching `End` if the cell is zero
+    Begin(usize),
+    /// Jump back past the matching `Begin` if the cell is not zero
+    End(usize),
+    /// `[]`, which loops forever if the cell is not zero, so the program stops instead
+    Halt,
+}
+
+/// Turn compiled code into operations, the same way it is simplified to C
+fn parse(code: &str) -> Result<Vec<Op>, RunError> {
+    let code: String = code.chars().filter(|ch| INSTRUCTIONS.contains(ch)).collect();
+    let code = code.replace("[-]", "0").replace("[]", "h");
+    let mut ops = vec![];
+    let mut open = vec![];
+    let mut chars = code.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let mut count = 1;
+        if "<>+-".contains(ch) {
+            while chars.peek() == Some(&ch) {
+                chars.next();
+                count += 1;
+            }
+        }
+        let op = match ch {
+            '>' => Op::Right(count),
+            '<' => Op::Left(count),
+            '+' => Op::Add(count as u32),
+            '-' => Op::Sub(count as u32),
+            '0' => Op::Zero,
+            '*' => Op::Deref,
+            '&' => Op::Refer,
+            '?' => Op::Alloc,
+            'h' => Op::Halt,
+            '.' => Op::Print,
+            ',' => Op::Read,
+            '[' => {
+                open.push(ops.len());
+                Op::Begin(0)
+            }
+            _ => {
+                let start = open.pop().ok_or(RunError::UnmatchedBracket(ops.len()))?;
+                ops[start] = Op::Begin(ops.len());
+                Op::End(start)
+            }
+        };
+        ops.push(op);
+    }
+    match open.pop() {
+        Some(start) => Err(RunError::UnmatchedBracket(start)),
+        None => Ok(ops),
+    }
+}
+
+/// Executes compiled code directly, the way the C it is simplified to does
+pub struct Interpreter<R: Read, W: Write> {
+    input: R,
+    output: W,
+    /// The most steps to execute, or None to run until the code ends
+    step_limit: Option<u64>,
+    steps: u64,
+    tape: Vec<u32>,
+    ptr: usize,
+    refs: [usize; REF_TAPE_SIZE],
+    ref_ptr: usize,
+    /// One more than the largest value a cell holds
+    modulus: u32,
+    /// The first cell of the heap
+    heap_start: usize,
+    /// If the program stopped itself, this flag is set
+    halted: bool,
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    /// Create an interpreter with the tape size and cell width of the last compiled program
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            step_limit: None,
+            steps: 0,
+            tape: vec![0; Program::tape_size() as usize],
+            ptr: 0,
+            refs: [0; REF_TAPE_SIZE],
+            ref_ptr: 0,
+            modulus: Program::cell_width().modulus(),
+            heap_start: Program::heap_start() as usize,
+            halted: false,
+        }
+    }
+
+    /// Use a tape with this many cells
+    pub fn with_tape_size(mut self, cells: usize) -> Self {
+        self.tape = vec![0; cells];
+        self
+    }
+
+    /// Use cells of this width
+    pub fn with_cell_width(mut self, width: CellWidth) -> Self {
+        self.modulus = width.modulus();
+        self
+    }
+
+    /// Stop running after this many steps
+    pub fn with_step_limit(mut self, steps: u64) -> Self {
+        self.step_limit = Some(steps);
+        self
+    }
+
+    /// The number of steps executed. A run of the same instruction, and the
+    /// loop `[-]` that clears a cell, each take one step.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The exit status the program stopped itself with, such as with `exit`,
+    /// or None if it hasn't stopped itself
+    pub fn exit_status(&self) -> Option<u8> {
+        let cell = exit_status_cell().offset as usize;
+        self.halted.then(|| (self.tape.get(cell).copied().unwrap_or(0) % 256) as u8)
+    }
+
+    /// The cells of the tape
+    pub fn tape(&self) -> &[u32] {
+        &self.tape
+    }
+
+    /// The sink the program's output is written to
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Stop interpreting, and get back the sink the output was written to
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Allocate `tape[ptr]` cells on the heap, and return the address they start at, or 0 if
+    /// there is no room. The cell before each block holds its size, so the blocks are walked
+    /// from the start of the heap, and the cells between them are zero.
+    fn allocate(&mut self) -> u32 {
+        let size = self.tape[self.ptr].max(1) as usize;
+        let max_address = self.tape.len().min(self.modulus as usize);
+        let mut start = self.heap_start;
+        let mut address = start;
+        while address < max_address {
+            if self.tape[address] == 0 {
+                address += 1;
+                if address - start == size + 1 {
+                    self.tape[start] = size as u32;
+                    return start as u32 + 1;
+                }
+            } else {
+                address += self.tape[address] as usize + 1;
+                start = address;
+            }
+        }
+        0
+    }
+
+    /// Execute one operation, and return the position of the next
+    fn step(&mut self, op: Op, pc: usize) -> Result<usize, RunError> {
+        let cell = self.tape[self.ptr];
+        let mut next = pc + 1;
+        match op {
+            Op::Right(n) => self.ptr += n,
+            Op::Left(n) => self.ptr = self.ptr.checked_sub(n).ok_or(RunError::TapeUnderflow)?,
+            Op::Add(n) => self.tape[self.ptr] = (cell + n % self.modulus) % self.modulus,
+            Op::Sub(n) => self.tape[self.ptr] = (cell + self.modulus - n % self.modulus) % self.modulus,
+            Op::Zero => self.tape[self.ptr] = 0,
+            Op::Deref => {
+                self.refs[self.ref_ptr % REF_TAPE_SIZE] = self.ptr;
+                self.ref_ptr += 1;
+                self.ptr = cell as usize;
+            }
+            Op::Refer => {
+                self.ref_ptr = self.ref_ptr.checked_sub(1).ok_or(RunError::RefUnderflow)?;
+                self.ptr = self.refs[self.ref_ptr % REF_TAPE_SIZE];
+            }
+            Op::Alloc => self.tape[self.ptr] = self.allocate(),
+            Op::Print => self.output.write_all(&[(cell % 256) as u8])?,
+            // At the end of the input, the cell keeps its value
+            Op::Read => {
+                let mut byte = [0];
+                if self.input.read(&mut byte)? == 1 {
+                    self.tape[self.ptr] = byte[0] as u32 % self.modulus;
+                }
+            }
+            Op::Begin(end) if cell == 0 => next = end + 1,
+            Op::End(start) if cell != 0 => next = start + 1,
+            Op::Begin(_) | Op::End(_) => {}
+            // Jump past the end of the code
+            Op::Halt if cell != 0 => {
+                self.halted = true;
+                next = usize::MAX;
+            }
+            Op::Halt => {}
+        }
+        if self.ptr >= self.tape.len() {
+            return Err(RunError::TapeOverflow(self.ptr));
+        }
+        Ok(next)
+    }
+
+    /// Run compiled code until it ends. The steps it takes count towards the step limit.
+    pub fn run(&mut self, code: &str) -> Result<(), RunError> {
+        let ops = parse(code)?;
+        let mut pc = 0;
+        while pc < ops.len() {
+            if let Some(limit) = self.step_limit {
+                if self.steps >= limit {
+                    self.output.flush()?;
+                    return Err(RunError::StepLimit(limit));
+                }
+            }
+            let result = self.step(ops[pc], pc);
+            self.steps += 1;
+            pc = match result {
+                Ok(next) => next,
+                Err(e) => {
+                    self.output.flush()?;
+                    return Err(e);
+                }
+            };
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+impl Program {
+    /// Compile the program and run it on some input, returning what it prints
+    pub fn run(self, input: &[u8]) -> Result<Vec<u8>, RunError> {
+        let code = self.compile()?;
+        let mut interpreter = Interpreter::new(input, vec![]);
+        interpreter.run(&code)?;
+        Ok(interpreter.into_output())
+    }
+}
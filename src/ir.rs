@@ -1,150 +1,773 @@
-use crate::{Error, Program, Eval, Literal, Lower};
+use crate::{current_function, location, state::Get, warn, Error, NumberKind, Program, Span, DIGIT_BASE, DIGIT_BITS, WIDE_DIGITS};
 use core::fmt;
-use std::sync::Mutex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+};
 
-lazy_static! {
-    static ref COMPILED: Mutex<String> = Mutex::new(String::new());
-    static ref CONTROL_REGISTERS: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+compiler_state! {
+    static COMPILED: String = String::new();
+    static CONTROL_REGISTERS: Vec<Value> = Vec::new();
 
     /// This variable is responsible for keeping track of each statically allocated variable
     /// For example, if a variable `test` is allocated statically with size 4, the STACK_PTR
     /// will be allocated by 4, and the next variable will be allocated at the STACK_PTR
-    pub static ref STACK_PTR: Mutex<u32> = Mutex::new(0);
-    pub static ref RETURN: Value = Value::new(1).unwrap();
-    pub static ref TEMP0: Value = Value::new(1).unwrap();
-    pub static ref TEMP1: Value = Value::new(1).unwrap();
-    pub static ref TEMP2: Value = Value::new(1).unwrap();
-    pub static ref TEMP3: Value = Value::new(1).unwrap();
-    pub static ref TEMP4: Value = Value::new(1).unwrap();
-    pub static ref TEMP5: Value = Value::new(1).unwrap();
-    pub static ref TEMP6: Value = Value::new(1).unwrap();
+    pub static STACK_PTR: u32 = 0;
+    /// The cells below the STACK_PTR that were freed while values above them
+    /// were still live. New values are allocated here first, so the temporaries
+    /// of a statement reuse the cells of the ones freed before it, and the stack
+    /// grows with the depth of an expression rather than its length. Holes above
+    /// the STACK_PTR are dropped by `set_stack`, and a call starts with none.
+    static HOLES: Holes = Holes::default();
+    /// The cells on the stack that are known to be zero where the code compiled
+    /// so far ends, so clearing them again can be skipped. A loop or a call
+    /// forgets them, because its code doesn't only run after the code before it.
+    static ZERO_CELLS: BTreeSet<u32> = BTreeSet::new();
+    pub static RETURN: Value = Value::new(1).unwrap();
+    pub static TEMP0: Value = Value::new(1).unwrap();
+    pub static TEMP1: Value = Value::new(1).unwrap();
+    pub static TEMP2: Value = Value::new(1).unwrap();
+    pub static TEMP3: Value = Value::new(1).unwrap();
+    pub static TEMP4: Value = Value::new(1).unwrap();
+    pub static TEMP5: Value = Value::new(1).unwrap();
+    pub static TEMP6: Value = Value::new(1).unwrap();
+
+    /// The number of cells on the tape of the target machine. The stack
+    /// can use every cell the heap doesn't.
+    pub static TAPE_SIZE: u32 = DEFAULT_TAPE_SIZE;
+    pub static HEAP_SIZE: u32 = 2048;
+    /// The largest values allocated on the stack, the largest first
+    static LARGEST_ALLOCATIONS: Vec<Allocation> = vec![];
+
+    /// The number of instructions emitted so far
+    static INSTRUCTION_COUNT: usize = 0;
+    /// The number of values allocated so far
+    static ALLOCATION_COUNT: usize = 0;
+    /// The highest value the STACK_PTR has reached since it was last reset
+    static PEAK_STACK_PTR: u32 = 0;
+
+    /// The operations lowered so far, if they are being recorded
+    static IR: Option<Vec<IrOp>> = None;
+    /// The code added since each capture started, the innermost capture last
+    static CAPTURES: Vec<String> = vec![];
+}
 
-    pub static ref STACK_SIZE: Mutex<u32> = Mutex::new(2048);
-    pub static ref HEAP_SIZE: Mutex<u32> = Mutex::new(2048);
+/// A cell on the tape, reached by moving to `offset` and dereferencing `depth` times
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Cell {
+    pub offset: u32,
+    pub depth: u32,
 }
 
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", "*".repeat(self.depth as usize), self.offset)
+    }
+}
 
+/// The instructions for operations on cells. Values compile themselves
+/// with these, and so does the SMPL backend.
+impl Cell {
+    /// Move from the start of the tape to this cell
+    pub fn to(&self) -> String {
+        ">".repeat(self.offset as usize) + &"*".repeat(self.depth as usize)
+    }
 
-pub fn increment_stack(allocation_size: u32) -> Result<(), Error> {
-    let mut stack_ptr = STACK_PTR.lock().unwrap();
-    *stack_ptr += allocation_size;
-    if *stack_ptr > *STACK_SIZE.lock().unwrap() {
-        Err(Error::StackOverflow)
+    /// Move from this cell back to the start of the tape
+    pub fn from(&self) -> String {
+        "&".repeat(self.depth as usize) + &"<".repeat(self.offset as usize)
+    }
+
+    /// Set `size` cells to zero
+    pub(crate) fn zero_code(&self, size: u32) -> String {
+        self.to() + &"[-]>".repeat(size as usize) + &"<".repeat(size as usize) + &self.from()
+    }
+
+    /// Set the cell to a number
+    pub(crate) fn set_code(&self, value: usize) -> String {
+        self.to() + "[-]" + &"+".repeat(value) + &self.from()
+    }
+
+    /// Add a number to the cell
+    pub(crate) fn add_code(&self, value: usize) -> String {
+        self.to() + &"+".repeat(value) + &self.from()
+    }
+
+    /// Add the characters of a string to the cells, and 1 to the cell after them
+    pub(crate) fn string_code(&self, text: &str) -> String {
+        let mut result = self.to();
+        for byte in text.bytes() {
+            result += &("+".repeat(byte as usize) + ">");
+        }
+        result += "+";
+        result += &"<".repeat(text.len());
+        result + &self.from()
+    }
+
+    /// Add (or subtract, if `sign` is `-`) `size` cells starting at `from` to
+    /// the cells starting at this one, clearing each cell first if `clear` is set.
+    /// The cells of `from` are moved through TEMP0 and back.
+    pub(crate) fn transfer_code(&self, from: Cell, size: u32, sign: char, clear: bool) -> String {
+        let temp = TEMP0.get().cell();
+        let mut result = temp.zero_code(1);
+        for cell in 0..size {
+            let cell_to = from.to() + &">".repeat(cell as usize);
+            let cell_from = "<".repeat(cell as usize) + &from.from();
+            let this_to = self.to() + &">".repeat(cell as usize);
+            let this_from = "<".repeat(cell as usize) + &self.from();
+            if clear {
+                result += &(this_to.clone() + "[-]" + &this_from);
+            }
+            result += &(cell_to.clone()
+                + "["
+                + &cell_from
+                + &this_to
+                + &sign.to_string()
+                + &this_from
+                + &temp.to()
+                + "+"
+                + &temp.from()
+                + &cell_to
+                + "-"
+                + &cell_from
+                + &cell_to
+                + "]"
+                + &cell_from);
+            result += &(temp.to()
+                + "["
+                + &temp.from()
+                + &cell_to
+                + "+"
+                + &cell_from
+                + &temp.to()
+                + "-"
+                + &temp.from()
+                + &temp.to()
+                + "]"
+                + &temp.from());
+        }
+        result
+    }
+
+    /// Allocate `size` cells on the heap, mark them as used, and store their address in the cell
+    pub(crate) fn heap_alloc_code(&self, size: u32) -> String {
+        self.to() + &"+".repeat(size as usize) + "?*" + &"+>".repeat(size as usize) + "&" + &self.from()
+    }
+
+    /// Allocate as many cells on the heap as the cell holds, and store their address in it
+    pub(crate) fn variable_heap_alloc_code(&self) -> String {
+        self.to() + "?" + &self.from()
+    }
+
+    /// Begin a loop that runs while the cell is not zero
+    pub(crate) fn loop_begin_code(&self) -> String {
+        self.to() + "[" + &self.from()
+    }
+
+    /// End a loop that runs while the cell is not zero
+    pub(crate) fn loop_end_code(&self) -> String {
+        self.to() + "]" + &self.from()
+    }
+
+    /// Stop the program. An empty loop on a cell that isn't zero never ends,
+    /// so the interpreter and the C the code is simplified to stop there instead.
+    pub(crate) fn halt_code(&self) -> String {
+        self.to() + "[-]+[]" + &self.from()
+    }
+
+    /// Print `size` cells
+    pub(crate) fn print_code(&self, size: u32) -> String {
+        self.to() + &".>".repeat(size as usize) + &"<".repeat(size as usize) + &self.from()
+    }
+
+    /// Print the string the cell points to, which ends at a cell holding 1
+    pub(crate) fn print_cstr_code(&self) -> String {
+        self.to() + "*-[+.>-]+&" + &self.from()
+    }
+
+    /// Read a character from the input into the cell
+    pub(crate) fn read_code(&self) -> String {
+        self.to() + "," + &self.from()
+    }
+}
+
+/// An operation on values, recorded while the program is lowered. The
+/// operations say what the compiled code does without the cell by cell
+/// detail of the instructions that do it.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum IrOp {
+    Alloc { at: Cell, size: u32 },
+    Free { at: Cell, size: u32 },
+    Zero { at: Cell, size: u32 },
+    Set { at: Cell, value: usize },
+    SetString { at: Cell, text: String },
+    Copy { to: Cell, from: Cell, size: u32 },
+    Add { to: Cell, from: Cell, size: u32 },
+    Sub { to: Cell, from: Cell, size: u32 },
+    Refer { to: Cell, of: Cell },
+    /// Allocate cells on the heap. With a size, the cells are marked as used,
+    /// and without one, the number of cells is the number `to` holds.
+    HeapAlloc { to: Cell, size: Option<u32> },
+    LoopBegin(Cell),
+    LoopEnd(Cell),
+    IfBegin(Cell),
+    Else,
+    IfEnd,
+    Print { at: Cell, size: u32 },
+    PrintCStr(Cell),
+    Read(Cell),
+    /// Stop the program, with the exit status in `status`
+    Halt { at: Cell, status: Cell },
+    /// A name was given to a value
+    Bind { name: String, at: Cell },
+    FnBegin(String),
+    FnEnd(String),
+}
+
+impl fmt::Display for IrOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Alloc { at, size } => write!(f, "alloc {} size={}", at, size),
+            Self::Free { at, size } => write!(f, "free {} size={}", at, size),
+            Self::Zero { at, size } => write!(f, "zero {} size={}", at, size),
+            Self::Set { at, value } => write!(f, "set {} = {}", at, value),
+            Self::SetString { at, text } => write!(f, "set {} = {:?}", at, text),
+            Self::Copy { to, from, size } => write!(f, "copy {} <- {} size={}", to, from, size),
+            Self::Add { to, from, size } => write!(f, "add {} += {} size={}", to, from, size),
+            Self::Sub { to, from, size } => write!(f, "sub {} -= {} size={}", to, from, size),
+            Self::Refer { to, of } => write!(f, "refer {} = &{}", to, of),
+            Self::HeapAlloc { to, size: Some(size) } => write!(f, "heap_alloc {} size={}", to, size),
+            Self::HeapAlloc { to, size: None } => write!(f, "heap_alloc {}", to),
+            Self::LoopBegin(at) => write!(f, "loop_begin {}", at),
+            Self::LoopEnd(at) => write!(f, "loop_end {}", at),
+            Self::IfBegin(at) => write!(f, "if {}", at),
+            Self::Else => write!(f, "else"),
+            Self::IfEnd => write!(f, "end if"),
+            Self::Print { at, size } => write!(f, "print {} size={}", at, size),
+            Self::PrintCStr(at) => write!(f, "print_cstr {}", at),
+            Self::Read(at) => write!(f, "read {}", at),
+            Self::Halt { at, status } => write!(f, "halt {} status={}", at, status),
+            Self::Bind { name, at } => write!(f, "bind {} = {}", name, at),
+            Self::FnBegin(name) => write!(f, "fn {}", name),
+            Self::FnEnd(name) => write!(f, "end fn {}", name),
+        }
+    }
+}
+
+/// Print operations one per line, indented inside functions and loops
+pub fn pretty_ir(ops: &[IrOp]) -> String {
+    let mut result = String::new();
+    let mut depth: usize = 0;
+    for op in ops {
+        if let IrOp::LoopEnd(_) | IrOp::IfEnd | IrOp::Else | IrOp::FnEnd(_) = op {
+            depth = depth.saturating_sub(1);
+        }
+        result += &format!("{}{}\n", "    ".repeat(depth), op);
+        if let IrOp::LoopBegin(_) | IrOp::IfBegin(_) | IrOp::Else | IrOp::FnBegin(_) = op {
+            depth += 1;
+        }
+    }
+    result
+}
+
+/// Record the operations lowered from now on, or stop and return the ones recorded
+pub fn record_ir(enable: bool) -> Vec<IrOp> {
+    IR.replace(if enable { Some(vec![]) } else { None }).unwrap_or_default()
+}
+
+/// Record an operation, if operations are being recorded
+pub fn emit_ir(op: IrOp) {
+    IR.with_borrow_mut(|ir| {
+        if let Some(ops) = ir.as_mut() {
+            ops.push(op);
+        }
+    });
+}
+
+/// The number of operations recorded so far, or None if they aren't being recorded
+pub(crate) fn ir_len() -> Option<usize> {
+    IR.with_borrow(|ir| ir.as_ref().map(Vec::len))
+}
+
+/// The operations recorded after the first `start` operations
+pub(crate) fn ir_since(start: usize) -> Vec<IrOp> {
+    IR.with_borrow(|ir| ir.as_ref().map(|ops| ops[start..].to_vec()).unwrap_or_default())
+}
+
+/// Compile something without recording the operations it is lowered to,
+/// for code that is recorded as a single operation of its own
+fn without_ir<T>(f: impl FnOnce() -> T) -> T {
+    let recording = IR.with_borrow_mut(|ir| ir.take());
+    let result = f();
+    IR.set(recording);
+    result
+}
+
+/// The number of cells on the tape, unless another size is chosen
+pub const DEFAULT_TAPE_SIZE: u32 = 30000;
+
+/// How many of the largest allocations a tape overflow reports
+const REPORTED_ALLOCATIONS: usize = 3;
+
+/// A value allocated on the stack, with the function and statement it was allocated in
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Allocation {
+    pub cells: u32,
+    pub function: String,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} cells in `{}`", self.cells, self.function)?;
+        match self.span {
+            Some(span) => write!(f, " at {}", span),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Remember an allocation if it is one of the largest so far
+fn record_allocation(cells: u32) {
+    LARGEST_ALLOCATIONS.with_borrow_mut(|largest| {
+        if largest.len() == REPORTED_ALLOCATIONS && largest.last().is_some_and(|smallest| smallest.cells >= cells) {
+            return;
+        }
+        let allocation = Allocation {
+            cells,
+            function: current_function(),
+            span: location(),
+        };
+        let i = largest.iter().position(|other| other.cells < cells).unwrap_or(largest.len());
+        largest.insert(i, allocation);
+        largest.truncate(REPORTED_ALLOCATIONS);
+    });
+}
+
+/// Make sure the stack, with the heap after it, still fits on the tape
+fn check_tape(stack_ptr: u32) -> Result<(), Error> {
+    let limit = TAPE_SIZE.get();
+    let needed = stack_ptr + HEAP_SIZE.get();
+    if needed > limit {
+        Err(Error::TapeOverflow {
+            needed,
+            limit,
+            function: current_function(),
+            largest: LARGEST_ALLOCATIONS.get(),
+        })
     } else {
         Ok(())
     }
 }
 
+/// The characters in the compiled output that are instructions
+pub const INSTRUCTIONS: [char; 11] = ['>', '<', ',', '.', '[', ']', '+', '-', '*', '?', '&'];
+
+
+
+pub fn increment_stack(allocation_size: u32) -> Result<(), Error> {
+    let stack_ptr = STACK_PTR.get() + allocation_size;
+    STACK_PTR.set(stack_ptr);
+    PEAK_STACK_PTR.set(PEAK_STACK_PTR.get().max(stack_ptr));
+    check_tape(stack_ptr)
+}
+
 pub fn set_stack(stack_size: u32) -> Result<(), Error> {
-    let mut stack_ptr = STACK_PTR.lock().unwrap();
-    *stack_ptr = stack_size;
-    if *stack_ptr > *STACK_SIZE.lock().unwrap() {
-        Err(Error::StackOverflow)
+    STACK_PTR.set(stack_size);
+
+    // Cells above the new STACK_PTR are no longer holes, they are free stack
+    HOLES.with_borrow_mut(|holes| {
+        for (offset, size) in mem::take(holes).iter() {
+            if offset < stack_size {
+                holes.insert(offset, size.min(stack_size - offset));
+            }
+        }
+    });
+    check_tape(stack_size)
+}
+
+/// Start a call's frame with no holes, so the values of the call are
+/// allocated above the caller's, and return the caller's holes
+pub(crate) fn take_holes() -> Holes {
+    HOLES.take()
+}
+
+/// Give the caller back its holes once the stack is set back to where the
+/// call's frame started. The arguments may have been allocated in the caller's
+/// holes, and the holes they leave when they are freed are kept too.
+pub(crate) fn restore_holes(holes: Holes) {
+    HOLES.with_borrow_mut(|current| {
+        for (offset, size) in holes.iter() {
+            current.insert(offset, size);
+        }
+    });
+}
+
+/// Give cells back to the stack. If they are at the top of the stack, the
+/// STACK_PTR is moved down, otherwise they are remembered as a hole. Cells
+/// that are already free, or that are above the STACK_PTR, are ignored.
+pub fn release_cells(offset: u32, size: u32) {
+    let mut stack_ptr = STACK_PTR.get();
+    HOLES.with_borrow_mut(|holes| {
+        if size == 0 || offset + size > stack_ptr || holes.overlaps(offset, size) {
+            return;
+        }
+
+        holes.insert(offset, size);
+        while let Some(offset) = holes.remove_ending_at(stack_ptr) {
+            stack_ptr = offset;
+        }
+    });
+    STACK_PTR.set(stack_ptr);
+}
+
+/// The holes in the stack, kept in a free list for each size
+#[derive(Clone, Debug, Default, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct Holes(BTreeMap<u32, Vec<u32>>);
+
+impl Holes {
+    /// Every hole, as (offset, size)
+    fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.0
+            .iter()
+            .flat_map(|(&size, offsets)| offsets.iter().map(move |&offset| (offset, size)))
+    }
+
+    fn insert(&mut self, offset: u32, size: u32) {
+        self.0.entry(size).or_default().push(offset);
+    }
+
+    /// Take the hole freed last of the smallest size that fits a value of
+    /// this size. A larger hole is only split when none of the size is free.
+    fn take(&mut self, size: u32) -> Option<u32> {
+        let (&hole_size, offsets) = self.0.range_mut(size..).next()?;
+        let offset = offsets.pop()?;
+        if offsets.is_empty() {
+            self.0.remove(&hole_size);
+        }
+        if hole_size > size {
+            self.insert(offset + size, hole_size - size);
+        }
+        Some(offset)
+    }
+
+    fn overlaps(&self, offset: u32, size: u32) -> bool {
+        self.iter()
+            .any(|(hole, hole_size)| offset < hole + hole_size && hole < offset + size)
+    }
+
+    /// Remove the hole that ends at this cell, and return where it started
+    fn remove_ending_at(&mut self, end: u32) -> Option<u32> {
+        let (offset, size) = self.iter().find(|&(offset, size)| offset + size == end)?;
+        let offsets = self.0.get_mut(&size)?;
+        offsets.retain(|&hole| hole != offset);
+        if offsets.is_empty() {
+            self.0.remove(&size);
+        }
+        Some(offset)
+    }
+}
+
+/// Are `size` cells starting at this one known to be zero? Cells reached
+/// through a pointer never are.
+fn known_zero(at: Cell, size: u32) -> bool {
+    at.depth == 0
+        && ZERO_CELLS.with_borrow(|zero_cells| (at.offset..at.offset + size).all(|offset| zero_cells.contains(&offset)))
+}
+
+/// Remember that code was just emitted to clear `size` cells starting at this one
+fn mark_zero(at: Cell, size: u32) {
+    if at.depth == 0 {
+        ZERO_CELLS.with_borrow_mut(|zero_cells| zero_cells.extend(at.offset..at.offset + size));
+    }
+}
+
+/// Remember that code was just emitted that can change `size` cells starting
+/// at this one. A pointer can point at any cell on the stack, so writing through
+/// one forgets every cell.
+fn mark_written(at: Cell, size: u32) {
+    ZERO_CELLS.with_borrow_mut(|zero_cells| {
+        if at.depth == 0 {
+            for offset in at.offset..at.offset + size {
+                zero_cells.remove(&offset);
+            }
+        } else {
+            zero_cells.clear();
+        }
+    });
+}
+
+/// Forget which cells are known to be zero, before code that may run
+/// after code other than what was compiled right before it
+pub(crate) fn forget_zero_cells() {
+    ZERO_CELLS.with_borrow_mut(|zero_cells| zero_cells.clear());
+}
+
+/// Forget the code compiled on this thread and every value on the tape
+/// but the registers, which are cleared like they were the first time
+pub fn reset_tape() {
+    let registers = [&RETURN, &TEMP0, &TEMP1, &TEMP2, &TEMP3, &TEMP4, &TEMP5, &TEMP6].map(|register| register.get());
+    let end = registers.iter().map(|register| register.offset + register.size()).max().unwrap_or(0);
+
+    COMPILED.with_borrow_mut(|compiled| compiled.clear());
+    CAPTURES.with_borrow_mut(|captures| captures.clear());
+    CONTROL_REGISTERS.with_borrow_mut(|control_registers| control_registers.clear());
+    INSTRUCTION_COUNT.set(0);
+    ALLOCATION_COUNT.set(0);
+    IR.set(None);
+    HOLES.set(Holes::default());
+    ZERO_CELLS.with_borrow_mut(|zero_cells| zero_cells.clear());
+    STACK_PTR.set(end);
+    PEAK_STACK_PTR.set(end);
+    TAPE_SIZE.set(DEFAULT_TAPE_SIZE);
+    HEAP_SIZE.set(2048);
+    LARGEST_ALLOCATIONS.with_borrow_mut(|largest_allocations| largest_allocations.clear());
+    for register in &registers {
+        add_to_compiled(register.cell().zero_code(register.size()));
+    }
+}
+
+/// Take the code compiled since it was last taken,
+/// unless it is in a loop that hasn't been closed yet
+pub fn take_compiled() -> Option<String> {
+    if CONTROL_REGISTERS.with_borrow(|control_registers| control_registers.is_empty()) {
+        Some(COMPILED.take())
     } else {
-        Ok(())
+        None
     }
 }
 
 pub fn add_to_compiled(s: impl ToString) {
-    let mut c = COMPILED.lock().unwrap();
-    (*c) += &s.to_string();
+    let s = s.to_string();
+    let instructions = s.chars().filter(|ch| INSTRUCTIONS.contains(ch)).count();
+    INSTRUCTION_COUNT.with_borrow_mut(|count| *count += instructions);
+    CAPTURES.with_borrow_mut(|captures| {
+        if let Some(capture) = captures.last_mut() {
+            *capture += &s;
+        }
+    });
+    COMPILED.with_borrow_mut(|compiled| *compiled += &s);
+}
+
+/// Start keeping a copy of the code added from now on. The copy isn't
+/// affected by the compiled code being taken, and captures can be nested.
+pub(crate) fn start_capture() {
+    CAPTURES.with_borrow_mut(|captures| captures.push(String::new()));
+}
+
+/// Stop the innermost capture and return the code it kept. The code
+/// is part of the capture around it too, if there is one.
+pub(crate) fn end_capture() -> String {
+    CAPTURES.with_borrow_mut(|captures| {
+        let code = captures.pop().unwrap_or_default();
+        if let Some(outer) = captures.last_mut() {
+            *outer += &code;
+        }
+        code
+    })
+}
+
+/// Where values are allocated on the tape, which decides
+/// the code that the next values are compiled to
+#[derive(Clone, Debug, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct TapeState {
+    stack_ptr: u32,
+    peak_stack_ptr: u32,
+    holes: Holes,
+    zero_cells: BTreeSet<u32>,
+    tape_size: u32,
+    heap_size: u32,
+}
+
+impl TapeState {
+    /// Get the current state of the tape
+    pub(crate) fn get() -> Self {
+        Self {
+            stack_ptr: STACK_PTR.get(),
+            peak_stack_ptr: PEAK_STACK_PTR.get(),
+            holes: HOLES.get(),
+            zero_cells: ZERO_CELLS.get(),
+            tape_size: TAPE_SIZE.get(),
+            heap_size: HEAP_SIZE.get(),
+        }
+    }
+
+    /// Put the tape back in this state
+    pub(crate) fn restore(&self) {
+        STACK_PTR.set(self.stack_ptr);
+        PEAK_STACK_PTR.set(self.peak_stack_ptr);
+        HOLES.set(self.holes.clone());
+        ZERO_CELLS.set(self.zero_cells.clone());
+        TAPE_SIZE.set(self.tape_size);
+        HEAP_SIZE.set(self.heap_size);
+    }
+}
+
+/// Get the number of instructions emitted so far
+pub fn instruction_count() -> usize {
+    INSTRUCTION_COUNT.get()
+}
+
+/// The cell that holds the exit status after a program stops itself
+pub fn exit_status_cell() -> Cell {
+    RETURN.get().cell()
+}
+
+/// Get the number of values allocated so far
+pub fn allocation_count() -> usize {
+    ALLOCATION_COUNT.get()
+}
+
+/// Count values allocated by code that was compiled before, and is used again
+pub(crate) fn add_allocations(count: usize) {
+    ALLOCATION_COUNT.with_borrow_mut(|allocations| *allocations += count);
+}
+
+/// Get the highest value the STACK_PTR has reached since the last reset
+pub fn peak_stack_ptr() -> u32 {
+    PEAK_STACK_PTR.get()
+}
+
+/// Set the recorded peak of the STACK_PTR, and return the previous peak
+pub fn reset_peak_stack_ptr(peak: u32) -> u32 {
+    PEAK_STACK_PTR.replace(peak)
 }
 
 #[allow(unused_must_use)]
 pub fn init() {
     let f = |_: &Value| {};
-    f(&RETURN);
-    f(&TEMP0);
-    f(&TEMP1);
-    f(&TEMP2);
-    f(&TEMP3);
-    f(&TEMP4);
-    f(&TEMP5);
-    f(&TEMP6);
+    f(&RETURN.get());
+    f(&TEMP0.get());
+    f(&TEMP1.get());
+    f(&TEMP2.get());
+    f(&TEMP3.get());
+    f(&TEMP4.get());
+    f(&TEMP5.get());
+    f(&TEMP6.get());
 
     add_to_compiled(format!(
         "STARTING STACK PTR IS {} ",
-        *STACK_PTR.lock().unwrap()
+        STACK_PTR.get()
     ));
 }
 
 pub fn compile() -> String {
-    RETURN.free();
-    TEMP0.free();
-    TEMP1.free();
-    TEMP2.free();
-    TEMP3.free();
-    TEMP4.free();
-    TEMP5.free();
-    TEMP6.free();
+    RETURN.get().free();
+    TEMP0.get().free();
+    TEMP1.get().free();
+    TEMP2.get().free();
+    TEMP3.get().free();
+    TEMP4.get().free();
+    TEMP5.get().free();
+    TEMP6.get().free();
 
     add_to_compiled(format!(
         "FINAL STACK PTR IS {} ",
-        *STACK_PTR.lock().unwrap()
+        STACK_PTR.get()
     ));
 
-    COMPILED.lock().unwrap().clone()
+    COMPILED.get()
 }
 
 pub struct Control;
 impl Control {
+    /// Begin code that runs if the cell is not zero. The then branch is a loop
+    /// on a copy of the cell, and the else branch is a loop on a flag it clears.
     pub fn if_begin(var: Value) -> Result<(), Error> {
-        add_to_compiled("\nIF BEGIN\n");
-        TEMP5.assign(var)?;
-        TEMP6.assign(Eval::Literal(Literal::byte_int(1)).lower()?)?;
+        emit_ir(IrOp::IfBegin(var.cell()));
+        without_ir(|| {
+            add_to_compiled("\nIF BEGIN\n");
+            TEMP5.get().assign(var)?;
+            TEMP6.get().set(1usize);
 
-        // CONTROL_REGISTERS.lock().unwrap().push(var);
+            // CONTROL_REGISTERS.with_borrow_mut(|control_registers| control_registers.push(var));
 
-        Self::while_begin(*TEMP5);
-        add_to_compiled("\nTHEN CODE BEGIN\n");
-        Ok(())
+            Self::while_begin(TEMP5.get());
+            add_to_compiled("\nTHEN CODE BEGIN\n");
+            Ok(())
+        })
     }
 
+    /// End the then branch, and begin code that runs if the cell given to `if_begin` was zero
     pub fn else_begin() -> Result<(), Error> {
-        add_to_compiled("\nTHEN CODE END\n");
-        TEMP5.zero();
-        TEMP6.zero();
-        Self::while_end();
-        Self::while_begin(*TEMP6);
-        add_to_compiled("\nELSE CODE BEGIN\n");
-        // TEMP0.zero();
-        // let var = CONTROL_REGISTERS.lock().unwrap().pop().unwrap();
-        // TEMP1.assign(var)?;
-        // var.zero();
-        // add_to_compiled(var.to() + "]" + &var.from());
-        // var.assign(*TEMP1)?;
-        // TEMP1.zero();
+        emit_ir(IrOp::Else);
+        without_ir(|| {
+            add_to_compiled("\nTHEN CODE END\n");
+            TEMP5.get().zero();
+            TEMP6.get().zero();
+            Self::while_end();
+            Self::while_begin(TEMP6.get());
+            add_to_compiled("\nELSE CODE BEGIN\n");
+        });
         Ok(())
     }
 
+    /// End the else branch, which every if has, even when it is empty
     pub fn if_end() -> Result<(), Error> {
-        add_to_compiled("\nELSE CODE END\n");
-        TEMP6.zero();
-        Self::while_end();
-        // TEMP0.zero();
-        // let var = CONTROL_REGISTERS.lock().unwrap().pop().unwrap();
-        // TEMP1.assign(var)?;
-        // var.zero();
-        // add_to_compiled(var.to() + "]" + &var.from());
-        // var.assign(*TEMP1)?;
-        // TEMP1.zero();
+        emit_ir(IrOp::IfEnd);
+        without_ir(|| {
+            add_to_compiled("\nELSE CODE END\n");
+            TEMP6.get().zero();
+            Self::while_end();
+        });
         add_to_compiled("\nIF END\n");
         Ok(())
     }
 
     pub fn while_begin(var: Value) {
+        // The body runs again after itself, and after the code before the loop
+        forget_zero_cells();
+        emit_ir(IrOp::LoopBegin(var.cell()));
         add_to_compiled("\nWHILE BEGIN\n");
-        // TEMP0.zero();
-        CONTROL_REGISTERS.lock().unwrap().push(var);
-        add_to_compiled(var.to() + "[" + &var.from());
+        // TEMP0.get().zero();
+        CONTROL_REGISTERS.with_borrow_mut(|control_registers| control_registers.push(var));
+        add_to_compiled(var.cell().loop_begin_code());
         add_to_compiled("\nCODE BEGIN\n");
     }
 
     pub fn while_end() {
         add_to_compiled("\nCODE END\n");
-        let var = CONTROL_REGISTERS.lock().unwrap().pop().unwrap();
-        add_to_compiled(var.to() + "]" + &var.from());
+        let var = CONTROL_REGISTERS.with_borrow_mut(|control_registers| control_registers.pop().unwrap());
+        add_to_compiled(var.cell().loop_end_code());
         add_to_compiled("\nWHILE END\n");
+        emit_ir(IrOp::LoopEnd(var.cell()));
+        // The body may not have run at all, but the loop only ends on a zero
+        forget_zero_cells();
+        mark_zero(var.cell(), 1);
+    }
+
+    /// Print a message on its own line, and stop the program with an exit status of 1
+    pub fn abort(message: &str) -> Result<(), Error> {
+        let message = Value::string(message.to_string() + "\n")?;
+        Stdout::print(Value {
+            number_cells: message.size() - 1,
+            ..message
+        });
+        let status = Value::byte_int(1)?;
+        Self::halt(status)?;
+        status.discard();
+        message.discard();
+        Ok(())
+    }
+
+    /// Stop the program wherever this is reached, with an exit status
+    pub fn halt(status: Value) -> Result<(), Error> {
+        RETURN.get().assign(status)?;
+        emit_ir(IrOp::Halt {
+            at: TEMP6.get().cell(),
+            status: exit_status_cell(),
+        });
+        add_to_compiled("\nHALT\n");
+        add_to_compiled(TEMP6.get().cell().halt_code());
+        mark_written(TEMP6.get().cell(), 1);
+        Ok(())
     }
 }
 
@@ -153,35 +776,18 @@ impl Stdout {
     /// This prints a Value according to its size.
     /// Basically, this prints each cell that the Value owns.
     pub fn print(var: Value) {
-        let mut result = String::new();
-
-        result += &var.to();
-        for _ in 0..var.size() {
-            result += ".>";
-        }
-        for _ in 0..var.size() {
-            result += "<";
-        }
-        result += &var.from();
-
+        emit_ir(IrOp::Print { at: var.cell(), size: var.size() });
         add_to_compiled("\nPRINT CELL\n");
-        add_to_compiled(&result);
+        add_to_compiled(var.cell().print_code(var.size()));
         add_to_compiled("\nDONE\n");
     }
 
     /// This prints a pointer to a value like a CString
     /// This requires brainfuck compatibility mode to be disabled.
     pub fn print_cstr(var: Value) -> Result<(), Error> {
-        let mut result = String::new();
-
-        // Dereference value and print string
-        result += &var.to();
-        result += "*-[+.>-]+&";
-        // Refer back to home
-        result += &var.from();
-
+        emit_ir(IrOp::PrintCStr(var.cell()));
         add_to_compiled("\nPRINT CELL\n");
-        add_to_compiled(&result);
+        add_to_compiled(var.cell().print_cstr_code());
         add_to_compiled("\nDONE\n");
 
         if Program::brainfuck_enabled() {
@@ -192,15 +798,67 @@ impl Stdout {
     }
 }
 
+pub struct Stdin;
+impl Stdin {
+    /// This reads a character from the input into a single cell.
+    /// Reading leaves the cell unchanged when the input has ended,
+    /// so the cell is set to the EOF value first.
+    pub fn getc(var: Value) {
+        var.set(Program::eof_value());
+        emit_ir(IrOp::Read(var.cell()));
+        add_to_compiled("\nREAD CELL\n");
+        add_to_compiled(var.cell().read_code());
+        mark_written(var.cell(), 1);
+        add_to_compiled("\nDONE\n");
+    }
+}
+
+/// This describes what the cells of a Value are known to hold.
+/// Because free is typeless, most values (function returns,
+/// dereferenced cells) are `Unknown`, and only values that are
+/// certainly data or certainly references are marked as such.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ValueKind {
+    Data,
+    Reference,
+    Unknown,
+}
+
+impl ValueKind {
+    /// Combine the kinds of two values that could be stored in the same cells
+    pub fn merge(self, other: Self) -> Self {
+        if self == other {
+            self
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
 /// This object represents a value stored on the tape.
 /// Objects can be stored indirectly or directly.
 /// In brainfuck compatibility mode, dereferencing
 /// or referencing an object is illegal.
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Value {
     pub offset: u32,
     pub reference_depth: u32,
     pub number_cells: u32,
+    pub kind: ValueKind,
+    /// The struct these cells hold, or the struct a reference points to,
+    /// as an index into the table of struct definitions
+    pub layout: Option<usize>,
+    /// The cells this value points to, as (offset, size), when it is
+    /// known to be a reference to another value
+    pub referent: Option<(u32, u32)>,
+    /// If the cell holds a character rather than a number, so it is printed as one, this is set
+    pub character: bool,
+    /// If the cells are an array of bytes rather than a string, so there is no terminator, this is set
+    pub array: bool,
+    /// How the cells are read as a number
+    pub number: NumberKind,
 }
 
 /// This is for debugging.
@@ -218,59 +876,131 @@ impl fmt::Debug for Value {
 
 impl Value {
     pub fn new(size: u32) -> Result<Self, Error> {
+        // Reclaimed cells may hold old data, so every new value is zeroed,
+        // unless its cells are known to be zero already
+        let hole = if size > 0 { HOLES.with_borrow_mut(|holes| holes.take(size)) } else { None };
         let result = Self {
-            offset: *STACK_PTR.lock().unwrap(),
+            offset: hole.unwrap_or_else(|| STACK_PTR.get()),
             reference_depth: 0,
             number_cells: size,
+            kind: ValueKind::Unknown,
+            layout: None,
+            referent: None,
+            character: false,
+            array: false,
+            number: NumberKind::Unsigned,
         };
 
-        result.zero();
+        if !known_zero(result.cell(), size) {
+            emit_ir(IrOp::Alloc { at: result.cell(), size });
+            add_to_compiled(result.cell().zero_code(size));
+            mark_zero(result.cell(), size);
+        }
+        ALLOCATION_COUNT.with_borrow_mut(|count| *count += 1);
 
-        increment_stack(size)?;
+        if hole.is_none() {
+            record_allocation(size);
+            increment_stack(size)?;
+        }
         Ok(result)
     }
 
     pub fn alloc(size: u32) -> Result<Self, Error> {
+        // The size is counted out in a cell when the block is allocated
+        Self::check_fits_cell(size)?;
         let mut result = Self::new(1)?;
         result.number_cells = size;
+        result.kind = ValueKind::Reference;
 
+        emit_ir(IrOp::HeapAlloc { to: result.cell(), size: Some(size) });
         add_to_compiled(format!("\nALLOCATING {} CELLS\n", size));
-        add_to_compiled(result.to());
-        add_to_compiled("+".repeat(size as usize));
-        add_to_compiled("?*");
-        add_to_compiled("+>".repeat(size as usize));
-        add_to_compiled("&");
-        add_to_compiled(result.from());
+        add_to_compiled(result.cell().heap_alloc_code(size));
+        mark_written(result.cell(), 1);
         add_to_compiled("\nDONE\n");
 
         if Program::brainfuck_enabled() {
             Err(Error::CannotUsePointersInBrainFuckMode)
         } else {
+            result.check_allocated()?;
             Ok(result)
         }
     }
 
     pub fn variable_alloc(size: Self) -> Result<Self, Error> {
-        let result = Self::new(1)?;
+        let mut result = Self::new(1)?;
+        result.kind = ValueKind::Reference;
 
         result.assign(size)?;
 
+        emit_ir(IrOp::HeapAlloc { to: result.cell(), size: None });
         add_to_compiled("\nALLOCATING CELLS\n");
-        add_to_compiled(&result.to());
-        add_to_compiled("?");
-        add_to_compiled(&result.from());
+        add_to_compiled(result.cell().variable_heap_alloc_code());
+        mark_written(result.cell(), 1);
         add_to_compiled("\nDONE\n");
 
         if Program::brainfuck_enabled() {
             Err(Error::CannotUsePointersInBrainFuckMode)
         } else {
+            result.check_allocated()?;
             Ok(result)
         }
     }
 
+    /// Stop the program with a message if an allocation found no room on the heap,
+    /// which leaves the pointer to the cells zero
+    fn check_allocated(&self) -> Result<(), Error> {
+        let failed = Self::new(1)?;
+        self.when_zero(|| failed.set(1usize))?;
+        Control::if_begin(failed)?;
+        Control::abort("OUT OF MEMORY")?;
+        Control::else_begin()?;
+        Control::if_end()?;
+        failed.discard();
+        Ok(())
+    }
+
+    /// Give the cells a pointer from `alloc` points to back to the heap, so they
+    /// can be allocated again. The cell before a block holds its size, which is
+    /// zeroed with the block, so freeing a block twice stops the program with a message,
+    /// unless the block was allocated again in between.
+    pub fn heap_free(&self) -> Result<(), Error> {
+        let one = Self::byte_int(1)?;
+        let ptr = Self::new(1)?;
+        ptr.assign(Value { number_cells: 1, ..*self })?;
+        ptr.minus_eq(one);
+        let header = ptr.deref()?;
+        let size = Self::new(1)?;
+        size.assign(header)?;
+
+        let freed = Self::new(1)?;
+        size.when_zero(|| freed.set(1usize))?;
+        Control::if_begin(freed)?;
+        Control::abort("DOUBLE FREE")?;
+        Control::else_begin()?;
+        Control::if_end()?;
+
+        header.zero();
+        ptr.plus_eq(one);
+        Control::while_begin(size);
+        ptr.deref()?.zero();
+        ptr.plus_eq(one);
+        size.minus_eq(one);
+        Control::while_end();
+        for scratch in [freed, size, ptr, one] {
+            scratch.discard();
+        }
+        Ok(())
+    }
+
     pub fn copy(&self) -> Result<Self, Error> {
-        let val = Self::new(self.number_cells)?;
+        let mut val = Self::new(self.number_cells)?;
         val.assign(*self)?;
+        val.kind = self.kind;
+        val.layout = self.layout;
+        val.referent = self.referent;
+        val.character = self.character;
+        val.array = self.array;
+        val.number = self.number;
         Ok(val)
     }
 
@@ -278,196 +1008,252 @@ impl Value {
         self.reference_depth > 0
     }
 
-    pub fn zero(&self) {
-        add_to_compiled(self.to());
-        for _ in 0..self.number_cells {
-            add_to_compiled("[-]>");
+    /// Is this value certainly not a reference?
+    pub fn is_data(&self) -> bool {
+        self.kind == ValueKind::Data
+    }
+
+    /// Is this value known to point into the cells of another value?
+    pub fn refers_to(&self, other: &Self) -> bool {
+        match self.referent {
+            Some((offset, size)) => {
+                !other.is_ref()
+                    && offset < other.offset + other.size().max(1)
+                    && other.offset < offset + size.max(1)
+            }
+            None => false,
         }
-        for _ in 0..self.number_cells {
-            add_to_compiled("<");
+    }
+
+    /// Do these two values occupy the same cells?
+    pub fn same_cells(&self, other: &Self) -> bool {
+        self.offset == other.offset
+            && self.reference_depth == other.reference_depth
+            && self.number_cells == other.number_cells
+    }
+
+    pub fn zero(&self) {
+        if known_zero(self.cell(), self.size()) {
+            return;
         }
-        add_to_compiled(self.from());
+        emit_ir(IrOp::Zero { at: self.cell(), size: self.size() });
+        add_to_compiled(self.cell().zero_code(self.size()));
+        mark_zero(self.cell(), self.size());
     }
 
     pub fn free(&self) {
-        add_to_compiled(&format!(
+        if known_zero(self.cell(), self.size()) {
+            return;
+        }
+        emit_ir(IrOp::Free { at: self.cell(), size: self.size() });
+        add_to_compiled(format!(
             "\nFREEING CELLS {}~{}\n",
             self.offset,
             self.offset + self.size()
         ));
-        add_to_compiled(&self.to());
-
-        for _ in 0..self.size() {
-            add_to_compiled("[-]>");
-        }
+        add_to_compiled(self.cell().zero_code(self.size()));
+        add_to_compiled("\nDONE\n");
+        mark_zero(self.cell(), self.size());
+    }
 
-        for _ in 0..self.size() {
-            add_to_compiled("<");
+    /// Free this value's cells and let later values be allocated in them.
+    /// A pointer made by `alloc` only has its first cell on the stack, so
+    /// only that cell of a pointer is given back.
+    pub fn discard(&self) {
+        if !self.is_ref() {
+            self.free();
+            let cells = if self.kind == ValueKind::Reference { 1 } else { self.number_cells };
+            release_cells(self.offset, cells);
         }
-
-        add_to_compiled(self.from());
-
-        add_to_compiled("\nDONE\n");
     }
 
     pub fn set(&self, val: impl Into<usize>) {
-        add_to_compiled(&self.to());
-        add_to_compiled("[-]");
-        add_to_compiled("+".repeat(val.into()));
-        add_to_compiled(self.from());
+        let value = val.into();
+        emit_ir(IrOp::Set { at: self.cell(), value });
+        add_to_compiled(self.cell().set_code(value));
+        mark_written(self.cell(), 1);
     }
 
     pub fn assign(&self, val: Self) -> Result<(), Error> {
-        if val == *self {
+        if val.same_cells(self) {
             return Ok(());
         }
+        // Different pointers can point to the same cell, which would be
+        // cleared before it is copied, so the value is copied out first
+        if self.is_ref() && val.is_ref() {
+            return self.assign(val.copy()?);
+        }
 
         if Program::brainfuck_enabled() && val.size() > self.size() {
             return Err(Error::CannotAssignLargerValueToSmallerValueInBrainFuckMode);
         } else if Program::size_warn_enabled() && val.size() > self.size() {
-            eprintln!("Warning: assigning larger value to smaller value");
+            warn("assigning larger value to smaller value");
         }
 
-        TEMP0.zero();
-
-        for cell in 0..val.size() {
-            let cell_to = val.to() + &">".repeat(cell as usize);
-            let cell_from = "<".repeat(cell as usize) + &val.from();
-            let this_to = self.to() + &">".repeat(cell as usize);
-            let this_from = "<".repeat(cell as usize) + &self.from();
-            add_to_compiled(this_to.clone() + "[-]" + &this_from);
-            add_to_compiled(
-                cell_to.clone()
-                    + "["
-                    + &cell_from
-                    + &this_to
-                    + "+"
-                    + &this_from
-                    + &TEMP0.to()
-                    + "+"
-                    + &TEMP0.from()
-                    + &cell_to
-                    + "-"
-                    + &cell_from
-                    + &cell_to
-                    + "]"
-                    + &cell_from,
-            );
-            add_to_compiled(
-                TEMP0.to()
-                    + "["
-                    + &TEMP0.from()
-                    + &cell_to
-                    + "+"
-                    + &cell_from
-                    + &TEMP0.to()
-                    + "-"
-                    + &TEMP0.from()
-                    + &TEMP0.to()
-                    + "]"
-                    + &TEMP0.from(),
-            );
+        // Cells that are already zero can be added to instead of cleared first
+        if known_zero(self.cell(), val.size()) {
+            emit_ir(IrOp::Add { to: self.cell(), from: val.cell(), size: val.size() });
+            add_to_compiled(self.cell().transfer_code(val.cell(), val.size(), '+', false));
+        } else {
+            emit_ir(IrOp::Copy { to: self.cell(), from: val.cell(), size: val.size() });
+            add_to_compiled(self.cell().transfer_code(val.cell(), val.size(), '+', true));
         }
+        mark_written(self.cell(), val.size());
         Ok(())
     }
 
     pub fn plus_eq(&self, val: Self) {
-        TEMP0.zero();
+        emit_ir(IrOp::Add { to: self.cell(), from: val.cell(), size: val.size() });
+        add_to_compiled(self.cell().transfer_code(val.cell(), val.size(), '+', false));
+        mark_written(self.cell(), val.size());
+    }
 
-        for cell in 0..val.size() {
-            let cell_to = val.to() + &">".repeat(cell as usize);
-            let cell_from = "<".repeat(cell as usize) + &val.from();
-            let this_to = self.to() + &">".repeat(cell as usize);
-            let this_from = "<".repeat(cell as usize) + &self.from();
-            add_to_compiled(
-                cell_to.clone()
-                    + "["
-                    + &cell_from
-                    + &this_to
-                    + "+"
-                    + &this_from
-                    + &TEMP0.to()
-                    + "+"
-                    + &TEMP0.from()
-                    + &cell_to
-                    + "-"
-                    + &cell_from
-                    + &cell_to
-                    + "]"
-                    + &cell_from,
-            );
-            add_to_compiled(
-                TEMP0.to()
-                    + "["
-                    + &TEMP0.from()
-                    + &cell_to
-                    + "+"
-                    + &cell_from
-                    + &TEMP0.to()
-                    + "-"
-                    + &TEMP0.from()
-                    + &TEMP0.to()
-                    + "]"
-                    + &TEMP0.from(),
-            );
+    pub fn minus_eq(&self, val: Self) {
+        emit_ir(IrOp::Sub { to: self.cell(), from: val.cell(), size: val.size() });
+        add_to_compiled(self.cell().transfer_code(val.cell(), val.size(), '-', false));
+        mark_written(self.cell(), val.size());
+    }
+
+    /// Add a value one at a time, stopping the program with a message
+    /// if this goes past the largest value a cell holds
+    pub fn checked_plus_eq(&self, val: Self, function: &str) -> Result<(), Error> {
+        self.checked_transfer(val, true, function)
+    }
+
+    /// Subtract a value one at a time, stopping the program with a message if this goes below zero
+    pub fn checked_minus_eq(&self, val: Self, function: &str) -> Result<(), Error> {
+        self.checked_transfer(val, false, function)
+    }
+
+    fn checked_transfer(&self, val: Self, add: bool, function: &str) -> Result<(), Error> {
+        let one = Self::byte_int(1)?;
+        let count = Self::new(1)?;
+        count.assign(val)?;
+        let overflowed = Self::new(1)?;
+
+        Control::while_begin(count);
+        // Subtracting from zero goes below it, and adding to the largest value wraps to zero
+        if !add {
+            self.when_zero(|| overflowed.set(1usize))?;
+            self.minus_eq(one);
+        } else {
+            self.plus_eq(one);
+            self.when_zero(|| overflowed.set(1usize))?;
         }
+        count.minus_eq(one);
+        Control::while_end();
+
+        Control::if_begin(overflowed)?;
+        Control::abort(&format!("OVERFLOW in {}", function))?;
+        Control::else_begin()?;
+        Control::if_end()?;
+        for scratch in [overflowed, count, one] {
+            scratch.discard();
+        }
+        Ok(())
     }
 
-    pub fn minus_eq(&self, val: Self) {
-        TEMP0.zero();
+    /// Stop the program with a message if this index isn't less than a length,
+    /// naming the variable being indexed if there is one
+    pub fn check_index(&self, length: u32, name: Option<&str>) -> Result<(), Error> {
+        // The length is counted down in a cell
+        Self::check_fits_cell(length)?;
+        let one = Self::byte_int(1)?;
+        let count = Self::new(1)?;
+        count.assign(Value { number_cells: 1, ..*self })?;
+        let left = Self::new(1)?;
+        left.set(length as usize);
+        let out_of_range = Self::new(1)?;
+        if length == 0 {
+            out_of_range.set(1usize);
+        }
 
-        for cell in 0..val.size() {
-            let cell_to = val.to() + &">".repeat(cell as usize);
-            let cell_from = "<".repeat(cell as usize) + &val.from();
-            let this_to = self.to() + &">".repeat(cell as usize);
-            let this_from = "<".repeat(cell as usize) + &self.from();
-            add_to_compiled(
-                cell_to.clone()
-                    + "["
-                    + &cell_from
-                    + &this_to
-                    + "-"
-                    + &this_from
-                    + &TEMP0.to()
-                    + "+"
-                    + &TEMP0.from()
-                    + &cell_to
-                    + "-"
-                    + &cell_from
-                    + &cell_to
-                    + "]"
-                    + &cell_from,
-            );
-            add_to_compiled(
-                TEMP0.to()
-                    + "["
-                    + &TEMP0.from()
-                    + &cell_to
-                    + "+"
-                    + &cell_from
-                    + &TEMP0.to()
-                    + "-"
-                    + &TEMP0.from()
-                    + &TEMP0.to()
-                    + "]"
-                    + &TEMP0.from(),
-            );
+        // Count the length down once for every step of the index; running out means it's too big
+        Control::while_begin(count);
+        left.minus_eq(one);
+        left.when_zero(|| out_of_range.set(1usize))?;
+        count.minus_eq(one);
+        Control::while_end();
+
+        Control::if_begin(out_of_range)?;
+        match name {
+            Some(name) => Control::abort(&format!("INDEX OUT OF RANGE in {}", name))?,
+            None => Control::abort("INDEX OUT OF RANGE")?,
+        }
+        Control::else_begin()?;
+        Control::if_end()?;
+        for scratch in [out_of_range, left, count, one] {
+            scratch.discard();
+        }
+        Ok(())
+    }
+
+    /// Subtract a value one at a time, stopping at zero instead of wrapping around
+    pub fn saturating_minus_eq(&self, val: Self) -> Result<(), Error> {
+        let one = Self::byte_int(1)?;
+        let count = Self::new(1)?;
+        count.assign(val)?;
+        let nonzero = Self::new(1)?;
+
+        Control::while_begin(count);
+        nonzero.assign(Value { number_cells: 1, ..*self })?;
+        Control::while_begin(nonzero);
+        self.minus_eq(one);
+        nonzero.zero();
+        Control::while_end();
+        count.minus_eq(one);
+        Control::while_end();
+        for scratch in [nonzero, count, one] {
+            scratch.discard();
         }
+        Ok(())
+    }
+
+    /// Run the code from `body` if this value's first cell is zero
+    pub(crate) fn when_zero(&self, body: impl FnOnce()) -> Result<(), Error> {
+        let is_zero = Self::byte_int(1)?;
+        let test = Self::new(1)?;
+        test.assign(Value { number_cells: 1, ..*self })?;
+        Control::while_begin(test);
+        is_zero.zero();
+        test.zero();
+        Control::while_end();
+
+        Control::while_begin(is_zero);
+        body();
+        is_zero.zero();
+        Control::while_end();
+        test.discard();
+        is_zero.discard();
+        Ok(())
     }
 
     pub fn byte_int(value: u8) -> Result<Self, Error> {
-        let result = Self::new(1)?;
-        add_to_compiled(result.to());
-        add_to_compiled("+".repeat(value as usize));
-        add_to_compiled(result.from());
+        let mut result = Self::new(1)?;
+        result.kind = ValueKind::Data;
+        emit_ir(IrOp::Set { at: result.cell(), value: value as usize });
+        add_to_compiled(result.cell().add_code(value as usize));
+        mark_written(result.cell(), 1);
         Ok(result)
     }
 
+    /// Return an error if a number cannot fit in a single cell
+    fn check_fits_cell(value: u32) -> Result<(), Error> {
+        let width = Program::cell_width();
+        if value > width.max_value() {
+            Err(Error::ValueTooLargeForCell(value, width))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn unsigned_short(value: u16) -> Result<Self, Error> {
-        let result = Self::new(1)?;
-        add_to_compiled(result.to());
-        add_to_compiled("+".repeat(value as usize));
-        add_to_compiled(result.from());
+        Self::check_fits_cell(value as u32)?;
+        let mut result = Self::new(1)?;
+        result.kind = ValueKind::Data;
+        emit_ir(IrOp::Set { at: result.cell(), value: value as usize });
+        add_to_compiled(result.cell().add_code(value as usize));
+        mark_written(result.cell(), 1);
 
         if Program::brainfuck_enabled() {
             Err(Error::CannotUseUnsignedShortsInBrainFuckMode)
@@ -476,48 +1262,121 @@ impl Value {
         }
     }
 
+    /// Store an unsigned 4 byte number in eight cells of four bits each, least significant first
+    pub fn unsigned_4byte_int(value: u32) -> Result<Self, Error> {
+        Self::four_byte_int(value, NumberKind::Unsigned4Byte)
+    }
+
+    /// Store a signed 4 byte number like an unsigned one, in two's complement
+    pub fn signed_4byte_int(value: i32) -> Result<Self, Error> {
+        Self::four_byte_int(value as u32, NumberKind::Signed4Byte)
+    }
+
+    /// Store a fixed point number as the `i32` counting its 65536ths,
+    /// rounded to the nearest one
+    pub fn fixed(value: f64) -> Result<Self, Error> {
+        Self::four_byte_int((value * 65536.0).round() as i32 as u32, NumberKind::Fixed)
+    }
+
+    fn four_byte_int(bits: u32, number: NumberKind) -> Result<Self, Error> {
+        let mut result = Self::new(WIDE_DIGITS)?;
+        result.kind = ValueKind::Data;
+        result.number = number;
+        for i in 0..WIDE_DIGITS {
+            let digit = (bits >> (i * DIGIT_BITS)) % DIGIT_BASE;
+            let cell = result.cells(i, 1).cell();
+            emit_ir(IrOp::Set { at: cell, value: digit as usize });
+            add_to_compiled(cell.add_code(digit as usize));
+            mark_written(cell, 1);
+        }
+        Ok(result)
+    }
+
     pub fn character(value: char) -> Result<Self, Error> {
-        let result = Self::new(1)?;
-        add_to_compiled(result.to());
-        add_to_compiled("+".repeat(value as usize));
-        add_to_compiled(result.from());
+        Self::check_fits_cell(value as u32)?;
+        let mut result = Self::new(1)?;
+        result.kind = ValueKind::Data;
+        result.character = true;
+        emit_ir(IrOp::Set { at: result.cell(), value: value as usize });
+        add_to_compiled(result.cell().add_code(value as usize));
+        mark_written(result.cell(), 1);
         Ok(result)
     }
 
     pub fn string(value: impl ToString) -> Result<Self, Error> {
-        let result = Self::new((value.to_string().len() + 1) as u32)?;
-
-        add_to_compiled(result.to());
-        for ch in value.to_string().chars() {
-            add_to_compiled("+".repeat(ch as usize) + ">");
-        }
-        add_to_compiled("+");
-        for _ in value.to_string().chars() {
-            add_to_compiled("<");
+        // Each byte of the string is a cell, so without UTF-8 strings only ASCII strings are stored
+        if !value.to_string().is_ascii() && !Program::utf8_strings_enabled() {
+            return Err(Error::NonAsciiString(value.to_string()));
         }
-        add_to_compiled(result.from());
+        let mut result = Self::new((value.to_string().len() + 1) as u32)?;
+        result.kind = ValueKind::Data;
+        emit_ir(IrOp::SetString { at: result.cell(), text: value.to_string() });
+        add_to_compiled(result.cell().string_code(&value.to_string()));
+        mark_written(result.cell(), result.size());
 
         Ok(result)
     }
 
+    /// Store bytes in cells, one byte to a cell, as an array without a terminator
+    pub fn bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut result = Self::new(bytes.len() as u32)?;
+        result.kind = ValueKind::Data;
+        result.array = true;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let cell = result.cells(i as u32, 1).cell();
+            emit_ir(IrOp::Set { at: cell, value: byte as usize });
+            add_to_compiled(cell.add_code(byte as usize));
+            mark_written(cell, 1);
+        }
+        Ok(result)
+    }
+
     pub fn to(&self) -> String {
-        ">".repeat(self.offset as usize).to_string() + &"*".repeat(self.reference_depth as usize)
+        self.cell().to()
     }
 
     pub fn from(&self) -> String {
-        "&".repeat(self.reference_depth as usize) + &"<".repeat(self.offset as usize).to_string()
+        self.cell().from()
+    }
+
+    /// The first cell of this value
+    pub fn cell(&self) -> Cell {
+        Cell {
+            offset: self.offset,
+            depth: self.reference_depth,
+        }
     }
 
     pub fn size(&self) -> u32 {
         self.number_cells
     }
 
-    pub fn deref(&self) -> Result<Self, Error> {
-        let mut result = Self::new(1)?;
-        // result.number_cells = self.size();
+    /// Get `count` cells of this value, starting at the cell `start`
+    pub fn cells(&self, start: u32, count: u32) -> Self {
+        Self {
+            offset: self.offset + start,
+            number_cells: count,
+            layout: None,
+            referent: None,
+            array: false,
+            number: NumberKind::Unsigned,
+            ..*self
+        }
+    }
 
-        result.reference_depth = self.reference_depth + 1;
-        result.offset = self.offset;
+    pub fn deref(&self) -> Result<Self, Error> {
+        // The cell pointed to is reached through this one, so nothing is allocated
+        let result = Self {
+            reference_depth: self.reference_depth + 1,
+            number_cells: 1,
+            kind: ValueKind::Unknown,
+            layout: None,
+            referent: None,
+            character: false,
+            array: false,
+            number: NumberKind::Unsigned,
+            ..*self
+        };
 
         if Program::brainfuck_enabled() {
             Err(Error::CannotUsePointersInBrainFuckMode)
@@ -527,11 +1386,19 @@ impl Value {
     }
 
     pub fn refer(&self) -> Result<Self, Error> {
-        let result = Self::new(1)?;
+        Self::check_fits_cell(self.offset)?;
+        let mut result = Self::new(1)?;
+        result.kind = ValueKind::Reference;
+        result.layout = self.layout;
+        if !self.is_ref() {
+            result.referent = Some((self.offset, self.number_cells));
+        }
 
-        add_to_compiled(result.to());
-        add_to_compiled("+".repeat(self.offset as usize));
-        add_to_compiled(result.from());
+        emit_ir(IrOp::Refer { to: result.cell(), of: self.cell() });
+        add_to_compiled(result.cell().add_code(self.offset as usize));
+        mark_written(result.cell(), 1);
+        // The cells can be changed through the reference from now on
+        mark_written(self.cell(), self.size());
 
         if Program::brainfuck_enabled() {
             Err(Error::CannotUsePointersInBrainFuckMode)
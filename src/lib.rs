@@ -1,13 +1,31 @@
 #[macro_use]
-extern crate lazy_static;
+mod state;
 
+pub mod backend;
+pub use backend::*;
 pub mod compile;
 pub use compile::*;
 pub mod env;
 pub use env::*;
+pub mod interp;
+pub use interp::*;
 pub mod ir;
 pub use ir::*;
+pub mod lint;
+pub use lint::*;
+pub mod macros;
+pub mod number;
+pub use number::*;
+pub mod opt;
+pub use opt::*;
+#[allow(clippy::all, unused_parens, unused_variables)]
 pub mod parser;
 pub use parser::*;
+pub mod runner;
 pub mod simplify;
 pub use simplify::*;
+pub mod source;
+pub use source::*;
+pub mod stdlib;
+pub mod visit;
+pub use visit::*;
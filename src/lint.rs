@@ -0,0 +1,148 @@
+use crate::{
+    reachable_functions, walk_eval, walk_expr, Assign, Define, Destructure, Eval, Expr, FnAttr, Load, Program, Span, UserFn, Visit,
+    Warning,
+};
+
+/// The lints that `#![allow(...)]` can turn off
+pub const LINTS: [&str; 2] = ["unused", "shadowing"];
+
+/// Look for suspicious code in the functions of a program
+pub fn lint(funs: &[UserFn], globals: &[Define]) -> Vec<Warning> {
+    let mut warnings = vec![];
+    for fun in funs {
+        warnings.extend(unused_variables(fun));
+        warnings.extend(shadowed_variables(fun, globals));
+    }
+    warnings.extend(unused_functions(funs));
+    warnings
+}
+
+/// Find the functions that can't be reached from the entry point. Functions
+/// marked used with `Program::mark_used` or `#[keep]` are left out, and so is every
+/// function in a library, since a later program may call any of them.
+pub fn unused_functions(funs: &[UserFn]) -> Vec<Warning> {
+    if Program::library_mode() {
+        return vec![];
+    }
+
+    let mut reachable = reachable_functions(funs, &Program::entry_point());
+    for fun in funs.iter().filter(|f| Program::marked_used(&f.name) || f.has_attribute(FnAttr::Keep)) {
+        reachable.extend(reachable_functions(funs, &fun.name));
+    }
+    funs.iter()
+        .filter(|f| !reachable.contains(&f.name) && !f.name.starts_with('_'))
+        .map(|f| Warning::UnusedFunction {
+            name: f.name.clone(),
+            span: f.span,
+        })
+        .collect()
+}
+
+/// Find the variables and parameters of a function that it never uses.
+/// Temporaries, and names that start with an underscore, are left out.
+pub fn unused_variables(fun: &UserFn) -> Vec<Warning> {
+    let Uses { declared, used, .. } = Uses::of(fun);
+    declared
+        .into_iter()
+        .filter(|(name, _)| !ignored(name) && !used.contains(name))
+        .map(|(name, span)| Warning::UnusedVariable {
+            name,
+            function: fun.name.clone(),
+            span,
+        })
+        .collect()
+}
+
+/// Find the variables and parameters of a function that hide a global of
+/// the same name, and the variables a function defines more than once.
+/// Temporaries, and names that start with an underscore, are left out.
+pub fn shadowed_variables(fun: &UserFn, globals: &[Define]) -> Vec<Warning> {
+    let Uses { declared, redeclared, .. } = Uses::of(fun);
+    let shadowed = declared
+        .into_iter()
+        .filter(|(name, _)| !ignored(name) && globals.iter().any(|Define(global, _)| global == name))
+        .map(|(name, span)| Warning::ShadowedVariable {
+            name,
+            function: fun.name.clone(),
+            // Globals aren't statements, so they have no span
+            original_span: None,
+            shadow_span: span,
+        });
+    let redefined = redeclared
+        .into_iter()
+        .filter(|(name, _, _)| !ignored(name))
+        .map(|(name, original_span, span)| Warning::Redefinition {
+            name,
+            function: fun.name.clone(),
+            original_span,
+            span,
+        });
+    shadowed.chain(redefined).collect()
+}
+
+/// Is a variable left out of the lints about variables?
+fn ignored(name: &str) -> bool {
+    name.starts_with('%') || name.starts_with('_')
+}
+
+/// Collects the variables a function declares, where they are first
+/// declared, and the variables it uses
+#[derive(Default)]
+struct Uses {
+    declared: Vec<(String, Option<Span>)>,
+    /// The variables declared again, where they were first declared, and where they were declared again
+    redeclared: Vec<(String, Option<Span>, Option<Span>)>,
+    used: Vec<String>,
+    /// The span of the statement being visited
+    span: Option<Span>,
+}
+
+impl Uses {
+    /// Collect the parameters and variables of a function
+    fn of(fun: &UserFn) -> Self {
+        let mut uses = Self::default();
+        for (i, parameter) in fun.parameters.iter().enumerate() {
+            // Assigning to a reference parameter hands the value back to the caller
+            if !fun.is_reference_parameter(i) {
+                uses.declare(parameter, fun.span);
+            }
+        }
+        uses.visit_user_fn(fun);
+        uses
+    }
+
+    fn declare(&mut self, name: &str, span: Option<Span>) {
+        match self.declared.iter().find(|(declared, _)| declared == name) {
+            Some((_, original)) => self.redeclared.push((name.to_string(), *original, span)),
+            None => self.declared.push((name.to_string(), span)),
+        }
+    }
+}
+
+impl Visit for Uses {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Locate(span) => self.span = Some(*span),
+            Expr::Define(Define(name, value)) => {
+                self.declare(name, self.span);
+                self.visit_eval(value);
+            }
+            Expr::Destructure(Destructure(names, value)) => {
+                for name in names {
+                    self.declare(name, self.span);
+                }
+                self.visit_eval(value);
+            }
+            // Assigning to a variable doesn't use it
+            Expr::Assign(Assign(Eval::Load(_), value)) => self.visit_eval(value),
+            _ => walk_expr(self, expr),
+        }
+    }
+
+    fn visit_eval(&mut self, eval: &Eval) {
+        if let Eval::Load(Load(name)) = eval {
+            self.used.push(name.clone());
+        }
+        walk_eval(self, eval)
+    }
+}
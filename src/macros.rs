@@ -0,0 +1,272 @@
+use crate::Literal;
+
+/// The Rust literals that can be written in `free_ast!`
+#[doc(hidden)]
+pub trait IntoLiteral {
+    fn into_literal(self) -> Literal;
+}
+
+impl IntoLiteral for &str {
+    fn into_literal(self) -> Literal {
+        Literal::string(self)
+    }
+}
+
+impl IntoLiteral for char {
+    fn into_literal(self) -> Literal {
+        Literal::character(self)
+    }
+}
+
+/// Integer literals are written without a type, so they are typed
+/// as i32 and turned into the smallest number literal that holds them
+impl IntoLiteral for i32 {
+    fn into_literal(self) -> Literal {
+        if self < 0 || self > u16::MAX as i32 {
+            panic!("the number {} does not fit in an unsigned short", self);
+        }
+        Literal::number(self as u16)
+    }
+}
+
+/// Build the statements of a function body from Rust, with a syntax close to free's.
+///
+/// ```
+/// # use fr::*;
+/// let step = Eval::Literal(Literal::byte_int(1));
+/// let body: Vec<Expr> = free_ast! {
+///     define x = 5;
+///     while (x) {
+///         x = call sub(x, #step);
+///     }
+///     for i in 0 .. x {
+///         call putc(call add('0', i));
+///     }
+///     if (call eq(x, 0)) {
+///         call puts("done");
+///     } else {
+///         call puts(&x);
+///     }
+///     return *x;
+/// };
+/// ```
+///
+/// Names are names in the free program, so `x` is a variable and
+/// `call f(..)` calls the function `f`. To use a Rust expression of type
+/// `Eval`, write `#name` or `#(expression)`. Literals are strings,
+/// characters, and integers, and expressions can be grouped in parentheses.
+/// Bodies with more than about a hundred statements may need a higher
+/// `recursion_limit` in the crate using them.
+///
+/// Malformed statements are errors when the Rust code is compiled, such as a
+/// missing semicolon,
+///
+/// ```compile_fail
+/// # use fr::*;
+/// let body: Vec<Expr> = free_ast! { define x = 5 };
+/// ```
+///
+/// an empty statement,
+///
+/// ```compile_fail
+/// # use fr::*;
+/// let body: Vec<Expr> = free_ast! { define x = 5;; };
+/// ```
+///
+/// or something that isn't an expression.
+///
+/// ```compile_fail
+/// # use fr::*;
+/// let body: Vec<Expr> = free_ast! { define x = 5 + 1; };
+/// ```
+#[macro_export]
+macro_rules! free_ast {
+    // Split the body into its statements, then build every statement.
+    // Statements are split before any is built, and tokens are taken eight at
+    // a time when none of them is a semicolon, so long bodies don't nest too deeply
+    (@split [$($done:tt)*] [] if $cond:tt $then:tt else $otherwise:tt $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [if $cond $then else $otherwise]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [] if $cond:tt $then:tt $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [if $cond $then]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [] while $cond:tt $inner:tt $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [while $cond $inner]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [] repeat $count:tt $inner:tt $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [repeat $count $inner]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [] for $var:ident in $start:tt .. $end:tt $inner:tt $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [for $var in $start .. $end $inner]] [] $($rest)*)
+    };
+    (@split [$([$($done:tt)*])*] []) => {{
+        #[allow(unused_mut)]
+        let mut body: Vec<$crate::Expr> = Vec::new();
+        $($crate::free_ast!(@stmt body; $($done)*);)*
+        body
+    }};
+    (@split [$($done:tt)*] [$($stmt:tt)*] ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)*]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a $b]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt $c:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a $b $c]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt $c:tt $d:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a $b $c $d]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt $c:tt $d:tt $e:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a $b $c $d $e]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt $c:tt $d:tt $e:tt $f:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a $b $c $d $e $f]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt $c:tt $d:tt $e:tt $f:tt $g:tt ; $($rest:tt)*) => {
+        $crate::free_ast!(@split [$($done)* [$($stmt)* $a $b $c $d $e $f $g]] [] $($rest)*)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $a:tt $b:tt $c:tt $d:tt $e:tt $f:tt $g:tt $h:tt $($rest:tt)+) => {
+        $crate::free_ast!(@split [$($done)*] [$($stmt)* $a $b $c $d $e $f $g $h] $($rest)+)
+    };
+    (@split [$($done:tt)*] [$($stmt:tt)*] $($rest:tt)*) => {
+        compile_error!(concat!("expected `;` after `", stringify!($($stmt)* $($rest)*), "`"))
+    };
+
+    // Statements that end with a block
+    (@stmt $body:ident; if ($($cond:tt)+) { $($then:tt)* } else { $($otherwise:tt)* }) => {
+        $body.push($crate::Expr::If($crate::If::new(
+            $crate::free_ast!(@eval $($cond)+),
+            $crate::free_ast!($($then)*),
+            $crate::free_ast!($($otherwise)*),
+        )));
+    };
+    (@stmt $body:ident; if ($($cond:tt)+) { $($then:tt)* }) => {
+        $body.push($crate::Expr::If($crate::If::new(
+            $crate::free_ast!(@eval $($cond)+),
+            $crate::free_ast!($($then)*),
+            vec![],
+        )));
+    };
+    (@stmt $body:ident; while ($($cond:tt)+) { $($inner:tt)* }) => {
+        $body.push($crate::Expr::While($crate::While::new(
+            $crate::free_ast!(@eval $($cond)+),
+            $crate::free_ast!($($inner)*),
+        )));
+    };
+    (@stmt $body:ident; repeat $count:tt { $($inner:tt)* }) => {
+        $body.push($crate::Expr::Repeat($crate::Repeat::new(
+            $crate::free_ast!(@eval $count),
+            $crate::free_ast!($($inner)*),
+        )));
+    };
+    (@stmt $body:ident; for $var:ident in $start:tt .. $end:tt { $($inner:tt)* }) => {
+        $body.push($crate::Expr::ForRange($crate::ForRange::new(
+            stringify!($var),
+            $crate::free_ast!(@eval $start),
+            $crate::free_ast!(@eval $end),
+            $crate::free_ast!($($inner)*),
+        )));
+    };
+
+    // Statements that end with a semicolon
+    (@stmt $body:ident; define $name:ident = $($value:tt)+) => {
+        $body.push($crate::Expr::Define($crate::Define::new(
+            stringify!($name),
+            $crate::free_ast!(@eval $($value)+),
+        )));
+    };
+    (@stmt $body:ident; def $name:ident = $($value:tt)+) => {
+        $crate::free_ast!(@stmt $body; define $name = $($value)+);
+    };
+    (@stmt $body:ident; const $name:ident = $($value:tt)+) => {
+        $body.push($crate::Expr::Const($crate::Const::new(
+            stringify!($name),
+            $crate::free_ast!(@eval $($value)+),
+        )));
+    };
+    (@stmt $body:ident; exit) => {
+        $body.push($crate::Expr::Exit($crate::Exit::new(None)));
+    };
+    (@stmt $body:ident; exit ($($status:tt)+)) => {
+        $body.push($crate::Expr::Exit($crate::Exit::new(Some($crate::free_ast!(@eval $($status)+)))));
+    };
+    (@stmt $body:ident; assert ($($cond:tt)+)) => {
+        $body.push($crate::Expr::Assert($crate::Assert::new($crate::free_ast!(@eval $($cond)+), None)));
+    };
+    (@stmt $body:ident; return $($value:tt)+) => {
+        $body.push($crate::Expr::Return($crate::Return::new($crate::free_ast!(@eval $($value)+))));
+    };
+    (@stmt $body:ident; $($stmt:tt)+) => {
+        $crate::free_ast!(@assign $body; []; $($stmt)+);
+    };
+    (@stmt $body:ident;) => {
+        compile_error!("expected a statement before `;`")
+    };
+
+    // A statement is an assignment if it has an `=`, and an expression otherwise
+    (@assign $body:ident; [$($lhs:tt)+]; = $($rhs:tt)+) => {
+        $body.push($crate::Expr::Assign($crate::Assign::new(
+            $crate::free_ast!(@eval $($lhs)+),
+            $crate::free_ast!(@eval $($rhs)+),
+        )));
+    };
+    (@assign $body:ident; [$($lhs:tt)*]; $next:tt $($rest:tt)*) => {
+        $crate::free_ast!(@assign $body; [$($lhs)* $next]; $($rest)*);
+    };
+    (@assign $body:ident; [$($value:tt)+];) => {
+        $body.push($crate::Expr::Eval($crate::free_ast!(@eval $($value)+)));
+    };
+
+    // Expressions
+    (@eval # $value:tt) => {{
+        #[allow(unused_parens)]
+        let value: $crate::Eval = $value;
+        value
+    }};
+    (@eval call $($name:ident)::+ ($($args:tt)*)) => {
+        $crate::Eval::Call($crate::Call::new(
+            [$(stringify!($name)),+].join("::"),
+            $crate::free_ast!(@args []; []; $($args)*),
+        ))
+    };
+    (@eval & $($value:tt)+) => {
+        $crate::Eval::Refer($crate::Refer::new($crate::free_ast!(@eval $($value)+)))
+    };
+    (@eval * $($value:tt)+) => {
+        $crate::Eval::Deref($crate::Deref::new($crate::free_ast!(@eval $($value)+)))
+    };
+    (@eval ($($value:tt)+)) => {
+        $crate::free_ast!(@eval $($value)+)
+    };
+    (@eval $name:ident) => {
+        $crate::Eval::Load($crate::Load::new(stringify!($name)))
+    };
+    (@eval $literal:literal) => {
+        $crate::Eval::Literal($crate::macros::IntoLiteral::into_literal($literal))
+    };
+    (@eval $($other:tt)*) => {
+        compile_error!(concat!("expected an expression, found `", stringify!($($other)*), "`"))
+    };
+
+    // Split the arguments of a call at their commas
+    (@args [$($done:tt)*]; [];) => {
+        vec![$($done)*]
+    };
+    (@args [$($done:tt)*]; [$($arg:tt)+];) => {
+        vec![$($done)* $crate::free_ast!(@eval $($arg)+)]
+    };
+    (@args [$($done:tt)*]; [$($arg:tt)+]; , $($rest:tt)*) => {
+        $crate::free_ast!(@args [$($done)* $crate::free_ast!(@eval $($arg)+),]; []; $($rest)*)
+    };
+    (@args [$($done:tt)*]; [$($arg:tt)*]; $next:tt $($rest:tt)*) => {
+        $crate::free_ast!(@args [$($done)*]; [$($arg)* $next]; $($rest)*)
+    };
+
+    ($($stmts:tt)*) => {
+        $crate::free_ast!(@split [] [] $($stmts)*)
+    };
+}
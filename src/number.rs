@@ -0,0 +1,788 @@
+//! Signed numbers, 4 byte numbers, and the arithmetic that takes more than
+//! adding or subtracting cells: comparing, multiplying and dividing.
+//!
+//! A cell holds a number in two's complement whether it is signed or not,
+//! so adding and subtracting work the same on both, and the kinds only differ
+//! in how they are compared, divided and printed. Those spread the number over
+//! cells of four bits each, least significant first, called digits here.
+//! Testing or copying a cell counts through its value, so a digit takes at
+//! most 15 steps where a whole cell could take 65535.
+//!
+//! A 4 byte number is always kept as its eight digits, so it is as wide
+//! with 8 bit cells as with 16 bit cells, and carries from one digit to the next.
+//! A fixed point number is kept the same way, as an `i32` counting 65536ths,
+//! so 16 of its bits are before the point and 16 after it.
+use crate::{Control, Error, Eval, Literal, Lower, Program, Value, ValueKind};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::Range};
+
+/// How the cells of a value are read as a number
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NumberKind {
+    /// A cell from zero up to the largest value a cell holds
+    Unsigned,
+    /// A cell in two's complement, where the upper half of a cell's values are negative
+    Signed,
+    /// A `u32`, in eight digits
+    Unsigned4Byte,
+    /// An `i32`, in eight digits in two's complement
+    Signed4Byte,
+    /// A number with 16 bits after the point, in eight digits in two's complement
+    Fixed,
+}
+
+impl NumberKind {
+    pub fn is_signed(self) -> bool {
+        matches!(self, Self::Signed | Self::Signed4Byte | Self::Fixed)
+    }
+
+    /// Is the number spread over digits rather than held in a single cell?
+    pub fn is_wide(self) -> bool {
+        matches!(self, Self::Unsigned4Byte | Self::Signed4Byte | Self::Fixed)
+    }
+}
+
+/// Numbers are described this way in error messages
+impl fmt::Display for NumberKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unsigned => write!(f, "an unsigned number"),
+            Self::Signed => write!(f, "a signed number"),
+            Self::Unsigned4Byte => write!(f, "a `u32`"),
+            Self::Signed4Byte => write!(f, "an `i32`"),
+            Self::Fixed => write!(f, "a fixed point number"),
+        }
+    }
+}
+
+/// The bits in each digit of a number spread over cells
+pub(crate) const DIGIT_BITS: u32 = 4;
+/// The number of values a digit holds
+pub(crate) const DIGIT_BASE: u32 = 1 << DIGIT_BITS;
+/// The number of digits in a 4 byte number
+pub(crate) const WIDE_DIGITS: u32 = 32 / DIGIT_BITS;
+/// The number of digits after the point in a fixed point number
+pub(crate) const FRACTION_DIGITS: u32 = 16 / DIGIT_BITS;
+/// The fixed point numbers there are, with 16 bits before the point in two's complement
+pub const FIXED_RANGE: Range<f64> = -32768.0..32768.0;
+
+/// The number of digits a cell is spread over
+fn cell_digits() -> u32 {
+    Program::cell_width().max_value().count_ones() / DIGIT_BITS
+}
+
+/// Lower the operands of an arithmetic function, which have to be the same kind
+/// of number. A number literal is lowered as the kind of the other operand, so
+/// `add(x, 1)` works whatever kind of number `x` is, and two literals are lowered
+/// as the wider of their kinds, so `add(-3, 5)` is an `i32`.
+pub(crate) fn operands(function: &str, a: &Eval, b: &Eval) -> Result<(Value, Value), Error> {
+    let (a, b) = match (number_literal(a), number_literal(b)) {
+        (Some(literal), None) => {
+            let b = b.lower()?;
+            (literal_as(literal, b.number)?, b)
+        }
+        (None, Some(literal)) => {
+            let a = a.lower()?;
+            let b = literal_as(literal, a.number)?;
+            (a, b)
+        }
+        (Some(x), Some(y)) => {
+            let kind = literal_kind(x).max(literal_kind(y));
+            (literal_as(x, kind)?, literal_as(y, kind)?)
+        }
+        (None, None) => (a.lower()?, b.lower()?),
+    };
+    if a.number != b.number {
+        return Err(Error::MixedNumbers {
+            function: function.to_string(),
+            left: a.number,
+            right: b.number,
+        });
+    }
+    Ok((a, b))
+}
+
+/// Lower an argument as a kind of number, which a number literal is lowered as
+pub(crate) fn lower_as(arg: &Eval, kind: NumberKind) -> Result<Value, Error> {
+    match number_literal(arg) {
+        Some(literal) => literal_as(literal, kind),
+        None => arg.lower(),
+    }
+}
+
+/// The literal an argument is, if it is a number literal
+fn number_literal(arg: &Eval) -> Option<&Literal> {
+    match arg {
+        Eval::Literal(literal) if literal.integer().is_some() => Some(literal),
+        Eval::Literal(literal @ Literal::Fixed(_)) => Some(literal),
+        _ => None,
+    }
+}
+
+/// Work out a function of two number literals while compiling, if either of
+/// them is fixed point. Multiplying or dividing fixed point numbers at run time
+/// rounds them to 65536ths first, so `mul(0.1, 10)` would be a little over 1.
+pub(crate) fn fold_fixed(a: &Eval, b: &Eval, fold: impl Fn(f64, f64) -> f64) -> Option<Result<Value, Error>> {
+    let (a, b) = (number_literal(a)?, number_literal(b)?);
+    if literal_kind(a).max(literal_kind(b)) != NumberKind::Fixed {
+        return None;
+    }
+    let value = |literal: &Literal| match literal {
+        Literal::Fixed(n) => *n,
+        literal => literal.integer().unwrap_or_default() as f64,
+    };
+    Some(Literal::fixed(fold(value(a), value(b))).lower())
+}
+
+/// The kind of number a number literal is when nothing else decides it
+fn literal_kind(literal: &Literal) -> NumberKind {
+    match literal {
+        Literal::Unsigned4ByteInt(_) => NumberKind::Unsigned4Byte,
+        Literal::Signed4ByteInt(_) => NumberKind::Signed4Byte,
+        Literal::Fixed(_) => NumberKind::Fixed,
+        _ => NumberKind::Unsigned,
+    }
+}
+
+/// Lower a number literal as a kind of number, if it is in the kind's range
+fn literal_as(literal: &Literal, kind: NumberKind) -> Result<Value, Error> {
+    let out_of_range = || Error::NumberOutOfRange {
+        number: literal.to_string(),
+        kind,
+    };
+    // A fixed point number is only ever one, where a whole number can be any kind
+    if let Literal::Fixed(_) = literal {
+        return match kind {
+            NumberKind::Fixed => literal.lower(),
+            _ => Err(out_of_range()),
+        };
+    }
+
+    let n = literal.integer().unwrap_or_default();
+    let modulus = Program::cell_width().modulus() as i64;
+    let (min, max) = match kind {
+        NumberKind::Unsigned => (0, modulus - 1),
+        NumberKind::Signed => (-modulus / 2, modulus / 2 - 1),
+        NumberKind::Unsigned4Byte => (0, u32::MAX as i64),
+        NumberKind::Signed4Byte => (i32::MIN as i64, i32::MAX as i64),
+        NumberKind::Fixed => (FIXED_RANGE.start as i64, FIXED_RANGE.end as i64 - 1),
+    };
+    if n < min || n > max {
+        return Err(out_of_range());
+    }
+
+    // A negative number is lowered as the bits of its two's complement
+    let mut val = match kind {
+        NumberKind::Fixed => Literal::fixed(n as f64).lower()?,
+        kind if kind.is_wide() => Literal::unsigned_4byte_int(n as u32).lower()?,
+        _ => Literal::number(n.rem_euclid(modulus) as u16).lower()?,
+    };
+    val.number = kind;
+    Ok(val)
+}
+
+/// Add two numbers of the same kind. Adding a number to a character makes a character.
+pub(crate) fn add_numbers(a: Value, b: Value) -> Result<Value, Error> {
+    if a.number.is_wide() {
+        return add_wide(a, b, false, "add");
+    }
+    let mut result = a.copy()?;
+    // A signed number going below zero is its own value, not an overflow
+    if !a.number.is_signed() && Program::overflow_checks_enabled() {
+        result.checked_plus_eq(b, "add")?;
+    } else {
+        result.plus_eq(b);
+    }
+    result.character = a.character || b.character;
+    Ok(result)
+}
+
+/// Subtract one number from another of the same kind. Subtracting a number from a
+/// character makes a character, and subtracting two characters makes a number.
+pub(crate) fn sub_numbers(a: Value, b: Value) -> Result<Value, Error> {
+    if a.number.is_wide() {
+        return add_wide(a, b, true, "sub");
+    }
+    let mut result = a.copy()?;
+    if !a.number.is_signed() && Program::overflow_checks_enabled() {
+        result.checked_minus_eq(b, "sub")?;
+    } else {
+        result.minus_eq(b);
+    }
+    result.character = a.character && !b.character;
+    Ok(result)
+}
+
+/// Add or subtract two 4 byte numbers a digit at a time, carrying into the next
+/// digit. With overflow checks, a `u32` that carries out of its last digit, or
+/// borrows past it, stops the program.
+fn add_wide(a: Value, b: Value, subtracting: bool, function: &str) -> Result<Value, Error> {
+    let result = a.copy()?;
+    let carry = if subtracting {
+        subtract(result, b)?
+    } else {
+        let carry = Value::new(1)?;
+        add_digits(result, Some(b), carry, DIGIT_BASE)?;
+        carry
+    };
+    if !a.number.is_signed() && Program::overflow_checks_enabled() {
+        // Subtracting borrowed if nothing carried out
+        if subtracting {
+            let borrowed = Value::byte_int(1)?;
+            borrowed.minus_eq(carry);
+            carry.assign(borrowed)?;
+            borrowed.discard();
+        }
+        Control::if_begin(carry)?;
+        Control::abort(&format!("OVERFLOW in {}", function))?;
+        Control::else_begin()?;
+        Control::if_end()?;
+    }
+    carry.discard();
+    Ok(result)
+}
+
+/// Negate a number in two's complement, which makes it signed. It wraps
+/// around like `sub` does, even with overflow checks.
+pub(crate) fn neg_number(a: Value) -> Result<Value, Error> {
+    if a.number.is_wide() {
+        let mut result = a.copy()?;
+        negate(result)?;
+        result.number = a.number.max(NumberKind::Signed4Byte);
+        return Ok(result);
+    }
+    let mut result = Value::byte_int(0)?;
+    result.minus_eq(a);
+    result.number = NumberKind::Signed;
+    Ok(result)
+}
+
+/// Convert a number to another kind. A cell and a number of the same width
+/// are read as the other kind without changing them, a cell is widened to a
+/// 4 byte number with its sign, and a 4 byte number is cut down to a cell,
+/// keeping the bits that fit. A whole number converted to fixed point keeps
+/// its lowest 16 bits, and a fixed point number converted to a whole number
+/// loses its fraction, rounding toward zero. A literal cast to a 4 byte number
+/// is lowered as one, since it may not fit in a cell.
+pub(crate) fn cast_number(arg: &Eval, kind: NumberKind) -> Result<Value, Error> {
+    let a = match number_literal(arg) {
+        Some(literal) if kind.is_wide() && literal.cell_value().is_some() => literal_as(literal, kind)?,
+        _ => arg.lower()?,
+    };
+    let mut result = match (a.number, kind) {
+        (NumberKind::Fixed, NumberKind::Fixed) => a.copy()?,
+        (NumberKind::Fixed, kind) => {
+            let whole = whole_part(a)?;
+            if kind.is_wide() {
+                whole
+            } else {
+                let result = from_digits(whole)?;
+                whole.discard();
+                result
+            }
+        }
+        (from, NumberKind::Fixed) => {
+            let digits = if from.is_wide() { a.copy()? } else { widen(a)? };
+            let mut result = Value::new(WIDE_DIGITS)?;
+            result.kind = ValueKind::Data;
+            result.cells(FRACTION_DIGITS, FRACTION_DIGITS).assign(digits.cells(0, FRACTION_DIGITS))?;
+            digits.discard();
+            result
+        }
+        (from, to) => match (from.is_wide(), to.is_wide()) {
+            (false, true) => widen(a)?,
+            (true, false) => from_digits(a)?,
+            _ => a.copy()?,
+        },
+    };
+    result.character = false;
+    result.number = kind;
+    Ok(result)
+}
+
+/// Is one number less than another? Signed numbers are compared in two's
+/// complement, so a cell holding its largest value is -1, which is less than 0.
+/// The result is 1 if it is and 0 if it isn't.
+pub(crate) fn less_than(a: Value, b: Value) -> Result<Value, Error> {
+    let (x, y) = (digits_of(a)?, digits_of(b)?);
+    // Moving both by half of their range puts the negative numbers below the others
+    if a.number.is_signed() {
+        flip_sign(x)?;
+        flip_sign(y)?;
+    }
+    let at_least = subtract(x, y)?;
+    let result = Value::byte_int(1)?;
+    result.minus_eq(at_least);
+    for scratch in [at_least, y, x] {
+        scratch.discard();
+    }
+    Ok(result)
+}
+
+/// Multiply two numbers of the same kind, wrapping around at their width.
+/// With overflow checks, an unsigned product too large for it stops the program.
+pub(crate) fn mul_numbers(a: Value, b: Value) -> Result<Value, Error> {
+    if a.number == NumberKind::Fixed {
+        return mul_fixed(a, b);
+    }
+    let (x, y) = (digits_of(a)?, digits_of(b)?);
+    let checked = !a.number.is_signed() && Program::overflow_checks_enabled();
+    let product = multiply(x, y, if checked { 2 * x.size() } else { x.size() })?;
+    if checked {
+        let fits = is_zero(product.cells(x.size(), x.size()))?;
+        Control::if_begin(fits)?;
+        Control::else_begin()?;
+        Control::abort("OVERFLOW in mul")?;
+        Control::if_end()?;
+        fits.discard();
+    }
+
+    let result = from_digits_as(product.cells(0, x.size()), a.number)?;
+    for scratch in [product, y, x] {
+        scratch.discard();
+    }
+    Ok(result)
+}
+
+/// Divide one number by another of the same kind, rounding toward zero, so
+/// -7 divided by 2 is -3. Dividing by zero stops the program.
+pub(crate) fn div_numbers(a: Value, b: Value) -> Result<Value, Error> {
+    if a.number == NumberKind::Fixed {
+        return div_fixed(a, b);
+    }
+    let (x, y) = (digits_of(a)?, digits_of(b)?);
+    let quotient = divide(x, y, a.number.is_signed())?;
+    let result = from_digits_as(quotient, a.number)?;
+    for scratch in [quotient, y, x] {
+        scratch.discard();
+    }
+    Ok(result)
+}
+
+/// Multiply two fixed point numbers. The product of their magnitudes has 32 bits
+/// after the point, so it is shifted down by 16 of them, rounding to the nearest
+/// 65536th, and wraps around past 16 bits before the point like an `i32` does.
+fn mul_fixed(a: Value, b: Value) -> Result<Value, Error> {
+    let (x, y) = (a.copy()?, b.copy()?);
+    let negative = magnitudes(x, y)?;
+    let product = multiply(x, y, WIDE_DIGITS + FRACTION_DIGITS)?;
+    // Adding half of the last bit that is kept rounds to the nearest
+    let half = Value::byte_int((DIGIT_BASE / 2) as u8)?;
+    add_digits(product.cells(FRACTION_DIGITS - 1, WIDE_DIGITS + 1), None, half, DIGIT_BASE)?;
+
+    let result = from_digits_as(product.cells(FRACTION_DIGITS, WIDE_DIGITS), NumberKind::Fixed)?;
+    Control::if_begin(negative)?;
+    negate(result)?;
+    Control::else_begin()?;
+    Control::if_end()?;
+    for scratch in [half, product, negative, y, x] {
+        scratch.discard();
+    }
+    Ok(result)
+}
+
+/// Divide one fixed point number by another, rounding toward zero. The dividend
+/// is moved up by the 16 bits after the point first, so the quotient keeps them.
+fn div_fixed(a: Value, b: Value) -> Result<Value, Error> {
+    let shifted = Value::new(WIDE_DIGITS + FRACTION_DIGITS)?;
+    shifted.cells(FRACTION_DIGITS, WIDE_DIGITS).assign(a)?;
+    let quotient = divide(shifted, b, true)?;
+    let result = from_digits_as(quotient.cells(0, WIDE_DIGITS), NumberKind::Fixed)?;
+    for scratch in [quotient, shifted] {
+        scratch.discard();
+    }
+    Ok(result)
+}
+
+/// The whole part of a fixed point number as the digits of a 4 byte number,
+/// rounding toward zero, so the whole part of -1.5 is -1
+fn whole_part(a: Value) -> Result<Value, Error> {
+    let magnitude = a.copy()?;
+    let negative = is_negative(magnitude)?;
+    Control::if_begin(negative)?;
+    negate(magnitude)?;
+    Control::else_begin()?;
+    Control::if_end()?;
+
+    let mut whole = Value::new(WIDE_DIGITS)?;
+    whole.kind = ValueKind::Data;
+    whole.cells(0, FRACTION_DIGITS).assign(magnitude.cells(FRACTION_DIGITS, FRACTION_DIGITS))?;
+    Control::if_begin(negative)?;
+    negate(whole)?;
+    Control::else_begin()?;
+    Control::if_end()?;
+    for scratch in [negative, magnitude] {
+        scratch.discard();
+    }
+    Ok(whole)
+}
+
+/// The decimal digits of a fixed point number that isn't negative, rounded to
+/// `places` decimal places. The digits of the whole part are given like
+/// `decimal_digits` gives them, and the digits after the point in a cell each,
+/// most significant first. Each of those is what carries out of the fraction
+/// when it is multiplied by ten.
+pub(crate) fn fixed_decimal_digits(x: Value, places: u32) -> Result<(Value, Vec<Value>), Error> {
+    let rounded = x.copy()?;
+    // Adding half of the last decimal place rounds to the nearest
+    let half = Value::unsigned_4byte_int((32768.0 / 10f64.powi(places as i32)).round() as u32)?;
+    let carry = Value::new(1)?;
+    add_digits(rounded, Some(half), carry, DIGIT_BASE)?;
+
+    let whole = Value::new(WIDE_DIGITS)?;
+    whole.cells(0, FRACTION_DIGITS).assign(rounded.cells(FRACTION_DIGITS, FRACTION_DIGITS))?;
+    let decimal = decimal_digits(whole)?;
+
+    let ten = Value::byte_int(10)?;
+    let mut fraction = rounded.cells(0, FRACTION_DIGITS);
+    let mut digits = vec![];
+    for _ in 0..places {
+        let product = multiply(fraction, ten, FRACTION_DIGITS + 1)?;
+        digits.push(product.cells(FRACTION_DIGITS, 1));
+        fraction = product.cells(0, FRACTION_DIGITS);
+    }
+    for scratch in [ten, whole, carry, half, rounded] {
+        scratch.discard();
+    }
+    Ok((decimal, digits))
+}
+
+/// Is a signed number negative? The cell this gives is 1 if it is.
+pub(crate) fn is_negative_number(a: Value) -> Result<Value, Error> {
+    let x = digits_of(a)?;
+    let result = is_negative(x)?;
+    x.discard();
+    Ok(result)
+}
+
+/// Add up the digits of a 4 byte number in a cell, which is zero only if the
+/// number is, so the cell can be tested in its place. It holds at most 120.
+pub(crate) fn sum_digits(val: Value) -> Result<Value, Error> {
+    let total = Value::new(1)?;
+    for i in 0..val.size() {
+        total.plus_eq(val.cells(i, 1));
+    }
+    Ok(total)
+}
+
+/// The decimal digits of an unsigned 4 byte number, least significant first, in
+/// a cell each. Its bits are shifted out of the top of a copy of it, and doubling
+/// the decimal digits shifts each one in.
+pub(crate) fn decimal_digits(x: Value) -> Result<Value, Error> {
+    let bits = x.copy()?;
+    // A `u32` has at most ten decimal digits
+    let decimal = Value::new(10)?;
+    let bit = Value::new(1)?;
+    let one = Value::byte_int(1)?;
+    let count = Value::new(1)?;
+    count.set((x.size() * DIGIT_BITS) as usize);
+    Control::while_begin(count);
+    add_digits(bits, Some(bits), bit, DIGIT_BASE)?;
+    add_digits(decimal, Some(decimal), bit, 10)?;
+    count.minus_eq(one);
+    Control::while_end();
+    for scratch in [count, one, bit, bits] {
+        scratch.discard();
+    }
+    Ok(decimal)
+}
+
+/// A copy of a number's digits, which can be changed without changing the number
+fn digits_of(val: Value) -> Result<Value, Error> {
+    if val.number.is_wide() {
+        val.copy()
+    } else {
+        to_digits(val)
+    }
+}
+
+/// Make a number of a kind from digits, which a 4 byte number keeps as they are
+fn from_digits_as(digits: Value, kind: NumberKind) -> Result<Value, Error> {
+    let mut result = if kind.is_wide() {
+        let mut result = digits.copy()?;
+        result.kind = ValueKind::Data;
+        result
+    } else {
+        from_digits(digits)?
+    };
+    result.number = kind;
+    Ok(result)
+}
+
+/// Spread a cell over the digits of a 4 byte number. The sign of a signed cell
+/// fills the digits above it, so a cell holding -1 is still -1.
+fn widen(a: Value) -> Result<Value, Error> {
+    let mut digits = Value::new(WIDE_DIGITS)?;
+    digits.kind = ValueKind::Data;
+    let low = digits.cells(0, cell_digits());
+    let carry = Value::new(1)?;
+    carry.assign(a)?;
+    add_digits(low, None, carry, DIGIT_BASE)?;
+    carry.discard();
+    if a.number.is_signed() {
+        let sign = is_negative(low)?;
+        Control::if_begin(sign)?;
+        for i in cell_digits()..WIDE_DIGITS {
+            digits.cells(i, 1).set((DIGIT_BASE - 1) as usize);
+        }
+        Control::else_begin()?;
+        Control::if_end()?;
+        sign.discard();
+    }
+    Ok(digits)
+}
+
+/// Spread the number in a cell over digits
+fn to_digits(val: Value) -> Result<Value, Error> {
+    let digits = Value::new(cell_digits())?;
+    let carry = Value::new(1)?;
+    carry.assign(val)?;
+    add_digits(digits, None, carry, DIGIT_BASE)?;
+    carry.discard();
+    Ok(digits)
+}
+
+/// Gather digits back into a cell, dropping any that don't fit in it
+fn from_digits(digits: Value) -> Result<Value, Error> {
+    let mut result = Value::byte_int(0)?;
+    let one = Value::byte_int(1)?;
+    for i in 0..digits.size().min(cell_digits()) {
+        // Each unit of a digit adds its place's value to the cell
+        let place = Value::new(1)?;
+        place.set(DIGIT_BASE.pow(i) as usize);
+        let count = digits.cells(i, 1).copy()?;
+        Control::while_begin(count);
+        result.plus_eq(place);
+        count.minus_eq(one);
+        Control::while_end();
+        for scratch in [count, place] {
+            scratch.discard();
+        }
+    }
+    one.discard();
+    result.number = NumberKind::Unsigned;
+    Ok(result)
+}
+
+/// Add the digits of `y` to the digits of `x` in `base`, least significant first,
+/// along with a carry into the first digit, which may be any number a cell holds.
+/// What carries out of the last digit is left in `carry`. `y` may have fewer digits
+/// than `x`, or be left out to only add the carry. `x` and `y` may be the same
+/// digits, which doubles them.
+fn add_digits(x: Value, y: Option<Value>, carry: Value, base: u32) -> Result<(), Error> {
+    let one = Value::byte_int(1)?;
+    for i in 0..x.size() {
+        let digit = x.cells(i, 1);
+        let count = Value::new(1)?;
+        count.assign(carry)?;
+        carry.zero();
+        if let Some(y) = y.filter(|y| i < y.size()) {
+            count.plus_eq(y.cells(i, 1));
+        }
+
+        // What is left before the digit wraps is counted down beside it, since
+        // that stays small enough to test, where the count may not be
+        let left = Value::new(1)?;
+        left.set(base as usize);
+        left.minus_eq(digit);
+        Control::while_begin(count);
+        digit.plus_eq(one);
+        left.minus_eq(one);
+        left.when_zero(|| {
+            digit.zero();
+            left.set(base as usize);
+            carry.plus_eq(one);
+        })?;
+        count.minus_eq(one);
+        Control::while_end();
+        for scratch in [left, count] {
+            scratch.discard();
+        }
+    }
+    one.discard();
+    Ok(())
+}
+
+/// Subtract each digit from the largest digit, padding `x` with zeros to `size`
+/// digits. This is `-x - 1` in two's complement.
+fn complement(x: Value, size: u32) -> Result<Value, Error> {
+    let result = Value::new(size)?;
+    for i in 0..size {
+        let digit = result.cells(i, 1);
+        digit.set((DIGIT_BASE - 1) as usize);
+        if i < x.size() {
+            digit.minus_eq(x.cells(i, 1));
+        }
+    }
+    Ok(result)
+}
+
+/// Negate digits in place, in two's complement
+pub(crate) fn negate(x: Value) -> Result<(), Error> {
+    let flipped = complement(x, x.size())?;
+    let carry = Value::byte_int(1)?;
+    add_digits(flipped, None, carry, DIGIT_BASE)?;
+    x.assign(flipped)?;
+    for scratch in [carry, flipped] {
+        scratch.discard();
+    }
+    Ok(())
+}
+
+/// Subtract the digits of `y` from the digits of `x` in place. The cell this gives
+/// is 1 if nothing was borrowed, which is when `x` was at least `y`.
+fn subtract(x: Value, y: Value) -> Result<Value, Error> {
+    let flipped = complement(y, x.size())?;
+    let carry = Value::byte_int(1)?;
+    add_digits(x, Some(flipped), carry, DIGIT_BASE)?;
+    flipped.discard();
+    Ok(carry)
+}
+
+/// Flip the top bit of digits, which is the sign in two's complement
+fn flip_sign(x: Value) -> Result<(), Error> {
+    let half = Value::byte_int((DIGIT_BASE / 2) as u8)?;
+    let carry = Value::new(1)?;
+    add_digits(x.cells(x.size() - 1, 1), Some(half), carry, DIGIT_BASE)?;
+    for scratch in [carry, half] {
+        scratch.discard();
+    }
+    Ok(())
+}
+
+/// Is the top bit of digits set, which makes them negative in two's complement?
+fn is_negative(x: Value) -> Result<Value, Error> {
+    at_least(x.cells(x.size() - 1, 1), DIGIT_BASE / 2)
+}
+
+/// Is a small number in a cell at least `n`? The two are counted down
+/// together, so this takes no more than `n` steps.
+fn at_least(cell: Value, n: u32) -> Result<Value, Error> {
+    let result = Value::byte_int(0)?;
+    let count = Value::new(1)?;
+    count.assign(cell)?;
+    let left = Value::new(1)?;
+    left.set(n as usize);
+    let one = Value::byte_int(1)?;
+
+    Control::while_begin(count);
+    count.minus_eq(one);
+    left.minus_eq(one);
+    left.when_zero(|| {
+        result.set(1usize);
+        count.zero();
+    })?;
+    Control::while_end();
+    for scratch in [one, left, count] {
+        scratch.discard();
+    }
+    Ok(result)
+}
+
+/// Is every digit zero? The cell this gives is 1 if it is.
+fn is_zero(x: Value) -> Result<Value, Error> {
+    let total = Value::new(1)?;
+    for i in 0..x.size() {
+        total.plus_eq(x.cells(i, 1));
+    }
+    let result = Value::byte_int(0)?;
+    total.when_zero(|| result.set(1usize))?;
+    total.discard();
+    Ok(result)
+}
+
+/// Multiply two numbers of digits, keeping the lowest `size` digits of the product
+fn multiply(x: Value, y: Value, size: u32) -> Result<Value, Error> {
+    let product = Value::new(size)?;
+    let carry = Value::new(1)?;
+    let one = Value::byte_int(1)?;
+    for i in 0..x.size().min(size) {
+        // Each unit of a digit of `x` adds `y`, moved up to the digit's place
+        let count = x.cells(i, 1).copy()?;
+        Control::while_begin(count);
+        add_digits(product.cells(i, size - i), Some(y), carry, DIGIT_BASE)?;
+        carry.zero();
+        count.minus_eq(one);
+        Control::while_end();
+        count.discard();
+    }
+    for scratch in [one, carry] {
+        scratch.discard();
+    }
+    Ok(product)
+}
+
+/// Make two numbers of digits in two's complement their magnitudes, in place.
+/// The cell this gives is 1 if just one of them was negative, so their
+/// product or quotient is.
+fn magnitudes(x: Value, y: Value) -> Result<Value, Error> {
+    let negative = Value::byte_int(0)?;
+    for n in [x, y] {
+        let sign = is_negative(n)?;
+        Control::if_begin(sign)?;
+        negate(n)?;
+        let flipped = Value::byte_int(1)?;
+        flipped.minus_eq(negative);
+        negative.assign(flipped)?;
+        flipped.discard();
+        Control::else_begin()?;
+        Control::if_end()?;
+        sign.discard();
+    }
+    Ok(negative)
+}
+
+/// Divide one number of digits by another, a bit at a time, rounding toward zero.
+/// Signed numbers are divided as their magnitudes, and the quotient is negated if
+/// just one of them was negative. Dividing by zero stops the program.
+fn divide(x: Value, y: Value, signed: bool) -> Result<Value, Error> {
+    let dividend = x.copy()?;
+    let divisor = y.copy()?;
+    let one = Value::byte_int(1)?;
+    let negative = if signed { magnitudes(dividend, divisor)? } else { Value::byte_int(0)? };
+
+    let by_zero = is_zero(divisor)?;
+    Control::if_begin(by_zero)?;
+    Control::abort("DIVISION BY ZERO")?;
+    Control::else_begin()?;
+    Control::if_end()?;
+    by_zero.discard();
+
+    // The remainder has a digit more than the divisor, so doubling it never overflows
+    let quotient = Value::new(dividend.size())?;
+    let remainder = Value::new(divisor.size() + 1)?;
+    let bit = Value::new(1)?;
+    // The bits are counted down while the program runs, so the code isn't repeated for each of them
+    let count = Value::new(1)?;
+    count.set((dividend.size() * DIGIT_BITS) as usize);
+    Control::while_begin(count);
+    // The top bit of the dividend carries out of it into the remainder,
+    // and doubling the quotient makes room for the next bit
+    add_digits(dividend, Some(dividend), bit, DIGIT_BASE)?;
+    add_digits(remainder, Some(remainder), bit, DIGIT_BASE)?;
+    add_digits(quotient, Some(quotient), bit, DIGIT_BASE)?;
+
+    let trial = remainder.copy()?;
+    let fits = subtract(trial, divisor)?;
+    Control::if_begin(fits)?;
+    remainder.assign(trial)?;
+    quotient.cells(0, 1).plus_eq(one);
+    Control::else_begin()?;
+    Control::if_end()?;
+    for scratch in [fits, trial] {
+        scratch.discard();
+    }
+    count.minus_eq(one);
+    Control::while_end();
+
+    Control::if_begin(negative)?;
+    negate(quotient)?;
+    Control::else_begin()?;
+    Control::if_end()?;
+    for scratch in [count, bit, remainder, negative, one, divisor, dividend] {
+        scratch.discard();
+    }
+    Ok(quotient)
+}
@@ -0,0 +1,1248 @@
+use crate::{bind_arguments, namespace_of, qualify, map_code, rebuild_eval, rebuild_expr, walk_body, walk_eval, walk_expr, warn, Fold, Visit, Assert, Assign, Exit, Call, CallIndirect, Concat, Const, Construct, Define, Deref, Destructure, Eval, Expr, Field, FnAttr, FnRef, ForRange, If, Repeat, Index, Literal, Load, Program, Refer, Return, Tuple, UserFn, While, INSTRUCTIONS};
+use std::sync::Arc;
+
+/// The highest optimization level
+pub const MAX_OPTIMIZE_LEVEL: u8 = 2;
+
+/// An optimization pass. A pass can rewrite the functions of
+/// a program before they are compiled, the compiled code, or both.
+pub trait Pass {
+    /// The lowest optimization level that enables this pass
+    fn level(&self) -> u8;
+
+    /// Rewrite the functions of a program before they are compiled
+    fn transform(&self, funs: Vec<UserFn>) -> Vec<UserFn> {
+        funs
+    }
+
+    /// Rewrite the compiled code
+    fn transform_output(&self, code: String) -> String {
+        code
+    }
+}
+
+/// Every optimization pass, in the order they are run
+pub fn passes() -> Vec<Box<dyn Pass>> {
+    vec![
+        Box::new(TailCalls),
+        Box::new(ConstantFold),
+        Box::new(DeadCode),
+        Box::new(Unroll::default()),
+        Box::new(Inline),
+        Box::new(StripComments),
+        Box::new(CancelMoves),
+        Box::new(CancelPairs),
+        Box::new(Peephole),
+    ]
+}
+
+/// The passes enabled by the current optimization level
+pub fn enabled_passes() -> Vec<Box<dyn Pass>> {
+    let level = Program::optimize_level();
+    passes().into_iter().filter(|pass| pass.level() <= level).collect()
+}
+
+/// Remove everything from the compiled code that isn't an instruction
+pub struct StripComments;
+
+impl Pass for StripComments {
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn transform_output(&self, code: String) -> String {
+        // Debug comments are asked for, so they are kept
+        map_code(&code, |part| part.chars().filter(|ch| INSTRUCTIONS.contains(ch)).collect())
+    }
+}
+
+/// Remove pointer moves that are immediately undone, such as `>><<`
+pub struct CancelMoves;
+
+impl CancelMoves {
+    /// The longest run of moves that is cancelled
+    const MAX_MOVE: usize = 10;
+}
+
+impl Pass for CancelMoves {
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn transform_output(&self, mut code: String) -> String {
+        for n in 1..=Self::MAX_MOVE {
+            let to = ">".repeat(n);
+            let back = "<".repeat(n);
+
+            let move1 = to.clone() + &back;
+            let move2 = back + &to;
+            for _ in 0..10 {
+                code = code.replace(&move1, "").replace(&move2, "");
+            }
+        }
+        code
+    }
+}
+
+/// Remove every pair of adjacent instructions that undo each other,
+/// such as `+-` and `<>`, until none are left
+pub struct CancelPairs;
+
+impl CancelPairs {
+    fn inverse(ch: char) -> Option<char> {
+        match ch {
+            '+' => Some('-'),
+            '-' => Some('+'),
+            '>' => Some('<'),
+            '<' => Some('>'),
+            _ => None,
+        }
+    }
+}
+
+impl Pass for CancelPairs {
+    fn level(&self) -> u8 {
+        2
+    }
+
+    fn transform_output(&self, code: String) -> String {
+        let mut result = String::with_capacity(code.len());
+        for ch in code.chars() {
+            let cancels = match Self::inverse(ch) {
+                Some(inverse) => result.ends_with(inverse),
+                None => false,
+            };
+            if cancels {
+                result.pop();
+            } else {
+                result.push(ch);
+            }
+        }
+        result
+    }
+}
+
+/// Remove clears and loops that can never change the tape, because
+/// the current cell is known to be zero. The current cell is zero right
+/// after a loop ends or after it is cleared with `[-]`. This runs with
+/// `CancelPairs` until neither pass can shrink the code any further.
+pub struct Peephole;
+
+impl Peephole {
+    fn remove_zero_ops(code: &str) -> String {
+        let code: Vec<char> = code.chars().collect();
+        let mut result = String::with_capacity(code.len());
+        let mut known_zero = false;
+        let mut i = 0;
+        while i < code.len() {
+            let is_clear = code[i] == '[' && code.get(i + 1) == Some(&'-') && code.get(i + 2) == Some(&']');
+            if is_clear {
+                if !known_zero {
+                    result.push_str("[-]");
+                }
+                known_zero = true;
+                i += 3;
+            } else if code[i] == '[' && known_zero {
+                // Skip the whole loop, it never runs
+                let mut depth = 0;
+                while i < code.len() {
+                    match code[i] {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            } else {
+                known_zero = code[i] == ']';
+                result.push(code[i]);
+                i += 1;
+            }
+        }
+        result
+    }
+}
+
+impl Pass for Peephole {
+    fn level(&self) -> u8 {
+        2
+    }
+
+    fn transform_output(&self, mut code: String) -> String {
+        loop {
+            let next = CancelPairs.transform_output(Self::remove_zero_ops(&code));
+            if next.len() == code.len() {
+                return next;
+            }
+            code = next;
+        }
+    }
+}
+
+/// Replace calls to the builtin arithmetic functions on literals, and
+/// `strlen` of a string literal, with the computed literal, and remove
+/// branches with constant conditions
+pub struct ConstantFold;
+
+impl Pass for ConstantFold {
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn transform(&self, funs: Vec<UserFn>) -> Vec<UserFn> {
+        // Builtins that the program redefines can't be folded
+        let shadowed: Vec<String> = funs.iter().map(|f| f.name.clone()).collect();
+        let mut folder = FoldConstants { shadowed: &shadowed };
+        funs.into_iter().map(|f| folder.fold_user_fn(f)).collect()
+    }
+}
+
+/// Replace the variables in an expression with the expressions they are paired with
+pub fn substitute(eval: &Eval, substitutions: &[(String, Eval)]) -> Eval {
+    Rename { prefix: "", substitutions }.fold_eval(eval.clone())
+}
+
+/// Fold an expression, assuming the builtin arithmetic functions are not redefined
+pub fn fold(expr: Eval) -> Eval {
+    FoldConstants { shadowed: &[] }.fold_eval(expr)
+}
+
+/// Compute a call to a builtin arithmetic function on the value of two cells.
+/// With overflow checks, a call that overflows is left to stop the program when it runs.
+fn fold_builtin(name: &str, a: u32, b: u32) -> Option<u32> {
+    let modulus = Program::cell_width().modulus();
+    if Program::overflow_checks_enabled() {
+        match name {
+            "add" if a + b >= modulus => return None,
+            "sub" if b > a => return None,
+            _ => {}
+        }
+    }
+    match name {
+        "add" => Some((a + b) % modulus),
+        "sub" => Some((a + modulus - b % modulus) % modulus),
+        _ => None,
+    }
+}
+
+/// Folds the constant expressions in a program
+struct FoldConstants<'a> {
+    /// The builtins that the program redefines
+    shadowed: &'a [String],
+}
+
+impl Fold for FoldConstants<'_> {
+    fn fold_eval(&mut self, expr: Eval) -> Eval {
+        match expr {
+            Eval::Deref(Deref(inner)) => {
+                // An address computed from literals is still an address, so the
+                // call producing it is kept to avoid making it a data literal
+                let inner = match (*inner).clone() {
+                    Eval::Call(call) => rebuild_eval(self, Eval::Call(call)),
+                    other => self.fold_eval(other),
+                };
+                Eval::Deref(Deref(Arc::new(inner)))
+            }
+            expr => match rebuild_eval(self, expr) {
+                Eval::Call(Call(name, args, named)) if !self.shadowed.contains(&name) && named.is_empty() => {
+                    if let ("strlen", [Eval::Literal(Literal::String(s))]) = (name.as_str(), args.as_slice()) {
+                        return Eval::Literal(Literal::number(s.len() as u16));
+                    }
+                    if let [Eval::Literal(a), Eval::Literal(b)] = args.as_slice() {
+                        // Folding keeps the rules for which results are characters
+                        let character = match (name.as_str(), a, b) {
+                            ("add", Literal::Character(_), _) | ("add", _, Literal::Character(_)) => true,
+                            ("sub", Literal::Character(_), Literal::Character(_)) => false,
+                            ("sub", Literal::Character(_), _) => true,
+                            _ => false,
+                        };
+                        if let (Some(a), Some(b)) = (a.cell_value(), b.cell_value()) {
+                            if let Some(n) = fold_builtin(&name, a, b) {
+                                if let Some(ch) = char::from_u32(n).filter(|_| character) {
+                                    return Eval::Literal(Literal::character(ch));
+                                }
+                                return Eval::Literal(Literal::number(n as u16));
+                            }
+                        }
+                    }
+                    Eval::Call(Call(name, args, named))
+                }
+                other => other,
+            },
+        }
+    }
+
+    fn fold_body(&mut self, body: Vec<Expr>) -> Vec<Expr> {
+        let mut result = vec![];
+        for expr in body {
+            match self.fold_expr(expr) {
+                Expr::If(If(condition, then, otherwise)) => match constant_condition(&condition) {
+                    Some(true) => result.extend(then),
+                    Some(false) => result.extend(otherwise),
+                    None => result.push(Expr::If(If(condition, then, otherwise))),
+                },
+                Expr::While(While(condition, _)) if constant_condition(&condition) == Some(false) => {}
+                other => result.push(other),
+            }
+        }
+        result
+    }
+}
+
+/// Is this expression a single cell literal, and if so is it non-zero?
+fn constant_condition(condition: &Eval) -> Option<bool> {
+    match condition {
+        Eval::Literal(l) => l.cell_value().map(|n| n != 0),
+        _ => None,
+    }
+}
+
+/// Replace loops that run a few times known while compiling with a copy of
+/// the body for each time through. The loop variable's value is put in each
+/// copy, and the copies are folded again, so nothing is left of the counter.
+/// There is no `break` or `continue`, so every copy always runs.
+pub struct Unroll {
+    /// The most times a loop can run and still be unrolled
+    pub max_trips: usize,
+    /// The most statements, counting nested statements, an unrolled loop can have
+    pub max_size: usize,
+}
+
+impl Default for Unroll {
+    fn default() -> Self {
+        Self { max_trips: 8, max_size: 32 }
+    }
+}
+
+impl Pass for Unroll {
+    fn level(&self) -> u8 {
+        2
+    }
+
+    fn transform(&self, funs: Vec<UserFn>) -> Vec<UserFn> {
+        let shadowed: Vec<String> = funs.iter().map(|f| f.name.clone()).collect();
+        let all = funs.clone();
+        let mut unroller = Unroller { limits: self, funs: &all, shadowed: &shadowed };
+        funs.into_iter().map(|f| unroller.fold_user_fn(f)).collect()
+    }
+}
+
+/// Unrolls the loops of a body, the innermost first
+struct Unroller<'a> {
+    limits: &'a Unroll,
+    funs: &'a [UserFn],
+    /// The builtins that the program redefines
+    shadowed: &'a [String],
+}
+
+impl Unroller<'_> {
+    /// A copy of the body for each value of the loop variable, or None
+    /// if the loop can't be unrolled
+    fn unroll(&self, var: &str, values: &[Literal], body: &[Expr]) -> Option<Vec<Expr>> {
+        if values.len() > self.limits.max_trips
+            || values.len() * body_size(body) > self.limits.max_size
+            || writes_to(body, var)
+            || has_return(body)
+            || has_loop(body)
+            || borrows(body, var, self.funs)
+        {
+            return None;
+        }
+
+        let mut result = vec![];
+        for value in values {
+            let substitutions = [(var.to_string(), Eval::Literal(value.clone()))];
+            let copy = Rename { prefix: "", substitutions: &substitutions }.fold_body(body.to_vec());
+            result.extend(FoldConstants { shadowed: self.shadowed }.fold_body(copy));
+        }
+        Some(result)
+    }
+
+    /// The values `var` counts down from in a loop like
+    /// `def var = 3; while var { ...; var = sub(var, 1); }`, and the body without the decrement
+    fn countdown(&self, before: &[Expr], var: &str, body: &[Expr]) -> Option<(Vec<Literal>, Vec<Expr>)> {
+        let start = match before.iter().rev().find(|expr| !matches!(expr, Expr::Locate(_)))? {
+            Expr::Define(Define(name, Eval::Literal(start))) | Expr::Assign(Assign(Eval::Load(Load(name)), Eval::Literal(start)))
+                if name == var =>
+            {
+                start.cell_value()?
+            }
+            _ => return None,
+        };
+
+        let last = body.iter().rposition(|expr| !matches!(expr, Expr::Locate(_)))?;
+        let decrement = Expr::Assign(Assign(
+            Eval::Load(Load(var.to_string())),
+            Eval::Call(Call(
+                String::from("sub"),
+                vec![Eval::Load(Load(var.to_string())), Eval::Literal(Literal::byte_int(1))],
+                vec![],
+            )),
+        ));
+        if body[last] != decrement || self.shadowed.iter().any(|name| name == "sub") {
+            return None;
+        }
+
+        let values = (1..=start).rev().map(|n| Literal::number(n as u16)).collect();
+        Some((values, body[..last].to_vec()))
+    }
+}
+
+impl Fold for Unroller<'_> {
+    fn fold_body(&mut self, body: Vec<Expr>) -> Vec<Expr> {
+        let mut result = vec![];
+        for expr in body {
+            match self.fold_expr(expr) {
+                Expr::ForRange(ForRange(var, Eval::Literal(start), Eval::Literal(end), body)) => {
+                    let values: Option<Vec<Literal>> = match (start.cell_value(), end.cell_value()) {
+                        // A range of characters counts in characters
+                        (Some(a), Some(b)) => (a..b)
+                            .map(|n| match start {
+                                Literal::Character(_) => char::from_u32(n).map(Literal::character),
+                                _ => Some(Literal::number(n as u16)),
+                            })
+                            .collect(),
+                        _ => None,
+                    };
+                    match values.and_then(|values| self.unroll(&var, &values, &body)) {
+                        Some(copies) => result.extend(copies),
+                        None => result.push(Expr::ForRange(ForRange(var, Eval::Literal(start), Eval::Literal(end), body))),
+                    }
+                }
+                Expr::While(While(Eval::Load(Load(var)), body)) => {
+                    let unrolled = self
+                        .countdown(&result, &var, &body)
+                        .and_then(|(values, rest)| self.unroll(&var, &values, &rest));
+                    match unrolled {
+                        // The counter ends at zero, as it would after the loop
+                        Some(copies) => {
+                            result.extend(copies);
+                            result.push(Expr::Assign(Assign(
+                                Eval::Load(Load(var)),
+                                Eval::Literal(Literal::byte_int(0)),
+                            )));
+                        }
+                        None => result.push(Expr::While(While(Eval::Load(Load(var)), body))),
+                    }
+                }
+                other => result.push(other),
+            }
+        }
+        result
+    }
+}
+
+/// Does a body have a loop at any depth?
+fn has_loop(body: &[Expr]) -> bool {
+    body.iter().any(|expr| match expr {
+        Expr::While(_) | Expr::ForRange(_) => true,
+        Expr::If(If(_, then, otherwise)) => has_loop(then) || has_loop(otherwise),
+        Expr::Repeat(Repeat(_, body)) => has_loop(body),
+        _ => false,
+    })
+}
+
+/// Does a body point to a variable, or pass it to a reference parameter?
+fn borrows(body: &[Expr], var: &str, funs: &[UserFn]) -> bool {
+    struct Borrows<'a> {
+        var: &'a str,
+        funs: &'a [UserFn],
+        found: bool,
+    }
+
+    impl Visit for Borrows<'_> {
+        fn visit_eval(&mut self, eval: &Eval) {
+            let var = self.var;
+            let is_var = |eval: &Eval| matches!(eval, Eval::Load(Load(name)) if name == var);
+            match eval {
+                Eval::Refer(Refer(inner)) if is_var(inner) => self.found = true,
+                Eval::Call(Call(name, args, named)) => {
+                    let count = args.len() + named.len();
+                    for f in self.funs.iter().filter(|f| &f.name == name && f.accepts(count)) {
+                        let positional = args.iter().enumerate().map(|(i, arg)| (Some(i), arg));
+                        let by_name = named
+                            .iter()
+                            .map(|(param, arg)| (f.parameters.iter().position(|p| p == param), arg));
+                        for (i, arg) in positional.chain(by_name) {
+                            if is_var(arg) && i.is_some_and(|i| f.is_reference_parameter(i)) {
+                                self.found = true;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+            walk_eval(self, eval)
+        }
+    }
+
+    let mut visitor = Borrows { var, funs, found: false };
+    walk_body(&mut visitor, body);
+    visitor.found
+}
+
+/// Remove functions that can't be reached from the entry point.
+/// Statements after a `return` are kept, because a return only sets
+/// the return value and doesn't stop the function
+pub struct DeadCode;
+
+impl Pass for DeadCode {
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn transform(&self, funs: Vec<UserFn>) -> Vec<UserFn> {
+        // Every function in a library may be called by a later program
+        if Program::library_mode() {
+            return funs;
+        }
+
+        // Functions with `#[keep]` are kept, along with everything they call
+        let mut reachable = reachable_functions(&funs, &Program::entry_point());
+        for f in funs.iter().filter(|f| f.has_attribute(FnAttr::Keep)) {
+            reachable.extend(reachable_functions(&funs, &f.name));
+        }
+        let (live, dead): (Vec<UserFn>, Vec<UserFn>) =
+            funs.into_iter().partition(|f| reachable.contains(&f.name));
+
+        if !dead.is_empty() && Program::size_warn_enabled() {
+            let names: Vec<String> = dead.iter().map(|f| f.name.clone()).collect();
+            warn(format!("removed unused functions: {}", names.join(", ")));
+        }
+
+        live
+    }
+}
+
+/// Find the names of the functions that can be called, directly or
+/// indirectly, from the function named `entry`. Every name that appears
+/// in a call counts, so foreign functions are included.
+pub fn reachable_functions(funs: &[UserFn], entry: &str) -> Vec<String> {
+    let mut reachable = vec![entry.to_string()];
+    let mut i = 0;
+    while i < reachable.len() {
+        let name = reachable[i].clone();
+        for f in funs.iter().filter(|f| f.name == name) {
+            let mut called = vec![];
+            calls_in_body(&f.body, &mut called);
+            for name in called {
+                if !reachable.contains(&name) {
+                    reachable.push(name);
+                }
+            }
+        }
+        i += 1;
+    }
+    reachable
+}
+
+/// Collects the names of the functions called, or the variables loaded
+struct Names<'a> {
+    names: &'a mut Vec<String>,
+    calls: bool,
+}
+
+impl Visit for Names<'_> {
+    fn visit_eval(&mut self, eval: &Eval) {
+        match eval {
+            Eval::Call(Call(name, ..)) if self.calls => self.names.push(name.clone()),
+            // A function whose reference is taken can be called through it
+            Eval::FnRef(FnRef(name)) if self.calls => self.names.push(name.clone()),
+            Eval::Load(Load(name)) if !self.calls => self.names.push(name.clone()),
+            _ => {}
+        }
+        walk_eval(self, eval)
+    }
+}
+
+/// Collect the names of every function called in a body
+pub fn calls_in_body(body: &[Expr], names: &mut Vec<String>) {
+    walk_body(&mut Names { names, calls: true }, body)
+}
+
+/// Collect the names of every function called in an expression
+pub fn calls_in_eval(eval: &Eval, names: &mut Vec<String>) {
+    Names { names, calls: true }.visit_eval(eval)
+}
+
+/// Collect the names of the functions whose references are taken in a body,
+/// leaving out the ones already collected
+pub fn fn_refs_in_body(body: &[Expr], names: &mut Vec<String>) {
+    struct FnRefs<'a>(&'a mut Vec<String>);
+
+    impl Visit for FnRefs<'_> {
+        fn visit_eval(&mut self, eval: &Eval) {
+            if let Eval::FnRef(FnRef(name)) = eval {
+                if !self.0.contains(name) {
+                    self.0.push(name.clone());
+                }
+            }
+            walk_eval(self, eval)
+        }
+    }
+
+    walk_body(&mut FnRefs(names), body)
+}
+
+/// Collect the names of every variable loaded in a body
+pub fn variables_in_body(body: &[Expr], names: &mut Vec<String>) {
+    walk_body(&mut Names { names, calls: false }, body)
+}
+
+/// Collect the names of every variable loaded in an expression
+pub fn variables_in_eval(eval: &Eval, names: &mut Vec<String>) {
+    Names { names, calls: false }.visit_eval(eval)
+}
+
+/// Counts the uses of each string literal. A literal that is assigned
+/// to or pointed to could be changed, so it is never shared.
+#[derive(Default)]
+struct Strings {
+    uses: Vec<(String, usize)>,
+    written: Vec<String>,
+}
+
+impl Visit for Strings {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::Assign(Assign(lhs, _)) = expr {
+            self.written.extend(strings_in(lhs));
+        }
+        walk_expr(self, expr)
+    }
+
+    fn visit_eval(&mut self, eval: &Eval) {
+        if let Eval::Refer(Refer(inner)) = eval {
+            self.written.extend(strings_in(inner));
+        }
+        walk_eval(self, eval)
+    }
+
+    fn visit_literal(&mut self, literal: &Literal) {
+        if let Literal::String(text) = literal {
+            match self.uses.iter_mut().find(|(used, _)| used == text) {
+                Some((_, count)) => *count += 1,
+                None => self.uses.push((text.clone(), 1)),
+            }
+        }
+    }
+}
+
+/// The string literals in an expression
+fn strings_in(eval: &Eval) -> Vec<String> {
+    let mut strings = Strings::default();
+    strings.visit_eval(eval);
+    strings.uses.into_iter().map(|(text, _)| text).collect()
+}
+
+/// The string literals used more than once in the functions, in the order they
+/// are first used, leaving out any that are assigned to or pointed to
+pub fn repeated_strings<'a>(funs: impl IntoIterator<Item = &'a UserFn>) -> Vec<String> {
+    let mut strings = Strings::default();
+    for fun in funs {
+        strings.visit_user_fn(fun);
+    }
+    let Strings { uses, written } = strings;
+    uses.into_iter()
+        .filter(|(text, count)| *count > 1 && !written.contains(text))
+        .map(|(text, _)| text)
+        .collect()
+}
+
+/// Replace calls to small functions with the body of the function.
+/// Parameters become definitions at the call site, every name in the
+/// body is renamed so it can't collide with the caller's variables, and
+/// the returned expression takes the place of the call.
+pub struct Inline;
+
+impl Inline {
+    /// The largest number of statements a function can have and still be inlined
+    const MAX_SIZE: usize = 8;
+
+    /// Can calls to this function be replaced with its body?
+    fn can_inline(f: &UserFn, funs: &[UserFn]) -> bool {
+        // Only the last statement may return, because there is
+        // no way to skip the rest of an inlined body
+        let returns_early = f.body.iter().enumerate().any(|(i, expr)| {
+            let last = i + 1 == f.body.len();
+            match expr {
+                Expr::Return(_) => !last,
+                Expr::If(If(_, then, otherwise)) => has_return(then) || has_return(otherwise),
+                Expr::While(While(_, body)) | Expr::ForRange(ForRange(_, _, _, body)) | Expr::Repeat(Repeat(_, body)) => {
+                    has_return(body)
+                }
+                _ => false,
+            }
+        });
+
+        let mut called = vec![];
+        calls_in_body(&f.body, &mut called);
+        let recursive = called.iter().any(|name| reachable_functions(funs, name).contains(&f.name));
+
+        // Types are checked when the callee is called and its return statements are compiled
+        let typed = f.return_type.is_some() || f.parameter_types.iter().any(Option::is_some);
+
+        // A reference parameter must stay bound to the caller's variable
+        let borrows = f.reference_parameters.contains(&true);
+
+        // Every name in the body is renamed, so the body must not use global variables
+        let mut loaded = vec![];
+        variables_in_body(&f.body, &mut loaded);
+        let uses_globals = loaded
+            .iter()
+            .any(|name| !f.parameters.contains(name) && !defines(&f.body, name));
+
+        // An attribute can ask for any size of function to be inlined, or for none to be
+        let size_allowed = body_size(&f.body) <= Self::MAX_SIZE || f.has_attribute(FnAttr::Inline);
+
+        size_allowed
+            && !f.has_attribute(FnAttr::NeverInline)
+            && !returns_early
+            && !recursive
+            && !uses_pointers(&f.body)
+            && !typed
+            && !borrows
+            && !uses_globals
+    }
+
+}
+
+/// Expands the calls to inlinable functions made by statements
+struct Inliner<'a> {
+    inlinable: &'a [UserFn],
+    funs: &'a [UserFn],
+    /// The number of calls expanded so far, which keeps the names of each expansion apart
+    count: usize,
+}
+
+impl Inliner<'_> {
+    /// Expand a call into the statements of the called function, and the
+    /// expression it returns if the value is needed. The returned expression
+    /// must be evaluated after the statements.
+    fn expand(&mut self, call: &Call, needs_value: bool) -> Option<(Vec<Expr>, Option<Eval>)> {
+        let Call(name, args, named) = call;
+        let f = self.inlinable.iter().find(|f| &f.name == name && f.accepts(args.len() + named.len()))?;
+        // A call with named arguments is inlined if they bind, otherwise compiling it reports why
+        let args = &bind_arguments(name, &f.parameters, &f.parameter_defaults, args, named).ok()?;
+        let returns = matches!(f.body.last(), Some(Expr::Return(_)));
+        if args.len() != f.parameters.len() || (needs_value && !returns) {
+            return None;
+        }
+
+        let prefix = format!("%INLINE{}_{}%", self.count, name);
+        self.count += 1;
+
+        // Arguments that are variables or literals are substituted for
+        // parameters that are never written, so they don't need a copy.
+        // Passing a parameter to a reference parameter can write to it too.
+        let mut body = vec![];
+        let mut substitutions = vec![];
+        for (param, arg) in f.parameters.iter().zip(args) {
+            match arg {
+                Eval::Load(_) | Eval::Literal(_) if !writes_to(&f.body, param) && !borrows(&f.body, param, self.funs) => {
+                    substitutions.push((param.clone(), arg.clone()))
+                }
+                _ => body.push(Expr::Define(Define(prefix.clone() + param, arg.clone()))),
+            }
+        }
+
+        let mut value = None;
+        let mut rename = Rename { prefix: &prefix, substitutions: &substitutions };
+        for expr in rename.fold_body(f.body.clone()) {
+            match expr {
+                Expr::Return(Return(e)) if needs_value => value = Some(e),
+                Expr::Return(Return(e)) => body.push(Expr::Eval(e)),
+                // Errors in the inlined statements are reported at the call
+                Expr::Locate(_) => {}
+                other => body.push(other),
+            }
+        }
+        Some((body, value))
+    }
+}
+
+impl Fold for Inliner<'_> {
+    fn fold_body(&mut self, body: Vec<Expr>) -> Vec<Expr> {
+        let mut result = vec![];
+        for expr in body {
+            match expr {
+                Expr::Eval(Eval::Call(call)) => match self.expand(&call, false) {
+                    Some((body, _)) => result.extend(body),
+                    None => result.push(Expr::Eval(Eval::Call(call))),
+                },
+                Expr::Define(Define(name, Eval::Call(call))) => match self.expand(&call, true) {
+                    Some((body, Some(val))) => {
+                        result.extend(body);
+                        result.push(Expr::Define(Define(name, val)));
+                    }
+                    _ => result.push(Expr::Define(Define(name, Eval::Call(call)))),
+                },
+                Expr::Assign(Assign(lhs, Eval::Call(call))) if !has_call(&lhs) => match self.expand(&call, true) {
+                    Some((body, Some(val))) => {
+                        result.extend(body);
+                        result.push(Expr::Assign(Assign(lhs, val)));
+                    }
+                    _ => result.push(Expr::Assign(Assign(lhs, Eval::Call(call)))),
+                },
+                Expr::Return(Return(Eval::Call(call))) => match self.expand(&call, true) {
+                    Some((body, Some(val))) => {
+                        result.extend(body);
+                        result.push(Expr::Return(Return(val)));
+                    }
+                    _ => result.push(Expr::Return(Return(Eval::Call(call)))),
+                },
+                Expr::If(If(condition, then, otherwise)) => {
+                    let then = self.fold_body(then);
+                    let otherwise = self.fold_body(otherwise);
+                    let condition = match condition {
+                        Eval::Call(call) => match self.expand(&call, true) {
+                            Some((body, Some(val))) => {
+                                result.extend(body);
+                                val
+                            }
+                            _ => Eval::Call(call),
+                        },
+                        other => other,
+                    };
+                    result.push(Expr::If(If(condition, then, otherwise)));
+                }
+                Expr::While(While(condition, body)) => result.push(Expr::While(While(condition, self.fold_body(body)))),
+                Expr::ForRange(ForRange(var, start, end, body)) => {
+                    result.push(Expr::ForRange(ForRange(var, start, end, self.fold_body(body))))
+                }
+                Expr::Repeat(Repeat(count, body)) => result.push(Expr::Repeat(Repeat(count, self.fold_body(body)))),
+                other => result.push(other),
+            }
+        }
+        result
+    }
+}
+
+impl Pass for Inline {
+    fn level(&self) -> u8 {
+        2
+    }
+
+    fn transform(&self, funs: Vec<UserFn>) -> Vec<UserFn> {
+        let inlinable: Vec<UserFn> = funs.iter().filter(|f| Self::can_inline(f, &funs)).cloned().collect();
+        let all = funs.clone();
+        let mut inliner = Inliner { inlinable: &inlinable, funs: &all, count: 0 };
+        funs.into_iter().map(|f| inliner.fold_user_fn(f)).collect()
+    }
+}
+
+/// Count the statements in a body, including nested statements
+fn body_size(body: &[Expr]) -> usize {
+    body.iter()
+        .map(|expr| match expr {
+            Expr::If(If(_, then, otherwise)) => 1 + body_size(then) + body_size(otherwise),
+            Expr::While(While(_, body)) | Expr::ForRange(ForRange(_, _, _, body)) | Expr::Repeat(Repeat(_, body)) => {
+                1 + body_size(body)
+            }
+            Expr::Locate(_) => 0,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Does a body contain a return statement at any depth?
+fn has_return(body: &[Expr]) -> bool {
+    body.iter().any(|expr| match expr {
+        Expr::Return(_) => true,
+        Expr::If(If(_, then, otherwise)) => has_return(then) || has_return(otherwise),
+        Expr::While(While(_, body)) | Expr::ForRange(ForRange(_, _, _, body)) | Expr::Repeat(Repeat(_, body)) => {
+            has_return(body)
+        }
+        _ => false,
+    })
+}
+
+/// Does an expression contain a function call?
+fn has_call(eval: &Eval) -> bool {
+    let mut names = vec![];
+    calls_in_eval(eval, &mut names);
+    !names.is_empty()
+}
+
+/// Does a body reference or dereference anything?
+fn uses_pointers(body: &[Expr]) -> bool {
+    fn eval_uses_pointers(eval: &Eval) -> bool {
+        match eval {
+            Eval::Deref(_) | Eval::Refer(_) | Eval::Index(_) | Eval::Field(_) => true,
+            Eval::Call(Call(_, args, named)) => {
+                args.iter().chain(named.iter().map(|(_, arg)| arg)).any(eval_uses_pointers)
+            }
+            Eval::CallIndirect(CallIndirect(callee, args)) => {
+                eval_uses_pointers(callee) || args.iter().any(eval_uses_pointers)
+            }
+            Eval::Concat(Concat(lhs, rhs)) => eval_uses_pointers(lhs) || eval_uses_pointers(rhs),
+            Eval::Construct(Construct(_, fields)) => fields.iter().any(|(_, value)| eval_uses_pointers(value)),
+            Eval::Tuple(Tuple(elements)) => elements.iter().any(eval_uses_pointers),
+            _ => false,
+        }
+    }
+
+    body.iter().any(|expr| match expr {
+        Expr::If(If(condition, then, otherwise)) => {
+            eval_uses_pointers(condition) || uses_pointers(then) || uses_pointers(otherwise)
+        }
+        Expr::While(While(condition, body)) => eval_uses_pointers(condition) || uses_pointers(body),
+        Expr::ForRange(ForRange(_, start, end, body)) => {
+            eval_uses_pointers(start) || eval_uses_pointers(end) || uses_pointers(body)
+        }
+        Expr::Repeat(Repeat(_, body)) => uses_pointers(body),
+        Expr::Eval(e) | Expr::Define(Define(_, e)) | Expr::Destructure(Destructure(_, e)) | Expr::Const(Const(_, e))
+        | Expr::Return(Return(e))
+        | Expr::Assert(Assert(e, _))
+        | Expr::Exit(Exit(Some(e))) => eval_uses_pointers(e),
+        Expr::Exit(Exit(None)) => false,
+        Expr::Assign(Assign(lhs, rhs)) => eval_uses_pointers(lhs) || eval_uses_pointers(rhs),
+        Expr::Locate(_) => false,
+    })
+}
+
+/// Is a variable or constant defined anywhere in a body?
+pub(crate) fn defines(body: &[Expr], name: &str) -> bool {
+    body.iter().any(|expr| match expr {
+        Expr::If(If(_, then, otherwise)) => defines(then, name) || defines(otherwise, name),
+        Expr::While(While(_, body)) | Expr::Repeat(Repeat(_, body)) => defines(body, name),
+        Expr::ForRange(ForRange(var, _, _, body)) => var == name || defines(body, name),
+        Expr::Define(Define(var, _)) | Expr::Const(Const(var, _)) => var == name,
+        Expr::Destructure(Destructure(vars, _)) => vars.iter().any(|var| var == name),
+        _ => false,
+    })
+}
+
+/// Is a variable assigned to or defined anywhere in a body?
+fn writes_to(body: &[Expr], name: &str) -> bool {
+    body.iter().any(|expr| match expr {
+        Expr::If(If(_, then, otherwise)) => writes_to(then, name) || writes_to(otherwise, name),
+        Expr::While(While(_, body)) | Expr::Repeat(Repeat(_, body)) => writes_to(body, name),
+        Expr::ForRange(ForRange(var, _, _, body)) => var == name || writes_to(body, name),
+        Expr::Define(Define(var, _)) | Expr::Const(Const(var, _)) => var == name,
+        Expr::Destructure(Destructure(vars, _)) => vars.iter().any(|var| var == name),
+        Expr::Assign(Assign(Eval::Load(Load(var)), _)) => var == name,
+        Expr::Assign(Assign(Eval::Index(Index(base, _)), _)) | Expr::Assign(Assign(Eval::Field(Field(base, _)), _)) => {
+            **base == Eval::Load(Load(name.to_string()))
+        }
+        _ => false,
+    })
+}
+
+/// Adds a prefix to every variable name, and replaces
+/// the substituted variables with their expressions
+struct Rename<'a> {
+    prefix: &'a str,
+    substitutions: &'a [(String, Eval)],
+}
+
+impl Fold for Rename<'_> {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match rebuild_expr(self, expr) {
+            Expr::Define(Define(name, e)) => Expr::Define(Define(self.prefix.to_string() + &name, e)),
+            Expr::Destructure(Destructure(names, e)) => Expr::Destructure(Destructure(
+                names.into_iter().map(|name| self.prefix.to_string() + &name).collect(),
+                e,
+            )),
+            Expr::Const(Const(name, e)) => Expr::Const(Const(self.prefix.to_string() + &name, e)),
+            Expr::ForRange(ForRange(var, start, end, body)) => {
+                Expr::ForRange(ForRange(self.prefix.to_string() + &var, start, end, body))
+            }
+            other => other,
+        }
+    }
+
+    fn fold_eval(&mut self, eval: Eval) -> Eval {
+        match eval {
+            Eval::Load(Load(name)) => match self.substitutions.iter().find(|(var, _)| *var == name) {
+                Some((_, e)) => e.clone(),
+                None => Eval::Load(Load(self.prefix.to_string() + &name)),
+            },
+            other => rebuild_eval(self, other),
+        }
+    }
+}
+
+/// Replaces the name of every function called
+struct RenameCalls<'a>(&'a dyn Fn(&str) -> String);
+
+impl Fold for RenameCalls<'_> {
+    fn fold_eval(&mut self, eval: Eval) -> Eval {
+        match rebuild_eval(self, eval) {
+            Eval::Call(Call(name, args, named)) => Eval::Call(Call((self.0)(&name), args, named)),
+            Eval::FnRef(FnRef(name)) => Eval::FnRef(FnRef((self.0)(&name))),
+            other => other,
+        }
+    }
+}
+
+/// Replace the name of every function called in a body
+pub fn rename_calls(body: &[Expr], rename: &dyn Fn(&str) -> String) -> Vec<Expr> {
+    RenameCalls(rename).fold_body(body.to_vec())
+}
+
+/// Turn functions whose only recursive calls are returned from the end
+/// of the body, or from the end of either branch of an if at the end of
+/// the body, into loops. A function that never returns a value may instead
+/// call itself as its last statement. Each tail call assigns the new
+/// arguments to the parameters and runs the body again. Functions can't
+/// recurse otherwise, because every call is expanded where it is made.
+///
+/// A return doesn't stop a function, so in `if c { return f(..); } return x;`
+/// the call isn't a tail call, because `x` replaces its value. Only an
+/// explicit `else` makes it one, so the loop always gives the same value
+/// as expanding the calls would.
+pub struct TailCalls;
+
+impl TailCalls {
+    const CONTINUE: &'static str = "%TAIL_CONTINUE%";
+
+    /// Is a call a call of `f` with every argument given in order?
+    fn calls_self(call: &Call, f: &UserFn) -> bool {
+        let Call(name, args, named) = call;
+        *name == f.name && args.len() == f.parameters.len() && named.is_empty()
+    }
+
+    /// Replace the tail calls to `f` in a body, counting how many were replaced
+    fn replace_tail_calls(mut body: Vec<Expr>, f: &UserFn, count: &mut usize) -> Vec<Expr> {
+        match body.pop() {
+            Some(Expr::Return(Return(Eval::Call(call)))) if Self::calls_self(&call, f) => {
+                Self::push_tail_call(&mut body, call.1, f);
+                *count += 1;
+            }
+            // The value of a call made as a statement is thrown away, so
+            // it is a tail call too if the function never returns a value
+            Some(Expr::Eval(Eval::Call(call))) if Self::calls_self(&call, f) && !has_return(&f.body) => {
+                Self::push_tail_call(&mut body, call.1, f);
+                *count += 1;
+            }
+            Some(Expr::If(If(condition, then, otherwise))) => {
+                let then = Self::replace_tail_calls(then, f, count);
+                let otherwise = Self::replace_tail_calls(otherwise, f, count);
+                body.push(Expr::If(If(condition, then, otherwise)));
+            }
+            Some(other) => body.push(other),
+            None => {}
+        }
+        body
+    }
+
+    /// The calls returned from the end of a body, or from the end of
+    /// either branch of an if at the end of the body
+    fn tail_calls(body: &[Expr]) -> Vec<&Call> {
+        match body.last() {
+            Some(Expr::Return(Return(Eval::Call(call)))) => vec![call],
+            Some(Expr::If(If(_, then, otherwise))) => {
+                let mut calls = Self::tail_calls(then);
+                calls.extend(Self::tail_calls(otherwise));
+                calls
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Replace the tail calls to the functions of a group with calls of
+    /// the function they are merged into, counting how many were replaced
+    fn redirect_tail_calls(mut body: Vec<Expr>, group: &[&UserFn], merged: &str, width: usize, count: &mut usize) -> Vec<Expr> {
+        match body.pop() {
+            Some(Expr::Return(Return(Eval::Call(call)))) => {
+                match group.iter().position(|g| Self::calls_self(&call, g)) {
+                    Some(which) => {
+                        body.push(Expr::Return(Return(Eval::Call(Self::dispatch(merged, which, call.1, width)))));
+                        *count += 1;
+                    }
+                    None => body.push(Expr::Return(Return(Eval::Call(call)))),
+                }
+            }
+            Some(Expr::If(If(condition, then, otherwise))) => {
+                let then = Self::redirect_tail_calls(then, group, merged, width, count);
+                let otherwise = Self::redirect_tail_calls(otherwise, group, merged, width, count);
+                body.push(Expr::If(If(condition, then, otherwise)));
+            }
+            Some(other) => body.push(other),
+            None => {}
+        }
+        body
+    }
+
+    /// A call of the merged function that runs the body of the function
+    /// numbered `which`, with unused arguments filled with zeros
+    fn dispatch(merged: &str, which: usize, mut args: Vec<Eval>, width: usize) -> Call {
+        args.resize(width, Eval::Literal(Literal::byte_int(0)));
+        args.insert(0, Eval::Literal(Literal::byte_int(which as u8)));
+        Call(merged.to_string(), args, vec![])
+    }
+
+    /// Merge functions that call each other only from their ends, like an
+    /// `is_even` that returns `is_odd(..)` and an `is_odd` that returns
+    /// `is_even(..)`, into one function that takes the number of the function
+    /// to run and calls itself from its end instead. Each of the functions
+    /// is left calling the merged function, which becomes a loop.
+    fn merge_mutual_calls(mut funs: Vec<UserFn>) -> Vec<UserFn> {
+        // Assigning to a reference parameter would change the caller's
+        // variable, instead of passing a new argument
+        let eligible = |f: &UserFn| !f.reference_parameters.contains(&true);
+        let callees = |f: &UserFn| -> Vec<String> {
+            let callees = Self::tail_calls(&f.body).into_iter().filter_map(|call| {
+                funs.iter().find(|g| eligible(g) && Self::calls_self(call, g)).map(|g| g.name.clone())
+            });
+            if eligible(f) { callees.collect() } else { vec![] }
+        };
+        let edges: Vec<(String, Vec<String>)> = funs.iter().map(|f| (f.name.clone(), callees(f))).collect();
+        let reach = |from: &str| {
+            let mut reached = vec![from.to_string()];
+            let mut i = 0;
+            while i < reached.len() {
+                let from = reached[i].clone();
+                for (_, to) in edges.iter().filter(|(name, _)| *name == from) {
+                    for name in to {
+                        if !reached.contains(name) {
+                            reached.push(name.clone());
+                        }
+                    }
+                }
+                i += 1;
+            }
+            reached
+        };
+
+        let mut merged_fns = vec![];
+        let mut grouped: Vec<String> = vec![];
+        for f in &funs {
+            if grouped.contains(&f.name) {
+                continue;
+            }
+            // The functions that f reaches with tail calls, and that reach f back
+            let group: Vec<&UserFn> = funs
+                .iter()
+                .filter(|g| g.name == f.name || (reach(&f.name).contains(&g.name) && reach(&g.name).contains(&f.name)))
+                .collect();
+            if group.len() < 2 {
+                continue;
+            }
+
+            let names: Vec<String> = group.iter().map(|g| g.name.clone()).collect();
+            let merged = qualify(namespace_of(&f.name), &format!("%{}%", names.join("|")));
+            let width = group.iter().map(|g| g.parameters.len()).max().unwrap_or(0);
+            let mut count = 0;
+            let bodies: Vec<Vec<Expr>> = group
+                .iter()
+                .map(|g| {
+                    let mut body: Vec<Expr> = g
+                        .parameters
+                        .iter()
+                        .enumerate()
+                        .map(|(i, param)| Expr::Define(Define(param.clone(), Eval::Load(Load(format!("%ARG_{}%", i))))))
+                        .collect();
+                    body.extend(Self::redirect_tail_calls(g.body.clone(), &group, &merged, width, &mut count));
+                    body
+                })
+                .collect();
+
+            // Give up if the functions call each other anywhere else
+            let mut called = vec![];
+            for body in &bodies {
+                calls_in_body(body, &mut called);
+            }
+            if group.iter().any(|g| called.contains(&g.name)) {
+                continue;
+            }
+
+            // Run the body of the function numbered `%WHICH%`, counting it down
+            let mut dispatch = bodies.last().cloned().unwrap_or_default();
+            for body in bodies.into_iter().rev().skip(1) {
+                dispatch = vec![Expr::If(If(
+                    Eval::Load(Load("%WHICH%".to_string())),
+                    vec![Expr::Assign(Assign(
+                        Eval::Load(Load("%WHICH%".to_string())),
+                        Eval::Call(Call("sub".to_string(), vec![Eval::Load(Load("%WHICH%".to_string())), Eval::Literal(Literal::byte_int(1))], vec![])),
+                    ))]
+                    .into_iter()
+                    .chain(dispatch)
+                    .collect(),
+                    body,
+                ))];
+            }
+
+            let mut parameters = vec!["%WHICH%".to_string()];
+            parameters.extend((0..width).map(|i| format!("%ARG_{}%", i)));
+            let return_type = Some(group[0].return_type).filter(|t| group.iter().all(|g| g.return_type == *t)).flatten();
+            merged_fns.push((UserFn { return_type, ..UserFn::new(&merged, parameters, dispatch) }, names.clone(), width));
+            grouped.extend(names);
+        }
+
+        // Each function of a group runs its body in the merged function
+        for (merged, names, width) in &merged_fns {
+            for (which, name) in names.iter().enumerate() {
+                if let Some(f) = funs.iter_mut().find(|f| f.name == *name) {
+                    let args = f.parameters.iter().map(|param| Eval::Load(Load(param.clone()))).collect();
+                    f.body = vec![Expr::Return(Return(Eval::Call(Self::dispatch(&merged.name, which, args, *width))))];
+                }
+            }
+        }
+        funs.extend(merged_fns.into_iter().map(|(merged, ..)| merged));
+        funs
+    }
+
+    /// Pass new arguments to the parameters, and run the body again
+    fn push_tail_call(body: &mut Vec<Expr>, args: Vec<Eval>, f: &UserFn) {
+        // Every argument is computed before any parameter changes
+        for (i, arg) in args.into_iter().enumerate() {
+            body.push(Expr::Define(Define(format!("%TAIL_ARG_{}%", i), arg)));
+        }
+        for (i, param) in f.parameters.iter().enumerate() {
+            body.push(Expr::Assign(Assign(
+                Eval::Load(Load(param.clone())),
+                Eval::Load(Load(format!("%TAIL_ARG_{}%", i))),
+            )));
+        }
+        body.push(Expr::Assign(Assign(
+            Eval::Load(Load(Self::CONTINUE.to_string())),
+            Eval::Literal(Literal::byte_int(1)),
+        )));
+    }
+}
+
+impl Pass for TailCalls {
+    fn level(&self) -> u8 {
+        1
+    }
+
+    fn transform(&self, funs: Vec<UserFn>) -> Vec<UserFn> {
+        Self::merge_mutual_calls(funs)
+            .into_iter()
+            .map(|f| {
+                // Assigning to a reference parameter would change the
+                // caller's variable, instead of passing a new argument
+                if f.reference_parameters.contains(&true) {
+                    return f;
+                }
+
+                let mut count = 0;
+                let body = Self::replace_tail_calls(f.body.clone(), &f, &mut count);
+
+                // Give up if the function calls itself anywhere else
+                let mut called = vec![];
+                calls_in_body(&body, &mut called);
+                if count == 0 || called.contains(&f.name) {
+                    return f;
+                }
+
+                let mut loop_body = vec![Expr::Assign(Assign(
+                    Eval::Load(Load(Self::CONTINUE.to_string())),
+                    Eval::Literal(Literal::byte_int(0)),
+                ))];
+                loop_body.extend(body);
+                UserFn {
+                    body: vec![
+                        Expr::Define(Define(Self::CONTINUE.to_string(), Eval::Literal(Literal::byte_int(1)))),
+                        Expr::While(While(Eval::Load(Load(Self::CONTINUE.to_string())), loop_body)),
+                    ],
+                    ..f
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{reset, stdlib, Error, Interpreter, Program, RunError};
+
+/// The most steps `run` executes before it stops a program
+pub const DEFAULT_STEP_LIMIT: u64 = 100_000_000;
+
+/// What a program did while it ran
+#[derive(Clone, Debug, PartialEq)]
+pub struct Run {
+    /// The bytes the program printed
+    pub output: Vec<u8>,
+    /// The number of steps executed. A run of the same instruction, and the
+    /// loop `[-]` that clears a cell, each take one step.
+    pub steps: u64,
+    /// The cells of the tape when the program stopped
+    pub tape: Vec<u32>,
+}
+
+/// How a program stopped running
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunOutcome {
+    /// The program ran to its end
+    Finished(Run),
+    /// The program stopped itself with this exit status, with `exit` or a failed `assert`
+    Exited(Run, u8),
+    /// The program executed as many steps as it was allowed to,
+    /// which usually means it loops forever
+    TimedOut(Run),
+    /// The program did something the machine can't do, such as moving off the tape
+    Faulted(Run, String),
+}
+
+impl RunOutcome {
+    /// What the program did, however it stopped
+    pub fn run(&self) -> &Run {
+        match self {
+            Self::Finished(run) | Self::Exited(run, _) | Self::TimedOut(run) | Self::Faulted(run, _) => run,
+        }
+    }
+
+    /// The bytes the program printed
+    pub fn output(&self) -> &[u8] {
+        &self.run().output
+    }
+
+    /// Did the program run to its end?
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Finished(_))
+    }
+
+    /// The exit status the program stopped itself with, if it did
+    pub fn exit_status(&self) -> Option<u8> {
+        match self {
+            Self::Exited(_, status) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+/// Compile a program with the standard library, and run it on some input.
+/// The compiler's state is reset first, so any number of programs can be run one after another.
+pub fn run(source: &str, input: &[u8]) -> Result<RunOutcome, Error> {
+    run_with_step_limit(source, input, DEFAULT_STEP_LIMIT)
+}
+
+/// Compile and run a program, stopping it after it executes `step_limit` steps
+pub fn run_with_step_limit(source: &str, input: &[u8], step_limit: u64) -> Result<RunOutcome, Error> {
+    reset();
+    let (program, mut errors) = Program::parse_all(source);
+    if !errors.is_empty() {
+        return Err(errors.remove(0));
+    }
+    let program = program.unwrap_or_else(|| Program::new(vec![], vec![]));
+
+    stdlib::install();
+    let code = program.compile()?;
+    let mut interpreter = Interpreter::new(input, vec![]).with_step_limit(step_limit);
+    let result = interpreter.run(&code);
+    let exit_status = interpreter.exit_status();
+    let run = Run {
+        steps: interpreter.steps(),
+        tape: interpreter.tape().to_vec(),
+        output: interpreter.into_output(),
+    };
+    Ok(match (result, exit_status) {
+        (Ok(()), Some(status)) => RunOutcome::Exited(run, status),
+        (Ok(()), None) => RunOutcome::Finished(run),
+        (Err(RunError::StepLimit(_)), _) => RunOutcome::TimedOut(run),
+        (Err(e), _) => RunOutcome::Faulted(run, e.to_string()),
+    })
+}
@@ -1,4 +1,4 @@
-use crate::Program;
+use crate::{exit_status_cell, Program};
 
 pub trait Simplify {
     fn prelude() -> String;
@@ -7,6 +7,7 @@ pub trait Simplify {
 }
 
 
+#[derive(Default)]
 pub struct C;
 impl C {
     pub fn new() -> Self {
@@ -19,19 +20,26 @@ impl Simplify for C {
         format!(r#"#include <stdio.h>
 const int TAPE_SIZE = {TAPE_SIZE};
 const int REF_TAPE_SIZE = {REF_TAPE_SIZE};
+const int MAX_ADDRESS = {MAX_ADDRESS};
+const int HEAP_START = {HEAP_START};
 
-unsigned short tape[{TAPE_SIZE}];
+{CELL_TYPE} tape[{TAPE_SIZE}];
 unsigned int ref_tape[{TAPE_SIZE}];
 unsigned int ptr = 0;
 unsigned int ref_ptr = 0;
 
 unsigned int allocate() {{
-    unsigned int size = tape[ptr]; 
-    int cons_empty_spaces = 0; 
-    for (int i=TAPE_SIZE-1; i>0; i--) {{
-        if (tape[i] == 0) {{ cons_empty_spaces++; }}
-        else {{ cons_empty_spaces = 0; }}
-        if (cons_empty_spaces == size) {{ return i; }}
+    unsigned int size = tape[ptr] ? tape[ptr] : 1;
+    unsigned int start = HEAP_START;
+    unsigned int i = start;
+    while (i < MAX_ADDRESS) {{
+        if (tape[i] == 0) {{
+            i++;
+            if (i - start == size + 1) {{ tape[start] = size; return start + 1; }}
+        }} else {{
+            i += tape[i] + 1;
+            start = i;
+        }}
     }}
     return 0;
 }}
@@ -67,7 +75,13 @@ void refer() {{
 }}
 
 int main() {{
-"#, TAPE_SIZE = Program::tape_size(), REF_TAPE_SIZE = 256)
+"#,
+            TAPE_SIZE = Program::tape_size(),
+            REF_TAPE_SIZE = 256,
+            MAX_ADDRESS = Program::max_address(),
+            HEAP_START = Program::heap_start(),
+            CELL_TYPE = Program::cell_width().c_type()
+        )
     }
 
     fn postlude() -> String {
@@ -80,7 +94,7 @@ int main() {{
         let mut last = '\0';
 
         let mut filtered = s.to_string().chars().filter(|c| ['>', '<', '+', '-', '*', '&', '?', '[', ']', '.', ','].contains(c)).collect::<String>();
-        filtered = filtered.replace("[-]", "0");
+        filtered = filtered.replace("[-]", "0").replace("[]", "h");
 
         for ch in filtered.chars() {
             if ch == last {
@@ -97,6 +111,8 @@ int main() {{
                     '?' => "tape[ptr] = allocate();".repeat(repeated),
                     '[' => "while (tape[ptr]) {".repeat(repeated),
                     ']' => "}\n".repeat(repeated),
+                    // An empty loop on a cell that isn't zero never ends, so the program stops
+                    'h' => format!("if (tape[ptr]) {{ return tape[{}] % 256; }}", exit_status_cell().offset).repeat(repeated),
                     '.' => "printf(\"%c\",(char)(tape[ptr]%256));".repeat(repeated),
                     ',' => "scanf(\"%c\", (char*)&tape[ptr]);".repeat(repeated),
                     _ => String::new()
@@ -0,0 +1,441 @@
+use crate::{
+    namespace_of, Assert, Assign, Exit, CellWidth, Const, Define, Destructure, Eval, Expr, Flag, FnAttr, ForRange, If, Literal, Load, Program, Repeat, Return, Struct,
+    UserFn, While,
+};
+use std::fmt;
+
+/// The indentation of each nested block
+const INDENT: &str = "    ";
+
+/// Escape a string or character literal so it reads back the same, inside the quote it is written in
+pub fn escape(text: &str, quote: char) -> String {
+    let mut result = String::new();
+    for ch in text.chars() {
+        match ch {
+            '\n' => result += "\\n",
+            '\r' => result += "\\r",
+            '\t' => result += "\\t",
+            '\0' => result += "\\0",
+            '\\' => result += "\\\\",
+            ch if ch == quote => {
+                result.push('\\');
+                result.push(ch);
+            }
+            ch => result.push(ch),
+        }
+    }
+    result
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "\"{}\"", escape(s, '"')),
+            Self::Character(ch) => write!(f, "'{}'", escape(&ch.to_string(), '\'')),
+            Self::ByteInt(n) => write!(f, "{}", n),
+            Self::UnsignedShort(n) => write!(f, "{}", n),
+            Self::Unsigned4ByteInt(n) => write!(f, "{}", n),
+            Self::Signed4ByteInt(n) => write!(f, "{}", n),
+            // Always written with a point, so it isn't read back as an integer
+            Self::Fixed(n) => write!(f, "{:?}", n),
+            Self::Bytes(bytes) => {
+                let text: String = bytes.iter().map(|&byte| byte as char).collect();
+                write!(f, "b\"{}\"", escape(&text, '"'))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::EnableBrainFuck => write!(f, "#[enable(brainfuck)]"),
+            Self::EnableSizeWarn => write!(f, "#[enable(size_warn)]"),
+            Self::EmitDebugComments => write!(f, "#[enable(debug_comments)]"),
+            Self::DisablePtrs => write!(f, "#[disable(ptrs)]"),
+            Self::StripAsserts => write!(f, "#[disable(asserts)]"),
+            Self::OverflowChecks => write!(f, "#[enable(overflow_checks)]"),
+            Self::BoundsChecks => write!(f, "#[enable(bounds_checks)]"),
+            Self::Utf8Strings => write!(f, "#[enable(utf8_strings)]"),
+            Self::DisableInterning => write!(f, "#[disable(interning)]"),
+            Self::HeapSize(size) => write!(f, "#[heap_size({})]", size),
+            Self::TapeSize(size) => write!(f, "#[tape_size({})]", size),
+            Self::RecursionLimit(limit) => write!(f, "#[recursion_limit({})]", limit),
+            Self::CellSize(CellWidth::Eight) => write!(f, "#[cell_size(8)]"),
+            Self::CellSize(CellWidth::Sixteen) => write!(f, "#[cell_size(16)]"),
+            Self::Optimize(level) => write!(f, "#[optimize({})]", level),
+            Self::EofValue(value) => write!(f, "#[eof({})]", value),
+            Self::EntryPoint(name) => write!(f, "#[entry_point({})]", name),
+            Self::Allow(lint) => write!(f, "#[allow({})]", lint),
+        }
+    }
+}
+
+/// How tightly an expression binds, from the loosest to the tightest
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    Init,
+    Value,
+    Term,
+    Postfix,
+}
+
+impl Eval {
+    fn precedence(&self) -> Precedence {
+        match self {
+            Self::Construct(_) => Precedence::Init,
+            Self::Concat(_) => Precedence::Value,
+            Self::Deref(_) => Precedence::Term,
+            _ => Precedence::Postfix,
+        }
+    }
+
+    /// Write the expression where it must bind at least as tightly as `at_least`
+    fn write_at(&self, f: &mut fmt::Formatter, at_least: Precedence) -> fmt::Result {
+        if self.precedence() < at_least {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Eval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Load(Load(name)) => write!(f, "{}", name),
+            Self::Literal(literal) => write!(f, "{}", literal),
+            Self::Call(call) => {
+                write!(f, "{}(", call.0)?;
+                for (i, arg) in call.1.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                for (i, (parameter, arg)) in call.2.iter().enumerate() {
+                    if i > 0 || !call.1.is_empty() {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", parameter, arg)?;
+                }
+                write!(f, ")")
+            }
+            Self::FnRef(fn_ref) => write!(f, "&fn {}", fn_ref.0),
+            Self::CallIndirect(call) => {
+                // A bare name before the arguments would be read back as a direct call
+                match &*call.0 {
+                    Eval::Load(Load(name)) => write!(f, "({})(", name)?,
+                    callee => {
+                        callee.write_at(f, Precedence::Postfix)?;
+                        write!(f, "(")?;
+                    }
+                }
+                for (i, arg) in call.1.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Self::Deref(deref) => {
+                write!(f, "*")?;
+                deref.0.write_at(f, Precedence::Term)
+            }
+            Self::Refer(refer) => {
+                write!(f, "&")?;
+                refer.0.write_at(f, Precedence::Postfix)
+            }
+            Self::Concat(concat) => {
+                concat.0.write_at(f, Precedence::Value)?;
+                write!(f, " ++ ")?;
+                concat.1.write_at(f, Precedence::Term)
+            }
+            Self::Index(index) => {
+                index.0.write_at(f, Precedence::Postfix)?;
+                write!(f, "[")?;
+                index.1.write_at(f, Precedence::Value)?;
+                write!(f, "]")
+            }
+            Self::Construct(construct) => {
+                write!(f, "{} {{ ", construct.0)?;
+                for (i, (field, value)) in construct.1.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", field, value)?;
+                }
+                write!(f, " }}")
+            }
+            Self::Field(field) => {
+                field.0.write_at(f, Precedence::Postfix)?;
+                write!(f, ".{}", field.1)
+            }
+            Self::Tuple(tuple) => {
+                write!(f, "(")?;
+                for (i, element) in tuple.0.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, ")")
+            }
+            // Values only exist while compiling, so they have no source
+            Self::Value(_) => write!(f, "%VALUE%"),
+        }
+    }
+}
+
+/// Write statements, each on its own line at a depth of indentation
+fn write_body(f: &mut fmt::Formatter, body: &[Expr], depth: usize) -> fmt::Result {
+    for expr in body {
+        if let Expr::Locate(_) = expr {
+            continue;
+        }
+        write!(f, "{}", INDENT.repeat(depth))?;
+        write_expr(f, expr, depth)?;
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+fn write_expr(f: &mut fmt::Formatter, expr: &Expr, depth: usize) -> fmt::Result {
+    match expr {
+        Expr::If(If(condition, then, otherwise)) => {
+            writeln!(f, "if {} {{", condition)?;
+            write_body(f, then, depth + 1)?;
+            write!(f, "{}}}", INDENT.repeat(depth))?;
+            if !otherwise.is_empty() {
+                writeln!(f, " else {{")?;
+                write_body(f, otherwise, depth + 1)?;
+                write!(f, "{}}}", INDENT.repeat(depth))?;
+            }
+            Ok(())
+        }
+        Expr::While(While(condition, body)) => {
+            writeln!(f, "while {} {{", condition)?;
+            write_body(f, body, depth + 1)?;
+            write!(f, "{}}}", INDENT.repeat(depth))
+        }
+        Expr::ForRange(ForRange(var, start, end, body)) => {
+            writeln!(f, "for {} in {}..{} {{", var, start, end)?;
+            write_body(f, body, depth + 1)?;
+            write!(f, "{}}}", INDENT.repeat(depth))
+        }
+        Expr::Repeat(Repeat(count, body)) => {
+            writeln!(f, "repeat {} {{", count)?;
+            write_body(f, body, depth + 1)?;
+            write!(f, "{}}}", INDENT.repeat(depth))
+        }
+        Expr::Eval(eval) => write!(f, "{};", eval),
+        Expr::Define(Define(name, value)) => write!(f, "def {} = {};", name, value),
+        Expr::Destructure(Destructure(names, value)) => write!(f, "def ({}) = {};", names.join(", "), value),
+        Expr::Const(Const(name, value)) => write!(f, "const {} = {};", name, value),
+        Expr::Assign(Assign(lhs, rhs)) => write!(f, "{} = {};", lhs, rhs),
+        Expr::Return(Return(value)) => write!(f, "return {};", value),
+        Expr::Exit(Exit(None)) => write!(f, "exit;"),
+        Expr::Exit(Exit(Some(status))) => write!(f, "exit({});", status),
+        Expr::Assert(Assert(condition, None)) => write!(f, "assert({});", condition),
+        Expr::Assert(Assert(condition, Some(message))) => {
+            write!(f, "assert({}, \"{}\");", condition, escape(message, '"'))
+        }
+        Expr::Locate(_) => Ok(()),
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_expr(f, self, 0)
+    }
+}
+
+/// Write a function with its own name, not the namespace it is in
+/// Write a function, with its local functions in its body. Once a program is
+/// read, they are among the program's functions, in the function's namespace.
+fn write_fn(f: &mut fmt::Formatter, fun: &UserFn, functions: &[UserFn], depth: usize) -> fmt::Result {
+    let name = match fun.name.rfind("::") {
+        Some(i) => &fun.name[i + 2..],
+        None => &fun.name,
+    };
+    for attribute in &fun.attributes {
+        writeln!(f, "{}{}", INDENT.repeat(depth), attribute)?;
+    }
+    write!(f, "{}fn {}(", INDENT.repeat(depth), name)?;
+    for (i, parameter) in fun.parameters.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        if fun.is_reference_parameter(i) {
+            write!(f, "&")?;
+        }
+        write!(f, "{}", parameter)?;
+        if let Some(Some(ty)) = fun.parameter_types.get(i) {
+            write!(f, ": {}", ty.name())?;
+        }
+        if let Some(Some(default)) = fun.parameter_defaults.get(i) {
+            write!(f, " = {}", default)?;
+        }
+    }
+    write!(f, ")")?;
+    if let Some(ty) = fun.return_type {
+        write!(f, " -> {}", ty.name())?;
+    }
+    writeln!(f, " {{")?;
+    let moved = functions.iter().filter(|local| local.enclosing.as_ref() == Some(&fun.name));
+    for local in fun.locals.iter().chain(moved) {
+        write_fn(f, local, functions, depth + 1)?;
+    }
+    write_body(f, &fun.body, depth + 1)?;
+    writeln!(f, "{}}}", INDENT.repeat(depth))
+}
+
+impl fmt::Display for UserFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_fn(f, self, &[], 0)
+    }
+}
+
+impl fmt::Display for FnAttr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inline => write!(f, "#[inline]"),
+            Self::NeverInline => write!(f, "#[inline(never)]"),
+            Self::Keep => write!(f, "#[keep]"),
+        }
+    }
+}
+
+impl fmt::Display for Struct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "struct {} {{ {} }}", self.0, self.1.join(", "))
+    }
+}
+
+/// The parts of a namespace, which is empty for the root namespace
+fn namespace_parts(namespace: &str) -> Vec<&str> {
+    if namespace.is_empty() {
+        vec![]
+    } else {
+        namespace.split("::").collect()
+    }
+}
+
+/// Write constants, writing the runs of constants named like enum variants as enums
+fn write_constants(f: &mut fmt::Formatter, constants: &[Const]) -> fmt::Result {
+    let mut i = 0;
+    while i < constants.len() {
+        let Const(name, value) = &constants[i];
+        let is_variant = |c: &Const| {
+            matches!(c.1, Eval::Literal(Literal::ByteInt(_))) && !namespace_of(&c.0).is_empty()
+        };
+        if !is_variant(&constants[i]) {
+            writeln!(f, "const {} = {};", name, value)?;
+            i += 1;
+            continue;
+        }
+
+        let enumeration = namespace_of(name);
+        let run = constants[i..]
+            .iter()
+            .take_while(|c| is_variant(c) && namespace_of(&c.0) == enumeration)
+            .count();
+        let variants: Vec<String> = constants[i..i + run]
+            .iter()
+            .map(|Const(name, value)| format!("{} = {}", &name[enumeration.len() + 2..], value))
+            .collect();
+        writeln!(f, "enum {} {{ {} }}", enumeration, variants.join(", "))?;
+        i += run;
+    }
+    Ok(())
+}
+
+/// Write functions, putting the functions in namespaces inside modules
+fn write_functions(f: &mut fmt::Formatter, functions: &[UserFn]) -> fmt::Result {
+    let mut open: Vec<&str> = vec![];
+    for fun in functions.iter().filter(|fun| fun.enclosing.is_none()) {
+        let parts = namespace_parts(namespace_of(&fun.name));
+        let shared = open.iter().zip(&parts).take_while(|(a, b)| a == b).count();
+        while open.len() > shared {
+            open.pop();
+            writeln!(f, "{}}}", INDENT.repeat(open.len()))?;
+        }
+        for part in &parts[shared..] {
+            writeln!(f, "{}mod {} {{", INDENT.repeat(open.len()), part)?;
+            open.push(part);
+        }
+        write_fn(f, fun, functions, open.len())?;
+    }
+    while !open.is_empty() {
+        open.pop();
+        writeln!(f, "{}}}", INDENT.repeat(open.len()))?;
+    }
+    Ok(())
+}
+
+impl Program {
+    /// Write the program as source code that parses back into the same program
+    pub fn to_source(&self) -> String {
+        ProgramSource(self).to_string()
+    }
+
+    /// Forget where the program's functions and statements are in the
+    /// source, so programs can be compared by their code alone
+    pub fn without_spans(self) -> Self {
+        let Program(flags, functions, globals, constants, structs, imports) = self;
+        let functions = functions
+            .into_iter()
+            .map(|fun| UserFn {
+                body: without_locations(fun.body),
+                span: None,
+                ..fun
+            })
+            .collect();
+        Program(flags, functions, globals, constants, structs, imports)
+    }
+}
+
+/// Remove the markers of where statements are in the source
+fn without_locations(body: Vec<Expr>) -> Vec<Expr> {
+    body.into_iter()
+        .filter_map(|expr| match expr {
+            Expr::Locate(_) => None,
+            Expr::If(If(condition, then, otherwise)) => Some(Expr::If(If(
+                condition,
+                without_locations(then),
+                without_locations(otherwise),
+            ))),
+            Expr::While(While(condition, body)) => Some(Expr::While(While(condition, without_locations(body)))),
+            Expr::ForRange(ForRange(var, start, end, body)) => {
+                Some(Expr::ForRange(ForRange(var, start, end, without_locations(body))))
+            }
+            Expr::Repeat(Repeat(count, body)) => Some(Expr::Repeat(Repeat(count, without_locations(body)))),
+            other => Some(other),
+        })
+        .collect()
+}
+
+/// Programs can be made from anything that can be written as a string,
+/// so a program is written as source through this instead of by Display
+struct ProgramSource<'a>(&'a Program);
+
+impl fmt::Display for ProgramSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Program(flags, functions, globals, constants, structs, imports) = self.0;
+        for flag in flags {
+            writeln!(f, "{}", flag)?;
+        }
+        for import in imports {
+            writeln!(f, "import \"{}\";", escape(import, '"'))?;
+        }
+        for structure in structs {
+            writeln!(f, "{}", structure)?;
+        }
+        write_constants(f, constants)?;
+        for Define(name, value) in globals {
+            writeln!(f, "def {} = {};", name, value)?;
+        }
+        write_functions(f, functions)
+    }
+}
@@ -0,0 +1,31 @@
+use std::{cell::RefCell, thread::LocalKey};
+
+/// Declare the compiler's state, with a separate value for each thread.
+/// Every thread compiles its own programs, so programs compiled on
+/// different threads never see each other's functions, variables, or settings.
+///
+/// Each value is created the first time its thread uses it, and is owned
+/// by the thread, so it is dropped when the thread ends. The values are
+/// in `RefCell`s, and are reached with `with_borrow`, `with_borrow_mut`,
+/// `set`, or `get`.
+macro_rules! compiler_state {
+    ($(#[$attr:meta])* $vis:vis static $name:ident : $t:ty = $init:expr; $($rest:tt)*) => {
+        thread_local! {
+            $(#[$attr])*
+            $vis static $name: ::std::cell::RefCell<$t> = ::std::cell::RefCell::new($init);
+        }
+        compiler_state!($($rest)*);
+    };
+    () => {};
+}
+
+/// Read a copy of a value of the compiler's state
+pub trait Get<T> {
+    fn get(&'static self) -> T;
+}
+
+impl<T: Clone + 'static> Get<T> for LocalKey<RefCell<T>> {
+    fn get(&'static self) -> T {
+        self.with_borrow(T::clone)
+    }
+}
@@ -0,0 +1,388 @@
+//! The standard foreign functions. Programs can use these after `install`
+//! is called, and a function the program defines with the same name
+//! and number of parameters takes precedence over them.
+//!
+//! * `putc(ch)` prints a single character, and `putc(ch, count)` prints it `count` times
+//! * `puts(s)` prints a string value, or the string a reference points to, followed by a newline.
+//!   Like `strlen`, it needs a literal or a reference for an empty string.
+//! * `getc()` reads a single character from the input
+//! * `sizeof(v)` returns the number of cells a value takes, which is the length of a byte
+//!   array, or one more than the length of a string value because of its terminator
+//! * `strlen(s)` returns the length of a string value, of a byte array, or of the string a reference
+//!   points to. A value that is known to be a number or character is an error. An empty string value is a
+//!   single cell like a character, so use a literal or a reference for empty strings.
+//! * `streq(a, b)` returns 1 if two strings, or the strings references point to, are equal and 0 otherwise
+//! * `memset(ptr, val, len)` sets `len` cells starting at `ptr` to `val`
+//! * `printf(fmt, ...)` prints a string literal, replacing each `{}` with the next
+//!   argument in decimal, with a minus sign for a signed number below zero. Strings and characters
+//!   are printed as they are, and `{{` and `}}` print a brace. A fixed point number is printed with
+//!   four decimal places, or with `N` of them for a `{:.N}` placeholder.
+use crate::{
+    decimal_digits, defforeign, fixed_decimal_digits, defforeign_raw, is_negative_number, negate, CellWidth, Control, Error, Eval, Literal, Load, Lower, NumberKind, Program, Stdin, Stdout,
+    Value, ValueKind, VARIADIC,
+};
+
+/// Register the standard foreign functions
+pub fn install() {
+    defforeign("putc", &["ch: char", "-> void"], |args| {
+        Stdout::print(args[0]);
+        Ok(None)
+    });
+
+    defforeign("putc", &["ch: char", "count", "-> void"], |args| {
+        let (ch, count) = (args[0], args[1]);
+        // The count is the function's own copy, so it can be counted down
+        Control::while_begin(count);
+        Stdout::print(ch);
+        count.minus_eq(Value::byte_int(1)?);
+        Control::while_end();
+        Ok(None)
+    });
+
+    defforeign_raw("puts", &["s", "-> void"], |args| {
+        // An empty string literal is a single cell, which would be read as a reference
+        if !matches!(&args[0], Eval::Literal(Literal::String(s)) if s.is_empty()) {
+            print_str(args[0].lower()?)?;
+        }
+        Stdout::print(Eval::Literal(Literal::character('\n')).lower()?);
+        Ok(None)
+    });
+
+    defforeign("getc", &[], |_| {
+        let mut ch = Value::new(1)?;
+        ch.kind = ValueKind::Data;
+        ch.character = true;
+        Stdin::getc(ch);
+        Ok(Some(ch))
+    });
+
+    defforeign_raw("sizeof", &["v"], |args| {
+        let size = args[0].lower()?.size();
+        Eval::Literal(Literal::number(size as u16)).lower().map(Some)
+    });
+
+    defforeign_raw("strlen", &["s"], |args| {
+        // The length of a string literal is known while compiling
+        if let Eval::Literal(Literal::String(s)) = &args[0] {
+            return Eval::Literal(Literal::number(s.len() as u16)).lower().map(Some);
+        }
+
+        let s = args[0].lower()?;
+        // So is the length of a byte array, which has no terminator
+        if s.array {
+            return Eval::Literal(Literal::number(s.size() as u16)).lower().map(Some);
+        }
+        if s.size() == 1 && s.is_data() {
+            let name = match &args[0] {
+                Eval::Load(Load(name)) => name.clone(),
+                other => format!("{:?}", other),
+            };
+            return Err(Error::NotAString(name));
+        }
+        strlen(s).map(Some)
+    });
+
+    defforeign("streq", &["a", "b"], |args| streq(args[0], args[1]).map(Some));
+
+    defforeign("memset", &["ptr: ref", "val", "len", "-> void"], |args| {
+        let (ptr, val, len) = (args[0], args[1], args[2]);
+        let one = Value::byte_int(1)?;
+
+        // The arguments are the function's own copies, so they can be changed
+        Control::while_begin(len);
+        ptr.deref()?.assign(val)?;
+        ptr.plus_eq(one);
+        len.minus_eq(one);
+        Control::while_end();
+        Ok(None)
+    });
+
+    defforeign_raw("printf", &["fmt", VARIADIC, "-> void"], |args| {
+        let format = match &args[0] {
+            Eval::Literal(Literal::String(format)) => format.clone(),
+            _ => return Err(Error::FormatNotLiteral(String::from("printf"))),
+        };
+
+        let (chunks, places) = split_format(&format);
+        let placeholders = chunks.len() - 1;
+        if placeholders != args.len() - 1 {
+            return Err(Error::FormatArgumentMismatch {
+                format,
+                placeholders,
+                arguments: args.len() - 1,
+            });
+        }
+
+        let args = args[1..].iter().zip(places).map(Some).chain(vec![None]);
+        for (chunk, arg) in chunks.iter().zip(args) {
+            if !chunk.is_empty() {
+                print_str(Value::string(chunk)?)?;
+            }
+            if let Some((arg, places)) = arg {
+                let val = arg.lower()?;
+                if val.number.is_signed() {
+                    print_signed(val, places)?;
+                } else if val.number.is_wide() {
+                    print_number(val, places)?;
+                } else if val.size() > 1 {
+                    print_str(val)?;
+                } else if val.character {
+                    Stdout::print(val);
+                } else {
+                    print_decimal(val)?;
+                }
+            }
+        }
+        Ok(None)
+    });
+}
+
+/// Get a reference to the first character of a string value, or the reference itself.
+/// An empty string value is a single cell, so it is told apart from a reference by its kind.
+fn string_ref(s: Value) -> Result<Value, Error> {
+    if s.size() > 1 || s.is_data() {
+        s.refer()
+    } else {
+        s.copy()
+    }
+}
+
+/// Run the code from `body` once if `condition` is not zero. The condition is zeroed,
+/// which avoids copying it like an if statement does.
+fn when_nonzero(condition: Value, body: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    Control::while_begin(condition);
+    body()?;
+    condition.zero();
+    Control::while_end();
+    Ok(())
+}
+
+/// Compare two strings a character at a time, up to the end of either one
+fn streq(a: Value, b: Value) -> Result<Value, Error> {
+    let a = string_ref(a)?;
+    let b = string_ref(b)?;
+    let one = Value::byte_int(1)?;
+
+    let mut result = Value::byte_int(1)?;
+    let running = Value::byte_int(1)?;
+    Control::while_begin(running);
+
+    // The strings differ if the characters differ
+    let difference = Value::new(1)?;
+    difference.assign(a.deref()?)?;
+    difference.minus_eq(b.deref()?);
+    let same = Value::byte_int(1)?;
+    when_nonzero(difference, || {
+        result.zero();
+        running.zero();
+        same.zero();
+        Ok(())
+    })?;
+
+    // Otherwise both strings end together if this character is the terminator
+    when_nonzero(same, || {
+        let not_end = Value::new(1)?;
+        not_end.assign(a.deref()?)?;
+        not_end.minus_eq(one);
+        let end = Value::byte_int(1)?;
+        when_nonzero(not_end, || {
+            end.zero();
+            Ok(())
+        })?;
+        when_nonzero(end, || {
+            running.zero();
+            Ok(())
+        })
+    })?;
+
+    a.plus_eq(one);
+    b.plus_eq(one);
+    Control::while_end();
+
+    result.kind = ValueKind::Data;
+    Ok(result)
+}
+
+/// Split a format string into the text around its `{}` placeholders, and the
+/// decimal places each placeholder asks for with `{:.N}`
+fn split_format(format: &str) -> (Vec<String>, Vec<Option<u32>>) {
+    let mut chunks = vec![String::new()];
+    let mut places = vec![];
+    let mut chars = format.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                chunks.last_mut().unwrap().push(ch);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                chunks.push(String::new());
+                places.push(None);
+            }
+            ('{', Some(':')) => {
+                // Anything but `:.` and a number before the closing brace is printed as it is
+                let spec: String = chars.clone().take_while(|&ch| ch != '}').collect();
+                let closed = chars.clone().nth(spec.chars().count()) == Some('}');
+                match spec.strip_prefix(":.").and_then(|n| n.parse().ok()).filter(|_| closed) {
+                    Some(n) => {
+                        chars.nth(spec.chars().count());
+                        chunks.push(String::new());
+                        places.push(Some(n));
+                    }
+                    None => chunks.last_mut().unwrap().push(ch),
+                }
+            }
+            _ => chunks.last_mut().unwrap().push(ch),
+        }
+    }
+    (chunks, places)
+}
+
+/// Print the number in a cell in decimal
+fn print_decimal(n: Value) -> Result<(), Error> {
+    let digit_count = match Program::cell_width() {
+        CellWidth::Eight => 3,
+        CellWidth::Sixteen => 5,
+    };
+
+    // Count up to the number in decimal, least significant digit first
+    let mut digits = vec![];
+    for _ in 0..digit_count {
+        digits.push(Value::byte_int(0)?);
+    }
+    let one = Value::byte_int(1)?;
+    let count = n.copy()?;
+    Control::while_begin(count);
+    increment_digit(&digits, 0, one)?;
+    count.minus_eq(one);
+    Control::while_end();
+    print_digits(&digits)
+}
+
+/// Print decimal digits, most significant first, skipping leading zeros but always printing the last digit
+fn print_digits(digits: &[Value]) -> Result<(), Error> {
+    let zero = Value::character('0')?;
+    let started = Value::byte_int(0)?;
+    for (i, digit) in digits.iter().enumerate().rev() {
+        if i > 0 {
+            Control::if_begin(*digit)?;
+            started.set(1u8);
+            Control::else_begin()?;
+            Control::if_end()?;
+        } else {
+            started.set(1u8);
+        }
+
+        Control::if_begin(started)?;
+        digit.plus_eq(zero);
+        Stdout::print(*digit);
+        Control::else_begin()?;
+        Control::if_end()?;
+    }
+    Ok(())
+}
+
+/// Print a number that isn't negative in decimal, whether it is a cell, a 4 byte
+/// number, or a fixed point number, which is printed with `places` decimal places
+fn print_number(n: Value, places: Option<u32>) -> Result<(), Error> {
+    if !n.number.is_wide() {
+        return print_decimal(n);
+    }
+    let (decimal, fraction) = if n.number == NumberKind::Fixed {
+        fixed_decimal_digits(n, places.unwrap_or(4))?
+    } else {
+        (decimal_digits(n)?, vec![])
+    };
+    let digits: Vec<Value> = (0..decimal.size()).map(|i| decimal.cells(i, 1)).collect();
+    print_digits(&digits)?;
+
+    if !fraction.is_empty() {
+        Stdout::print(Value::character('.')?);
+    }
+    let zero = Value::character('0')?;
+    for digit in fraction {
+        digit.plus_eq(zero);
+        Stdout::print(digit);
+    }
+    Ok(())
+}
+
+/// Print a signed number in decimal, with a minus sign if it is negative
+fn print_signed(n: Value, places: Option<u32>) -> Result<(), Error> {
+    let magnitude = n.copy()?;
+    let negative = is_negative_number(n)?;
+    Control::if_begin(negative)?;
+    Stdout::print(Value::character('-')?);
+    if n.number.is_wide() {
+        negate(magnitude)?;
+    } else {
+        magnitude.zero();
+        magnitude.minus_eq(n);
+    }
+    Control::else_begin()?;
+    Control::if_end()?;
+    print_number(magnitude, places)
+}
+
+/// Add one to a decimal digit, carrying into the next digit when it reaches ten
+fn increment_digit(digits: &[Value], i: usize, one: Value) -> Result<(), Error> {
+    digits[i].plus_eq(one);
+    if i + 1 == digits.len() {
+        return Ok(());
+    }
+
+    // Conditions are copied by counting, so this is kept small instead of wrapping below zero
+    let not_ten = Value::byte_int(10)?;
+    not_ten.minus_eq(digits[i]);
+    Control::if_begin(not_ten)?;
+    Control::else_begin()?;
+    digits[i].zero();
+    increment_digit(digits, i + 1, one)?;
+    Control::if_end()?;
+    Ok(())
+}
+
+/// Print a string value without its terminator, a byte array,
+/// or the string a reference points to
+fn print_str(s: Value) -> Result<(), Error> {
+    if s.array {
+        Stdout::print(s);
+        Ok(())
+    } else if s.size() > 1 {
+        Stdout::print(Value {
+            number_cells: s.size() - 1,
+            ..s
+        });
+        Ok(())
+    } else {
+        Stdout::print_cstr(s)
+    }
+}
+
+/// Count the cells of a string before its terminator
+fn strlen(s: Value) -> Result<Value, Error> {
+    // The length of a string value is known while compiling
+    if s.size() > 1 {
+        return Eval::Literal(Literal::number((s.size() - 1) as u16)).lower();
+    }
+
+    let mut result = Value::byte_int(0)?;
+    let one = Value::byte_int(1)?;
+    let ptr = s.copy()?;
+
+    // Strings end with a cell holding 1, so this is zero at the terminator
+    let running = Value::new(1)?;
+    running.assign(ptr.deref()?)?;
+    running.minus_eq(one);
+
+    Control::while_begin(running);
+    result.plus_eq(one);
+    ptr.plus_eq(one);
+    running.assign(ptr.deref()?)?;
+    running.minus_eq(one);
+    Control::while_end();
+
+    result.kind = ValueKind::Data;
+    Ok(result)
+}
@@ -0,0 +1,198 @@
+use crate::{
+    Assert, Assign, Call, CallIndirect, Exit, Concat, Const, Construct, Define, Deref, Destructure, Eval, Expr, Field, If, Index, Literal,
+    Refer, Repeat, Return, Tuple, UserFn, While, ForRange,
+};
+use std::sync::Arc;
+
+/// Look at every node of a function, statement, or expression.
+/// Each method visits the children of its node by default, so
+/// an analysis only overrides the methods for the nodes it cares about.
+/// An overriding method calls the matching `walk_*` function to keep going.
+pub trait Visit {
+    fn visit_user_fn(&mut self, fun: &UserFn) {
+        walk_body(self, &fun.body)
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_eval(&mut self, eval: &Eval) {
+        walk_eval(self, eval)
+    }
+
+    fn visit_literal(&mut self, _literal: &Literal) {}
+}
+
+/// Visit each statement of a body in order
+pub fn walk_body<V: Visit + ?Sized>(visitor: &mut V, body: &[Expr]) {
+    for expr in body {
+        visitor.visit_expr(expr);
+    }
+}
+
+/// Visit the expressions and nested statements of a statement
+pub fn walk_expr<V: Visit + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::If(If(condition, then, otherwise)) => {
+            visitor.visit_eval(condition);
+            walk_body(visitor, then);
+            walk_body(visitor, otherwise);
+        }
+        Expr::While(While(condition, body)) => {
+            visitor.visit_eval(condition);
+            walk_body(visitor, body);
+        }
+        Expr::ForRange(ForRange(_, start, end, body)) => {
+            visitor.visit_eval(start);
+            visitor.visit_eval(end);
+            walk_body(visitor, body);
+        }
+        Expr::Repeat(Repeat(count, body)) => {
+            visitor.visit_eval(count);
+            walk_body(visitor, body);
+        }
+        Expr::Eval(e) | Expr::Define(Define(_, e)) | Expr::Destructure(Destructure(_, e)) | Expr::Const(Const(_, e))
+        | Expr::Return(Return(e))
+        | Expr::Assert(Assert(e, _))
+        | Expr::Exit(Exit(Some(e))) => visitor.visit_eval(e),
+        Expr::Exit(Exit(None)) => {}
+        Expr::Assign(Assign(lhs, rhs)) => {
+            visitor.visit_eval(lhs);
+            visitor.visit_eval(rhs);
+        }
+        Expr::Locate(_) => {}
+    }
+}
+
+/// Visit the subexpressions of an expression
+pub fn walk_eval<V: Visit + ?Sized>(visitor: &mut V, eval: &Eval) {
+    match eval {
+        Eval::Literal(literal) => visitor.visit_literal(literal),
+        Eval::Call(Call(_, args, named)) => {
+            for arg in args.iter().chain(named.iter().map(|(_, arg)| arg)) {
+                visitor.visit_eval(arg);
+            }
+        }
+        Eval::Tuple(Tuple(elements)) => {
+            for element in elements {
+                visitor.visit_eval(element);
+            }
+        }
+        Eval::CallIndirect(CallIndirect(callee, args)) => {
+            visitor.visit_eval(callee);
+            for arg in args {
+                visitor.visit_eval(arg);
+            }
+        }
+        Eval::Deref(Deref(inner)) | Eval::Refer(Refer(inner)) => visitor.visit_eval(inner),
+        Eval::Concat(Concat(lhs, rhs)) | Eval::Index(Index(lhs, rhs)) => {
+            visitor.visit_eval(lhs);
+            visitor.visit_eval(rhs);
+        }
+        Eval::Construct(Construct(_, fields)) => {
+            for (_, value) in fields {
+                visitor.visit_eval(value);
+            }
+        }
+        Eval::Field(Field(base, _)) => visitor.visit_eval(base),
+        Eval::Load(_) | Eval::FnRef(_) | Eval::Value(_) => {}
+    }
+}
+
+/// Rebuild a function, statement, or expression node by node.
+/// Each method rebuilds its node from its rewritten children by default,
+/// so a rewrite only overrides the methods for the nodes it changes.
+/// An overriding method calls the matching `rebuild_*` function to keep going.
+pub trait Fold {
+    fn fold_user_fn(&mut self, fun: UserFn) -> UserFn {
+        UserFn {
+            body: self.fold_body(fun.body),
+            ..fun
+        }
+    }
+
+    /// Rewrite a body, which may add or remove statements
+    fn fold_body(&mut self, body: Vec<Expr>) -> Vec<Expr> {
+        body.into_iter().map(|expr| self.fold_expr(expr)).collect()
+    }
+
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        rebuild_expr(self, expr)
+    }
+
+    fn fold_eval(&mut self, eval: Eval) -> Eval {
+        rebuild_eval(self, eval)
+    }
+
+    fn fold_literal(&mut self, literal: Literal) -> Literal {
+        literal
+    }
+}
+
+/// Rewrite an expression inside an Arc, only cloning it if the Arc is shared
+fn fold_shared<F: Fold + ?Sized>(folder: &mut F, eval: Arc<Eval>) -> Arc<Eval> {
+    let eval = Arc::try_unwrap(eval).unwrap_or_else(|shared| (*shared).clone());
+    Arc::new(folder.fold_eval(eval))
+}
+
+/// Rebuild a statement from its rewritten expressions and bodies
+pub fn rebuild_expr<F: Fold + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    match expr {
+        Expr::If(If(condition, then, otherwise)) => Expr::If(If(
+            folder.fold_eval(condition),
+            folder.fold_body(then),
+            folder.fold_body(otherwise),
+        )),
+        Expr::While(While(condition, body)) => Expr::While(While(folder.fold_eval(condition), folder.fold_body(body))),
+        Expr::ForRange(ForRange(var, start, end, body)) => {
+            let start = folder.fold_eval(start);
+            Expr::ForRange(ForRange(var, start, folder.fold_eval(end), folder.fold_body(body)))
+        }
+        Expr::Repeat(Repeat(count, body)) => Expr::Repeat(Repeat(folder.fold_eval(count), folder.fold_body(body))),
+        Expr::Eval(e) => Expr::Eval(folder.fold_eval(e)),
+        Expr::Define(Define(name, e)) => Expr::Define(Define(name, folder.fold_eval(e))),
+        Expr::Destructure(Destructure(names, e)) => Expr::Destructure(Destructure(names, folder.fold_eval(e))),
+        Expr::Const(Const(name, e)) => Expr::Const(Const(name, folder.fold_eval(e))),
+        Expr::Assign(Assign(lhs, rhs)) => Expr::Assign(Assign(folder.fold_eval(lhs), folder.fold_eval(rhs))),
+        Expr::Return(Return(e)) => Expr::Return(Return(folder.fold_eval(e))),
+        Expr::Assert(Assert(e, message)) => Expr::Assert(Assert(folder.fold_eval(e), message)),
+        Expr::Exit(Exit(status)) => Expr::Exit(Exit(status.map(|e| folder.fold_eval(e)))),
+        Expr::Locate(span) => Expr::Locate(span),
+    }
+}
+
+/// Rebuild an expression from its rewritten subexpressions
+pub fn rebuild_eval<F: Fold + ?Sized>(folder: &mut F, eval: Eval) -> Eval {
+    match eval {
+        Eval::Deref(Deref(e)) => Eval::Deref(Deref(fold_shared(folder, e))),
+        Eval::Refer(Refer(e)) => Eval::Refer(Refer(fold_shared(folder, e))),
+        Eval::Concat(Concat(lhs, rhs)) => {
+            let lhs = fold_shared(folder, lhs);
+            Eval::Concat(Concat(lhs, fold_shared(folder, rhs)))
+        }
+        Eval::Index(Index(base, index)) => {
+            let base = fold_shared(folder, base);
+            Eval::Index(Index(base, fold_shared(folder, index)))
+        }
+        Eval::Field(Field(base, field)) => Eval::Field(Field(fold_shared(folder, base), field)),
+        Eval::Literal(literal) => Eval::Literal(folder.fold_literal(literal)),
+        Eval::Call(Call(name, args, named)) => Eval::Call(Call(
+            name,
+            args.into_iter().map(|arg| folder.fold_eval(arg)).collect(),
+            named.into_iter().map(|(parameter, arg)| (parameter, folder.fold_eval(arg))).collect(),
+        )),
+        Eval::CallIndirect(CallIndirect(callee, args)) => {
+            let callee = fold_shared(folder, callee);
+            Eval::CallIndirect(CallIndirect(callee, args.into_iter().map(|arg| folder.fold_eval(arg)).collect()))
+        }
+        Eval::Tuple(Tuple(elements)) => {
+            Eval::Tuple(Tuple(elements.into_iter().map(|element| folder.fold_eval(element)).collect()))
+        }
+        Eval::Construct(Construct(name, fields)) => Eval::Construct(Construct(
+            name,
+            fields.into_iter().map(|(field, value)| (field, folder.fold_eval(value))).collect(),
+        )),
+        other @ Eval::Load(_) | other @ Eval::FnRef(_) | other @ Eval::Value(_) => other,
+    }
+}
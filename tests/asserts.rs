@@ -0,0 +1,43 @@
+mod common;
+
+use common::output;
+use fr::runner;
+
+/// Run a program that stops itself, and return what it printed and its exit status
+fn stopped(source: &str) -> (String, Option<u8>) {
+    let outcome = runner::run(source, b"").unwrap();
+    (String::from_utf8_lossy(outcome.output()).into_owned(), outcome.exit_status())
+}
+
+#[test]
+fn a_passing_assert_prints_nothing() {
+    assert_eq!(output("fn start() {\n    def x = 2;\n    assert(x);\n    assert(sub(x, 1));\n    puts(\"after\");\n}\n"), "after\n");
+}
+
+#[test]
+fn a_failing_assert_stops_before_the_code_after_it() {
+    let source = r#"fn check(n) {
+    assert(n, "n must not be zero");
+    puts("checked");
+}
+
+fn start() {
+    check(1);
+    check(0);
+    puts("never printed");
+}
+"#;
+    assert_eq!(stopped(source), ("checked\nn must not be zero\n".to_string(), Some(1)));
+}
+
+#[test]
+fn a_failing_assert_names_its_function_with_debug_comments() {
+    let source = "#![debug_comments]\n\nfn check() {\n    assert(0);\n}\n\nfn start() {\n    check();\n}\n";
+    assert_eq!(stopped(source), ("ASSERT FAILED in check\n".to_string(), Some(1)));
+}
+
+#[test]
+fn stripped_asserts_are_not_checked() {
+    let source = "#![strip_asserts]\n\nfn start() {\n    def x = 0;\n    assert(x, \"x is zero\");\n    puts(\"after\");\n}\n";
+    assert_eq!(output(source), "after\n");
+}
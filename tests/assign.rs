@@ -0,0 +1,52 @@
+mod common;
+
+use common::{compile_error, output, warnings};
+use fr::{Program, Warning};
+
+#[test]
+fn a_character_cant_be_assigned_to_a_string() {
+    let error = compile_error("fn start() {\n    def s = \"abc\";\n    s = 'x';\n}\n");
+    assert_eq!(error, "3:5: cannot assign a value of 1 cell(s) to `s`, which is 4 cell(s)\n    in start at 3:5");
+}
+
+#[test]
+fn a_string_cant_be_assigned_to_a_character() {
+    let error = compile_error("fn start() {\n    def c = 'x';\n    c = \"abc\";\n}\n");
+    assert!(error.starts_with("3:5: cannot assign a value of 4 cell(s) to `c`, which is 1 cell(s)"), "{}", error);
+}
+
+#[test]
+fn strings_of_different_lengths_cant_be_assigned() {
+    let error = compile_error("fn start() {\n    def s = \"abc\";\n    s = \"abcd\";\n}\n");
+    assert!(error.starts_with("3:5: cannot assign a value of 5 cell(s) to `s`, which is 4 cell(s)"), "{}", error);
+}
+
+#[test]
+fn values_of_the_same_size_can_be_assigned() {
+    assert_eq!(output("fn start() {\n    def s = \"abc\";\n    s = \"xyz\";\n    puts(s);\n    def c = 'a';\n    c = 5;\n    c = add('a', c);\n    putc(c);\n}\n"), "xyz\nf");
+}
+
+#[test]
+fn anything_can_be_written_through_a_pointer() {
+    let source = r#"fn start() {
+    def s = "abc";
+    def p = &s;
+    *p = 'x';
+    puts(s);
+}
+"#;
+    assert_eq!(output(source), "xbc\n");
+}
+
+#[test]
+fn defining_a_variable_again_with_another_size_is_warned_about() {
+    output("fn start() {\n    def v = 'a';\n    def v = \"abc\";\n    puts(v);\n}\n");
+    let resized: Vec<(u32, u32)> = Program::warnings()
+        .into_iter()
+        .filter_map(|warning| match warning {
+            Warning::Resized { name, old_size, new_size, .. } if name == "v" => Some((old_size, new_size)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(resized, [(1, 4)], "{:?}", warnings());
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::parse;
+use fr::{runner, Backend, CBackend, Interpreter, SmplBackend};
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+const FIXTURE: &str = r#"fn shout(c) {
+    putc(sub(c, 32));
+}
+
+fn start() {
+    def greeting = "hi ";
+    puts(greeting);
+    def c = getc();
+    def left = 3;
+    while left {
+        shout(c);
+        left = sub(left, 1);
+    }
+    if c {
+        puts("!");
+    } else {
+        puts("?");
+    }
+    exit(7);
+}
+"#;
+
+/// Compile the fixture with a backend
+fn compile_with(backend: &mut dyn Backend) -> String {
+    parse(FIXTURE).compile_with_backend(backend).unwrap()
+}
+
+#[test]
+fn the_default_backend_runs_like_the_compiler() {
+    let code = compile_with(&mut SmplBackend::new());
+    let mut interpreter = Interpreter::new(&b"q"[..], vec![]).with_step_limit(1_000_000);
+    interpreter.run(&code).unwrap();
+
+    let expected = runner::run(FIXTURE, b"q").unwrap();
+    assert_eq!(interpreter.exit_status(), expected.exit_status());
+    assert_eq!(interpreter.into_output(), expected.output());
+    assert_eq!(expected.output(), b"hi \nQQQ!\n");
+}
+
+#[test]
+fn the_c_backend_writes_one_main_function() {
+    let code = compile_with(&mut CBackend::new());
+    assert!(code.starts_with("#include <stdio.h>\n"), "{}", code);
+    assert_eq!(code.matches("int main() {").count(), 1, "{}", code);
+    assert!(code.ends_with("    return 0;\n}\n"), "{}", code);
+    assert_eq!(code.matches('{').count(), code.matches('}').count(), "{}", code);
+    for line in ["/* fn start */", "/* fn shout */", "/* end fn shout */", "} else {"] {
+        assert!(code.contains(line), "no `{}` in\n{}", line, code);
+    }
+}
+
+#[test]
+fn the_c_backend_compiles_and_runs_like_the_interpreter() {
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("no C compiler, so the C code isn't run");
+        return;
+    }
+    let code = compile_with(&mut CBackend::new());
+    let dir = std::env::temp_dir().join(format!("fr-backend-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (source, binary) = (dir.join("main.c"), dir.join("main"));
+    fs::write(&source, code).unwrap();
+    let status = Command::new("cc").arg("-o").arg(&binary).arg(&source).status().unwrap();
+    assert!(status.success());
+
+    let mut child = Command::new(&binary).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"q").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.stdout, b"hi \nQQQ!\n");
+    assert_eq!(output.status.code(), Some(7));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::output_with_input;
+
+#[test]
+fn the_else_branch_runs_when_the_condition_is_zero() {
+    let source = r#"fn start() {
+    def on = sub(getc(), '0');
+    if on {
+        puts("then");
+    } else {
+        puts("else");
+    }
+    puts("after");
+}
+"#;
+    assert_eq!(output_with_input(source, b"0"), "else\nafter\n");
+    assert_eq!(output_with_input(source, b"1"), "then\nafter\n");
+    assert_eq!(output_with_input(source, b"7"), "then\nafter\n");
+}
+
+#[test]
+fn each_arm_of_an_else_if_chain_runs_on_its_own() {
+    let source = r#"fn start() {
+    def first = sub(getc(), '0');
+    def second = sub(getc(), '0');
+    if first {
+        puts("first");
+    } else if second {
+        puts("second");
+    } else {
+        puts("neither");
+    }
+    puts("after");
+}
+"#;
+    assert_eq!(output_with_input(source, b"10"), "first\nafter\n");
+    assert_eq!(output_with_input(source, b"11"), "first\nafter\n");
+    assert_eq!(output_with_input(source, b"01"), "second\nafter\n");
+    assert_eq!(output_with_input(source, b"00"), "neither\nafter\n");
+}
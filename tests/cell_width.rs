@@ -0,0 +1,80 @@
+mod common;
+
+use common::{compile_error, output, parse};
+use fr::runner;
+
+/// A program with a pragma choosing its cell width in front of it
+fn with_width(width: u8, source: &str) -> String {
+    format!("#![cell_size({})]\n{}", width, source)
+}
+
+const WRAPS: &str = r#"
+fn start() {
+    def a = 250;
+    printf("{}\n", add(a, 10));
+    printf("{}\n", sub(a, 251));
+}
+"#;
+
+#[test]
+fn arithmetic_wraps_around_at_the_cell_width() {
+    assert_eq!(output(&with_width(8, WRAPS)), "4\n255\n");
+    assert_eq!(output(&with_width(16, WRAPS)), "260\n65535\n");
+}
+
+#[test]
+fn folded_arithmetic_wraps_like_arithmetic_that_runs() {
+    let source = "#![optimize(1)]\nfn start() { printf(\"{}\\n\", add(250, 10)); }";
+    assert_eq!(output(&with_width(8, source)), "4\n");
+    assert_eq!(output(&with_width(16, source)), "260\n");
+}
+
+#[test]
+fn comparisons_see_the_wrapped_value() {
+    let source = r#"
+fn start() {
+    def a = 254;
+    if sub(add(a, 6), 4) {
+        puts("different");
+    } else {
+        puts("equal");
+    }
+}
+"#;
+    assert_eq!(output(&with_width(8, source)), "equal\n");
+    assert_eq!(output(&with_width(16, source)), "different\n");
+}
+
+#[test]
+fn overflow_checks_stop_at_the_largest_value_of_the_cell_width() {
+    let source = r#"#![overflow_checks]
+fn start() {
+    def a = 250;
+    printf("{}\n", add(a, 10));
+}
+"#;
+    let outcome = runner::run(&with_width(8, source), b"").unwrap();
+    assert_eq!(outcome.exit_status(), Some(1));
+    assert_eq!(outcome.output(), b"OVERFLOW in add\n");
+
+    assert_eq!(output(&with_width(16, source)), "260\n");
+}
+
+#[test]
+fn the_code_emitted_depends_on_the_cell_width() {
+    let eight = parse(&with_width(8, WRAPS)).compile().unwrap();
+    let sixteen = parse(&with_width(16, WRAPS)).compile().unwrap();
+    // Printing a number in decimal needs more digits with wider cells
+    assert!(eight.len() < sixteen.len(), "{} {}", eight.len(), sixteen.len());
+}
+
+#[test]
+fn numbers_that_dont_fit_a_cell_are_errors() {
+    let error = compile_error(&with_width(8, "fn start() { def a = 300; }"));
+    assert!(error.contains("ValueTooLargeForCell(300, Eight)"), "{}", error);
+
+    let error = compile_error(&with_width(8, "fn start() { def p = alloc(300); }"));
+    assert!(error.contains("ValueTooLargeForCell(300, Eight)"), "{}", error);
+
+    output(&with_width(16, "fn start() { def a = 300; def p = alloc(300); }"));
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::{output, output_with_input};
+
+#[test]
+fn digits_are_parsed_into_a_number() {
+    let source = r#"fn times_ten(n) {
+    def two = add(n, n);
+    def five = add(add(two, two), n);
+    return add(five, five);
+}
+
+fn start() {
+    def n = 0;
+    def c = getc();
+    while c {
+        n = add(times_ten(n), sub(c, '0'));
+        c = getc();
+    }
+    printf("{}\n", n);
+}
+"#;
+    assert_eq!(output_with_input(source, b"42"), "42\n");
+    assert_eq!(output_with_input(source, b"1307"), "1307\n");
+}
+
+#[test]
+fn letters_are_made_upper_case() {
+    let source = r#"fn start() {
+    def c = getc();
+    while c {
+        printf("{}", sub(c, 32));
+        c = getc();
+    }
+}
+"#;
+    assert_eq!(output_with_input(source, b"shout"), "SHOUT");
+}
+
+#[test]
+fn characters_and_numbers_print_as_what_they_are() {
+    let source = r#"fn start() {
+    def a = 'a';
+    printf("{} {} {} {}\n", add(a, 1), sub('z', 1), sub('c', a), add(2, 3));
+}
+"#;
+    assert_eq!(output(source), "b y 2 5\n");
+}
+
+#[test]
+fn a_function_declared_to_return_a_character_returns_one() {
+    let source = r#"fn next(c) -> char {
+    return add(c, 1);
+}
+
+fn number(c) {
+    return sub(c, 'a');
+}
+
+fn start() {
+    printf("{} {}\n", next('a'), number('d'));
+}
+"#;
+    assert_eq!(output(source), "b 3\n");
+}
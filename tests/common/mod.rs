@@ -0,0 +1,40 @@
+//! Helpers shared by the integration tests. Every test binary uses
+//! some of them, so the ones a binary doesn't use aren't warned about.
+#![allow(dead_code)]
+
+use fr::{reset, runner, stdlib, Program};
+
+/// Compile a program with the standard library, run it, and return what it printed
+pub fn output(source: &str) -> String {
+    output_with_input(source, b"")
+}
+
+/// Compile a program with the standard library, run it on some input, and return what it printed
+pub fn output_with_input(source: &str, input: &[u8]) -> String {
+    let outcome = runner::run(source, input).unwrap_or_else(|e| panic!("could not compile: {}", e));
+    assert!(outcome.is_finished(), "the program didn't finish: {:?}", outcome);
+    String::from_utf8_lossy(outcome.output()).into_owned()
+}
+
+/// Compile a program that shouldn't compile, and return the error it reports
+pub fn compile_error(source: &str) -> String {
+    match runner::run(source, b"") {
+        Ok(outcome) => panic!("the program compiled, and printed {:?}", String::from_utf8_lossy(outcome.output())),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Parse a program and install the standard library, so it can be
+/// compiled with any of the ways `Program` has to compile
+pub fn parse(source: &str) -> Program {
+    reset();
+    let (program, errors) = Program::parse_all(source);
+    assert!(errors.is_empty(), "could not parse: {:?}", errors);
+    stdlib::install();
+    program.expect("no items were parsed")
+}
+
+/// The warnings from compiling the last program, as text
+pub fn warnings() -> Vec<String> {
+    Program::warnings().iter().map(ToString::to_string).collect()
+}
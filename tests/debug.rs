@@ -0,0 +1,57 @@
+mod common;
+
+use common::parse;
+use fr::{runner, strip_debug};
+
+const COUNTDOWN: &str = r#"fn start() {
+    def i = 3;
+    while i {
+        putc(add('0', i));
+        i = sub(i, 1);
+    }
+}
+"#;
+
+/// Compile a program with and without debug comments
+fn both(flags: &str, source: &str) -> (String, String) {
+    let plain = parse(&format!("{}{}", flags, source)).compile().unwrap();
+    let debug = parse(&format!("{}#![debug_comments]\n{}", flags, source)).compile().unwrap();
+    (plain, debug)
+}
+
+#[test]
+fn debug_comments_mark_functions_and_statements() {
+    let (_, debug) = both("", COUNTDOWN);
+    let comments: Vec<&str> = debug.lines().filter(|line| line.starts_with("# ")).collect();
+    assert_eq!(
+        comments,
+        [
+            "# fn start",
+            "# define i @ line 3",
+            "# while i @ line 4",
+            "# call putc @ line 5",
+            "# assign i @ line 6",
+            "# end while i",
+            "# end fn start",
+        ]
+    );
+}
+
+#[test]
+fn stripping_debug_comments_gives_the_plain_code() {
+    let (plain, debug) = both("", COUNTDOWN);
+    assert_ne!(plain, debug);
+    assert_eq!(strip_debug(&debug), plain);
+}
+
+#[test]
+fn debug_comments_dont_change_what_the_program_does() {
+    // Optimizations don't rewrite code across a comment, so only the output is compared
+    for flags in ["", "#![optimize(1)]\n", "#![optimize(2)]\n"] {
+        let plain = runner::run(&format!("{}{}", flags, COUNTDOWN), b"").unwrap();
+        let commented = runner::run(&format!("{}#![debug_comments]\n{}", flags, COUNTDOWN), b"").unwrap();
+        assert_eq!(plain.output(), b"321");
+        assert_eq!(commented.output(), plain.output(), "with {:?}", flags);
+        assert!(commented.is_finished());
+    }
+}
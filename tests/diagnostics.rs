@@ -0,0 +1,59 @@
+mod common;
+
+use common::parse;
+use fr::{CompileEvent, DiagnosticSink, Program};
+use std::sync::{Arc, Mutex};
+
+/// A sink that keeps every event it is given
+struct Recorder(Arc<Mutex<Vec<CompileEvent>>>);
+
+impl DiagnosticSink for Recorder {
+    fn on_event(&self, event: CompileEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn the_sink_sees_every_call_and_definition() {
+    let program = parse(
+        r#"fn double(n) {
+    def twice = add(n, n);
+    return twice;
+}
+
+fn start() {
+    def x = double(2);
+    exit(x);
+}
+"#,
+    );
+    let events = Arc::new(Mutex::new(vec![]));
+    Program::set_diagnostic_sink(Some(Box::new(Recorder(events.clone()))));
+    let compiled = program.compile();
+    Program::set_diagnostic_sink(None);
+    compiled.unwrap();
+
+    let call = |name: &str, depth| CompileEvent::Call { name: name.to_string(), depth };
+    let done = |name: &str, depth| CompileEvent::CallDone { name: name.to_string(), depth };
+    let define = |name: &str, depth| CompileEvent::Define { name: name.to_string(), depth };
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            call("double", 1),
+            call("add", 2),
+            done("add", 2),
+            define("twice", 2),
+            done("double", 1),
+            define("x", 1),
+        ]
+    );
+}
+
+#[test]
+fn a_removed_sink_sees_nothing() {
+    let events = Arc::new(Mutex::new(vec![]));
+    Program::set_diagnostic_sink(Some(Box::new(Recorder(events.clone()))));
+    Program::set_diagnostic_sink(None);
+    parse("fn start() {\n    def x = 'a';\n    putc(x);\n}\n").compile().unwrap();
+    assert!(events.lock().unwrap().is_empty());
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{compile_error, output, parse};
+use fr::{lookup, Error, Interpreter};
+
+#[test]
+fn the_entry_point_can_be_chosen_with_a_pragma() {
+    let source = r#"#![entry_point(main)]
+
+fn start() {
+    puts("start");
+}
+
+fn main() {
+    puts("main");
+}
+"#;
+    assert_eq!(output(source), "main\n");
+}
+
+#[test]
+fn the_entry_point_can_be_chosen_when_compiling() {
+    let program = parse(
+        r#"fn start() {
+    exit(1);
+}
+
+fn other() {
+    exit(2);
+}
+"#,
+    );
+    let code = program.compile_with_entry("other").unwrap();
+    let mut interpreter = Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&code).unwrap();
+    assert_eq!(interpreter.exit_status(), Some(2));
+}
+
+#[test]
+fn a_missing_entry_point_is_an_error() {
+    let program = parse("fn start() {\n}\n");
+    match program.compile_with_entry("missing") {
+        Err(Error::NoEntryPoint { name, .. }) => assert_eq!(name, "missing"),
+        other => panic!("expected a missing entry point, got {:?}", other),
+    }
+}
+
+#[test]
+fn an_entry_point_with_parameters_is_an_error() {
+    let error = compile_error(
+        r#"#![entry_point(main)]
+
+fn main(argc) {
+    exit(argc);
+}
+"#,
+    );
+    assert!(error.contains("InvalidEntryPoint"), "{}", error);
+    assert!(error.contains("\"main\""), "{}", error);
+    assert!(error.contains("it takes 1 parameters"), "{}", error);
+}
+
+#[test]
+fn a_program_with_its_entry_point_compiles() {
+    assert_eq!(output("fn start() {\n    puts(\"hi\");\n}\n"), "hi\n");
+}
+
+#[test]
+fn a_program_without_an_entry_point_lists_its_functions() {
+    let error = compile_error("fn helper() {\n}\n\nfn other() {\n}\n");
+    assert!(error.starts_with("the entry point `start` is not defined"), "{}", error);
+    assert!(error.contains("helper, other"), "{}", error);
+}
+
+#[test]
+fn a_misspelled_entry_point_is_shown_among_the_functions() {
+    let error = compile_error("#![entry_point(main)]\n\nfn mian() {\n}\n");
+    assert!(error.starts_with("the entry point `main` is not defined"), "{}", error);
+    assert!(error.contains("mian"), "{}", error);
+    // The check comes before anything is compiled, so nothing is left registered
+    assert_eq!(lookup("mian"), None);
+}
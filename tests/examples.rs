@@ -0,0 +1,8 @@
+mod common;
+
+use common::output;
+
+#[test]
+fn to_upper() {
+    assert_eq!(output(include_str!("../examples/to_upper.fr")), "HELLO, WORLD!\n");
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::compile_error;
+use fr::runner::{self, RunOutcome};
+
+#[test]
+fn exit_in_a_nested_loop_in_a_call_stops_everything() {
+    let source = r#"fn search() {
+    def i = 3;
+    while i {
+        def j = 3;
+        while j {
+            putc(add('0', j));
+            if sub(j, 2) {
+            } else {
+                exit(4);
+            }
+            j = sub(j, 1);
+        }
+        putc('-');
+        i = sub(i, 1);
+    }
+    puts("searched");
+}
+
+fn start() {
+    search();
+    puts("never printed");
+}
+"#;
+    match runner::run(source, b"").unwrap() {
+        RunOutcome::Exited(run, status) => {
+            assert_eq!(run.output, b"32");
+            assert_eq!(status, 4);
+        }
+        other => panic!("expected the program to exit, got {:?}", other),
+    }
+}
+
+#[test]
+fn exit_without_a_status_exits_with_zero() {
+    let outcome = runner::run("fn start() {\n    puts(\"a\");\n    exit;\n    puts(\"b\");\n}\n", b"").unwrap();
+    assert_eq!(outcome.exit_status(), Some(0));
+    assert_eq!(outcome.output(), b"a\n");
+}
+
+#[test]
+fn a_program_that_doesnt_exit_has_no_status() {
+    let outcome = runner::run("fn start() {\n    puts(\"a\");\n}\n", b"").unwrap();
+    assert!(outcome.is_finished());
+    assert_eq!(outcome.exit_status(), None);
+}
+
+#[test]
+fn the_exit_status_is_a_single_byte() {
+    let error = compile_error("fn start() {\n    exit(\"no\");\n}\n");
+    assert!(error.starts_with("2:5: ExitStatusNotSingleByte"), "{}", error);
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{compile_error, output};
+use fr::Program;
+
+/// Print an expression with a format placeholder
+fn printed(placeholder: &str, expression: &str) -> String {
+    output(&format!("fn start() {{\n    printf(\"{}\", {});\n}}\n", placeholder, expression))
+}
+
+#[test]
+fn fixed_point_numbers_print_with_four_decimal_places() {
+    assert_eq!(printed("{}", "1.5"), "1.5000");
+    assert_eq!(printed("{}", "-0.25"), "-0.2500");
+    assert_eq!(printed("{}", "32767.5"), "32767.5000");
+    // They are as wide with 8 bit cells as with 16 bit cells
+    assert_eq!(output("#![cell_size(8)]\nfn start() {\n    printf(\"{}\", 1000.125);\n}\n"), "1000.1250");
+}
+
+#[test]
+fn fixed_point_numbers_print_with_the_decimal_places_asked_for() {
+    assert_eq!(printed("{:.2}", "3.14159"), "3.14");
+    assert_eq!(printed("{:.0}", "2.5"), "3");
+    assert_eq!(printed("{:.1}", "0.96"), "1.0");
+    assert_eq!(printed("{{:.2}} {:.2}", "0.5"), "{:.2} 0.50");
+}
+
+#[test]
+fn fixed_point_numbers_add_and_subtract() {
+    assert_eq!(printed("{:.2}", "add(1.5, 2.25)"), "3.75");
+    assert_eq!(printed("{}", "sub(1.5, 2.25)"), "-0.7500");
+    assert_eq!(printed("{}", "add(0.5, 2)"), "2.5000");
+}
+
+#[test]
+fn fixed_point_numbers_multiply_and_divide() {
+    // Literals are worked out before they are rounded to 65536ths, so this is exactly 1
+    assert_eq!(printed("{:.5}", "mul(0.1, 10)"), "1.00000");
+    let source = "fn start() {\n    def a = 1.5;\n    printf(\"{} {} {}\", mul(a, -2.5), mul(a, a), div(a, -0.25));\n}\n";
+    assert_eq!(output(source), "-3.7500 2.2500 -6.0000");
+    // 0.1 is rounded to 6554 65536ths, so ten of them are a few over 1
+    let source = "fn start() {\n    def tenth = 0.1;\n    printf(\"{:.3}\", mul(tenth, 10));\n}\n";
+    assert_eq!(output(source), "1.000");
+}
+
+#[test]
+fn fixed_point_numbers_compare_with_their_sign() {
+    assert_eq!(printed("{}", "lt(-1.5, 0.25)"), "1");
+    assert_eq!(printed("{}", "gt(0.5, 0.25)"), "1");
+    assert_eq!(printed("{}", "lt(1.5, 1.25)"), "0");
+}
+
+#[test]
+fn fixed_point_numbers_are_cast_to_and_from_whole_numbers() {
+    assert_eq!(printed("{}", "fixed(-3)"), "-3.0000");
+    assert_eq!(printed("{}", "i32(-2.75)"), "-2");
+    assert_eq!(printed("{}", "unsigned(7.9)"), "7");
+    let error = compile_error("fn start() {\n    printf(\"{}\", add(i32(1), 1.5));\n}\n");
+    assert!(error.contains("1.5 does not fit in an `i32`"), "{}", error);
+}
+
+#[test]
+fn functions_take_and_return_fixed_point_numbers_they_declare() {
+    let source = "fn half(x: fixed) -> fixed {\n    return div(x, 2);\n}\n\nfn start() {\n    printf(\"{} {}\", half(3), half(-1.5));\n}\n";
+    assert_eq!(output(source), "1.5000 -0.7500");
+}
+
+#[test]
+fn fixed_point_numbers_out_of_range_dont_parse() {
+    let (_, errors) = Program::parse_all("fn start() {\n    def a = 40000.0;\n}\n");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(
+        errors[0]
+            .to_string()
+            .ends_with("fixed point number 40000.0 is out of range, it must be at least -32768 and less than 32768"),
+        "{}",
+        errors[0]
+    );
+}
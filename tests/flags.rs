@@ -0,0 +1,169 @@
+mod common;
+
+use common::{compile_error, output, warnings};
+use fr::{CellWidth, Flag, Program};
+
+#[test]
+fn disable_ptrs_rejects_references_in_user_code() {
+    let error = compile_error(
+        r#"#[disable(ptrs)]
+
+fn start() {
+    def a = 1;
+    def p = &a;
+}
+"#,
+    );
+    assert!(error.contains("PointersDisabled(\"start\")"), "{}", error);
+}
+
+#[test]
+fn disable_ptrs_rejects_dereferences_in_user_code() {
+    let error = compile_error(
+        r#"#![disable_ptrs]
+
+fn read(p) {
+    return *p;
+}
+
+fn start() {
+    def a = read(1);
+}
+"#,
+    );
+    assert!(error.contains("PointersDisabled(\"read\")"), "{}", error);
+}
+
+#[test]
+fn disable_ptrs_still_lets_builtins_use_pointers() {
+    let source = r#"#![disable_ptrs]
+
+fn start() {
+    def p = alloc(2);
+    free(p);
+    printf("{}\n", strlen("four"));
+}
+"#;
+    assert_eq!(output(source), "4\n");
+}
+
+#[test]
+fn a_failed_call_is_taken_off_the_call_stack() {
+    let error = compile_error(
+        r#"#![disable_ptrs]
+
+fn inner() {
+    def a = 1;
+    def p = &a;
+}
+
+fn start() {
+    inner();
+}
+"#,
+    );
+    // The error still says which calls it happened in
+    assert!(error.contains("in inner"), "{}", error);
+    assert!(error.contains("in start"), "{}", error);
+    assert!(fr::backtrace().is_empty(), "{:?}", fr::backtrace());
+}
+
+#[test]
+fn a_failed_foreign_call_is_taken_off_the_call_stack() {
+    let error = compile_error(
+        r#"
+fn start() {
+    printf("{} {}\n", 1);
+}
+"#,
+    );
+    assert!(error.contains("in printf"), "{}", error);
+    assert!(fr::backtrace().is_empty(), "{:?}", fr::backtrace());
+}
+
+#[test]
+fn size_warn_reports_the_size_of_each_function_and_the_program() {
+    let source = r#"#![size_warn]
+
+fn double(n) {
+    return add(n, n);
+}
+
+fn start() {
+    def a = double(2);
+}
+"#;
+    output(source);
+    let warnings = warnings();
+    assert!(warnings.iter().any(|w| w.starts_with("function `double` generated")), "{:?}", warnings);
+    assert!(warnings.iter().any(|w| w.starts_with("function `start` generated")), "{:?}", warnings);
+    assert!(warnings.iter().any(|w| w.starts_with("program generated")), "{:?}", warnings);
+
+    let report = fr::Program::size_report();
+    let double = report.iter().find(|f| f.name == "double").expect("no report for `double`");
+    assert_eq!(double.calls, 1);
+    assert!(double.instructions > 0 && double.peak_stack > 0);
+}
+
+#[test]
+fn size_warn_warns_about_functions_over_the_threshold() {
+    let program = common::parse(
+        r#"#![size_warn]
+
+fn start() {
+    println("a long enough string");
+}
+"#,
+    );
+    fr::Program::set_size_warn_threshold(10);
+    program.compile().unwrap();
+    let warnings = warnings();
+    assert!(
+        warnings.iter().any(|w| w.contains("more than the limit of 10")),
+        "{:?}",
+        warnings
+    );
+}
+
+#[test]
+fn sizes_are_not_reported_without_size_warn() {
+    output("fn start() { println(\"hi\"); }");
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+#[test]
+fn pragmas_are_parsed_into_flags() {
+    let program = Program::from("#![disable_ptrs]\n#![size_warn]\n#![optimize(2)]\n#![cell_size(16)]\n\nfn start() {\n}\n");
+    assert_eq!(
+        program.flags(),
+        [Flag::DisablePtrs, Flag::EnableSizeWarn, Flag::Optimize(2), Flag::CellSize(CellWidth::Sixteen)]
+    );
+}
+
+#[test]
+fn a_program_without_pragmas_has_no_flags() {
+    assert!(Program::from("fn start() {\n}\n").flags().is_empty());
+}
+
+#[test]
+fn an_unknown_pragma_is_named_in_the_error() {
+    let (_, errors) = Program::parse_all("#![frobnicate]\n\nfn start() {\n}\n");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "1:1: syntax error: unknown pragma `frobnicate`");
+}
+
+#[test]
+fn a_pragma_after_a_definition_is_an_error() {
+    let (_, errors) = Program::parse_all("fn start() {\n}\n\n#![size_warn]\n");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].to_string().contains("must come before every definition"), "{}", errors[0]);
+}
+
+#[test]
+fn strip_asserts_pragma_removes_asserts() {
+    let source = "fn start() {\n    assert(0);\n    puts(\"done\");\n}\n";
+    assert_eq!(output(&format!("#![strip_asserts]\n{}", source)), "done\n");
+    let outcome = fr::runner::run(source, b"").unwrap();
+    assert_eq!(outcome.exit_status(), Some(1));
+    assert_eq!(outcome.output(), b"ASSERT FAILED\n");
+}
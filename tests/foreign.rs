@@ -0,0 +1,85 @@
+mod common;
+
+use common::parse;
+use fr::{defforeign, get, set_return, varargs, Stdout, VARARG_COUNT, VARIADIC};
+use std::sync::{Arc, Mutex};
+
+#[test]
+#[allow(deprecated)]
+fn variadic_foreign_functions_get_every_argument() {
+    let program = parse(
+        r#"fn start() {
+    show_all('a');
+    show_all('b', 'c');
+    show_all('d', 'e', 'f', 'g');
+    putc('\n');
+}
+"#,
+    );
+    fr::deforfun("show_all", &["first", VARIADIC], || {
+        // The count is stored too, for code that needs it while it runs
+        get(VARARG_COUNT)?;
+        Stdout::print(get("first")?);
+        for arg in varargs() {
+            Stdout::print(arg);
+        }
+        Ok(())
+    });
+    assert_eq!(program.run(b"").unwrap(), b"abcdefg\n");
+}
+
+#[test]
+fn foreign_functions_are_passed_their_arguments_and_return_a_value() {
+    let program = parse(
+        r#"fn start() {
+    def total = plus(40, 2);
+    printf("{}\n", total);
+}
+"#,
+    );
+    defforeign("plus", &["a", "b", "-> u16"], |args| {
+        let result = args[0].copy()?;
+        result.plus_eq(args[1]);
+        Ok(Some(result))
+    });
+    assert_eq!(program.run(b"").unwrap(), b"42\n");
+}
+
+#[test]
+#[allow(deprecated)]
+fn old_foreign_functions_still_work() {
+    let program = parse(
+        r#"fn start() {
+    def total = double(21);
+    printf("{}\n", total);
+}
+"#,
+    );
+    fr::deforfun("double", &["x"], || {
+        let x = get("x")?;
+        let result = x.copy()?;
+        result.plus_eq(x);
+        set_return(result)
+    });
+    assert_eq!(program.run(b"").unwrap(), b"42\n");
+}
+
+#[test]
+fn foreign_functions_can_be_closures() {
+    let program = parse(
+        r#"fn start() {
+    log("hello");
+    log("abc");
+}
+"#,
+    );
+    // The closure is called while the program is compiled, so it records the length of each string logged
+    let logged = Arc::new(Mutex::new(vec![]));
+    let log = logged.clone();
+    defforeign("log", &["s", "-> void"], move |args| {
+        log.lock().unwrap().push(args[0].size() as u8 - 1);
+        Ok(None)
+    });
+    program.compile().unwrap();
+    assert_eq!(*logged.lock().unwrap(), vec![5, 3]);
+}
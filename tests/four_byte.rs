@@ -0,0 +1,109 @@
+mod common;
+
+use common::{compile_error, output};
+use fr::runner;
+
+/// Print the result of an expression in decimal, with eight bit cells
+fn eight_bit(expression: &str) -> String {
+    output(&format!("#![cell_size(8)]\nfn start() {{\n    printf(\"{{}}\", {});\n}}\n", expression))
+}
+
+#[test]
+fn negative_literals_are_i32s() {
+    assert_eq!(eight_bit("-1"), "-1");
+    assert_eq!(eight_bit("-0"), "0");
+    // Too large for an 8 bit cell, but not for an `i32`
+    assert_eq!(eight_bit("-300"), "-300");
+    assert_eq!(eight_bit("-2147483648"), "-2147483648");
+    assert_eq!(eight_bit("2147483647"), "2147483647");
+}
+
+#[test]
+fn literals_too_large_for_a_cell_are_u32s() {
+    assert_eq!(eight_bit("70000"), "70000");
+    assert_eq!(eight_bit("4294967295"), "4294967295");
+    assert_eq!(eight_bit("add(70000, 70000)"), "140000");
+}
+
+#[test]
+fn literals_outside_4_bytes_are_syntax_errors() {
+    let (_, errors) = fr::Program::parse_all("fn start() {\n    def a = -2147483649;\n}\n");
+    assert!(errors[0].to_string().contains("number -2147483649 is too small"), "{}", errors[0]);
+    let (_, errors) = fr::Program::parse_all("fn start() {\n    def a = 4294967296;\n}\n");
+    assert!(errors[0].to_string().contains("number 4294967296 is too large"), "{}", errors[0]);
+}
+
+#[test]
+fn subtracting_a_larger_i32_leaves_a_negative_one() {
+    assert_eq!(eight_bit("sub(i32(3), 10)"), "-7");
+    assert_eq!(eight_bit("lt(sub(i32(3), 10), 0)"), "1");
+    assert_eq!(eight_bit("sub(-1, 2147483647)"), "-2147483648");
+    // The carry goes through every cell
+    assert_eq!(eight_bit("add(-1, 1)"), "0");
+    assert_eq!(eight_bit("add(u32(65535), 1)"), "65536");
+}
+
+#[test]
+fn i32s_wrap_around_at_the_smallest_and_largest_values() {
+    assert_eq!(eight_bit("sub(-2147483648, 1)"), "2147483647");
+    assert_eq!(eight_bit("add(i32(2147483647), 1)"), "-2147483648");
+    assert_eq!(eight_bit("neg(-2147483648)"), "-2147483648");
+}
+
+#[test]
+fn i32s_compare_multiply_and_divide_with_their_sign() {
+    assert_eq!(eight_bit("lt(-2147483648, 2147483647)"), "1");
+    assert_eq!(eight_bit("gt(-1, -2)"), "1");
+    assert_eq!(eight_bit("lt(-1, 0)"), "1");
+    assert_eq!(eight_bit("mul(-300, 7)"), "-2100");
+    assert_eq!(eight_bit("div(-70000, 7)"), "-10000");
+}
+
+#[test]
+fn i32s_and_u32s_are_cast_to_be_mixed() {
+    let error = compile_error("fn start() {\n    def a = -1;\n    def b = 70000;\n    printf(\"{}\", add(a, b));\n}\n");
+    assert!(error.contains("`add` cannot mix an `i32` with a `u32`"), "{}", error);
+
+    // A cast reads the same cells as the other kind
+    assert_eq!(eight_bit("u32(-1)"), "4294967295");
+    assert_eq!(eight_bit("i32(4294967295)"), "-1");
+    // A signed cell keeps its sign, and a 4 byte number is cut down to a cell
+    assert_eq!(eight_bit("i32(neg(5))"), "-5");
+    assert_eq!(eight_bit("i32(200)"), "200");
+    assert_eq!(eight_bit("unsigned(i32(300))"), "44");
+}
+
+#[test]
+fn cells_are_cast_to_be_mixed_with_4_byte_numbers() {
+    let error = compile_error("fn start() {\n    def a = 7;\n    printf(\"{}\", add(a, -1));\n}\n");
+    assert!(error.contains("-1 does not fit in an unsigned number"), "{}", error);
+    assert_eq!(output("fn start() {\n    def a = 7;\n    printf(\"{}\", add(i32(a), -10));\n}\n"), "-3");
+}
+
+#[test]
+fn functions_take_and_return_4_byte_numbers_they_declare() {
+    let source = "fn diff(a: i32, b: i32) -> i32 {\n    return sub(a, b);\n}\n\nfn start() {\n    printf(\"{}\", diff(3, 10));\n}\n";
+    assert_eq!(output(source), "-7");
+
+    let error = compile_error("fn f() {\n    return -1;\n}\n\nfn start() {\n    printf(\"{}\", f());\n}\n");
+    assert!(error.contains("function `f` returns a 4 byte number, so it must declare its return type"), "{}", error);
+}
+
+#[test]
+fn a_4_byte_number_is_true_unless_it_is_zero() {
+    let source = "fn start() {\n    if -256 {\n        printf(\"yes\");\n    }\n    if i32(0) {\n        printf(\"no\");\n    }\n}\n";
+    assert_eq!(output(source), "yes");
+
+    let error = compile_error("fn start() {\n    def a = -1;\n    while a {\n        a = i32(0);\n    }\n}\n");
+    assert!(error.contains("the condition of a while loop must be a single cell"), "{}", error);
+}
+
+#[test]
+fn u32s_are_checked_for_overflow() {
+    let outcome = runner::run("#[enable(overflow_checks)]\nfn start() {\n    printf(\"{}\", sub(u32(3), 4));\n}\n", b"").unwrap();
+    assert_eq!(outcome.output(), b"OVERFLOW in sub\n");
+    assert_eq!(outcome.exit_status(), Some(1));
+
+    let outcome = runner::run("#[enable(overflow_checks)]\nfn start() {\n    printf(\"{}\", mul(70000, 70000));\n}\n", b"").unwrap();
+    assert_eq!(outcome.output(), b"OVERFLOW in mul\n");
+}
@@ -0,0 +1,464 @@
+mod common;
+
+use common::{compile_error, output, output_with_input, parse, warnings};
+use fr::{defforeign, lookup, runner, FnKind, Interpreter, Program};
+
+#[test]
+fn functions_can_be_called_before_they_are_defined() {
+    let source = r#"fn start() {
+    printf("{}\n", helper(2));
+}
+
+fn helper(n) {
+    return add(n, 40);
+}
+"#;
+    assert_eq!(output(source), "42\n");
+}
+
+#[test]
+fn functions_can_refer_to_each_other() {
+    let source = r#"fn ping(n) {
+    if n {
+        puts("ping");
+    }
+    return &fn pong;
+}
+
+fn pong(n) {
+    if n {
+        puts("pong");
+    }
+    return &fn ping;
+}
+
+fn start() {
+    def next = ping(1);
+    next = (next)(1);
+    (next)(1);
+}
+"#;
+    assert_eq!(output(source), "ping\npong\nping\n");
+}
+
+#[test]
+fn calls_to_missing_functions_are_errors_even_if_they_never_run() {
+    let error = compile_error(
+        r#"fn start() {
+    if 0 {
+        missing(1);
+    }
+}
+"#,
+    );
+    assert!(error.contains("FunctionNotDefined { name: \"missing\""), "{}", error);
+}
+
+#[test]
+fn defining_a_function_twice_is_an_error() {
+    let error = compile_error(
+        r#"fn helper() {
+    return 1;
+}
+
+fn helper() {
+    return 2;
+}
+
+fn start() {
+    printf("{}\n", helper());
+}
+"#,
+    );
+    assert!(error.contains("FunctionRedefined(\"helper\")"), "{}", error);
+}
+
+/// Compile a library that defines `helper`, and then parse a program
+/// that defines it again, without forgetting the library
+fn program_after_library() -> Program {
+    parse("fn helper() {\n    return 1;\n}\n").compile_library().unwrap();
+    let source = r#"fn helper() {
+    return 2;
+}
+
+fn start() {
+    exit(helper());
+}
+"#;
+    let (program, errors) = Program::parse_all(source);
+    assert!(errors.is_empty(), "could not parse: {:?}", errors);
+    program.unwrap()
+}
+
+#[test]
+fn a_program_cant_redefine_a_function_from_a_library() {
+    let error = program_after_library().compile().unwrap_err().to_string();
+    assert!(error.contains("FunctionRedefined(\"helper\")"), "{}", error);
+}
+
+#[test]
+fn redefining_can_be_allowed() {
+    let program = program_after_library();
+    Program::allow_redefine(true);
+    let code = program.compile().unwrap();
+    let mut interpreter = Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&code).unwrap();
+    assert_eq!(interpreter.exit_status(), Some(2));
+}
+
+#[test]
+fn a_function_that_shadows_a_foreign_function_is_warned_about() {
+    let program = parse(
+        r#"fn putc(ch) {
+    return ch;
+}
+
+fn start() {
+    putc('a');
+}
+"#,
+    );
+    assert_eq!(lookup("putc"), Some(FnKind::Foreign));
+    program.compile().unwrap();
+    assert_eq!(lookup("putc"), Some(FnKind::User));
+    assert!(
+        warnings().contains(&"function `putc` shadows the foreign function of the same name".to_string()),
+        "{:?}",
+        warnings()
+    );
+}
+
+#[test]
+fn a_foreign_function_that_is_shadowed_is_warned_about() {
+    parse("fn shout(x) {\n    return x;\n}\n").compile_library().unwrap();
+    defforeign("shout", &["x"], |_| Ok(None));
+    assert_eq!(lookup("shout"), Some(FnKind::User));
+    assert!(
+        warnings().contains(
+            &"foreign function `shout` is shadowed by the function of the same name defined by the program".to_string()
+        ),
+        "{:?}",
+        warnings()
+    );
+    assert_eq!(lookup("nothing"), None);
+}
+
+const PADDED: &str = r#"fn pad(value, width = 4, fill = '.') {
+    def n = sub(width, 1);
+    while n {
+        putc(fill);
+        n = sub(n, 1);
+    }
+    width = 0;
+    printf("{}\n", value);
+}
+
+"#;
+
+#[test]
+fn defaults_fill_in_arguments_that_are_left_out() {
+    let source = format!(
+        "{}{}",
+        PADDED,
+        r#"fn start() {
+    pad(1, 2, '-');
+    pad(2, 3);
+    pad(3);
+    pad(4);
+}
+"#
+    );
+    // Each call gets its own copy of a default, so changing it doesn't change the next call
+    assert_eq!(output(&source), "-1\n..2\n...3\n...4\n");
+}
+
+#[test]
+fn parameters_without_defaults_cant_be_left_out() {
+    let error = compile_error(&format!("{}fn start() {{\n    pad();\n}}\n", PADDED));
+    assert!(error.contains(r#"ArgumentCountMismatch { function: "pad""#), "{}", error);
+    assert!(error.contains("got: 0 }"), "{}", error);
+}
+
+const SWAP: &str = r#"fn swap(&a, &b) {
+    def t = a;
+    a = b;
+    b = t;
+}
+
+"#;
+
+#[test]
+fn reference_parameters_change_the_callers_variables() {
+    let source = format!(
+        "{}{}",
+        SWAP,
+        r#"fn start() {
+    def x = 1;
+    def y = 2;
+    swap(x, y);
+    printf("{} {}\n", x, y);
+    swap(&x, &y);
+    printf("{} {}\n", x, y);
+}
+"#
+    );
+    assert_eq!(output(&source), "2 1\n1 2\n");
+}
+
+#[test]
+fn a_reference_parameter_can_be_bound_to_what_a_pointer_points_at() {
+    let source = format!(
+        "{}{}",
+        SWAP,
+        r#"fn start() {
+    def x = 'a';
+    def y = 'b';
+    def p = &x;
+    swap(*p, y);
+    putc(x);
+    putc(y);
+    putc('\n');
+}
+"#
+    );
+    assert_eq!(output(&source), "ba\n");
+}
+
+#[test]
+fn a_reference_parameter_needs_a_variable() {
+    let error = compile_error(&format!("{}fn start() {{\n    def x = 1;\n    swap(x, 2);\n}}\n", SWAP));
+    assert!(error.contains("ReferenceArgumentNotAVariable"), "{}", error);
+    assert!(error.contains(r#"parameter: "b""#), "{}", error);
+}
+
+const DIVMOD: &str = r#"fn divmod(n, d) {
+    def q = 0;
+    def r = 0;
+    def left = d;
+    while n {
+        n = sub(n, 1);
+        r = add(r, 1);
+        left = sub(left, 1);
+        if left {} else {
+            q = add(q, 1);
+            r = 0;
+            left = d;
+        }
+    }
+    return (q, r);
+}
+
+"#;
+
+#[test]
+fn a_returned_tuple_can_be_destructured() {
+    let source = format!(
+        "{}{}",
+        DIVMOD,
+        r#"fn start() {
+    def n = 1234;
+    while n {
+        def (q, digit) = divmod(n, 10);
+        putc(add(digit, '0'));
+        n = q;
+    }
+    def (tens, ones) = divmod(42, 10);
+    printf("\n{} {}\n", tens, ones);
+    def both = divmod(17, 5);
+    printf("{} {}\n", both[0], both[1]);
+}
+"#
+    );
+    assert_eq!(output(&source), "4321\n4 2\n3 2\n");
+}
+
+#[test]
+fn destructuring_needs_a_name_for_every_value() {
+    let error = compile_error(&format!("{}fn start() {{\n    def (q, r, extra) = divmod(7, 2);\n}}\n", DIVMOD));
+    assert!(error.contains("DestructureArityMismatch"), "{}", error);
+}
+
+#[test]
+fn every_return_gives_the_same_number_of_values() {
+    let error = compile_error(
+        r#"fn pair(n) {
+    if n {
+        return (n, n);
+    }
+    return (n, n, n);
+}
+
+fn start() {
+    def (a, b) = pair(1);
+}
+"#,
+    );
+    assert!(error.contains("ReturnArityMismatch"), "{}", error);
+}
+
+#[test]
+fn a_tuple_holds_single_cells() {
+    let error = compile_error("fn pair() {\n    return (1, \"two\");\n}\n\nfn start() {\n    def (a, b) = pair();\n}\n");
+    assert!(error.contains("TupleElementNotSingleCell"), "{}", error);
+}
+
+#[test]
+fn arguments_can_be_passed_by_name() {
+    let source = format!(
+        "{}{}",
+        PADDED,
+        r#"fn start() {
+    pad(fill: '-', width: 3, value: 5);
+    pad(7, fill: '*');
+    pad(8, 2, fill: '+');
+    putc(ch: 'x');
+    putc('\n');
+}
+"#
+    );
+    assert_eq!(output(&source), "--5\n***7\n+8\nx\n");
+}
+
+#[test]
+fn a_parameter_can_only_be_given_once() {
+    let error = compile_error(&format!("{}fn start() {{\n    pad(1, value: 2);\n}}\n", PADDED));
+    assert!(error.contains(r#"DuplicateArgument { function: "pad", parameter: "value" }"#), "{}", error);
+}
+
+#[test]
+fn naming_a_parameter_the_function_doesnt_have_is_an_error() {
+    let error = compile_error(&format!("{}fn start() {{\n    pad(1, colour: 2);\n}}\n", PADDED));
+    assert!(error.contains("UnknownArgument"), "{}", error);
+    assert!(error.contains(r#""colour""#), "{}", error);
+}
+
+#[test]
+fn a_parameter_without_a_default_must_be_named_if_it_isnt_given() {
+    let error = compile_error(&format!("{}fn start() {{\n    pad(width: 2);\n}}\n", PADDED));
+    assert!(error.contains("MissingArgument"), "{}", error);
+    assert!(error.contains(r#""value""#), "{}", error);
+}
+
+const SHOW: &str = r#"fn show(x) {
+    putc(x);
+}
+
+fn show(x, width) {
+    putc(' ', width);
+    putc(x);
+}
+
+"#;
+
+#[test]
+fn functions_can_be_overloaded_by_how_many_arguments_they_take() {
+    let source = format!("{}fn start() {{\n    show('a');\n    show('b', 3);\n    putc('!', 2);\n    putc('\\n');\n}}\n", SHOW);
+    assert_eq!(output(&source), "a   b!!\n");
+}
+
+#[test]
+fn a_call_that_matches_no_overload_lists_the_ones_there_are() {
+    let error = compile_error(&format!("{}fn start() {{\n    show();\n}}\n", SHOW));
+    assert!(error.contains(r#"NoMatchingOverload { name: "show", got: 0, available: [1, 2] }"#), "{}", error);
+}
+
+#[test]
+fn overloads_that_could_take_the_same_arguments_are_an_error() {
+    let error = compile_error("fn show(x) {\n    putc(x);\n}\n\nfn show(x, width = 1) {\n    putc(x);\n}\n\nfn start() {\n    show('a');\n}\n");
+    assert!(error.contains("FunctionRedefined(\"show\")"), "{}", error);
+}
+
+#[test]
+fn a_function_can_be_defined_inside_another() {
+    let source = r#"fn twice(c) {
+    puts("the global twice");
+}
+
+fn print_row(ch) {
+    fn twice(c) {
+        putc(c);
+        putc(c);
+    }
+    twice(ch);
+    twice('|');
+}
+
+fn start() {
+    print_row('a');
+    putc('\n');
+    twice('b');
+}
+"#;
+    assert_eq!(output(source), "aa||\nthe global twice\n");
+}
+
+#[test]
+fn a_local_function_cant_be_called_from_outside() {
+    let error = compile_error("fn outer() {\n    fn inner() {\n        putc('a');\n    }\n    inner();\n}\n\nfn start() {\n    outer();\n    inner();\n}\n");
+    assert!(error.starts_with("10:5: "), "{}", error);
+    assert!(error.contains("\"inner\""), "{}", error);
+}
+
+#[test]
+fn a_local_function_cant_use_the_variables_around_it() {
+    let error = compile_error(
+        r#"fn outer() {
+    def count = 3;
+    fn inner() {
+        putc(count);
+    }
+    inner();
+}
+
+fn start() {
+    outer();
+}
+"#,
+    );
+    assert!(error.contains("CapturedVariable"), "{}", error);
+    assert!(error.contains(r#"variable: "count""#), "{}", error);
+}
+
+const DISPATCH: &str = r#"fn upper(c: char) -> char {
+    return sub(c, 32);
+}
+
+fn same(c: char) -> char {
+    return c;
+}
+
+fn start() {
+    def handlers = b"\0\0";
+    handlers[0] = &fn upper;
+    handlers[1] = &fn same;
+    def op = getc();
+    while op {
+        op = sub(op, '0');
+        putc(handlers[op]('a'));
+        op = getc();
+    }
+    putc('\n');
+}
+"#;
+
+#[test]
+fn functions_can_be_called_through_a_table_of_references() {
+    assert_eq!(output_with_input(DISPATCH, b"0"), "A\n");
+    assert_eq!(output_with_input(DISPATCH, b"1"), "a\n");
+    assert_eq!(output_with_input(DISPATCH, b"0110"), "AaaA\n");
+}
+
+#[test]
+fn calling_through_a_number_that_isnt_a_function_stops_the_program() {
+    let source = DISPATCH.replace("handlers[1] = &fn same;", "handlers[1] = 9;\n    def keep = &fn same;");
+    let outcome = runner::run(&source, b"01").unwrap();
+    assert_eq!(String::from_utf8_lossy(outcome.output()), "ABAD FUNCTION REFERENCE\n");
+    assert_eq!(outcome.exit_status(), Some(1));
+}
+
+#[test]
+fn every_function_an_indirect_call_can_reach_must_take_its_arguments() {
+    let source = DISPATCH.replace("fn same(c: char) -> char {\n    return c;", "fn same(c, d) -> char {\n    return c;");
+    let error = compile_error(&source);
+    assert!(error.contains("\"same\""), "{}", error);
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::output;
+use fr::runner;
+
+#[test]
+fn allocated_cells_can_be_written_and_read_back() {
+    let source = r#"fn start() {
+    def p = alloc(3);
+    p[0] = 'f';
+    p[1] = 'o';
+    p[2] = 'x';
+    putc(p[0]);
+    putc(p[1]);
+    putc(p[2]);
+    putc('\n');
+    free(p);
+}
+"#;
+    assert_eq!(output(source), "fox\n");
+}
+
+#[test]
+fn a_freed_block_is_given_out_again() {
+    let source = r#"fn start() {
+    def first = alloc(4);
+    first[0] = 'a';
+    free(first);
+    def second = alloc(4);
+    // The recycled block is zeroed again
+    putc(add(second[0], '0'));
+    putc('\n');
+    exit(sub(first, second));
+}
+"#;
+    let outcome = runner::run(source, b"").unwrap();
+    assert_eq!(String::from_utf8_lossy(outcome.output()), "0\n");
+    assert_eq!(outcome.exit_status(), Some(0));
+}
+
+#[test]
+fn running_out_of_heap_stops_the_program() {
+    let source = r#"#![heap_size(16)]
+
+fn start() {
+    def p = alloc(64);
+    puts("unreachable");
+}
+"#;
+    let outcome = runner::run(source, b"").unwrap();
+    assert_eq!(String::from_utf8_lossy(outcome.output()), "OUT OF MEMORY\n");
+    assert_eq!(outcome.exit_status(), Some(1));
+}
+
+#[test]
+fn freeing_a_block_twice_stops_the_program() {
+    let source = r#"fn start() {
+    def p = alloc(2);
+    free(p);
+    free(p);
+    puts("unreachable");
+}
+"#;
+    let outcome = runner::run(source, b"").unwrap();
+    assert_eq!(String::from_utf8_lossy(outcome.output()), "DOUBLE FREE\n");
+    assert_eq!(outcome.exit_status(), Some(1));
+}
@@ -0,0 +1,83 @@
+use fr::{reset, stdlib, Error, Program};
+use std::fs;
+use std::path::PathBuf;
+
+/// Write some files into a new directory, named after the test using them
+fn fixture(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fr-imports-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (name, text) in files {
+        fs::write(dir.join(name), text).unwrap();
+    }
+    dir
+}
+
+fn load(path: PathBuf) -> Result<Program, Error> {
+    reset();
+    let program = Program::from_file(path);
+    stdlib::install();
+    program
+}
+
+#[test]
+fn imported_files_are_linked_once() {
+    let dir = fixture(
+        "once",
+        &[
+            ("main.fr", "import \"greet.fr\";\nimport \"count.fr\";\n\nfn start() {\n    greet();\n    count(2);\n}\n"),
+            ("greet.fr", "import \"shared.fr\";\n\nfn greet() {\n    say(\"hi\");\n}\n"),
+            ("count.fr", "import \"shared.fr\";\n\nfn count(n) {\n    printf(\"{}\\n\", n);\n}\n"),
+            ("shared.fr", "fn say(s) {\n    puts(s);\n}\n"),
+        ],
+    );
+    // Linking `shared.fr` twice would define `say` twice
+    let program = load(dir.join("main.fr")).unwrap();
+    assert_eq!(program.run(b"").unwrap(), b"hi\n2\n");
+}
+
+#[test]
+fn the_import_example_compiles() {
+    let program = load(PathBuf::from("examples/import/main.fr")).unwrap();
+    let output = program.run(b"").unwrap();
+    assert!(String::from_utf8_lossy(&output).ends_with("\n123\n"), "{:?}", output);
+}
+
+#[test]
+fn import_cycles_are_errors() {
+    let dir = fixture(
+        "cycle",
+        &[
+            ("main.fr", "import \"a.fr\";\n\nfn start() {\n}\n"),
+            ("a.fr", "import \"b.fr\";\n\nfn a() {\n}\n"),
+            ("b.fr", "import \"a.fr\";\n\nfn b() {\n}\n"),
+        ],
+    );
+    match load(dir.join("main.fr")) {
+        Err(Error::ImportCycle(cycle)) => {
+            let names: Vec<_> = cycle.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+            assert_eq!(names, ["a.fr", "b.fr", "a.fr"]);
+        }
+        other => panic!("expected an import cycle, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn a_function_defined_in_two_files_names_both() {
+    let dir = fixture(
+        "twice",
+        &[
+            ("main.fr", "import \"a.fr\";\nimport \"b.fr\";\n\nfn start() {\n}\n"),
+            ("a.fr", "fn helper() {\n}\n"),
+            ("b.fr", "fn helper() {\n}\n"),
+        ],
+    );
+    match load(dir.join("main.fr")) {
+        Err(Error::FunctionRedefinedInFiles { name, first, second }) => {
+            assert_eq!(name, "helper");
+            assert!(first.ends_with("a.fr"), "{:?}", first);
+            assert!(second.ends_with("b.fr"), "{:?}", second);
+        }
+        other => panic!("expected a redefinition, got {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use common::parse;
+use fr::{CompileEvent, DiagnosticSink, Program};
+use std::sync::{Arc, Mutex};
+
+const PROGRAM: &str = r#"fn a() {
+    putc('a');
+}
+
+fn b() {
+    putc('b');
+}
+
+fn c() {
+    putc('c');
+}
+
+fn start() {
+    a();
+    b();
+    c();
+}
+"#;
+
+/// A sink that keeps every event it is given
+struct Recorder(Arc<Mutex<Vec<CompileEvent>>>);
+
+impl DiagnosticSink for Recorder {
+    fn on_event(&self, event: CompileEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+/// Compile a program, and return its code with the functions that were
+/// lowered again and the functions whose code was reused
+fn compile(source: &str) -> (String, Vec<String>, Vec<String>) {
+    let program = parse(source);
+    let events = Arc::new(Mutex::new(vec![]));
+    Program::set_diagnostic_sink(Some(Box::new(Recorder(events.clone()))));
+    let code = program.compile();
+    Program::set_diagnostic_sink(None);
+
+    let events = events.lock().unwrap();
+    let reused: Vec<String> = events
+        .iter()
+        .filter_map(|event| match event {
+            CompileEvent::Reuse { name, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let lowered = events
+        .iter()
+        .filter_map(|event| match event {
+            CompileEvent::Call { name, depth: 1 } if !reused.contains(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    (code.unwrap(), lowered, reused)
+}
+
+#[test]
+fn only_the_changed_function_is_lowered_again() {
+    Program::set_incremental(true);
+    let (_, lowered, reused) = compile(PROGRAM);
+    assert_eq!(lowered, ["a", "b", "c"]);
+    assert!(reused.is_empty(), "{:?}", reused);
+
+    let changed = PROGRAM.replace("putc('c')", "putc('C')");
+    let (code, lowered, reused) = compile(&changed);
+    assert_eq!(lowered, ["c"]);
+    assert_eq!(reused, ["a", "b"]);
+    Program::set_incremental(false);
+
+    assert_eq!(code, parse(&changed).compile().unwrap());
+}
+
+#[test]
+fn reused_code_is_the_code_compiled_before() {
+    Program::set_incremental(true);
+    let (first, _, _) = compile(PROGRAM);
+    let (second, lowered, _) = compile(PROGRAM);
+    Program::set_incremental(false);
+    assert!(lowered.is_empty(), "{:?}", lowered);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn turning_incremental_compiling_off_forgets_the_code() {
+    Program::set_incremental(true);
+    compile(PROGRAM);
+    Program::set_incremental(false);
+    assert!(!Program::incremental());
+    Program::set_incremental(true);
+    let (_, lowered, _) = compile(PROGRAM);
+    Program::set_incremental(false);
+    assert_eq!(lowered, ["a", "b", "c"]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn a_saved_cache_can_be_loaded_by_another_compiler() {
+    let path = std::env::temp_dir().join(format!("fr-cache-{}.json", std::process::id()));
+    Program::set_incremental(true);
+    let (code, _, _) = compile(PROGRAM);
+    Program::save_cache_to(&path).unwrap();
+    Program::set_incremental(false);
+
+    // Each thread has its own compiler, which starts without a cache
+    let loaded = std::thread::spawn(move || {
+        Program::load_cache_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        compile(PROGRAM)
+    });
+    let (loaded_code, lowered, reused) = loaded.join().unwrap();
+    // Nothing changed, so the code for the whole entry point is reused
+    assert!(lowered.is_empty(), "{:?}", lowered);
+    assert_eq!(reused, ["start"]);
+    assert_eq!(loaded_code, code);
+}
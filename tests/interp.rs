@@ -0,0 +1,71 @@
+mod common;
+
+use common::parse;
+use fr::{CellWidth, Interpreter, RunError};
+
+/// An interpreter reading from a slice and writing to a vector
+type Machine<'a> = Interpreter<&'a [u8], Vec<u8>>;
+
+/// Run code on a fresh interpreter, returning the interpreter to look at
+fn interpret<'a>(code: &str, input: &'a [u8]) -> (Result<(), RunError>, Machine<'a>) {
+    let mut interpreter = Interpreter::new(input, vec![]).with_tape_size(100).with_cell_width(CellWidth::Eight);
+    let result = interpreter.run(code);
+    (result, interpreter)
+}
+
+#[test]
+fn nested_loops_multiply() {
+    // 3 * 3 * 7 = 63, which is `?`
+    let (result, interpreter) = interpret("+++[>+++[>+++++++<-]<-]>>.", b"");
+    result.unwrap();
+    assert_eq!(interpreter.output(), b"?");
+    assert_eq!(&interpreter.tape()[..3], [0, 0, 63]);
+}
+
+#[test]
+fn echo_stops_at_the_end_of_the_input() {
+    // The cell keeps its value at the end of the input, so it is cleared before each read
+    let (result, interpreter) = interpret(",[.[-],]", b"echo me");
+    result.unwrap();
+    assert_eq!(interpreter.into_output(), b"echo me");
+}
+
+#[test]
+fn cells_wrap_at_their_width() {
+    let (result, interpreter) = interpret("-", b"");
+    result.unwrap();
+    assert_eq!(interpreter.tape()[0], 255);
+
+    let mut wide = Interpreter::new(&b""[..], vec![]).with_tape_size(1).with_cell_width(CellWidth::Sixteen);
+    wide.run("-").unwrap();
+    assert_eq!(wide.tape()[0], 65535);
+}
+
+#[test]
+fn runtime_errors_are_reported() {
+    assert!(matches!(interpret("<", b"").0, Err(RunError::TapeUnderflow)));
+    assert!(matches!(interpret("+[", b"").0, Err(RunError::UnmatchedBracket(_))));
+    assert!(matches!(interpret("+]", b"").0, Err(RunError::UnmatchedBracket(_))));
+    assert!(matches!(interpret(&">".repeat(100), b"").0, Err(RunError::TapeOverflow(100))));
+    assert!(matches!(interpret("&", b"").0, Err(RunError::RefUnderflow)));
+
+    let mut forever = Interpreter::new(&b""[..], vec![]).with_tape_size(2).with_step_limit(500);
+    match forever.run("+[>+<]") {
+        Err(RunError::StepLimit(500)) => assert_eq!(forever.steps(), 500),
+        other => panic!("expected the step limit, got {:?}", other),
+    }
+}
+
+#[test]
+fn programs_run_on_their_input() {
+    let program = parse("fn start() {\n    def c = getc();\n    putc(add(c, 1));\n    puts(\"!\");\n}\n");
+    assert_eq!(program.run(b"H").unwrap(), b"I!\n");
+}
+
+#[test]
+fn programs_that_dont_compile_are_compile_errors() {
+    let program = parse("fn start() {\n    putc(nothing);\n}\n");
+    let error = program.run(b"").unwrap_err();
+    assert!(matches!(error, RunError::Compile(_)), "{:?}", error);
+    assert!(error.to_string().contains("`nothing` is not defined"), "{}", error);
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::parse;
+use fr::{pretty_ir, IrOp};
+
+const FIXTURE: &str = r#"fn start() {
+    def a = 5;
+    def b = a;
+    putc(b);
+}
+"#;
+
+#[test]
+fn the_ir_of_a_program_is_printed_one_op_per_line() {
+    let ir = parse(FIXTURE).compile_to_ir().unwrap();
+    let expected = "fn start
+    alloc @8 size=1
+    set @8 = 5
+    bind %TEMP#0% = @8
+    bind a = @8
+    alloc @9 size=1
+    add @9 += @8 size=1
+    bind b = @9
+    alloc @10 size=1
+    add @10 += @9 size=1
+    bind ch = @10
+    print @10 size=1
+    free @10 size=1
+end fn start
+free @9 size=1
+free @8 size=1
+free @0 size=1
+free @1 size=1
+free @2 size=1
+free @3 size=1
+free @4 size=1
+free @5 size=1
+free @6 size=1
+free @7 size=1
+";
+    assert_eq!(pretty_ir(&ir), expected);
+}
+
+#[test]
+fn a_definition_allocates_once_and_copies_once() {
+    let ir = parse(FIXTURE).compile_to_ir().unwrap();
+    let bound = |variable: &str| {
+        ir.iter()
+            .position(|op| matches!(op, IrOp::Bind { name, .. } if name == variable))
+            .unwrap()
+    };
+    let (a, b) = (bound("a"), bound("b"));
+    let (from, to) = match (&ir[a], &ir[b]) {
+        (IrOp::Bind { at: from, .. }, IrOp::Bind { at: to, .. }) => (*from, *to),
+        _ => unreachable!(),
+    };
+
+    // The new cells are known to be zero, so the copy adds to them instead of clearing them first
+    let ops = &ir[a + 1..b];
+    assert_eq!(ops.len(), 2, "{}", pretty_ir(ops));
+    assert_eq!(ops[0], IrOp::Alloc { at: to, size: 1 });
+    assert!(
+        matches!(ops[1], IrOp::Copy { to: t, from: f, size: 1 } | IrOp::Add { to: t, from: f, size: 1 } if t == to && f == from),
+        "{}",
+        pretty_ir(ops)
+    );
+}
@@ -0,0 +1,205 @@
+mod common;
+
+use common::{compile_error, output, parse, warnings};
+
+#[test]
+fn functions_share_globals() {
+    let source = r#"def counter = 0;
+
+fn up() {
+    counter = add(counter, 1);
+}
+
+fn up_twice() {
+    counter = add(counter, 2);
+}
+
+fn show() {
+    printf("{}\n", counter);
+}
+
+fn start() {
+    up();
+    up_twice();
+    up();
+    show();
+}
+"#;
+    assert_eq!(output(source), "4\n");
+}
+
+#[test]
+fn globals_can_be_computed_from_literals() {
+    let source = r#"def width = add(40, 40);
+
+fn start() {
+    printf("{}\n", width);
+}
+"#;
+    assert_eq!(output(source), "80\n");
+}
+
+#[test]
+fn a_variable_hides_a_global_of_the_same_name() {
+    let source = r#"def n = 1;
+
+fn local() {
+    def n = 5;
+    n = add(n, 1);
+}
+
+fn start() {
+    local();
+    printf("{}\n", n);
+}
+"#;
+    assert_eq!(output(source), "1\n");
+}
+
+#[test]
+fn globals_must_be_known_while_compiling() {
+    let error = compile_error("def key = getc();\n\nfn start() {\n}\n");
+    assert!(error.contains(r#"NotConstant("key")"#), "{}", error);
+}
+
+#[test]
+fn constants_can_bound_a_loop() {
+    let source = r#"const COUNT = 3;
+
+fn start() {
+    def i = COUNT;
+    while i {
+        putc('*');
+        i = sub(i, 1);
+    }
+    putc('\n');
+}
+"#;
+    assert_eq!(output(source), "***\n");
+}
+
+#[test]
+fn constants_can_use_constants() {
+    let source = r#"const WIDTH = 80;
+const HALF = sub(WIDTH, 40);
+
+fn start() {
+    const STAR = '*';
+    printf("{} {} {}\n", WIDTH, HALF, STAR);
+}
+"#;
+    assert_eq!(output(source), "80 40 *\n");
+}
+
+#[test]
+fn constants_take_no_cells() {
+    let with_const = parse("const N = 7;\n\nfn start() {\n    exit(N);\n}\n").compile().unwrap();
+    let with_literal = parse("fn start() {\n    exit(7);\n}\n").compile().unwrap();
+    assert_eq!(with_const, with_literal);
+}
+
+#[test]
+fn constants_cant_be_assigned_to() {
+    let error = compile_error("const X = 1;\n\nfn start() {\n    X = 2;\n}\n");
+    assert!(error.contains(r#"AssignToConst("X")"#), "{}", error);
+}
+
+#[test]
+fn a_variable_hidden_by_a_constant_is_warned_about() {
+    let source = "const X = 1;\n\nfn start() {\n    def X = 2;\n    printf(\"{}\\n\", X);\n}\n";
+    assert_eq!(output(source), "1\n");
+    assert_eq!(
+        warnings(),
+        vec!["definition of `X` in function `start` is hidden by the constant of the same name"]
+    );
+}
+
+#[test]
+fn enum_variants_are_numbered_byte_constants() {
+    let source = r#"enum State { Start, Digit, Done = 10, After }
+
+fn name(state) {
+    if sub(state, State::Start) {
+        if sub(state, State::Digit) {
+            puts("done");
+        } else {
+            puts("digit");
+        }
+    } else {
+        puts("start");
+    }
+}
+
+fn start() {
+    printf("{} {} {} {}\n", State::Start, State::Digit, State::Done, State::After);
+    name(State::Start);
+    name(State::Digit);
+    name(State::Done);
+}
+"#;
+    assert_eq!(output(source), "0 1 10 11\nstart\ndigit\ndone\n");
+}
+
+#[test]
+fn enum_variants_cant_share_a_name_or_value() {
+    let error = compile_error("enum E { A = 1, B = 1 }\n\nfn start() {\n}\n");
+    assert!(error.contains(r#"DuplicateVariantValue("E::B", 1)"#), "{}", error);
+    let error = compile_error("enum E { A, A }\n\nfn start() {\n}\n");
+    assert!(error.contains(r#"DuplicateVariant("E::A")"#), "{}", error);
+}
+
+#[test]
+fn enum_variants_must_fit_in_a_byte() {
+    let error = compile_error("enum E { A = 255, B }\n\nfn start() {\n}\n");
+    assert!(error.contains(r#"VariantValueTooLarge("E::B")"#), "{}", error);
+}
+
+const POINT: &str = r#"struct Point { x, y }
+
+fn shift(p) {
+    p.x = add(p.x, 1);
+}
+
+fn moved(p) {
+    p.y = add(p.y, 1);
+    return p.y;
+}
+
+"#;
+
+#[test]
+fn structs_are_built_and_their_fields_read_and_written() {
+    let source = format!(
+        "{}{}",
+        POINT,
+        r#"fn start() {
+    def p = Point { x: 1, y: 2 };
+    p.y = add(p.y, 10);
+    printf("{} {} {}\n", p.x, p.y, sizeof(p));
+}
+"#
+    );
+    assert_eq!(output(&source), "1 12 2\n");
+}
+
+#[test]
+fn structs_are_copied_when_passed_and_changed_through_references() {
+    let source = format!(
+        "{}{}",
+        POINT,
+        r#"fn start() {
+    def p = Point { x: 1, y: 2 };
+    shift(&p);
+    def y = moved(p);
+    printf("{} {} {}\n", p.x, p.y, y);
+}
+"#
+    );
+    assert_eq!(output(&source), "2 2 3\n");
+}
+
+#[test]
+fn unknown_fields_are_errors() {
+    let error = compile_error(&format!("{}fn start() {{\n    def p = Point {{ x: 1, y: 2 }};\n    p.z = 1;\n}}\n", POINT));
+    assert!(error.contains(r#"UnknownField { structure: "Point", field: "z" }"#), "{}", error);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::parse;
+use fr::{call, free_ast, CellWidth, Error, Eval, Flag, Literal, Program, UserFn};
+
+/// A program built from Rust rather than parsed, with a `double` function
+fn built() -> Program {
+    let body = free_ast! {
+        return call add(n, n);
+    };
+    Program::new(vec![], vec![UserFn::new("double", vec!["n".to_string()], body)])
+}
+
+#[test]
+fn merged_programs_call_each_other() {
+    let parsed = parse(
+        r#"fn start() {
+    exit(double(triple(2)));
+}
+
+fn triple(n) {
+    return add(n, add(n, n));
+}
+"#,
+    );
+    let program = parsed.merge(built()).unwrap();
+    let names: Vec<&str> = program.functions().iter().map(UserFn::name).collect();
+    assert_eq!(names, ["start", "triple", "double"]);
+    let code = program.compile().unwrap();
+    let mut interpreter = fr::Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&code).unwrap();
+    assert_eq!(interpreter.exit_status(), Some(12));
+}
+
+#[test]
+fn merged_programs_cant_define_the_same_function() {
+    let parsed = parse("fn double(x) {\n    return x;\n}\n");
+    match parsed.merge(built()) {
+        Err(Error::FunctionRedefined(name)) => assert_eq!(name, "double"),
+        other => panic!("expected a redefinition, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn merged_programs_cant_disagree_about_flags() {
+    let eight = parse("#![cell_size(8)]\nfn start() {\n}\n");
+    let sixteen = Program::new(vec![Flag::CellSize(CellWidth::Sixteen)], vec![]);
+    assert_eq!(eight.flags().len(), 1);
+    assert!(matches!(eight.merge(sixteen), Err(Error::ConflictingFlags(_, _))));
+}
+
+const LIBRARY: &str = r#"fn square(n) {
+    def result = 0;
+    def i = n;
+    while i {
+        result = add(result, n);
+        i = sub(i, 1);
+    }
+    return result;
+}
+
+fn square_plus(n, extra) {
+    return add(square(n), extra);
+}
+"#;
+
+#[test]
+fn a_later_program_can_call_a_library() {
+    parse(LIBRARY).compile_library().unwrap();
+    let (program, errors) = Program::parse_all("fn start() {\n    exit(square_plus(3, 1));\n}\n");
+    assert!(errors.is_empty(), "{:?}", errors);
+    let code = program.unwrap().compile().unwrap();
+    let mut interpreter = fr::Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&code).unwrap();
+    assert_eq!(interpreter.exit_status(), Some(10));
+}
+
+#[test]
+fn a_library_can_be_called_from_rust() {
+    parse(LIBRARY).compile_library().unwrap();
+    call("square", &[Eval::Literal(Literal::number(4))]).unwrap();
+}
+
+#[test]
+fn libraries_are_checked() {
+    let error = parse("fn f() {\n    return missing(1);\n}\n").compile_library().unwrap_err();
+    assert!(error.to_string().contains(r#"FunctionNotDefined { name: "missing""#), "{}", error);
+    let error = parse("fn f() {\n    return x;\n}\n").compile_library().unwrap_err();
+    assert!(error.to_string().contains("the variable `x` is not defined"), "{}", error);
+}
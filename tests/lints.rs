@@ -0,0 +1,159 @@
+mod common;
+
+use common::{output, parse, warnings};
+use fr::{Program, Warning};
+
+#[test]
+fn an_unused_variable_is_warned_about() {
+    output("fn start() {\n    def used = 'a';\n    def unused = 'b';\n    putc(used);\n}\n");
+    assert_eq!(warnings(), ["3:5: variable `unused` is never used in function `start`"]);
+    match &Program::warnings()[..] {
+        [Warning::UnusedVariable { name, function, span }] => {
+            assert_eq!((name.as_str(), function.as_str()), ("unused", "start"));
+            assert!(span.is_some());
+        }
+        other => panic!("expected one unused variable, got {:?}", other),
+    }
+}
+
+#[test]
+fn an_underscore_keeps_a_variable_from_being_warned_about() {
+    output("fn start() {\n    def _ignored = 'b';\n    putc('a');\n}\n");
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+#[test]
+fn an_unused_parameter_is_warned_about() {
+    output("fn show(c, unused) {\n    putc(c);\n}\n\nfn start() {\n    show('a', 'b');\n}\n");
+    let warnings = warnings();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].ends_with("variable `unused` is never used in function `show`"), "{:?}", warnings);
+}
+
+#[test]
+fn referring_to_a_variable_uses_it() {
+    output("fn bump(p) {\n    *p = add(*p, 1);\n}\n\nfn start() {\n    def x = 'a';\n    bump(&x);\n}\n");
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+#[test]
+fn temporaries_are_never_warned_about() {
+    output("fn start() {\n    putc(add('a', add(1, 2)));\n    puts(\"temporaries\");\n}\n");
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+#[test]
+fn unused_warnings_can_be_turned_off() {
+    output("#![allow(unused)]\n\nfn start() {\n    def unused = 'b';\n}\n");
+    assert!(warnings().is_empty(), "{:?}", warnings());
+
+    let program = parse("fn start() {\n    def unused = 'b';\n}\n");
+    Program::allow_lint("unused");
+    program.compile().unwrap();
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+#[test]
+fn hiding_a_global_is_shadowing() {
+    output("def n = 1;\n\nfn start() {\n    def n = 5;\n    printf(\"{}\", n);\n}\n");
+    match &Program::warnings()[..] {
+        [Warning::ShadowedVariable { name, function, shadow_span, .. }] => {
+            assert_eq!((name.as_str(), function.as_str()), ("n", "start"));
+            assert_eq!(shadow_span.unwrap().to_string(), "4:5");
+        }
+        other => panic!("expected one shadowed global, got {:?}", other),
+    }
+}
+
+#[test]
+fn a_parameter_can_shadow_a_global() {
+    output("def c = 'x';\n\nfn show(c) {\n    putc(c);\n}\n\nfn start() {\n    show('a');\n}\n");
+    let warnings = warnings();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("variable `c` in function `show` shadows the global `c`"), "{:?}", warnings);
+}
+
+#[test]
+fn defining_a_variable_again_in_its_scope_is_a_redefinition() {
+    // Blocks don't open a scope, so the definition in the loop replaces the first one too
+    output("fn start() {\n    def a = 'a';\n    def a = 'b';\n    def i = 1;\n    while i {\n        def a = 'c';\n        i = 0;\n    }\n    putc(a);\n}\n");
+    let redefined: Vec<(String, String)> = Program::warnings()
+        .iter()
+        .filter_map(|warning| match warning {
+            Warning::Redefinition { name, original_span, span, .. } => {
+                Some((name.clone(), format!("{} then {}", original_span.unwrap(), span.unwrap())))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        redefined,
+        [("a".to_string(), "2:5 then 3:5".to_string()), ("a".to_string(), "2:5 then 6:9".to_string())]
+    );
+    assert_eq!(Program::warnings().len(), 2, "{:?}", warnings());
+}
+
+#[test]
+fn an_underscore_keeps_shadowing_from_being_warned_about() {
+    output("def _n = 1;\n\nfn start() {\n    def _n = 5;\n    def _n = 6;\n    printf(\"{}\", _n);\n}\n");
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+/// Three functions, one of which nothing calls
+const ORPHAN: &str = r#"fn helper() {
+    putc('h');
+}
+
+fn orphan() {
+    putc('o');
+}
+
+fn start() {
+    helper();
+}
+"#;
+
+#[test]
+fn a_function_nothing_calls_is_warned_about() {
+    output(ORPHAN);
+    assert_eq!(warnings(), ["5:1: function `orphan` is never called"]);
+}
+
+#[test]
+fn unused_functions_are_found_without_optimizing() {
+    output(&format!("#![optimize(0)]\n\n{}", ORPHAN));
+    assert_eq!(warnings().len(), 1, "{:?}", warnings());
+}
+
+#[test]
+fn the_functions_only_an_unused_function_calls_are_unused_too() {
+    output("fn inner() {\n}\n\nfn outer() {\n    inner();\n}\n\nfn start() {\n}\n");
+    let unused: Vec<String> = Program::warnings()
+        .into_iter()
+        .filter_map(|warning| match warning {
+            Warning::UnusedFunction { name, .. } => Some(name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(unused, ["inner", "outer"]);
+}
+
+#[test]
+fn functions_can_be_kept_without_being_called() {
+    output(&ORPHAN.replace("fn orphan", "#[keep]\nfn orphan"));
+    assert!(warnings().is_empty(), "{:?}", warnings());
+
+    output(&ORPHAN.replace("fn orphan", "fn _orphan"));
+    assert!(warnings().is_empty(), "{:?}", warnings());
+
+    let program = parse(ORPHAN);
+    Program::mark_used("orphan");
+    program.compile().unwrap();
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
+
+#[test]
+fn every_function_of_a_library_may_be_used() {
+    parse(ORPHAN).compile_library().unwrap();
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
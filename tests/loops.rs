@@ -0,0 +1,99 @@
+mod common;
+
+use common::{compile_error, output, output_with_input, parse, warnings};
+
+#[test]
+fn a_range_runs_the_body_for_every_number_in_it() {
+    assert_eq!(output("fn start() {\n    for i in 0..10 {\n        putc(add('0', i));\n    }\n    putc('\\n');\n}\n"), "0123456789\n");
+    assert_eq!(output("fn start() {\n    for i in 3..6 {\n        putc(add('0', i));\n    }\n    putc('\\n');\n}\n"), "345\n");
+}
+
+#[test]
+fn an_empty_range_runs_the_body_zero_times() {
+    for range in ["5..5", "7..3", "0..0"] {
+        let source = format!("fn start() {{\n    for i in {} {{\n        putc('x');\n    }}\n    putc('\\n');\n}}\n", range);
+        assert_eq!(output(&source), "\n", "{}", range);
+    }
+}
+
+const RUNTIME_BOUNDS: &str = r#"fn start() {
+    def from = sub(getc(), '0');
+    def to = sub(getc(), '0');
+    for i in from..to {
+        putc(add('0', i));
+        i = 0;
+    }
+    putc('\n');
+}
+"#;
+
+#[test]
+fn the_bounds_can_be_worked_out_while_running() {
+    assert_eq!(output_with_input(RUNTIME_BOUNDS, b"25"), "234\n");
+    assert_eq!(output_with_input(RUNTIME_BOUNDS, b"44"), "\n");
+    assert_eq!(output_with_input(RUNTIME_BOUNDS, b"81"), "\n");
+}
+
+#[test]
+fn literal_bounds_make_a_smaller_loop() {
+    let literal = parse("fn start() {\n    for i in 2..5 {\n        putc(add('0', i));\n    }\n}\n").compile().unwrap();
+    let runtime = parse("fn start() {\n    def a = 2;\n    def b = 5;\n    for i in a..b {\n        putc(add('0', i));\n    }\n}\n").compile().unwrap();
+    assert!(literal.len() < runtime.len(), "{} >= {}", literal.len(), runtime.len());
+}
+
+#[test]
+fn the_loop_variable_is_only_defined_in_the_body() {
+    let error = compile_error("fn start() {\n    for i in 0..3 {\n        putc('x');\n    }\n    putc(i);\n}\n");
+    assert!(error.starts_with("5:5: the variable `i` is not defined"), "{}", error);
+}
+
+#[test]
+fn repeat_is_smaller_than_the_same_loop() {
+    let repeated = "fn start() {\n    repeat 3 {\n        putc(' ');\n    }\n    putc('|');\n}\n";
+    let looped = "fn start() {\n    def n = 3;\n    while n {\n        putc(' ');\n        n = sub(n, 1);\n    }\n    putc('|');\n}\n";
+    assert_eq!(output(repeated), "   |");
+    assert_eq!(output(looped), "   |");
+    let repeated = parse(repeated).compile().unwrap();
+    let looped = parse(looped).compile().unwrap();
+    assert!(repeated.len() < looped.len(), "{} >= {}", repeated.len(), looped.len());
+}
+
+#[test]
+fn repeating_zero_times_compiles_to_nothing() {
+    let zero = parse("fn start() {\n    repeat 0 {\n        putc(' ');\n    }\n    putc('|');\n}\n").compile().unwrap();
+    let without = parse("fn start() {\n    putc('|');\n}\n").compile().unwrap();
+    assert_eq!(zero, without);
+}
+
+#[test]
+fn each_time_through_a_repeat_gets_its_own_variables() {
+    let source = r#"const TIMES = 3;
+
+fn start() {
+    def n = 0;
+    repeat TIMES {
+        def next = add(n, 1);
+        n = next;
+        putc(add('0', next));
+    }
+    putc('\n');
+}
+"#;
+    assert_eq!(output(source), "123\n");
+    let error = compile_error("fn start() {\n    repeat 2 {\n        def x = 'a';\n    }\n    putc(x);\n}\n");
+    assert!(error.starts_with("5:5: the variable `x` is not defined"), "{}", error);
+}
+
+#[test]
+fn a_repeat_count_must_be_known_while_compiling() {
+    let error = compile_error("fn start() {\n    def n = getc();\n    repeat n {\n        putc(' ');\n    }\n}\n");
+    assert!(error.contains("RepeatCountNotConstant(\"n\")"), "{}", error);
+}
+
+#[test]
+fn a_large_repeat_is_warned_about() {
+    parse("fn start() {\n    repeat 2000 {\n        putc(' ');\n    }\n}\n").compile().unwrap();
+    assert_eq!(warnings(), ["`repeat` in function `start` compiles its body 2000 times, which makes the output large"]);
+    parse("fn start() {\n    repeat 1000 {\n        putc(' ');\n    }\n}\n").compile().unwrap();
+    assert!(warnings().is_empty(), "{:?}", warnings());
+}
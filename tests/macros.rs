@@ -0,0 +1,79 @@
+mod common;
+
+use common::output;
+use fr::*;
+
+fn load(name: &str) -> Eval {
+    Eval::Load(Load::new(name))
+}
+
+fn call(name: &str, args: Vec<Eval>) -> Eval {
+    Eval::Call(Call::new(name, args))
+}
+
+fn number(n: u16) -> Eval {
+    Eval::Literal(Literal::number(n))
+}
+
+#[test]
+fn the_macro_builds_the_same_tree_as_the_constructors() {
+    let one = number(1);
+    let built: Vec<Expr> = free_ast! {
+        define x = 5;
+        def p = &x;
+        while (x) {
+            x = call sub(x, #one);
+        }
+        if (call eq(*p, 0)) {
+            call puts("done");
+        } else {
+            call putc('?');
+        }
+        return (*p);
+    };
+
+    let by_hand = vec![
+        Expr::Define(Define::new("x", number(5))),
+        Expr::Define(Define::new("p", Eval::Refer(Refer::new(load("x"))))),
+        Expr::While(While::new(
+            load("x"),
+            vec![Expr::Assign(Assign::new(load("x"), call("sub", vec![load("x"), number(1)])))],
+        )),
+        Expr::If(If::new(
+            call("eq", vec![Eval::Deref(Deref::new(load("p"))), number(0)]),
+            vec![Expr::Eval(call("puts", vec![Eval::Literal(Literal::string("done"))]))],
+            vec![Expr::Eval(call("putc", vec![Eval::Literal(Literal::character('?'))]))],
+        )),
+        Expr::Return(Return::new(Eval::Deref(Deref::new(load("p"))))),
+    ];
+    assert_eq!(built, by_hand);
+}
+
+#[test]
+fn interpolated_expressions_are_used_as_they_are() {
+    let total = call("add", vec![number(2), number(3)]);
+    let built: Vec<Expr> = free_ast! {
+        call putc(#(call("add", vec![Eval::Literal(Literal::character('0')), total.clone()])));
+    };
+    let expected = Expr::Eval(call("putc", vec![call("add", vec![Eval::Literal(Literal::character('0')), total])]));
+    assert_eq!(built, [expected]);
+}
+
+#[test]
+fn namespaced_calls_keep_their_path() {
+    let built: Vec<Expr> = free_ast! { call str::len("abc"); };
+    assert_eq!(built, [Expr::Eval(call("str::len", vec![Eval::Literal(Literal::string("abc"))]))]);
+}
+
+#[test]
+fn a_built_program_runs() {
+    let body: Vec<Expr> = free_ast! {
+        def x = 3;
+        while (x) {
+            call putc(call add('0', x));
+            x = call sub(x, 1);
+        }
+    };
+    let source = Program::new(vec![], vec![UserFn::new("start", vec![], body)]).to_source();
+    assert_eq!(output(&source), "321");
+}
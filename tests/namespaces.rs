@@ -0,0 +1,93 @@
+mod common;
+
+use common::{compile_error, output, parse};
+use fr::{defforeign, lookup, FnKind};
+
+#[test]
+fn a_module_function_hides_a_top_level_one_inside_the_module() {
+    let source = r#"fn len(s) {
+    return 0;
+}
+
+mod str {
+    fn len(s) {
+        return strlen(s);
+    }
+
+    fn length_of(s) {
+        return len(s);
+    }
+}
+
+fn start() {
+    printf("{} {} {}\n", len("hello"), str::len("hello"), str::length_of("abc"));
+}
+"#;
+    assert_eq!(output(source), "0 5 3\n");
+}
+
+#[test]
+fn a_module_finds_top_level_functions() {
+    let source = r#"fn twice(n) {
+    return add(n, n);
+}
+
+mod math {
+    fn quadruple(n) {
+        return twice(twice(n));
+    }
+}
+
+fn start() {
+    printf("{}\n", math::quadruple(3));
+}
+"#;
+    assert_eq!(output(source), "12\n");
+}
+
+#[test]
+fn functions_are_registered_by_their_qualified_name() {
+    let program = parse("mod str {\n    fn len(s) {\n        return strlen(s);\n    }\n}\n");
+    program.compile_library().unwrap();
+    assert_eq!(lookup("str::len"), Some(FnKind::User));
+    assert_eq!(lookup("len"), None);
+}
+
+#[test]
+fn an_unknown_namespace_is_an_error() {
+    let error = compile_error("fn start() {\n    def n = text::len(\"a\");\n}\n");
+    assert!(error.contains("FunctionNotDefined"), "{}", error);
+    assert!(error.contains("\"text::len\""), "{}", error);
+    assert!(error.contains("searched: [\"text\"]"), "{}", error);
+}
+
+#[test]
+fn a_missing_function_lists_the_namespaces_searched() {
+    let error = compile_error(
+        r#"mod outer {
+    mod inner {
+        fn f() {
+            missing();
+        }
+    }
+}
+
+fn start() {
+    outer::inner::f();
+}
+"#,
+    );
+    assert!(error.contains("searched: [\"outer::inner\", \"outer\", \"\"]"), "{}", error);
+}
+
+#[test]
+fn foreign_functions_can_be_put_in_a_namespace() {
+    let program = parse("fn start() {\n    printf(\"{}\\n\", io::plus(4, 8));\n}\n");
+    defforeign("io::plus", &["a", "b", "-> u16"], |args| {
+        let result = args[0].copy()?;
+        result.plus_eq(args[1]);
+        Ok(Some(result))
+    });
+    assert_eq!(lookup("io::plus"), Some(FnKind::Foreign));
+    assert_eq!(program.run(b"").unwrap(), b"12\n");
+}
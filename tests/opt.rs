@@ -0,0 +1,355 @@
+mod common;
+
+use common::{compile_error, output, parse};
+use fr::{enabled_passes, fold, passes, pretty_ir, reset, runner, Call, Eval, Interpreter, IrOp, Literal, Load, Pass, Peephole, Program, MAX_OPTIMIZE_LEVEL};
+
+/// A program with a pragma choosing its optimization level in front of it
+fn at_level(level: u8, source: &str) -> String {
+    format!("#![optimize({})]\n{}", level, source)
+}
+
+const COUNTDOWN: &str = r#"
+fn show(n) {
+    printf("{} ", n);
+}
+
+fn start() {
+    def n = 5;
+    while n {
+        show(n);
+        n = sub(n, 1);
+    }
+    puts("done");
+}
+"#;
+
+#[test]
+fn the_optimize_pragma_sets_the_level() {
+    parse(&at_level(2, COUNTDOWN)).compile().unwrap();
+    assert_eq!(Program::optimize_level(), 2);
+}
+
+#[test]
+fn levels_above_the_highest_are_rejected_or_clamped() {
+    let error = compile_error(&at_level(3, COUNTDOWN));
+    assert!(error.contains("optimization level must be at most 2"), "{}", error);
+
+    Program::set_optimize_level(9);
+    assert_eq!(Program::optimize_level(), MAX_OPTIMIZE_LEVEL);
+}
+
+#[test]
+fn each_level_enables_the_passes_up_to_it() {
+    let mut counts = vec![];
+    for level in 0..=MAX_OPTIMIZE_LEVEL {
+        Program::set_optimize_level(level);
+        let enabled = enabled_passes();
+        assert!(enabled.iter().all(|pass| pass.level() <= level));
+        counts.push(enabled.len());
+    }
+    assert!(counts.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", counts);
+    assert_eq!(counts.last(), Some(&passes().len()));
+}
+
+#[test]
+fn every_level_runs_the_same_way() {
+    for level in 0..=MAX_OPTIMIZE_LEVEL {
+        assert_eq!(output(&at_level(level, COUNTDOWN)), "5 4 3 2 1 done\n", "at level {}", level);
+    }
+}
+
+#[test]
+fn higher_levels_run_in_fewer_steps() {
+    let steps: Vec<u64> = (0..=MAX_OPTIMIZE_LEVEL)
+        .map(|level| runner::run(&at_level(level, COUNTDOWN), b"").unwrap().run().steps)
+        .collect();
+    assert!(steps.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", steps);
+}
+
+fn num(n: u16) -> Eval {
+    Eval::Literal(Literal::number(n))
+}
+
+fn call(name: &str, args: Vec<Eval>) -> Eval {
+    Eval::Call(Call::new(name, args))
+}
+
+#[test]
+fn folding_computes_arithmetic_on_literals() {
+    reset();
+    assert_eq!(fold(call("add", vec![num(2), num(3)])), num(5));
+    assert_eq!(fold(call("add", vec![call("sub", vec![num(10), num(3)]), num(1)])), num(8));
+    // A negated number is signed, which a literal can't say
+    assert_eq!(fold(call("neg", vec![num(1)])), call("neg", vec![num(1)]));
+    assert_eq!(fold(call("strlen", vec![Eval::Literal(Literal::string("abc"))])), num(3));
+    assert_eq!(fold(call("add", vec![Eval::Literal(Literal::character('a')), num(1)])), Eval::Literal(Literal::character('b')));
+}
+
+#[test]
+fn folding_leaves_variables_alone() {
+    reset();
+    let expr = call("add", vec![Eval::Load(Load::new("x")), call("add", vec![num(1), num(2)])]);
+    assert_eq!(fold(expr), call("add", vec![Eval::Load(Load::new("x")), num(3)]));
+}
+
+#[test]
+fn folding_removes_branches_with_constant_conditions() {
+    let source = r#"#![optimize(1)]
+fn start() {
+    if sub(2, 2) {
+        puts("then");
+    } else {
+        puts("else");
+    }
+}
+"#;
+    let ir = parse(source).compile_to_ir().unwrap();
+    assert!(!ir.iter().any(|op| matches!(op, IrOp::IfBegin(_))), "{}", pretty_ir(&ir));
+    assert_eq!(output(source), "else\n");
+}
+
+#[test]
+fn builtins_the_program_redefines_are_not_folded() {
+    let source = r#"#![optimize(1)]
+fn add(a, b) {
+    return 7;
+}
+
+fn start() {
+    printf("{}\n", add(1, 2));
+}
+"#;
+    assert_eq!(output(source), "7\n");
+}
+
+#[test]
+fn statements_after_a_return_still_run() {
+    let source = r#"fn f(x) {
+    return 1;
+    puts("after return");
+}
+
+fn start() {
+    printf("{}\n", f(0));
+}
+"#;
+    assert_eq!(output(&at_level(0, source)), "after return\n1\n");
+    assert_eq!(output(&at_level(1, source)), output(&at_level(0, source)));
+}
+
+#[test]
+fn peephole_removes_redundant_instructions() {
+    assert_eq!(Peephole.transform_output("+-<>".to_string()), "");
+    assert_eq!(Peephole.transform_output(">+<-><".to_string()), ">+<-");
+    assert_eq!(Peephole.transform_output("[-][-]".to_string()), "[-]");
+    assert_eq!(Peephole.transform_output("[-][>+<-]".to_string()), "[-]");
+    assert_eq!(Peephole.transform_output("+[>+<-][->+<]".to_string()), "+[>+<-]");
+}
+
+#[test]
+fn peephole_keeps_loops_that_may_run() {
+    assert_eq!(Peephole.transform_output(",[-]".to_string()), ",[-]");
+    assert_eq!(Peephole.transform_output("+[-]-".to_string()), "+[-]-");
+    assert_eq!(Peephole.transform_output("[->+<]>[-<+>]".to_string()), "[->+<]>[-<+>]");
+}
+
+/// Run some code, or return `None` if it fails or takes too long
+fn interpret(code: &str) -> Option<Vec<u8>> {
+    let mut interpreter = Interpreter::new(&b""[..], vec![]).with_step_limit(10_000);
+    interpreter.run(code).ok()?;
+    Some(interpreter.into_output())
+}
+
+#[test]
+fn peephole_shrinks_a_compiled_program() {
+    let code = parse(&at_level(1, COUNTDOWN)).compile().unwrap();
+    let optimized = Peephole.transform_output(code.clone());
+    assert!(optimized.len() < code.len(), "{} >= {}", optimized.len(), code.len());
+
+    let mut interpreter = Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&optimized).unwrap();
+    assert_eq!(interpreter.into_output(), b"5 4 3 2 1 done\n");
+}
+
+#[test]
+fn peephole_does_not_change_what_code_does() {
+    // A small random number generator, so every run checks the same programs
+    let mut seed: u32 = 12345;
+    let mut next = move |n: u32| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (seed >> 16) % n
+    };
+
+    for _ in 0..2000 {
+        let mut code = String::new();
+        let mut depth = 0;
+        for _ in 0..next(40) {
+            match next(8) {
+                0 => code.push('+'),
+                1 => code.push('-'),
+                2 => code.push('>'),
+                3 if code.ends_with('>') => code.push('<'),
+                4 => code.push_str("[-]"),
+                5 => {
+                    code.push('[');
+                    depth += 1;
+                }
+                6 if depth > 0 => {
+                    code.push(']');
+                    depth -= 1;
+                }
+                _ => code.push('.'),
+            }
+        }
+        code.push_str(&"]".repeat(depth));
+
+        if let Some(expected) = interpret(&code) {
+            let optimized = Peephole.transform_output(code.clone());
+            assert_eq!(interpret(&optimized), Some(expected), "{} became {}", code, optimized);
+        }
+    }
+}
+
+#[test]
+fn inlining_copies_arguments_passed_on_by_reference() {
+    let source = r#"fn inc(&x) {
+    x = add(x, 1);
+}
+
+fn bump(p) {
+    inc(p);
+    return p;
+}
+
+fn start() {
+    def v = 5;
+    def w = bump(v);
+    printf("{} {}\n", v, w);
+}
+"#;
+    assert_eq!(output(&at_level(0, source)), "5 6\n");
+    assert_eq!(output(&at_level(2, source)), "5 6\n");
+}
+
+#[test]
+fn inlining_shrinks_calls_to_small_functions() {
+    let program = |attribute: &str| {
+        format!(
+            r#"#![optimize(2)]
+{}
+fn plus(a, b) {{
+    return add(a, b);
+}}
+
+fn start() {{
+    def total = 0;
+    def n = 10;
+    while n {{
+        total = plus(total, n);
+        n = sub(n, 1);
+    }}
+    exit(total);
+}}
+"#,
+            attribute
+        )
+    };
+    let inlined = parse(&program("")).compile().unwrap();
+    let called = parse(&program("#[inline(never)]")).compile().unwrap();
+    assert!(inlined.len() < called.len() * 4 / 5, "{} vs {}", inlined.len(), called.len());
+    for attribute in ["", "#[inline(never)]"] {
+        assert_eq!(runner::run(&program(attribute), b"").unwrap().exit_status(), Some(55));
+    }
+}
+
+/// A function too big to be inlined on its own, called from a loop
+fn big_function(attribute: &str) -> String {
+    format!(
+        "#![optimize(2)]\n{}\nfn shout(c) {{\n{}}}\n\nfn start() {{\n    def n = 2;\n    while n {{\n        shout(add('a', n));\n        n = sub(n, 1);\n    }}\n    putc('\\n');\n}}\n",
+        attribute,
+        "    putc(c);\n    putc(add(c, 1));\n    putc(sub(c, 1));\n".repeat(10)
+    )
+}
+
+#[test]
+fn inline_inlines_functions_of_any_size() {
+    let called = parse(&big_function("")).compile_to_ir().unwrap();
+    let inlined = parse(&big_function("#[inline]")).compile_to_ir().unwrap();
+    let calls = |ir: &[IrOp]| ir.iter().filter(|op| matches!(op, IrOp::FnBegin(name) if name == "shout")).count();
+    assert!(calls(&called) > 0, "{}", pretty_ir(&called));
+    assert_eq!(calls(&inlined), 0, "{}", pretty_ir(&inlined));
+    let expected = "cdb".repeat(10) + &"bca".repeat(10) + "\n";
+    assert_eq!(output(&big_function("")), expected);
+    assert_eq!(output(&big_function("#[inline]")), expected);
+}
+
+#[test]
+fn a_function_that_calls_itself_cant_be_inlined() {
+    let error = compile_error("#[inline]\nfn down(n) {\n    if n {\n        down(sub(n, 1));\n    }\n}\n\nfn start() {\n    down(3);\n}\n");
+    assert!(error.contains("RecursiveInline(\"down\")"), "{}", error);
+}
+
+#[test]
+fn an_unknown_attribute_is_an_error() {
+    let (_, errors) = Program::parse_all("#[inlien]\nfn f() {\n}\n\nfn start() {\n    f();\n}\n");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].to_string().contains("unknown attribute `inlien`"), "{}", errors[0]);
+}
+
+/// How many loops a program compiles to
+fn loops(source: &str) -> usize {
+    let ir = parse(source).compile_to_ir().unwrap();
+    ir.iter().filter(|op| matches!(op, IrOp::LoopBegin(_))).count()
+}
+
+#[test]
+fn short_loops_are_unrolled_at_level_two() {
+    let source = "fn start() {\n    for i in 0..3 {\n        putc(add('a', i));\n    }\n    def n = 3;\n    while n {\n        putc(add('0', n));\n        n = sub(n, 1);\n    }\n    putc('\\n');\n}\n";
+    assert_eq!(loops(&at_level(1, source)), 2);
+    assert_eq!(loops(&at_level(2, source)), 0);
+    assert_eq!(output(&at_level(1, source)), "abc321\n");
+    assert_eq!(output(&at_level(2, source)), "abc321\n");
+}
+
+#[test]
+fn long_loops_are_left_alone() {
+    let source = "fn start() {\n    def total = 0;\n    for i in 0..1000 {\n        total = add(total, 1);\n    }\n    exit(sub(total, 1000));\n}\n";
+    assert_eq!(loops(&at_level(2, source)), loops(&at_level(1, source)));
+    assert_eq!(runner::run(&at_level(2, source), b"").unwrap().exit_status(), Some(0));
+}
+
+#[test]
+fn loops_that_lend_their_variable_or_hold_loops_are_left_alone() {
+    let lent = "fn look(p) {\n    putc(add('a', *p));\n}\n\nfn start() {\n    for i in 0..3 {\n        look(&i);\n    }\n    putc('\\n');\n}\n";
+    assert_eq!(loops(&at_level(2, lent)), loops(&at_level(1, lent)));
+    assert_eq!(output(&at_level(2, lent)), "abc\n");
+
+    let nested = "fn start() {\n    for i in 0..2 {\n        def n = getc();\n        while n {\n            putc(n);\n            n = getc();\n        }\n    }\n}\n";
+    assert_eq!(loops(&at_level(2, nested)), loops(&at_level(1, nested)));
+}
+
+/// Compile a source file with the command line compiler to SMPL, and return the code it wrote
+fn compile_with_binary(name: &str, source: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("fr-opt-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (input, output) = (dir.join("main.fr"), dir.join("out.smpl"));
+    std::fs::write(&input, source).unwrap();
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_fr"))
+        .arg(&input)
+        .arg(&output)
+        .arg("-b")
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let code = std::fs::read_to_string(&output).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    code
+}
+
+#[test]
+fn the_command_line_optimizes_unless_the_source_sets_a_level() {
+    let explicit = at_level(0, COUNTDOWN);
+    assert_eq!(compile_with_binary("explicit", &explicit), parse(&explicit).compile().unwrap());
+    assert_eq!(compile_with_binary("default", COUNTDOWN), parse(&at_level(1, COUNTDOWN)).compile().unwrap());
+}
@@ -0,0 +1,141 @@
+mod common;
+
+use common::parse;
+use fr::{Error, Interpreter, Program};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+const PROGRAM: &str = r#"fn greet(name) {
+    puts("hello");
+    puts(name);
+}
+
+fn start() {
+    greet("world");
+    def i = 2;
+    while i {
+        greet("again");
+        i = sub(i, 1);
+    }
+}
+"#;
+
+/// The error under the calls and the place it was reported at
+fn innermost(error: &Error) -> &Error {
+    match error {
+        Error::Trace(_, error) | Error::At(_, error) => innermost(error),
+        error => error,
+    }
+}
+
+#[test]
+fn compiling_to_a_writer_gives_the_same_bytes_every_time() {
+    let first = parse(PROGRAM).compile_to(Vec::new()).unwrap();
+    let second = parse(PROGRAM).compile_to(Vec::new()).unwrap();
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+
+    let mut interpreter = Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&String::from_utf8(first).unwrap()).unwrap();
+    assert_eq!(interpreter.into_output(), b"hello\nworld\nhello\nagain\nhello\nagain\n");
+}
+
+#[test]
+fn nothing_is_returned_when_a_sink_is_set() {
+    let out = Arc::new(Mutex::new(Flushes::default()));
+    Program::set_output(Some(Box::new(Shared(out.clone()))));
+    let returned = parse(PROGRAM).compile();
+    Program::set_output(None);
+    assert_eq!(returned.unwrap(), "");
+
+    // The code is written as each function is done, not all at the end
+    let out = out.lock().unwrap();
+    assert!(out.flushes > 1, "flushed {} times", out.flushes);
+    assert!(!out.bytes.is_empty());
+}
+
+#[test]
+fn a_failing_writer_is_an_error() {
+    let error = match parse(PROGRAM).compile_to(Broken) {
+        Err(error) => error,
+        Ok(_) => panic!("writing to a broken pipe succeeded"),
+    };
+    match innermost(&error) {
+        Error::Io(io_error) => assert_eq!(io_error.kind(), io::ErrorKind::BrokenPipe),
+        other => panic!("expected an io error, got {:?}", other),
+    }
+    assert!(error.to_string().contains("could not write the compiled code: the pipe is closed"), "{}", error);
+    // The sink isn't kept after the error
+    assert!(!parse(PROGRAM).compile().unwrap().is_empty());
+}
+
+/// Counts how many times it is flushed
+#[derive(Default)]
+struct Flushes {
+    bytes: Vec<u8>,
+    flushes: usize,
+}
+
+struct Shared(Arc<Mutex<Flushes>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flushes += 1;
+        Ok(())
+    }
+}
+
+/// A writer that can't be written to
+struct Broken;
+
+impl Write for Broken {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "the pipe is closed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn every_missing_function_is_reported_before_anything_is_written() {
+    let source = r#"fn greet(name) {
+    puts("hello");
+    putz(name);
+}
+
+fn start() {
+    greet("world");
+    def i = 2;
+    while i {
+        greet("again");
+        i = subb(i, 1);
+    }
+    putc('a', 2, 3);
+}
+"#;
+    let out = Arc::new(Mutex::new(Flushes::default()));
+    Program::set_output(Some(Box::new(Shared(out.clone()))));
+    let result = parse(source).compile();
+    Program::set_output(None);
+
+    let error = result.unwrap_err();
+    match &error {
+        Error::Errors(errors) => assert_eq!(errors.len(), 3, "{}", error),
+        other => panic!("expected every error at once, got {:?}", other),
+    }
+    let lines: Vec<String> = error.to_string().lines().map(|line| line.chars().take(5).collect()).collect();
+    assert_eq!(lines, ["3:5: ", "11:9:", "13:5:"], "{}", error);
+    for name in ["\"putz\"", "\"subb\"", "NoMatchingOverload { name: \"putc\", got: 3"] {
+        assert!(error.to_string().contains(name), "{}", error);
+    }
+    assert!(out.lock().unwrap().bytes.is_empty());
+}
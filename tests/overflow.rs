@@ -0,0 +1,57 @@
+mod common;
+
+use common::{output, parse};
+use fr::runner;
+
+/// A program with eight bit cells that prints the result of an operation,
+/// with overflow checks or without
+fn program(checks: bool, operation: &str) -> String {
+    format!(
+        "#![cell_size(8)]\n{}\nfn start() {{\n    def a = 200;\n    printf(\"{{}}\\n\", {});\n    puts(\"after\");\n}}\n",
+        if checks { "#![overflow_checks]\n" } else { "" },
+        operation
+    )
+}
+
+/// Run a program that should stop itself, and return what it printed and its exit status
+fn stopped(source: &str) -> (String, Option<u8>) {
+    let outcome = runner::run(source, b"").unwrap();
+    (String::from_utf8_lossy(outcome.output()).into_owned(), outcome.exit_status())
+}
+
+#[test]
+fn adding_past_the_largest_value_wraps_without_checks() {
+    assert_eq!(output(&program(false, "add(a, 100)")), "44\nafter\n");
+}
+
+#[test]
+fn adding_past_the_largest_value_stops_with_checks() {
+    assert_eq!(stopped(&program(true, "add(a, 100)")), ("OVERFLOW in add\n".to_string(), Some(1)));
+}
+
+#[test]
+fn subtracting_below_zero_stops_with_checks() {
+    assert_eq!(output(&program(false, "sub(a, 201)")), "255\nafter\n");
+    assert_eq!(stopped(&program(true, "sub(a, 201)")), ("OVERFLOW in sub\n".to_string(), Some(1)));
+}
+
+#[test]
+fn multiplying_past_the_largest_value_stops_with_checks() {
+    assert_eq!(output(&program(false, "mul(a, 2)")), "144\nafter\n");
+    assert_eq!(stopped(&program(true, "mul(a, 2)")), ("OVERFLOW in mul\n".to_string(), Some(1)));
+}
+
+#[test]
+fn arithmetic_that_fits_is_the_same_with_checks() {
+    for operation in ["add(a, 55)", "sub(a, 200)", "add(a, 0)", "mul(a, 1)"] {
+        assert_eq!(output(&program(true, operation)), output(&program(false, operation)), "{}", operation);
+    }
+}
+
+#[test]
+fn programs_without_checks_have_no_checking_code() {
+    let without = parse(&program(false, "add(a, 100)")).compile().unwrap();
+    let with = parse(&program(true, "add(a, 100)")).compile().unwrap();
+    assert!(without.len() < with.len(), "{} {}", without.len(), with.len());
+    assert!(!without.contains("OVERFLOW"));
+}
@@ -0,0 +1,54 @@
+use fr::{Flag, Program, UserFn};
+
+/// Parse a program, returning the functions that parsed and the errors as text
+fn parse_all(source: &str) -> (Vec<String>, Vec<String>) {
+    let (program, errors) = Program::parse_all(source);
+    let names = program.map(|p| p.functions().iter().map(UserFn::name).map(String::from).collect()).unwrap_or_default();
+    (names, errors.iter().map(ToString::to_string).collect())
+}
+
+#[test]
+fn every_broken_function_is_reported() {
+    let source = r#"fn a() {
+    def = 1;
+}
+
+fn b() {
+    x + ;
+}
+
+fn c() {
+    def y = 1
+}
+
+fn start() {
+}
+"#;
+    let (names, errors) = parse_all(source);
+    assert_eq!(errors.len(), 3, "{:?}", errors);
+    assert!(errors[0].starts_with("2:9: syntax error"), "{:?}", errors);
+    assert!(errors[1].starts_with("6:7: syntax error"), "{:?}", errors);
+    assert!(errors[2].starts_with("11:1: syntax error"), "{:?}", errors);
+    assert_eq!(names, ["start"]);
+}
+
+#[test]
+fn a_missing_brace_is_reported_once() {
+    let source = "fn a() {\n    def x = 1;\n\nfn b() {\n    def y = 2;\n}\n\nfn c() {\n}\n";
+    let (_, errors) = parse_all(source);
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+}
+
+#[test]
+fn functions_that_parse_are_kept() {
+    let (names, errors) = parse_all("fn a() {\n}\n\nfn b( {\n}\n\nfn c() {\n}\n");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(names, ["a", "c"]);
+}
+
+#[test]
+fn a_pragma_can_follow_blank_lines() {
+    let (program, errors) = Program::parse_all("\n\n#![size_warn]\nfn start() {\n}\n");
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(program.unwrap().flags(), [Flag::EnableSizeWarn]);
+}
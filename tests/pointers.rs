@@ -0,0 +1,59 @@
+mod common;
+
+use common::{compile_error, output};
+
+#[test]
+fn dereferencing_a_string_is_an_error() {
+    let error = compile_error(
+        r#"
+fn start() {
+    def s = "text";
+    println(*s);
+}
+"#,
+    );
+    assert!(error.contains("cannot dereference `s`, which is not a reference"), "{}", error);
+}
+
+#[test]
+fn dereferencing_a_byte_is_an_error() {
+    let error = compile_error(
+        r#"
+fn start() {
+    def n = 5;
+    println(*n);
+}
+"#,
+    );
+    assert!(error.contains("cannot dereference `n`, which is not a reference"), "{}", error);
+}
+
+#[test]
+fn dereferencing_a_reference_reads_and_writes_through_it() {
+    let source = r#"
+fn start() {
+    def a = 'a';
+    def p = &a;
+    println(*p);
+    *p = 'b';
+    println(a);
+}
+"#;
+    assert_eq!(output(source), "a\nb\n");
+}
+
+#[test]
+fn a_reference_passed_to_a_function_can_be_dereferenced() {
+    let source = r#"
+fn inc(ptr) {
+    *ptr = add(*ptr, 1);
+}
+
+fn start() {
+    def a = '5';
+    inc(&a);
+    println(a);
+}
+"#;
+    assert_eq!(output(source), "6\n");
+}
@@ -0,0 +1,286 @@
+mod common;
+
+use common::{compile_error, output_with_input};
+
+const FACTORIAL: &str = r#"#![optimize(1)]
+fn fact(n, acc) {
+    if n {
+        return fact(sub(n, 1), mul(acc, n));
+    } else {
+        return acc;
+    }
+}
+
+fn mul(a, b) {
+    def result = 0;
+    while b {
+        result = add(result, a);
+        b = sub(b, 1);
+    }
+    return result;
+}
+
+fn start() {
+    printf("{}\n", fact(sub(getc(), '0'), 1));
+}
+"#;
+
+#[test]
+fn an_accumulator_factorial_is_a_loop() {
+    // The depth is only known when the program runs, so the calls can't be expanded
+    assert_eq!(output_with_input(FACTORIAL, b"0"), "1\n");
+    assert_eq!(output_with_input(FACTORIAL, b"5"), "120\n");
+    assert_eq!(output_with_input(FACTORIAL, b"8"), "40320\n");
+}
+
+#[test]
+fn calls_that_are_not_tail_calls_are_still_expanded() {
+    let error = compile_error(
+        r#"#![recursion_limit(8)]
+fn count(n) {
+    if n {
+        return add(count(sub(n, 1)), 1);
+    }
+    return 0;
+}
+
+fn start() {
+    printf("{}\n", count(getc()));
+}
+"#,
+    );
+    assert!(error.contains("function `count` calls itself more than 8 calls deep"), "{}", error);
+}
+
+#[test]
+fn a_return_after_a_tail_call_replaces_its_value() {
+    // A return doesn't stop the function, so `g` returns 9 even when
+    // its condition is true, and `f` never stops calling itself
+    let source = |level| {
+        format!(
+            r#"#![optimize({})]
+#![recursion_limit(8)]
+fn g(n) {{
+    if n {{
+        return 1;
+    }}
+    return 9;
+}}
+
+fn f(n, acc) {{
+    if n {{
+        return f(sub(n, 1), add(acc, n));
+    }}
+    return acc;
+}}
+
+fn start() {{
+    printf("{{}}\n", g(1));
+    printf("{{}}\n", f(3, 0));
+}}
+"#,
+            level
+        )
+    };
+    for level in 0..=2 {
+        let error = compile_error(&source(level));
+        assert!(error.contains("function `f` calls itself more than 8 calls deep"), "{}", error);
+    }
+}
+
+#[test]
+fn tail_calls_are_loops_only_when_optimizing() {
+    let source = |level| {
+        format!(
+            r#"#![optimize({})]
+#![recursion_limit(8)]
+fn f(n, acc) {{
+    if n {{
+        return f(sub(n, 1), add(acc, n));
+    }} else {{
+        return acc;
+    }}
+}}
+
+fn start() {{
+    printf("{{}}\n", f(sub(getc(), '0'), 0));
+}}
+"#,
+            level
+        )
+    };
+    let error = compile_error(&source(0));
+    assert!(error.contains("function `f` calls itself more than 8 calls deep"), "{}", error);
+    assert_eq!(output_with_input(&source(1), b"3"), "6\n");
+    assert_eq!(output_with_input(&source(2), b"3"), "6\n");
+}
+
+#[test]
+fn a_function_with_no_return_can_call_itself_last() {
+    let source = r#"#![optimize(1)]
+fn countdown(n) {
+    printf("{} ", n);
+    if n {
+        countdown(sub(n, 1));
+    }
+}
+
+fn start() {
+    countdown(sub(getc(), '0'));
+    puts("liftoff");
+}
+"#;
+    assert_eq!(output_with_input(source, b"3"), "3 2 1 0 liftoff\n");
+    assert_eq!(output_with_input(source, b"0"), "0 liftoff\n");
+}
+
+const EVEN_ODD: &str = r#"#![recursion_limit(4)]
+fn is_even(n) {
+    if n {
+        return is_odd(sub(n, 1));
+    } else {
+        return 1;
+    }
+}
+
+fn is_odd(n) {
+    if n {
+        return is_even(sub(n, 1));
+    } else {
+        return 0;
+    }
+}
+
+fn start() {
+    def n = sub(getc(), '0');
+    printf("{} {}\n", is_even(n), is_odd(n));
+}
+"#;
+
+#[test]
+fn functions_that_call_each_other_from_their_ends_are_a_loop() {
+    let source = format!("#![optimize(1)]\n{}", EVEN_ODD);
+    assert_eq!(output_with_input(&source, b"0"), "1 0\n");
+    assert_eq!(output_with_input(&source, b"7"), "0 1\n");
+    assert_eq!(output_with_input(&source, b"8"), "1 0\n");
+}
+
+#[test]
+fn a_cycle_of_functions_with_different_parameters_is_a_loop() {
+    let source = r#"#![optimize(1)]
+mod steps {
+    fn first(n) {
+        if n {
+            return second(sub(n, 1), 10);
+        } else {
+            return 1;
+        }
+    }
+
+    fn second(n, total) {
+        return third(n, add(total, 2), 'x');
+    }
+
+    fn third(n, total, _ch) {
+        if n {
+            return first(sub(n, 1));
+        } else {
+            return total;
+        }
+    }
+}
+
+fn start() {
+    printf("{} ", steps::first(sub(getc(), '0')));
+    printf("{}\n", steps::second(0, 5));
+}
+"#;
+    assert_eq!(output_with_input(source, b"0"), "1 7\n");
+    assert_eq!(output_with_input(source, b"1"), "12 7\n");
+    assert_eq!(output_with_input(source, b"2"), "1 7\n");
+    assert_eq!(output_with_input(source, b"3"), "12 7\n");
+}
+
+#[test]
+fn functions_that_call_each_other_are_an_error_without_optimizing() {
+    let error = compile_error(EVEN_ODD);
+    assert!(
+        error.contains("function `is_even` calls itself more than 4 calls deep: is_even -> is_odd -> is_even"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn functions_that_call_each_other_elsewhere_are_an_error() {
+    let error = compile_error(
+        r#"#![optimize(1)]
+#![recursion_limit(4)]
+fn is_even(n) {
+    if n {
+        return is_odd(sub(n, 1));
+    } else {
+        return 1;
+    }
+}
+
+fn is_odd(n) {
+    if n {
+        return sub(1, is_even(sub(n, 1)));
+    } else {
+        return 0;
+    }
+}
+
+fn start() {
+    printf("{}\n", is_even(getc()));
+}
+"#,
+    );
+    assert!(error.contains("calls itself more than 4 calls deep"), "{}", error);
+}
+
+const FOREVER: &str = r#"fn forever(n) {
+    return add(forever(n), 1);
+}
+
+fn start() {
+    printf("{}\n", forever(1));
+}
+"#;
+
+#[test]
+fn recursion_without_an_end_stops_at_the_default_limit() {
+    let error = compile_error(FOREVER);
+    assert!(error.contains("function `forever` calls itself more than 32 calls deep: forever -> forever"), "{}", error);
+}
+
+#[test]
+fn a_limit_of_zero_rejects_any_recursion() {
+    let error = compile_error(&format!("#![recursion_limit(0)]\n{}", FOREVER));
+    assert!(error.contains("function `forever` calls itself, and recursion is not allowed: forever -> forever"), "{}", error);
+}
+
+#[test]
+fn calls_that_dont_nest_and_calls_that_become_loops_are_under_any_limit() {
+    let source = r#"#![optimize(1)]
+#![recursion_limit(0)]
+fn show(n) {
+    printf("{} ", n);
+}
+
+fn countdown(n) {
+    show(n);
+    if n {
+        countdown(sub(n, 1));
+    }
+}
+
+fn start() {
+    show(9);
+    countdown(sub(getc(), '0'));
+    show(9);
+}
+"#;
+    assert_eq!(output_with_input(source, b"2"), "9 2 1 0 9 ");
+}
@@ -0,0 +1,61 @@
+use fr::{reset, stdlib, Error, Interpreter, Program};
+
+const FIRST: &str = r#"#![optimize(2)]
+
+fn greet() {
+    puts("first");
+}
+
+fn start() {
+    def x = 1;
+    greet();
+}
+"#;
+
+const SECOND: &str = r#"fn greet() {
+    puts("second");
+}
+
+fn start() {
+    greet();
+}
+"#;
+
+/// Compile a program with the standard library, without resetting first, and run it
+fn run(source: &str) -> Result<String, Error> {
+    let program = Program::from(source);
+    stdlib::install();
+    let code = program.compile()?;
+    let mut interpreter = Interpreter::new(&b""[..], vec![]);
+    interpreter.run(&code).unwrap();
+    Ok(String::from_utf8(interpreter.into_output()).unwrap())
+}
+
+#[test]
+fn a_reset_program_uses_its_own_definitions() {
+    reset();
+    assert_eq!(run(FIRST).unwrap(), "first\n");
+    reset();
+    assert_eq!(run(SECOND).unwrap(), "second\n");
+}
+
+#[test]
+fn without_a_reset_the_first_definitions_are_kept() {
+    reset();
+    run(FIRST).unwrap();
+    let error = run(SECOND).unwrap_err();
+    assert!(error.to_string().contains("FunctionRedefined(\"greet\")"), "{}", error);
+}
+
+#[test]
+fn a_reset_forgets_the_settings_and_foreign_functions() {
+    reset();
+    run(FIRST).unwrap();
+    assert_eq!(Program::optimize_level(), 2);
+
+    reset();
+    assert_eq!(Program::optimize_level(), 0);
+    // The standard library can only be called once it is installed again
+    let error = Program::from(SECOND).compile().unwrap_err();
+    assert!(error.to_string().contains("\"puts\""), "{}", error);
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::output_with_input;
+use fr::runner::{self, RunOutcome};
+
+#[test]
+fn cat_echoes_its_input() {
+    let source = r#"fn start() {
+    def c = getc();
+    while c {
+        putc(c);
+        c = getc();
+    }
+}
+"#;
+    assert_eq!(output_with_input(source, b"hello, world\n"), "hello, world\n");
+    assert_eq!(output_with_input(source, b""), "");
+}
+
+#[test]
+fn a_counting_loop_prints_every_number() {
+    let source = r#"fn start() {
+    def i = 0;
+    def left = 5;
+    while left {
+        putc(add('0', i));
+        i = add(i, 1);
+        left = sub(left, 1);
+    }
+}
+"#;
+    let outcome = runner::run(source, b"").unwrap();
+    assert!(outcome.is_finished(), "{:?}", outcome);
+    assert_eq!(outcome.output(), b"01234");
+    assert!(outcome.run().steps > 0);
+    // Every cell the program used is given back
+    assert!(outcome.run().tape.iter().all(|&cell| cell == 0), "{:?}", outcome.run().tape);
+}
+
+#[test]
+fn functions_are_called_with_their_arguments() {
+    let source = r#"fn shout(c) {
+    putc(sub(c, 32));
+    putc('!');
+}
+
+fn start() {
+    shout('a');
+    shout(getc());
+}
+"#;
+    assert_eq!(output_with_input(source, b"z"), "A!Z!");
+}
+
+#[test]
+fn an_exit_status_is_reported() {
+    let outcome = runner::run("fn start() {\n    putc('a');\n    exit(3);\n}\n", b"").unwrap();
+    assert_eq!(outcome.exit_status(), Some(3));
+    assert_eq!(outcome.output(), b"a");
+}
+
+#[test]
+fn a_program_that_never_stops_times_out() {
+    let source = "fn start() {\n    putc('.');\n    def on = 1;\n    while on {\n    }\n}\n";
+    match runner::run_with_step_limit(source, b"", 10_000).unwrap() {
+        RunOutcome::TimedOut(run) => {
+            assert_eq!(run.steps, 10_000);
+            assert_eq!(run.output, b".");
+        }
+        other => panic!("expected the program to time out, got {:?}", other),
+    }
+}
+
+#[test]
+fn programs_that_dont_compile_are_errors() {
+    assert!(runner::run("fn start() {\n    putc(nothing);\n}\n", b"").is_err());
+    assert!(runner::run("fn start( {\n}\n", b"").is_err());
+}
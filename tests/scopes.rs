@@ -0,0 +1,242 @@
+mod common;
+
+use common::{compile_error, parse};
+use fr::{pretty_ir, runner, Env, Error, IrOp, NumberKind, Value, ValueKind};
+use std::collections::HashMap;
+
+const VARIABLES: &str = r#"fn start() {
+    def a = 1;
+    def b = "two";
+    def c = 3;
+    def d = &a;
+    def e = 5;
+    putc(add(a, add(c, e)));
+}
+"#;
+
+#[test]
+fn variables_are_freed_in_the_same_order_every_time() {
+    let first = parse(VARIABLES).compile().unwrap();
+    for _ in 0..5 {
+        assert_eq!(parse(VARIABLES).compile().unwrap(), first);
+    }
+}
+
+#[test]
+fn variables_are_freed_last_defined_first() {
+    let ir = parse(VARIABLES).compile_to_ir().unwrap();
+    let variables: HashMap<u32, &str> = ir
+        .iter()
+        .filter_map(|op| match op {
+            IrOp::Bind { name, at } if name.len() == 1 => Some((at.offset, name.as_str())),
+            _ => None,
+        })
+        .collect();
+    let freed: Vec<&str> = ir
+        .iter()
+        .filter_map(|op| match op {
+            IrOp::Free { at, .. } => variables.get(&at.offset).copied(),
+            _ => None,
+        })
+        .collect();
+    // The cells are reused by the calls, but the scope of `start` is freed last
+    assert!(freed.ends_with(&["e", "d", "c", "b", "a"]), "{:?}\n{}", freed, pretty_ir(&ir));
+}
+
+#[test]
+fn returning_a_reference_to_a_local_is_an_error() {
+    let error = compile_error(
+        r#"fn local() {
+    def x = 5;
+    return &x;
+}
+
+fn start() {
+    def p = local();
+}
+"#,
+    );
+    assert!(error.starts_with("3:5: DanglingReference(\"x\")"), "{}", error);
+}
+
+#[test]
+fn references_can_be_passed_to_the_functions_called() {
+    let source = r#"fn bump(p) {
+    *p = add(*p, 1);
+}
+
+fn start() {
+    def x = 5;
+    bump(&x);
+    exit(x);
+}
+"#;
+    assert_eq!(runner::run(source, b"").unwrap().exit_status(), Some(6));
+}
+
+/// A one cell value at an offset, made without compiling anything
+fn cell(offset: u32) -> Value {
+    Value {
+        offset,
+        reference_depth: 0,
+        number_cells: 1,
+        kind: ValueKind::Data,
+        layout: None,
+        referent: None,
+        character: false,
+        array: false,
+        number: NumberKind::Unsigned,
+    }
+}
+
+#[test]
+fn a_missing_variable_lists_the_names_in_scope() {
+    let mut env = Env::new();
+    env.define_no_cp("count", cell(100));
+    env.define_no_cp("%TEMP#1%", cell(101));
+    env.define_no_cp("total", cell(102));
+    assert!(env.contains("count"));
+    assert!(!env.contains("cuont"));
+    match env.get("cuont") {
+        Err(Error::VariableNotDefined(name, in_scope)) => {
+            assert_eq!(name, "cuont");
+            assert_eq!(in_scope, ["count", "total"]);
+        }
+        other => panic!("expected an undefined variable, got {:?}", other),
+    }
+}
+
+#[test]
+fn a_missing_variable_in_an_empty_scope_says_so() {
+    let error = compile_error("fn start() {\n    putc(x);\n}\n");
+    assert!(error.starts_with("2:5: the variable `x` is not defined, and no variables are in scope"), "{}", error);
+}
+
+/// The operations in `start` from the first one up to where `name` is bound
+fn ops_before_bind(source: &str, name: &str) -> Vec<IrOp> {
+    let ir = parse(source).compile_to_ir().unwrap();
+    ir.into_iter()
+        .skip_while(|op| !matches!(op, IrOp::FnBegin(_)))
+        .skip(1)
+        .take_while(|op| !matches!(op, IrOp::Bind { name: bound, .. } if bound == name))
+        .filter(|op| !matches!(op, IrOp::Bind { .. }))
+        .collect()
+}
+
+#[test]
+fn defining_a_literal_or_a_returned_value_doesnt_copy_it() {
+    let literal = ops_before_bind("fn start() {\n    def x = 5;\n    putc(x);\n}\n", "x");
+    assert!(matches!(literal[..], [IrOp::Alloc { .. }, IrOp::Set { .. }]), "{:?}", literal);
+    let string = ops_before_bind("fn start() {\n    def x = \"abc\";\n    puts(x);\n}\n", "x");
+    assert!(matches!(string[..], [IrOp::Alloc { .. }, IrOp::SetString { .. }]), "{:?}", string);
+    let ir = parse("fn f() {\n    return 5;\n}\n\nfn start() {\n    def x = f();\n    putc(x);\n}\n").compile_to_ir().unwrap();
+    let names: Vec<&str> = ir
+        .iter()
+        .filter_map(|op| match op {
+            IrOp::Bind { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert!(!names.iter().any(|name| name.contains("DEFINE")), "{:?}", names);
+}
+
+#[test]
+fn defining_a_variable_copies_it_once_and_leaves_it_alone() {
+    let source = "fn start() {\n    def x = 5;\n    def y = x;\n    y = add(y, 1);\n    printf(\"{} {}\\n\", x, y);\n}\n";
+    let copies = ops_before_bind(source, "y")
+        .into_iter()
+        .filter(|op| matches!(op, IrOp::Add { .. } | IrOp::Copy { .. }))
+        .count();
+    assert_eq!(copies, 1);
+    assert_eq!(runner::run(source, b"").unwrap().output(), b"5 6\n");
+}
+
+/// How many times a program's cells are cleared before they are used
+fn clears(source: &str) -> usize {
+    let ir = parse(source).compile_to_ir().unwrap();
+    ir.iter().filter(|op| matches!(op, IrOp::Alloc { .. })).count()
+}
+
+#[test]
+fn cells_known_to_be_zero_are_not_cleared_again() {
+    let literals = |count: usize| format!("fn start() {{\n{}}}\n", "    putc('x');\n    puts(\"yz\");\n".repeat(count));
+    assert_eq!(clears(&literals(2)), clears(&literals(20)));
+    assert_eq!(runner::run(&literals(3), b"").unwrap().output(), b"xyz\nxyz\nxyz\n");
+}
+
+#[test]
+fn cells_written_through_a_pointer_or_in_a_loop_are_cleared() {
+    let source = r#"fn fill(p) {
+    *p = 'q';
+}
+
+fn start() {
+    def i = 3;
+    while i {
+        putc(add('a', i));
+        i = sub(i, 1);
+    }
+    def a = 0;
+    fill(&a);
+    putc(a);
+    def b = 0;
+    def p = &b;
+    *p = 'r';
+    putc(b);
+    def c = 0;
+    putc(add(c, 's'));
+    putc('\n');
+}
+"#;
+    assert_eq!(runner::run(source, b"").unwrap().output(), b"dcbqrs\n");
+}
+
+#[test]
+fn a_typo_in_an_else_branch_is_an_error_even_if_it_never_runs() {
+    let error = compile_error(
+        r#"fn start() {
+    def value = 'a';
+    def on = 1;
+    if on {
+        putc(value);
+    } else {
+        putc(valeu);
+    }
+}
+"#,
+    );
+    assert!(error.starts_with("7:9: the variable `valeu` is not defined"), "{}", error);
+}
+
+#[test]
+fn a_variable_defined_in_a_loop_cant_be_used_after_it() {
+    let error = compile_error(
+        r#"fn start() {
+    def i = 2;
+    while i {
+        def last = i;
+        i = sub(i, 1);
+    }
+    putc(last);
+}
+"#,
+    );
+    assert!(error.starts_with("7:5: the variable `last` is not defined"), "{}", error);
+}
+
+#[test]
+fn a_variable_defined_in_both_branches_cant_be_used_after_them() {
+    let error = compile_error(
+        r#"fn start() {
+    def on = getc();
+    if on {
+        def c = 'y';
+    } else {
+        def c = 'n';
+    }
+    putc(c);
+}
+"#,
+    );
+    assert!(error.starts_with("8:5: the variable `c` is not defined"), "{}", error);
+}
@@ -0,0 +1,88 @@
+#![cfg(feature = "serde")]
+
+mod common;
+
+use common::parse;
+use fr::{Flag, Program};
+
+/// A program with every kind of definition, statement and expression
+const EVERYTHING: &str = r#"#![optimize(1)]
+
+const SIZE = 3;
+
+struct Point {
+    x,
+    y,
+}
+
+enum Color {
+    Red,
+    Green = 5,
+}
+
+def counter = 1;
+
+mod text {
+    fn shout(s) {
+        puts(s ++ "!");
+    }
+}
+
+fn pick(n: byte) -> byte {
+    return n;
+}
+
+fn split() {
+    return (1, 2);
+}
+
+fn start() {
+    def p = Point { x: 1, y: 2 };
+    def (a, b) = split();
+    def r = &a;
+    *r = add(*r, b);
+    def f = &fn pick;
+    def name = "abc";
+    putc(add('0', (f)(name[1])));
+    if a {
+        text::shout("hey");
+    } else {
+        exit(1);
+    }
+    while b {
+        b = sub(b, 1);
+    }
+    for i in 0..SIZE {
+        putc(add('0', i));
+    }
+    repeat 2 {
+        putc('.');
+    }
+    assert(add(p.x, p.y), "sum");
+    putc(add('0', Color::Green));
+    exit(a);
+}
+"#;
+
+#[test]
+fn programs_read_back_from_json_the_same() {
+    let program = parse(EVERYTHING);
+    let json = serde_json::to_string(&program).unwrap();
+    let read: Program = serde_json::from_str(&json).unwrap();
+    assert_eq!(read, program);
+}
+
+#[test]
+fn programs_read_back_from_json_compile_the_same() {
+    let compiled = parse(EVERYTHING).compile().unwrap();
+    let json = serde_json::to_string(&parse(EVERYTHING)).unwrap();
+    let read: Program = serde_json::from_str(&json).unwrap();
+    assert_eq!(read.compile().unwrap(), compiled);
+}
+
+#[test]
+fn flags_are_written_by_name() {
+    let json = serde_json::to_string(&Flag::Optimize(2)).unwrap();
+    assert_eq!(json, r#"{"Optimize":2}"#);
+    assert_eq!(serde_json::from_str::<Flag>(&json).unwrap(), Flag::Optimize(2));
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{compile_error, output};
+use fr::runner;
+
+/// Print the result of an expression in decimal, with eight bit cells
+fn eight_bit(expression: &str) -> String {
+    output(&format!("#![cell_size(8)]\nfn start() {{\n    printf(\"{{}}\", {});\n}}\n", expression))
+}
+
+#[test]
+fn negating_a_number_makes_it_signed() {
+    assert_eq!(eight_bit("neg(1)"), "-1");
+    assert_eq!(eight_bit("neg(128)"), "-128");
+    assert_eq!(eight_bit("neg(0)"), "0");
+    assert_eq!(eight_bit("neg(neg(42))"), "42");
+    // The cells hold the number in two's complement
+    assert_eq!(eight_bit("unsigned(neg(1))"), "255");
+}
+
+#[test]
+fn negative_numbers_add_and_subtract() {
+    assert_eq!(eight_bit("add(neg(3), 5)"), "2");
+    assert_eq!(eight_bit("sub(neg(3), 5)"), "-8");
+    // Subtracting a larger number leaves a negative one
+    assert_eq!(eight_bit("sub(signed(3), 5)"), "-2");
+}
+
+#[test]
+fn negating_a_variable_leaves_it_alone() {
+    let source = "fn start() {\n    def a = 7;\n    def b = neg(a);\n    printf(\"{} {}\", a, add(signed(a), b));\n}\n";
+    assert_eq!(output(source), "7 0");
+}
+
+#[test]
+fn negative_literals_are_taken_as_signed_cells_beside_them() {
+    assert_eq!(eight_bit("add(neg(1), -3)"), "-4");
+    assert_eq!(eight_bit("lt(-128, signed(0))"), "1");
+    // Two literals are added as an `i32`
+    assert_eq!(eight_bit("add(-3, 5)"), "2");
+    assert_eq!(eight_bit("sub(-1, 1)"), "-2");
+}
+
+#[test]
+fn signed_numbers_below_zero_are_less_than_zero() {
+    assert_eq!(eight_bit("lt(-1, 0)"), "1");
+    assert_eq!(eight_bit("gt(-1, 0)"), "0");
+    assert_eq!(eight_bit("lt(-128, 127)"), "1");
+    assert_eq!(eight_bit("gt(neg(2), -3)"), "1");
+    // Unsigned numbers are compared as they are
+    assert_eq!(eight_bit("lt(255, 0)"), "0");
+    assert_eq!(eight_bit("gt(200, 100)"), "1");
+}
+
+#[test]
+fn signed_division_rounds_toward_zero() {
+    assert_eq!(eight_bit("div(-7, 2)"), "-3");
+    assert_eq!(eight_bit("div(7, neg(2))"), "-3");
+    assert_eq!(eight_bit("div(-7, -2)"), "3");
+    assert_eq!(eight_bit("mul(-7, 3)"), "-21");
+    assert_eq!(eight_bit("div(250, 7)"), "35");
+}
+
+#[test]
+fn dividing_by_zero_stops_the_program() {
+    let outcome = runner::run("fn start() {\n    def a = 0;\n    printf(\"{}\", div(5, a));\n}\n", b"").unwrap();
+    assert_eq!(outcome.output(), b"DIVISION BY ZERO\n");
+    assert_eq!(outcome.exit_status(), Some(1));
+}
+
+#[test]
+fn signed_and_unsigned_numbers_are_cast_to_be_mixed() {
+    let source = "fn start() {\n    def a = 7;\n    def b = neg(a);\n    printf(\"{}\", add(a, b));\n}\n";
+    let error = compile_error(source);
+    assert!(error.contains("`add` cannot mix an unsigned number with a signed number"), "{}", error);
+    assert!(compile_error(&source.replace("neg(a)", "neg(1)").replace("add", "lt")).contains("`lt` cannot mix"));
+}
+
+#[test]
+fn a_literal_has_to_fit_the_kind_of_number_it_is_used_as() {
+    let error = compile_error("#![cell_size(8)]\nfn start() {\n    printf(\"{}\", add(neg(1), 200));\n}\n");
+    assert!(error.contains("200 does not fit in a signed number"), "{}", error);
+}
+
+#[test]
+fn only_numbers_can_be_written_negative() {
+    let (_, errors) = fr::Program::parse_all("fn start() {\n    def a = 1;\n    def b = -a;\n}\n");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].to_string().starts_with("3:14: syntax error"), "{}", errors[0]);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::parse;
+use fr::{instruction_count, FnSize, Program, INSTRUCTIONS};
+
+const PROGRAM: &str = r#"fn small() {
+    putc('a');
+}
+
+fn big(n) {
+    def i = n;
+    while i {
+        puts("a string to print on every pass of the loop");
+        printf("{}\n", i);
+        i = sub(i, 1);
+    }
+    small();
+}
+
+fn start() {
+    small();
+    big(3);
+}
+"#;
+
+/// Compile the program, and return its code with the report for each function
+fn report() -> (String, Vec<FnSize>) {
+    let code = parse(PROGRAM).compile().unwrap();
+    (code, Program::size_report())
+}
+
+fn find<'a>(report: &'a [FnSize], name: &str) -> &'a FnSize {
+    report.iter().find(|f| f.name == name).unwrap_or_else(|| panic!("no report for `{}`", name))
+}
+
+#[test]
+fn bigger_functions_report_more_of_everything() {
+    let (_, report) = report();
+    let (small, big) = (find(&report, "small"), find(&report, "big"));
+    assert!(big.instructions > small.instructions, "{:?} {:?}", big, small);
+    assert!(big.own_instructions > small.own_instructions, "{:?} {:?}", big, small);
+    assert!(big.allocations > small.allocations, "{:?} {:?}", big, small);
+    assert!(big.frame_peak > small.frame_peak, "{:?} {:?}", big, small);
+    assert_eq!((small.calls, big.calls), (2, 1));
+}
+
+#[test]
+fn the_instructions_of_every_function_add_up_to_the_program() {
+    let (code, report) = report();
+    let total = instruction_count();
+    assert_eq!(total, code.chars().filter(|ch| INSTRUCTIONS.contains(ch)).count());
+
+    // Every call is made from the entry point, so its instructions are the
+    // own instructions of every function, and the rest set up the program
+    let own: usize = report.iter().map(|f| f.own_instructions).sum();
+    let start = find(&report, "start");
+    assert_eq!(own, start.instructions);
+    assert!(start.instructions < total);
+}
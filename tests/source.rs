@@ -0,0 +1,92 @@
+use fr::{free_ast, CellWidth, Eval, Expr, Flag, Literal, Program, UserFn};
+
+/// Write a program as source, read it back, and check nothing changed
+fn round_trip(program: Program) {
+    let source = program.to_source();
+    let parsed = Program::from(source.as_str()).without_spans();
+    assert_eq!(parsed, program, "the source was:\n{}", source);
+}
+
+fn function(name: &str, parameters: &[&str], body: Vec<Expr>) -> UserFn {
+    UserFn::new(name, parameters.iter().map(ToString::to_string).collect(), body)
+}
+
+#[test]
+fn loops_and_branches_read_back_the_same() {
+    let body = free_ast! {
+        define x = 5;
+        while (x) {
+            x = call sub(x, 1);
+        }
+        for i in 0 .. x {
+            call putc(call add('0', i));
+        }
+        if (call eq(x, 0)) {
+            call puts("done");
+        } else {
+            call puts(&x);
+        }
+        return *x;
+    };
+    round_trip(Program::new(vec![], vec![function("start", &[], body)]));
+}
+
+#[test]
+fn literals_are_escaped_on_the_way_out() {
+    let quoted = Eval::Literal(Literal::string("say \"hi\"\n\tback\\slash"));
+    let quote = Eval::Literal(Literal::character('\''));
+    let body = free_ast! {
+        call puts(#quoted);
+        call putc(#quote);
+        call putc('\0');
+    };
+    round_trip(Program::new(vec![], vec![function("start", &[], body)]));
+}
+
+#[test]
+fn flags_and_several_functions_read_back_the_same() {
+    let double = free_ast! {
+        return call add(n, n);
+    };
+    let start = free_ast! {
+        define twice = call double(21);
+        call putc(twice);
+    };
+    round_trip(Program::new(
+        vec![Flag::CellSize(CellWidth::Sixteen), Flag::Optimize(2), Flag::EntryPoint("start".to_string())],
+        vec![function("double", &["n"], double), function("start", &[], start)],
+    ));
+}
+
+#[test]
+fn parsed_programs_read_back_the_same() {
+    let source = r#"#![disable_ptrs]
+
+const SIZE = 3;
+
+struct Point {
+    x,
+    y,
+}
+
+def counter = 0;
+
+mod text {
+    fn shout(s) {
+        puts(s ++ "!");
+    }
+}
+
+fn start() {
+    def p = Point { x: 1, y: 2 };
+    def (a, b) = b"ab";
+    repeat SIZE {
+        text::shout("hey");
+    }
+    assert(add(p.x, p.y), "sum");
+    exit(a);
+}
+"#;
+    let program = Program::from(source).without_spans();
+    round_trip(program);
+}
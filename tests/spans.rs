@@ -0,0 +1,74 @@
+mod common;
+
+use common::compile_error;
+use fr::{reset, stdlib, Program};
+use std::fs;
+
+#[test]
+fn errors_point_at_the_statement_deep_in_nested_loops() {
+    let source = r#"/* a comment
+   over two lines */
+fn start() {
+    def i = 2;
+    while i {
+        def j = 1; // counts down
+        while j {
+            j = sub(j, 1);
+            i = sub(i, missing);
+        }
+    }
+}
+"#;
+    let error = compile_error(source);
+    assert!(error.starts_with("9:13: the variable `missing` is not defined"), "{}", error);
+}
+
+#[test]
+fn missing_functions_are_reported_where_they_are_called() {
+    let error = compile_error("fn start() {\n    def a = 1;\n\n    def b = nothing(a);\n}\n");
+    assert!(error.starts_with("4:5: "), "{}", error);
+    assert!(error.contains("\"nothing\""), "{}", error);
+}
+
+#[test]
+fn errors_in_files_name_the_file() {
+    let dir = std::env::temp_dir().join(format!("fr-spans-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("main.fr");
+    fs::write(&path, "fn start() {\n    // the value\n    exit(value);\n}\n").unwrap();
+
+    reset();
+    let program = Program::from_file(&path).unwrap();
+    stdlib::install();
+    let error = program.compile().unwrap_err().to_string();
+    assert!(error.starts_with(&format!("{}:3:5: ", path.display())), "{}", error);
+}
+
+#[test]
+fn errors_show_the_calls_they_happened_in() {
+    let source = r#"fn read_digit() {
+    def c = 1;
+    return strlen(c);
+}
+
+fn parse() {
+    return read_digit();
+}
+
+fn start() {
+    exit(parse());
+}
+"#;
+    let error = compile_error(source);
+    let lines: Vec<&str> = error.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "3:5: NotAString(\"c\")",
+            "    in start at 11:5",
+            "    in parse at 7:5",
+            "    in read_digit at 3:5",
+            "    in strlen (foreign)",
+        ]
+    );
+}
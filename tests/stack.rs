@@ -0,0 +1,188 @@
+mod common;
+
+use common::{output, parse};
+use fr::{peak_stack_ptr, temp_name, Error, IrOp};
+
+/// The error under the calls and the place it was reported at
+fn innermost(error: &Error) -> &Error {
+    match error {
+        Error::Trace(_, error) | Error::At(_, error) => innermost(error),
+        error => error,
+    }
+}
+
+#[test]
+fn running_out_of_tape_is_an_error_not_a_panic() {
+    let program = parse(
+        r#"#![tape_size(20)]
+
+fn start() {
+    def a = "a string longer than the tape";
+    puts(a);
+}
+"#,
+    );
+    let error = program.compile().unwrap_err();
+    match innermost(&error) {
+        Error::TapeOverflow { limit, function, .. } => {
+            assert_eq!(*limit, 20);
+            assert_eq!(function, "start");
+        }
+        other => panic!("expected running out of tape, got {:?}", other),
+    }
+    assert!(error.to_string().starts_with("4:5: the program needs"), "{}", error);
+
+    // Nothing is left half defined for the next program
+    assert_eq!(output("fn start() {\n    puts(\"fine\");\n}\n"), "fine\n");
+}
+
+#[test]
+fn temporaries_get_names_that_are_never_reused() {
+    let first = temp_name();
+    let second = temp_name();
+    assert_ne!(first, second);
+    assert!(first.starts_with("%TEMP#") && first.ends_with('%'), "{}", first);
+}
+
+#[test]
+fn literals_in_sibling_scopes_dont_overwrite_each_other() {
+    let source = r#"fn show(s) {
+    puts(s);
+}
+
+fn start() {
+    def a = "ab";
+    if 1 {
+        def c = "three";
+        show(c);
+    }
+    if 1 {
+        def d = "four";
+        show("five");
+        show(d);
+    }
+    show(a);
+}
+"#;
+    assert_eq!(output(source), "three\nfive\nfour\nab\n");
+}
+
+#[test]
+fn statements_in_a_loop_give_back_their_temporaries() {
+    let peak = |calls: usize| {
+        let source = format!(
+            "fn start() {{\n    def i = 3;\n    while i {{\n{}        i = sub(i, 1);\n    }}\n}}\n",
+            "        putc(add('a', i));\n".repeat(calls)
+        );
+        parse(&source).compile().unwrap();
+        peak_stack_ptr()
+    };
+    assert_eq!(peak(1), peak(20));
+}
+
+#[test]
+fn long_functions_only_need_tape_for_their_live_variables() {
+    let peak = |statements: usize| {
+        let source = format!(
+            "fn start() {{\n    def x = 0;\n    def p = &x;\n{}    exit(x);\n}}\n",
+            r#"    if 1 {
+        def t = "abc";
+        x = add(x, strlen(t));
+    }
+    for i in 0..x {
+        assert(p[0]);
+    }
+    x = sub(*p, 1);
+"#
+            .repeat(statements)
+        );
+        parse(&source).compile().unwrap();
+        peak_stack_ptr()
+    };
+    assert_eq!(peak(10), peak(1000));
+}
+
+#[test]
+fn the_results_of_two_calls_can_be_used_together() {
+    let source = r#"fn f() {
+    return 40;
+}
+
+fn g() {
+    return 2;
+}
+
+fn start() {
+    def x = add(f(), g());
+    printf("{} {}\n", x, sub(f(), g()));
+}
+"#;
+    assert_eq!(output(source), "42 38\n");
+}
+
+#[test]
+fn returned_values_arent_named_in_the_callers_scope() {
+    let ir = parse("fn f() {\n    return 1;\n}\n\nfn start() {\n    def x = add(f(), f());\n    putc(x);\n}\n")
+        .compile_to_ir()
+        .unwrap();
+    let names: Vec<&str> = ir
+        .iter()
+        .filter_map(|op| match op {
+            IrOp::Bind { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert!(names.contains(&"x"), "{:?}", names);
+    assert!(!names.iter().any(|name| name.contains("RETURN")), "{:?}", names);
+}
+
+/// A program that prints a string of 40,000 characters
+fn long_string(flags: &str) -> String {
+    format!("{}fn start() {{\n    def long = \"{}\";\n    putc(long[0]);\n}}\n", flags, "x".repeat(40_000))
+}
+
+#[test]
+fn a_long_string_is_more_than_the_default_tape() {
+    let error = parse(&long_string("")).compile().unwrap_err();
+    match innermost(&error) {
+        Error::TapeOverflow { limit, needed, function, .. } => {
+            assert_eq!(*limit, 30_000);
+            assert!(*needed > 40_000, "{}", needed);
+            assert_eq!(function, "start");
+        }
+        other => panic!("expected running out of tape, got {:?}", other),
+    }
+    let message = error.to_string();
+    assert!(message.contains("the largest allocations are "), "{}", message);
+    assert!(message.contains("40001"), "{}", message);
+}
+
+#[test]
+fn a_long_string_fits_on_a_longer_tape() {
+    parse(&long_string("#![tape_size(50000)]\n")).compile().unwrap();
+    assert!(peak_stack_ptr() > 40_000);
+}
+
+/// `x` with `terms` numbers added to it one after another, as a chain of
+/// calls that is one call deep wherever it is cut
+fn sum_chain(terms: usize) -> String {
+    (1..=terms).fold(String::from("x"), |sum, n| format!("add({}, {})", sum, n % 9))
+}
+
+/// `x` with `terms` differences added to it, each inside the one before,
+/// so the calls are `terms` deep and each difference is kept while the rest is worked out
+fn nested_sum(terms: usize) -> String {
+    (1..=terms).fold(String::from("x"), |sum, n| format!("add(sub(x, {}), {})", n % 9, sum))
+}
+
+#[test]
+fn the_tape_an_expression_needs_grows_with_its_depth_not_its_length() {
+    let peak = |expression: String, statements: usize| {
+        let source = format!("fn start() {{\n    def x = 1;\n{}    putc(x);\n}}\n", format!("    x = {};\n", expression).repeat(statements));
+        parse(&source).compile().unwrap();
+        peak_stack_ptr()
+    };
+    assert_eq!(peak(sum_chain(3), 1), peak(sum_chain(40), 1));
+    assert_eq!(peak(sum_chain(3), 1), peak(sum_chain(3), 40));
+    assert!(peak(nested_sum(3), 1) < peak(nested_sum(12), 1));
+}
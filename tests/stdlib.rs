@@ -0,0 +1,136 @@
+mod common;
+
+use common::{compile_error, output, output_with_input};
+
+#[test]
+fn putc_prints_a_character_once_or_several_times() {
+    let source = r#"fn start() {
+    putc('a');
+    putc('b', 3);
+    putc('\n');
+}
+"#;
+    assert_eq!(output(source), "abbb\n");
+}
+
+#[test]
+fn puts_prints_a_string_and_a_newline() {
+    let source = r#"fn start() {
+    def s = "value";
+    puts(s);
+    puts(&s);
+    puts("");
+    puts("literal");
+}
+"#;
+    assert_eq!(output(source), "value\nvalue\n\nliteral\n");
+}
+
+#[test]
+fn getc_reads_a_character() {
+    let source = r#"fn start() {
+    def c = getc();
+    putc(add(c, 1));
+}
+"#;
+    assert_eq!(output_with_input(source, b"a"), "b");
+}
+
+#[test]
+fn sizeof_counts_cells() {
+    let source = r#"fn start() {
+    def s = "abc";
+    def n = 5;
+    printf("{} {}\n", sizeof(s), sizeof(n));
+}
+"#;
+    assert_eq!(output(source), "4 1\n");
+}
+
+#[test]
+fn strlen_counts_characters_before_the_terminator() {
+    let source = r#"fn start() {
+    def s = "hello";
+    def p = &s;
+    printf("{} {} {}\n", strlen("abc"), strlen(s), strlen(p));
+}
+"#;
+    assert_eq!(output(source), "3 5 5\n");
+}
+
+#[test]
+fn strlen_of_a_number_is_an_error() {
+    let error = compile_error("fn start() {\n    def n = 5;\n    def len = strlen(n);\n}\n");
+    assert!(error.contains(r#"NotAString("n")"#), "{}", error);
+}
+
+#[test]
+fn streq_compares_strings() {
+    let source = r#"fn start() {
+    def a = "same";
+    def b = "same";
+    def c = "diff";
+    printf("{} {} {}\n", streq(a, b), streq(a, c), streq(&a, &b));
+}
+"#;
+    assert_eq!(output(source), "1 0 1\n");
+}
+
+#[test]
+fn memset_fills_cells() {
+    let source = r#"fn start() {
+    def s = "abcde";
+    memset(&s, 'z', 3);
+    puts(s);
+}
+"#;
+    assert_eq!(output(source), "zzzde\n");
+}
+
+#[test]
+fn getc_echoes_its_input() {
+    let source = r#"fn start() {
+    def c = getc();
+    while c {
+        putc(c);
+        c = getc();
+    }
+}
+"#;
+    assert_eq!(output_with_input(source, b"ab"), "ab");
+}
+
+#[test]
+fn getc_reads_the_eof_value_after_the_input() {
+    // Subtracting a character makes a number, so the EOF value is printed in decimal
+    let source = r#"fn start() {
+    def a = getc();
+    def b = getc();
+    printf("{} {}\n", a, sub(b, '\0'));
+}
+"#;
+    assert_eq!(output_with_input(source, b"x"), "x 0\n");
+    assert_eq!(output_with_input(&format!("#![eof(255)]\n{}", source), b"x"), "x 255\n");
+}
+
+#[test]
+fn printf_prints_numbers_in_decimal() {
+    assert_eq!(output("fn start() {\n    printf(\"x={} y={}\\n\", 7, 300);\n}\n"), "x=7 y=300\n");
+}
+
+#[test]
+fn printf_prints_braces_that_are_doubled() {
+    assert_eq!(output("fn start() {\n    printf(\"{{{}}}\\n\", 5);\n}\n"), "{5}\n");
+}
+
+#[test]
+fn printf_needs_an_argument_for_each_placeholder() {
+    let error = compile_error("fn start() {\n    printf(\"{} and {}\\n\", 1);\n}\n");
+    assert!(
+        error.contains(r#"FormatArgumentMismatch { format: "{} and {}\n", placeholders: 2, arguments: 1 }"#),
+        "{}",
+        error
+    );
+    let error = compile_error("fn start() {\n    printf(\"{}\\n\", 1, 2);\n}\n");
+    assert!(error.contains("placeholders: 1, arguments: 2"), "{}", error);
+}
@@ -0,0 +1,301 @@
+mod common;
+
+use common::{compile_error, output, parse};
+use fr::{peak_stack_ptr, runner, IrOp};
+
+#[test]
+fn strings_and_characters_can_be_joined() {
+    let source = r#"fn start() {
+    def greeting = "Hello" ++ ", " ++ "world" ++ '!';
+    puts(greeting);
+    puts("" ++ 'a' ++ "");
+}
+"#;
+    assert_eq!(output(source), "Hello, world!\na\n");
+}
+
+#[test]
+fn a_string_can_be_built_a_character_at_a_time() {
+    let source = r#"fn start() {
+    def s = "a";
+    repeat 3 {
+        def s = s ++ 'a';
+    }
+    puts(s);
+}
+"#;
+    assert_eq!(output(source), "aaaa\n");
+}
+
+#[test]
+fn joined_strings_are_freed_when_the_function_returns() {
+    let peak = |calls: usize| {
+        let source = format!(
+            "fn shout(name) {{\n    def s = name ++ \"!!!\";\n    puts(s);\n}}\n\nfn start() {{\n{}}}\n",
+            "    shout(\"hello\");\n".repeat(calls)
+        );
+        parse(&source).compile().unwrap();
+        peak_stack_ptr()
+    };
+    assert_eq!(peak(1), peak(5));
+}
+
+#[test]
+fn streq_compares_strings_up_to_their_terminators() {
+    let source = r#"fn start() {
+    printf("{}", streq("abc", "abc"));
+    printf("{}", streq("abc", "abd"));
+    printf("{}", streq("ab", "abc"));
+    printf("{}", streq("abc", "ab"));
+    printf("{}", streq("", ""));
+    printf("{}\n", streq("", "a"));
+}
+"#;
+    assert_eq!(output(source), "100010\n");
+}
+
+#[test]
+fn streq_can_be_a_condition() {
+    let source = r#"fn start() {
+    def word = "quit";
+    if streq(word, "quit") {
+        puts("bye");
+    } else {
+        puts("again");
+    }
+}
+"#;
+    assert_eq!(output(source), "bye\n");
+}
+
+/// Does a program compile to a loop anywhere?
+fn has_loop(source: &str) -> bool {
+    let ir = parse(source).compile_to_ir().unwrap();
+    ir.iter().any(|op| matches!(op, IrOp::LoopBegin(_)))
+}
+
+#[test]
+fn the_length_of_a_literal_or_array_is_known_while_compiling() {
+    let source = r#"fn start() {
+    def table = b"0123456789";
+    def s = "hello";
+    def a = strlen("abc");
+    def b = strlen(s);
+    def c = strlen(table);
+    exit(add(a, add(b, c)));
+}
+"#;
+    assert!(!has_loop(source));
+    assert_eq!(runner::run(source, b"").unwrap().exit_status(), Some(18));
+}
+
+#[test]
+fn the_length_of_a_string_behind_a_reference_is_counted_while_running() {
+    let source = r#"fn length(p) {
+    return strlen(p);
+}
+
+fn start() {
+    def s = "hello, world";
+    exit(length(&s));
+}
+"#;
+    assert!(has_loop(source));
+    assert_eq!(runner::run(source, b"").unwrap().exit_status(), Some(12));
+}
+
+#[test]
+fn the_length_of_a_number_is_an_error() {
+    let error = compile_error("fn start() {\n    def c = 'x';\n    exit(strlen(c));\n}\n");
+    assert!(error.contains(r#"NotAString("c")"#), "{}", error);
+}
+
+#[test]
+fn characters_can_be_read_and_written_by_index() {
+    let source = r#"fn start() {
+    def s = "hello";
+    def i = 4;
+    s[0] = 'j';
+    s[i] = 'y';
+    def p = &s;
+    p[1] = 'e';
+    printf("{} {} {}\n", s[1], p[i], s);
+}
+"#;
+    // Only the cells of a string value are known to be characters
+    assert_eq!(output(source), "e 121 jelly\n");
+}
+
+#[test]
+fn a_character_can_be_copied_to_where_it_already_is() {
+    let source = r#"fn start() {
+    def s = "abc";
+    def p = &s;
+    def i = 1;
+    s[i] = s[i];
+    p[i] = p[i];
+    p[0] = s[i];
+    puts(s);
+}
+"#;
+    assert_eq!(output(source), "bbc\n");
+}
+
+#[test]
+fn writing_a_terminator_ends_a_string_early() {
+    let source = r#"fn start() {
+    def s = "hello";
+    def i = 2;
+    s[i] = 1;
+    def p = &s;
+    printf("{}\n", strlen(p));
+    puts(p);
+}
+"#;
+    assert_eq!(output(source), "2\nhe\n");
+}
+
+#[test]
+fn a_constant_index_past_the_end_is_an_error() {
+    let error = compile_error("fn start() {\n    def s = \"abc\";\n    s[3] = 'x';\n}\n");
+    assert!(error.contains("IndexOutOfRange { index: 3, length: 3 }"), "{}", error);
+}
+
+#[test]
+fn strings_can_span_lines() {
+    let source = "fn start() {\n    puts(\"two\n  lines // not a comment\");\n}\n";
+    assert_eq!(output(source), "two\n  lines // not a comment\n");
+}
+
+#[test]
+fn raw_strings_keep_their_backslashes_and_quotes() {
+    let source = r##"fn start() {
+    puts(r"C:\path\n");
+    puts(r#"say "hi" /* not a comment */"#);
+}
+"##;
+    assert_eq!(output(source), "C:\\path\\n\nsay \"hi\" /* not a comment */\n");
+}
+
+#[test]
+fn comments_after_strings_are_still_comments() {
+    let source = "fn start() {\n    puts(\"a // b\"); // c\n    /* \"d\" */\n    puts(r\"e\"); // \"f\n}\n";
+    assert_eq!(output(source), "a // b\ne\n");
+}
+
+/// A program that prints the same literal five times, with or without sharing it
+fn five_uses(flags: &str) -> String {
+    format!("{}fn start() {{\n{}}}\n", flags, "    puts(\"a literal used five times\");\n".repeat(5))
+}
+
+#[test]
+fn a_repeated_literal_is_only_written_once() {
+    let shared = parse(&five_uses("")).compile().unwrap();
+    let copied = parse(&five_uses("#![disable_interning]\n")).compile().unwrap();
+    assert!(shared.len() < copied.len(), "{} >= {}", shared.len(), copied.len());
+    assert_eq!(output(&five_uses("")), "a literal used five times\n".repeat(5));
+    assert_eq!(output(&five_uses("#![disable_interning]\n")), "a literal used five times\n".repeat(5));
+}
+
+#[test]
+fn changing_one_use_of_a_literal_leaves_the_others_alone() {
+    let source = r#"fn start() {
+    def a = "abc";
+    a[0] = 'x';
+    puts(a);
+    puts("abc");
+    def b = "abc";
+    puts(b);
+}
+"#;
+    assert_eq!(output(source), "xbc\nabc\nabc\n");
+}
+
+#[test]
+fn a_byte_string_is_a_lookup_table() {
+    let source = r#"fn start() {
+    def digits = b"0123456789abcdef";
+    def n = 181;
+    def high = 0;
+    def low = 0;
+    def carry = 0;
+    while n {
+        n = sub(n, 1);
+        low = add(low, 1);
+        carry = sub(16, low);
+        if carry {
+            carry = 0;
+        } else {
+            low = 0;
+            high = add(high, 1);
+        }
+    }
+    putc(digits[high]);
+    putc(digits[low]);
+    putc('\n');
+    exit(sizeof(digits));
+}
+"#;
+    let outcome = runner::run(source, b"").unwrap();
+    assert_eq!(String::from_utf8_lossy(outcome.output()), "b5\n");
+    assert_eq!(outcome.exit_status(), Some(16));
+}
+
+#[test]
+fn changing_a_byte_string_leaves_the_same_literal_alone() {
+    let source = r#"fn start() {
+    def digits = b"0123";
+    digits[0] = 'x';
+    putc(digits[0]);
+    puts("0123");
+    puts("0123");
+}
+"#;
+    assert_eq!(output(source), "x0123\n0123\n");
+}
+
+/// A program that prints the characters of a string up to a runtime index
+fn index_up_to(flags: &str) -> String {
+    format!(
+        r#"{}fn start() {{
+    def s = "abc";
+    def after = 'z';
+    def i = 0;
+    def left = sub(getc(), '0');
+    while left {{
+        putc(s[i]);
+        i = add(i, 1);
+        left = sub(left, 1);
+    }}
+    putc(after);
+    putc('\n');
+}}
+"#,
+        flags
+    )
+}
+
+#[test]
+fn indexes_in_bounds_work_the_same_with_bounds_checks() {
+    let unchecked = runner::run(&index_up_to(""), b"3").unwrap();
+    let checked = runner::run(&index_up_to("#![bounds_checks]\n"), b"3").unwrap();
+    assert_eq!(unchecked.output(), b"abcz\n");
+    assert_eq!(checked.output(), b"abcz\n");
+    assert_eq!(checked.exit_status(), None);
+}
+
+#[test]
+fn an_index_past_the_end_stops_the_program_with_bounds_checks() {
+    let outcome = runner::run(&index_up_to("#![bounds_checks]\n"), b"5").unwrap();
+    assert_eq!(String::from_utf8_lossy(outcome.output()), "abcINDEX OUT OF RANGE in s\n");
+    assert_eq!(outcome.exit_status(), Some(1));
+}
+
+#[test]
+fn an_index_past_the_end_reads_whatever_comes_next_without_bounds_checks() {
+    let outcome = runner::run(&index_up_to(""), b"5").unwrap();
+    assert!(outcome.is_finished());
+    assert!(outcome.output().starts_with(b"abc"), "{:?}", outcome.output());
+    assert!(outcome.output().ends_with(b"z\n"), "{:?}", outcome.output());
+    assert_eq!(outcome.output().len(), 7);
+}
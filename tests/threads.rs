@@ -0,0 +1,91 @@
+use fr::{peak_stack_ptr, runner, Program};
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// A program that prints a character `n` times, through a call
+fn repeat(ch: char, n: usize) -> String {
+    format!(
+        "fn show(c) {{\n    putc(c);\n}}\n\nfn start() {{\n    def i = {};\n    while i {{\n        show('{}');\n        i = sub(i, 1);\n    }}\n}}\n",
+        n, ch
+    )
+}
+
+#[test]
+fn programs_compile_on_different_threads_at_once() {
+    let threads: Vec<_> = [('a', 3), ('b', 5), ('c', 7), ('d', 1)]
+        .iter()
+        .map(|&(ch, n)| {
+            thread::spawn(move || {
+                let mut outputs = vec![];
+                for _ in 0..20 {
+                    let outcome = runner::run(&repeat(ch, n), b"").unwrap();
+                    outputs.push(String::from_utf8(outcome.output().to_vec()).unwrap());
+                }
+                (ch, n, outputs)
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        let (ch, n, outputs) = thread.join().unwrap();
+        for output in outputs {
+            assert_eq!(output, ch.to_string().repeat(n));
+        }
+    }
+}
+
+#[test]
+fn each_thread_has_its_own_stack() {
+    let small = thread::spawn(|| {
+        runner::run("fn start() {\n    putc('a');\n}\n", b"").unwrap();
+        peak_stack_ptr()
+    });
+    let large = thread::spawn(|| {
+        runner::run("fn start() {\n    def s = \"a much longer string than one cell\";\n    puts(s);\n}\n", b"").unwrap();
+        peak_stack_ptr()
+    });
+    let (small, large) = (small.join().unwrap(), large.join().unwrap());
+    assert!(small < large, "{} {}", small, large);
+
+    // The same small program on this thread needs the same stack
+    runner::run("fn start() {\n    putc('a');\n}\n", b"").unwrap();
+    assert_eq!(peak_stack_ptr(), small);
+}
+
+/// Output that records when it is dropped
+struct Watched(Arc<AtomicBool>);
+
+impl Write for Watched {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Watched {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn a_threads_state_is_dropped_when_it_ends() {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let output = Watched(dropped.clone());
+    thread::spawn(move || {
+        Program::set_output(Some(Box::new(output)));
+        runner::run("fn start() {\n    putc('a');\n}\n", b"").unwrap();
+    })
+    .join()
+    .unwrap();
+    assert!(dropped.load(Ordering::SeqCst));
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use common::{compile_error, output, parse};
+use fr::{defforeign, peak_stack_ptr};
+
+#[test]
+fn functions_can_declare_what_they_return() {
+    let source = r#"fn next(c) -> char {
+    return add(c, 1);
+}
+
+fn twice(n) -> u16 {
+    return add(n, n);
+}
+
+fn greet() -> void {
+    puts("hi");
+}
+
+fn start() {
+    greet();
+    printf("{} {}\n", next('a'), twice(21));
+}
+"#;
+    assert_eq!(output(source), "hi\nb 42\n");
+}
+
+#[test]
+fn returning_the_wrong_type_is_an_error() {
+    let error = compile_error(
+        r#"fn name() -> byte {
+    return "abc";
+}
+
+fn start() {
+    def n = name();
+}
+"#,
+    );
+    assert!(error.contains(r#"ReturnTypeMismatch { function: "name", expected: Byte, got: Str }"#), "{}", error);
+}
+
+#[test]
+fn a_void_function_cant_return_a_value() {
+    let error = compile_error(
+        r#"fn nothing() -> void {
+    return 1;
+}
+
+fn start() {
+    nothing();
+}
+"#,
+    );
+    assert!(error.contains(r#"ReturnTypeMismatch { function: "nothing", expected: Void, got: Byte }"#), "{}", error);
+}
+
+#[test]
+fn a_function_that_declares_a_value_must_return_one() {
+    let error = compile_error(
+        r#"fn maybe(n) -> u16 {
+    if n {
+        return 1;
+    }
+}
+
+fn start() {
+    def x = maybe(1);
+}
+"#,
+    );
+    assert!(error.contains(r#"ReturnTypeMismatch { function: "maybe", expected: Short, got: Void }"#), "{}", error);
+}
+
+#[test]
+fn arguments_are_checked_against_parameter_types() {
+    let source = r#"fn first(s: ref) -> char {
+    return *s;
+}
+
+fn shout(c: char, times: byte) {
+    putc(c, times);
+}
+
+fn start() {
+    def s = "xyz";
+    shout(first(&s), 3);
+    putc('\n');
+}
+"#;
+    assert_eq!(output(source), "xxx\n");
+}
+
+#[test]
+fn passing_a_string_for_a_byte_is_an_error() {
+    let error = compile_error(
+        r#"fn shout(c: byte) {
+    putc(c);
+}
+
+fn start() {
+    shout("abc");
+}
+"#,
+    );
+    assert!(
+        error.contains(r#"ArgumentTypeMismatch { function: "shout", parameter: "c", expected: Byte, got: Str }"#),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn passing_a_value_for_a_reference_is_an_error() {
+    let error = compile_error(
+        r#"fn first(s: ref) -> char {
+    return *s;
+}
+
+fn start() {
+    def c = first(65);
+}
+"#,
+    );
+    assert!(
+        error.contains(r#"ArgumentTypeMismatch { function: "first", parameter: "s", expected: Ref, got: Byte }"#),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn foreign_functions_can_declare_parameter_types() {
+    let program = parse("fn start() {\n    double(\"ab\");\n}\n");
+    defforeign("double", &["x: byte", "-> byte"], |args| Ok(Some(args[0])));
+    let error = program.compile().unwrap_err().to_string();
+    assert!(
+        error.contains(r#"ArgumentTypeMismatch { function: "double", parameter: "x", expected: Byte, got: Str }"#),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn void_calls_dont_take_tape() {
+    let peak = |calls: usize| {
+        let source = format!(
+            "fn log(c) {{\n    putc(c);\n}}\n\nfn start() {{\n    def i = 3;\n    while i {{\n{}        i = sub(i, 1);\n    }}\n{}}}\n",
+            "        log('a');\n".repeat(calls),
+            "    log('b');\n".repeat(calls)
+        );
+        parse(&source).compile().unwrap();
+        peak_stack_ptr()
+    };
+    assert_eq!(peak(1), peak(20));
+}
+
+#[test]
+fn the_result_of_a_void_call_cant_be_used() {
+    let error = compile_error("fn log(c) {\n    putc(c);\n}\n\nfn start() {\n    def x = log('a');\n}\n");
+    assert!(error.contains(r#"VoidValueUsed("log")"#), "{}", error);
+    let error = compile_error("fn start() {\n    def x = 1;\n    x = putc('a');\n}\n");
+    assert!(error.contains(r#"VoidValueUsed("putc")"#), "{}", error);
+}
+
+#[test]
+fn foreign_functions_can_be_void() {
+    let program = parse("fn start() {\n    def x = nothing(1);\n}\n");
+    defforeign("nothing", &["x", "-> void"], |_| Ok(None));
+    let error = program.compile().unwrap_err().to_string();
+    assert!(error.contains(r#"VoidValueUsed("nothing")"#), "{}", error);
+
+    let program = parse("fn start() {\n    nothing(1);\n    putc('a');\n}\n");
+    defforeign("nothing", &["x", "-> void"], |_| Ok(None));
+    program.compile().unwrap();
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use common::{compile_error, output};
+use fr::{runner, Program};
+
+#[test]
+fn characters_must_be_ascii() {
+    let (_, errors) = Program::parse_all("fn start() {\n    putc('é');\n}\n");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].to_string().ends_with("syntax error: character 'é' is not ASCII"), "{}", errors[0]);
+
+    let (_, errors) = Program::parse_all("#![utf8_strings]\nfn start() {\n    putc('é');\n}\n");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+}
+
+#[test]
+fn strings_must_be_ascii_without_utf8_strings() {
+    let error = compile_error("fn start() {\n    puts(\"héllo\");\n}\n");
+    assert!(error.starts_with("2:5: ") && error.contains("NonAsciiString(\"héllo\")"), "{}", error);
+}
+
+#[test]
+fn utf8_strings_are_stored_as_their_bytes() {
+    let source = r#"#![utf8_strings]
+fn start() {
+    def s = "héllo";
+    puts(s);
+    printf("{} {}\n", strlen(s), sizeof(s));
+    printf("{}\n", strlen("日本"));
+}
+"#;
+    assert_eq!(output(source), "héllo\n6 7\n6\n");
+}
+
+#[test]
+fn indexing_a_utf8_string_gets_a_byte() {
+    let source = "#![utf8_strings]\nfn start() {\n    def s = \"é!\";\n    printf(\"{} {}\", s[0], s[2]);\n}\n";
+    let outcome = runner::run(source, b"").unwrap();
+    assert_eq!(outcome.output(), [0xc3, b' ', b'!']);
+}
@@ -0,0 +1,133 @@
+use fr::{rebuild_eval, rebuild_expr, walk_eval, Define, Eval, Expr, Fold, Load, Program, UserFn, Visit};
+use std::collections::BTreeMap;
+
+const FIXTURE: &str = r#"fn double(x) {
+    def y = add(x, x);
+    return y;
+}
+
+fn start() {
+    def x = double(3);
+    if x {
+        x = sub(x, 1);
+    }
+    while x {
+        putc(add('0', x));
+        x = sub(x, 1);
+    }
+    def p = &x;
+    putc(*p);
+}
+"#;
+
+/// Counts the calls to each function
+#[derive(Default)]
+struct CallCounter(BTreeMap<String, usize>);
+
+impl Visit for CallCounter {
+    fn visit_eval(&mut self, eval: &Eval) {
+        if let Eval::Call(call) = eval {
+            *self.0.entry(call.name().to_string()).or_default() += 1;
+        }
+        walk_eval(self, eval)
+    }
+}
+
+/// Renames a variable
+struct Rename {
+    from: &'static str,
+    to: &'static str,
+}
+
+impl Fold for Rename {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Define(define) if define.name() == self.from => {
+                let value = self.fold_eval(define.value().clone());
+                Expr::Define(Define::new(self.to, value))
+            }
+            expr => rebuild_expr(self, expr),
+        }
+    }
+
+    fn fold_eval(&mut self, eval: Eval) -> Eval {
+        match eval {
+            Eval::Load(load) if load.name() == self.from => Eval::Load(Load::new(self.to)),
+            eval => rebuild_eval(self, eval),
+        }
+    }
+}
+
+/// The fixture with `x` renamed in `start` only
+const RENAMED: &str = r#"fn double(x) {
+    def y = add(x, x);
+    return y;
+}
+
+fn start() {
+    def count = double(3);
+    if count {
+        count = sub(count, 1);
+    }
+    while count {
+        putc(add('0', count));
+        count = sub(count, 1);
+    }
+    def p = &count;
+    putc(*p);
+}
+"#;
+
+/// Rename `x` to `count` in `start`
+fn rename_in_start(program: &Program) -> Vec<UserFn> {
+    let mut rename = Rename { from: "x", to: "count" };
+    program
+        .functions()
+        .iter()
+        .cloned()
+        .map(|fun| if fun.name() == "start" { rename.fold_user_fn(fun) } else { fun })
+        .collect()
+}
+
+fn fixture() -> Program {
+    Program::from(FIXTURE).without_spans()
+}
+
+#[test]
+fn a_visitor_counts_the_calls_in_every_function() {
+    let mut counter = CallCounter::default();
+    for fun in fixture().functions() {
+        counter.visit_user_fn(fun);
+    }
+    let counts: Vec<(&str, usize)> = counter.0.iter().map(|(name, count)| (name.as_str(), *count)).collect();
+    assert_eq!(counts, [("add", 2), ("double", 1), ("putc", 2), ("sub", 2)]);
+}
+
+#[test]
+fn a_visitor_sees_calls_nested_in_arguments() {
+    let mut counter = CallCounter::default();
+    for fun in Program::from("fn start() {\n    putc(add(add(1, 2), sub(4, 3)));\n}\n").functions() {
+        counter.visit_user_fn(fun);
+    }
+    assert_eq!(counter.0.values().sum::<usize>(), 4);
+    assert_eq!(counter.0["add"], 2);
+}
+
+#[test]
+fn a_fold_renames_a_variable() {
+    let program = fixture();
+    let renamed = Program::new(program.flags().to_vec(), rename_in_start(&program));
+    assert_eq!(renamed, Program::from(RENAMED).without_spans());
+}
+
+#[test]
+fn folding_a_shared_tree_leaves_the_original_alone() {
+    let program = fixture();
+    let shared = program.clone();
+    let renamed = rename_in_start(&shared);
+    assert_ne!(renamed, program.functions());
+
+    // The reference in `def p = &x;` is behind an Arc both copies point at
+    assert_eq!(program, fixture());
+    assert_eq!(shared, fixture());
+}